@@ -0,0 +1,72 @@
+use alloc::string::String;
+use core::str::from_utf8;
+
+use crate::fs::vfs::{File, Vfs};
+use crate::mutex::Mutex;
+
+/// Settings for the apps, as `key=value` lines. Blank lines and lines
+/// starting with `#` are ignored.
+pub const CONFIG_PATH: &str = "/disk/config.txt";
+
+pub struct Config {
+    /// The address the network services answer on
+    pub ip: [u8; 4],
+    /// UDP port of the remote shell
+    pub port: u16,
+    pub prompt: String,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            ip: [192, 168, 14, 4],
+            port: 44,
+            prompt: "$> ".into(),
+        }
+    }
+}
+
+fn parse_ip(value: &str) -> Option<[u8; 4]> {
+    let mut ip = [0; 4];
+    let mut octets = value.split('.');
+    for octet in ip.iter_mut() {
+        *octet = octets.next()?.parse().ok()?;
+    }
+    match octets.next() {
+        Some(_) => None,
+        None => Some(ip),
+    }
+}
+
+impl Config {
+    /// Reads `CONFIG_PATH`, keeping the defaults for anything it doesn't set
+    /// or sets to something unusable, and all of them if it can't be read
+    pub fn load(vfs: &Mutex<Vfs>) -> Config {
+        let file = vfs.lock().open(CONFIG_PATH, 0);
+        let text = file.and_then(|mut file| file.read_to_end());
+        match text {
+            Ok(text) => Config::parse(from_utf8(&text).unwrap_or("")),
+            Err(_) => Config::default(),
+        }
+    }
+
+    pub fn parse(text: &str) -> Config {
+        let mut config = Config::default();
+        for line in text.lines().map(str::trim) {
+            if line.starts_with('#') {
+                continue;
+            }
+            let (key, value) = match line.find('=') {
+                Some(equals) => (line[..equals].trim(), line[equals + 1..].trim()),
+                None => continue,
+            };
+            match key {
+                "ip" => config.ip = parse_ip(value).unwrap_or(config.ip),
+                "port" => config.port = value.parse().unwrap_or(config.port),
+                "prompt" => config.prompt = value.into(),
+                _ => {}
+            }
+        }
+        config
+    }
+}
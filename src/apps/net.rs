@@ -1,20 +1,35 @@
+use alloc::vec;
+
 use crate::utils::*;
-use crate::virtio::VirtIONet;
+use crate::virtio::{Offload, VirtIONet};
 
 type BEU16 = Endian<u16, Big>;
 
+/// Where the ICMP and UDP checksums start in a frame, after the Ethernet and
+/// IP headers
+const L4_START: u16 = 14 + 20;
+const ICMP_CHECKSUM: Offload = Offload {
+    checksum: Some((L4_START, 2)),
+    segmentation: None,
+};
+const UDP_CHECKSUM: Offload = Offload {
+    checksum: Some((L4_START, 6)),
+    segmentation: None,
+};
+
 fn checksum(payload: &[u8]) -> u16 {
-    let mut sum: u32 = 0;
-    for i in 0..(payload.len() / 2) {
-        sum += (payload[i * 2 + 1] as u32) | ((payload[i * 2] as u32) << 8);
-    }
-    if payload.len() % 2 != 0 {
-        sum += payload[payload.len() - 1] as u32;
-    }
-    while (sum >> 16) != 0 {
-        sum = (sum & 0xffff) + (sum >> 16);
-    }
-    return !(sum as u16);
+    !ones_complement_sum(0, payload)
+}
+
+/// Sum of the IPv4 pseudo-header that TCP and UDP checksums cover, for a
+/// segment of `len` bytes
+fn pseudo_header_sum(ip: &IpHeader, len: usize) -> u16 {
+    let mut pseudo = [0; 12];
+    pseudo[..4].copy_from_slice(&ip.src_addr);
+    pseudo[4..8].copy_from_slice(&ip.dst_addr);
+    pseudo[9] = ip.protocol;
+    pseudo[10..].copy_from_slice(&(len as u16).to_be_bytes());
+    ones_complement_sum(0, &pseudo)
 }
 
 #[repr(C)]
@@ -67,13 +82,45 @@ struct ICMP {
 
 pub struct Net<'a, 'b: 'a> {
     pub net: &'a mut VirtIONet<'b>,
+    /// Our IPv4 address
+    pub ip: [u8; 4],
+    /// UDP port the shell listens on
+    pub port: u16,
 }
 
 impl<'a, 'b> Net<'a, 'b> {
+    /// Broadcasts a gratuitous ARP so switches learn where our MAC address
+    /// went, after the VM was migrated
+    fn announce(&mut self) {
+        let mut buf = [0u8; 14 + core::mem::size_of::<Arp>()];
+        let eth_header = unsafe { &mut *(buf.as_mut_ptr() as *mut EthernetHeader) };
+        eth_header.dst_mac = [0xff; 6];
+        eth_header.src_mac = self.net.mac();
+        eth_header.ethertype = 0x0806.into();
+        let arp = unsafe { &mut *(buf[14..].as_mut_ptr() as *mut Arp) };
+        arp.hw_type = 1.into();
+        arp.protocol_type = 0x0800.into();
+        arp.hw_addr_len = 6;
+        arp.proto_addr_len = 4;
+        arp.operation = 1.into();
+        arp.sender_hw_addr = self.net.mac();
+        arp.sender_proto_addr = self.ip;
+        arp.target_proto_addr = self.ip;
+        let _ = self.net.write(&buf);
+        let _ = self.net.announced();
+    }
+
     pub fn run(&mut self, shell: &mut super::shell::Shell) {
-        let mut buf = [0; 1526];
+        let mut buf = vec![0; self.net.max_receive_len()];
         loop {
-            self.net.read(&mut buf);
+            // Every pair sees the request, but only one announces
+            if self.net.take_announce() {
+                self.announce();
+            }
+            let received = self.net.read_offloaded(&mut buf);
+            if received.len < 14 {
+                continue;
+            }
             let eth_header = unsafe { &mut *(buf.as_ptr() as *mut EthernetHeader) };
             let eth_payload = &buf[14..];
             match eth_header.ethertype.native() {
@@ -86,32 +133,47 @@ impl<'a, 'b> Net<'a, 'b> {
                     ) == (1, 0x0800, 1)
                     {
                         // ARP request
-                        if arp.target_proto_addr == [192, 168, 14, 4] {
+                        if arp.target_proto_addr == self.ip {
                             eth_header.dst_mac = eth_header.src_mac;
-                            eth_header.src_mac = self.net.config().mac;
+                            eth_header.src_mac = self.net.mac();
 
                             arp.operation = 2.into();
 
                             arp.target_hw_addr = arp.sender_hw_addr;
                             arp.target_proto_addr = arp.sender_proto_addr;
 
-                            arp.sender_hw_addr = self.net.config().mac;
-                            arp.sender_proto_addr = [192, 168, 14, 4];
-                            self.net.write(&mut buf);
+                            arp.sender_hw_addr = self.net.mac();
+                            arp.sender_proto_addr = self.ip;
+                            let _ = self.net.write(&buf[..14 + core::mem::size_of::<Arp>()]);
                         }
                     }
                 }
                 0x0800 => {
                     let ip = unsafe { &mut *(eth_payload.as_ptr() as *mut IpHeader) };
                     let ip_payload = &eth_payload[20..];
-                    if ip.dst_addr == [192, 168, 14, 4] {
+                    let ip_len = ip.length.native() as usize;
+                    if received.len < 14 + 20 || ip_len < 20 + 8 || 14 + ip_len > received.len {
+                        continue;
+                    }
+                    // Checked here unless the device already has
+                    let l4_valid = received.checksum_valid
+                        || match ip.protocol {
+                            0x1 => checksum(&ip_payload[..ip_len - 20]) == 0,
+                            0x11 if ip_payload[6..8] == [0, 0] => true,
+                            0x11 => {
+                                let sum = pseudo_header_sum(ip, ip_len - 20);
+                                !ones_complement_sum(sum as u32, &ip_payload[..ip_len - 20]) == 0
+                            }
+                            _ => true,
+                        };
+                    if ip.dst_addr == self.ip && l4_valid {
                         if ip.protocol == 0x1 {
                             // ICMP
                             eth_header.dst_mac = eth_header.src_mac;
-                            eth_header.src_mac = self.net.config().mac;
+                            eth_header.src_mac = self.net.mac();
 
                             ip.dst_addr = ip.src_addr;
-                            ip.src_addr = [192, 168, 14, 4];
+                            ip.src_addr = self.ip;
                             ip.id = 0.into();
                             ip.flags_offset = 0.into();
                             ip.checksum = 0.into();
@@ -120,21 +182,23 @@ impl<'a, 'b> Net<'a, 'b> {
                             let icmp = unsafe { &mut *(ip_payload.as_ptr() as *mut ICMP) };
                             icmp.icmp_type = 0.into();
                             icmp.checksum = 0.into();
-                            icmp.checksum =
-                                checksum(&ip_payload[..(ip.length.native() as usize - 20)]).into();
 
-                            self.net.write(&mut buf);
-                        } else if ip.protocol == 0x11 && ip_payload[2] == 0 && ip_payload[3] == 44 {
-                            // UDP port 44
+                            let _ = self
+                                .net
+                                .write_offloaded(&buf[..14 + ip_len], &ICMP_CHECKSUM);
+                        } else if ip.protocol == 0x11
+                            && u16::from_be_bytes([ip_payload[2], ip_payload[3]]) == self.port
+                        {
+                            // UDP to the shell's port
                             let length = (ip_payload[4] as usize) << 8 | ip_payload[5] as usize;
                             let mut line = [0; 1024];
                             line[..(length - 8)].copy_from_slice(&ip_payload[8..length]);
 
                             eth_header.dst_mac = eth_header.src_mac;
-                            eth_header.src_mac = self.net.config().mac;
+                            eth_header.src_mac = self.net.mac();
 
                             ip.dst_addr = ip.src_addr;
-                            ip.src_addr = [192, 168, 14, 4];
+                            ip.src_addr = self.ip;
                             ip.id = 0.into();
                             ip.flags_offset = 0.into();
 
@@ -166,10 +230,14 @@ impl<'a, 'b> Net<'a, 'b> {
                                 };
                                 udp_packet[4] = ((8 + output.len()) >> 8) as u8;
                                 udp_packet[5] = output.len() as u8 + 8;
-                                udp_packet[6] = 0;
-                                udp_packet[7] = 0;
+                                udp_packet[6..8].copy_from_slice(
+                                    &pseudo_header_sum(ip, 8 + output.len()).to_be_bytes(),
+                                );
                                 udp_packet[8..(8 + output.len())].copy_from_slice(output);
-                                self.net.write(&buf);
+                                let _ = self.net.write_offloaded(
+                                    &buf[..14 + ip.length.native() as usize],
+                                    &UDP_CHECKSUM,
+                                );
                             });
                             if exit {
                                 return;
@@ -187,10 +255,13 @@ impl<'a, 'b> Net<'a, 'b> {
                             };
                             udp_packet[4] = ((8 + 1) >> 8) as u8;
                             udp_packet[5] = 1 as u8 + 8;
-                            udp_packet[6] = 0;
-                            udp_packet[7] = 0;
+                            udp_packet[6..8]
+                                .copy_from_slice(&pseudo_header_sum(ip, 8 + 1).to_be_bytes());
                             udp_packet[8] = b'\n';
-                            self.net.write(&buf);
+                            let _ = self.net.write_offloaded(
+                                &buf[..14 + ip.length.native() as usize],
+                                &UDP_CHECKSUM,
+                            );
                         }
                     }
                 }
@@ -1,12 +1,173 @@
+use alloc::boxed::Box;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use alloc::{format, vec};
 use core::str::from_utf8;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 
+use super::config::Config;
+use crate::block::{
+    read_partitions, BlockDevice, BufferCache, CryptDevice, Locked, Partition, PerCore, Sharded,
+    DEFAULT_ITERATIONS, SALT_SIZE,
+};
+use crate::fs::kv::KvStore;
+use crate::fs::vfs::{File, Vfs, O_CREATE, O_TRUNCATE, O_WRITE};
+use crate::fs::{FileType, FsError};
 use crate::mutex::Mutex;
 use crate::uart::UART;
-use crate::virtio::{VirtIOBlk, VirtIOEntropy};
+use crate::utils::{counter, counter_frequency};
+use crate::virtio::{BlkError, NetStats, VirtIOBlk, VirtIOEntropy, SECTOR_SIZE};
+use crate::xmodem::{Receiver, Sender, Serial, TransferError};
 
 pub struct Shell<'a, 'b> {
     pub blk: &'a Mutex<Option<VirtIOBlk<'b>>>,
     pub entropy: &'a Mutex<Option<VirtIOEntropy<'b>>>,
+    /// Buffer cache over the disk, which `read`, `writerand`, `sync` and
+    /// `trim` go through, in shards so cores seldom wait for each other. It
+    /// misses to the calling core's queue, like `blk`.
+    pub cache: &'a [Mutex<Option<BufferCache<PerCore<'b, VirtIOBlk<'b>>>>>],
+    /// The mounted filesystems `ls`, `cat` and the other file commands use
+    pub vfs: &'a Mutex<Vfs>,
+    pub kv: &'a Mutex<Option<KvStore<Box<dyn BlockDevice + Sync + 'b>>>>,
+    /// The device `cryptsetup open` set up, which is `crypt0`
+    pub crypt: &'a Mutex<Option<CryptDevice<Box<dyn BlockDevice + Sync + 'a>>>>,
+    /// The UART passphrases are typed on. The network shell has none, so
+    /// they never cross the network in the clear.
+    pub console: Option<&'a Mutex<Option<UART>>>,
+    /// Counters of the network device, which the network thread holds
+    pub net_stats: &'a Mutex<Option<Arc<NetStats>>>,
+}
+
+/// Reports a failed block operation, returning the result if it succeeded
+fn report_blk_error<T, F: FnMut(&[u8])>(
+    result: Option<Result<T, BlkError>>,
+    mut f: F,
+) -> Option<T> {
+    match result {
+        Some(Ok(result)) => return Some(result),
+        Some(Err(err)) => {
+            f(b"Error: ");
+            f(err.as_str().as_bytes());
+        }
+        None => f(b"Error: no block device"),
+    }
+    None
+}
+
+/// Reports a failed filesystem operation, returning the result if it
+/// succeeded
+fn report_fs_error<T, F: FnMut(&[u8])>(result: Result<T, FsError>, mut f: F) -> Option<T> {
+    match result {
+        Ok(result) => Some(result),
+        Err(err) => {
+            f(b"Error: ");
+            f(err.as_str().as_bytes());
+            None
+        }
+    }
+}
+
+fn report_transfer_error<F: FnMut(&[u8])>(err: TransferError, mut f: F) {
+    f(b"Error: ");
+    f(err.as_str().as_bytes());
+}
+
+/// The next word as a path, `/` if there isn't one
+fn path_arg<'w>(words: &mut dyn Iterator<Item = &'w [u8]>) -> &'w str {
+    words
+        .next()
+        .and_then(|path| from_utf8(path).ok())
+        .unwrap_or("/")
+}
+
+/// Where a transfer's data goes or comes from
+enum Target<'d> {
+    File(String),
+    /// A device, from a block on
+    Device(Box<dyn BlockDevice + Sync + 'd>, u64),
+}
+
+/// Gathers a stream of bytes into whole blocks written to a device
+struct BlockWriter<'d> {
+    device: Box<dyn BlockDevice + Sync + 'd>,
+    next: u64,
+    pending: Vec<u8>,
+}
+
+impl<'d> BlockWriter<'d> {
+    fn write(&mut self, data: &[u8]) -> Result<(), &'static str> {
+        self.pending.extend_from_slice(data);
+        let block_size = self.device.block_size();
+        let whole = self.pending.len() / block_size * block_size;
+        if whole > 0 {
+            self.device
+                .write(self.next, &self.pending[..whole])
+                .map_err(|err| err.as_str())?;
+            self.next += (whole / block_size) as u64;
+            self.pending.drain(..whole);
+        }
+        Ok(())
+    }
+
+    /// Writes what's left, padded with zeros to a whole block
+    fn finish(&mut self) -> Result<(), &'static str> {
+        if !self.pending.is_empty() {
+            let block_size = self.device.block_size();
+            let len = (self.pending.len() + block_size - 1) / block_size * block_size;
+            self.pending.resize(len, 0);
+            self.device
+                .write(self.next, &self.pending)
+                .map_err(|err| err.as_str())?;
+            self.pending.clear();
+        }
+        self.device.flush().map_err(|err| err.as_str())
+    }
+}
+
+/// Reads a run of blocks from a device as a stream of bytes
+struct BlockReader<'d> {
+    device: Box<dyn BlockDevice + Sync + 'd>,
+    next: u64,
+    end: u64,
+    block: Vec<u8>,
+    /// How much of `block` has been read
+    offset: usize,
+}
+
+impl<'d> BlockReader<'d> {
+    fn new(device: Box<dyn BlockDevice + Sync + 'd>, start: u64, count: Option<u64>) -> Self {
+        let block_size = device.block_size();
+        let available = device.block_count().saturating_sub(start);
+        BlockReader {
+            end: start + count.unwrap_or(available).min(available),
+            device,
+            next: start,
+            block: vec![0; block_size],
+            offset: block_size,
+        }
+    }
+
+    fn len(&self) -> u64 {
+        (self.end - self.next) * self.block.len() as u64
+    }
+
+    fn read(&mut self, data: &mut [u8]) -> Result<usize, &'static str> {
+        if self.offset == self.block.len() {
+            if self.next == self.end {
+                return Ok(0);
+            }
+            self.device
+                .read(self.next, &mut self.block)
+                .map_err(|err| err.as_str())?;
+            self.next += 1;
+            self.offset = 0;
+        }
+        let len = data.len().min(self.block.len() - self.offset);
+        data[..len].copy_from_slice(&self.block[self.offset..self.offset + len]);
+        self.offset += len;
+        Ok(len)
+    }
 }
 
 impl<'a, 'b> Shell<'a, 'b> {
@@ -17,87 +178,745 @@ impl<'a, 'b> Shell<'a, 'b> {
         f(&data);
     }
 
-    fn write_random<F: FnMut(&[u8])>(&mut self, words: &mut dyn Iterator<Item = &[u8]>, mut f: F) {
-        let mut sector = words
-            .next()
+    /// The device called `name`: `blk0` for the whole disk, or `blk0pN` for
+    /// one of its partitions, either through the buffer cache, or `crypt0`
+    /// for the opened encrypted device
+    fn open(&self, name: &[u8]) -> Result<Box<dyn BlockDevice + Sync + 'a>, BlkError> {
+        if name == b"crypt0" {
+            if self.crypt.lock().is_none() {
+                return Err(BlkError::NoDevice);
+            }
+            return Ok(Box::new(Locked(self.crypt)));
+        }
+        let mut disk = Sharded(self.cache);
+        if disk.block_count() == 0 {
+            return Err(BlkError::NoDevice);
+        }
+        match name.strip_prefix(b"blk0") {
+            Some(b"") => Ok(Box::new(disk)),
+            Some(number) => {
+                let number = number
+                    .strip_prefix(b"p")
+                    .and_then(|number| from_utf8(number).ok())
+                    .and_then(|number| number.parse::<u32>().ok())
+                    .ok_or(BlkError::NoDevice)?;
+                let entry = read_partitions(&mut disk)?
+                    .into_iter()
+                    .find(|entry| entry.number == number)
+                    .ok_or(BlkError::NoDevice)?;
+                Ok(Box::new(Partition::new(disk, &entry)?))
+            }
+            None => Err(BlkError::NoDevice),
+        }
+    }
+
+    /// Takes an optional device name and a block number off the front of
+    /// `words`, defaulting to the whole disk
+    fn device_and_block(
+        &self,
+        words: &mut dyn Iterator<Item = &[u8]>,
+    ) -> Result<(Box<dyn BlockDevice + Sync + 'a>, u64), BlkError> {
+        let mut word = words.next();
+        let device = match word {
+            Some(name) if name.starts_with(b"blk") || name.starts_with(b"crypt") => {
+                word = words.next();
+                self.open(name)?
+            }
+            _ => self.open(b"blk0")?,
+        };
+        let block = word
             .and_then(|sec| from_utf8(sec).ok())
             .and_then(|sec| sec.parse::<u64>().ok())
             .unwrap_or(0);
-        let mut len = words
+        Ok((device, block))
+    }
+
+    fn write_random<F: FnMut(&[u8])>(&mut self, words: &mut dyn Iterator<Item = &[u8]>, mut f: F) {
+        let (mut device, block) = match report_blk_error(Some(self.device_and_block(words)), &mut f)
+        {
+            Some(device) => device,
+            None => return,
+        };
+        let len = words
             .next()
             .and_then(|len| from_utf8(len).ok())
             .and_then(|len| len.parse::<usize>().ok())
             .unwrap_or(0);
-        while len > 0 {
-            let mut outdata: [u8; 512] = [0; 512];
-            let curlen = core::cmp::min(512, len);
-            {
-                let curbuf = &mut outdata[..curlen];
-                self.entropy.map(|e| e.read(curbuf));
-                for b in curbuf.iter_mut() {
-                    *b = ((*b as u32 * 100) / 272 + 32) as u8;
-                }
+        let block_size = device.block_size();
+        // The tail of the last block is zeroed
+        let mut outdata = vec![0u8; (len + block_size - 1) / block_size * block_size];
+        for chunk in outdata[..len].chunks_mut(512) {
+            self.entropy.map(|e| e.read(chunk));
+            for b in chunk.iter_mut() {
+                *b = ((*b as u32 * 100) / 272 + 32) as u8;
             }
-            self.blk.map(|blk| blk.write(sector, &outdata));
-            sector += 1;
-            len -= curlen;
+        }
+        if report_blk_error(Some(device.write(block, &outdata)), &mut f).is_none() {
+            return;
         }
         f(b"done");
     }
 
     fn read<F: FnMut(&[u8])>(&mut self, words: &mut dyn Iterator<Item = &[u8]>, mut f: F) {
-        let sector = words
-            .next()
-            .and_then(|sec| from_utf8(sec).ok())
-            .and_then(|sec| sec.parse::<u64>().ok())
-            .unwrap_or(0);
-        let mut len = words
+        let (mut device, block) = match report_blk_error(Some(self.device_and_block(words)), &mut f)
+        {
+            Some(device) => device,
+            None => return,
+        };
+        let len = words
             .next()
             .and_then(|len| from_utf8(len).ok())
             .and_then(|len| len.parse::<usize>().ok())
             .unwrap_or(512);
-        let mut data: [u8; 512] = [0; 512];
+        let block_size = device.block_size();
+        let mut data = vec![0u8; (len + block_size - 1) / block_size * block_size];
+        if report_blk_error(Some(device.read(block, &mut data)), &mut f).is_none() {
+            return;
+        }
+        f(&data[..len]);
+    }
+
+    fn sync<F: FnMut(&[u8])>(&mut self, mut f: F) {
+        if report_fs_error(self.vfs.lock().sync(), &mut f).is_none() {
+            return;
+        }
+        if report_blk_error(Some(Sharded(self.cache).flush()), &mut f).is_some() {
+            f(b"done");
+        }
+    }
+
+    fn trim<F: FnMut(&[u8])>(&mut self, words: &mut dyn Iterator<Item = &[u8]>, mut f: F) {
+        let (mut device, block) = match report_blk_error(Some(self.device_and_block(words)), &mut f)
+        {
+            Some(device) => device,
+            None => return,
+        };
+        let count = words
+            .next()
+            .and_then(|count| from_utf8(count).ok())
+            .and_then(|count| count.parse::<u64>().ok())
+            .unwrap_or(1);
+        if report_blk_error(Some(device.discard(block, count)), &mut f).is_some() {
+            f(b"done");
+        }
+    }
+
+    fn ls<F: FnMut(&[u8])>(&mut self, words: &mut dyn Iterator<Item = &[u8]>, mut f: F) {
+        let path = path_arg(words);
+        let vfs = self.vfs.lock();
+        let entries = match report_fs_error(vfs.read_dir(path), &mut f) {
+            Some(entries) => entries,
+            None => return,
+        };
+        for (i, entry) in entries.iter().enumerate() {
+            if i > 0 {
+                f(b"\n");
+            }
+            // Sizes of files only, with `/` after directories
+            let line = match entry.kind {
+                FileType::Dir => format!("{:>10}  {}/", "", entry.name),
+                FileType::Symlink => {
+                    let target = vfs
+                        .read_link(&format!("{}/{}", path, entry.name))
+                        .unwrap_or_default();
+                    format!("{:>10}  {} -> {}", "", entry.name, target)
+                }
+                _ => format!("{:>10}  {}", entry.size, entry.name),
+            };
+            f(line.as_bytes());
+        }
+    }
+
+    fn cat<F: FnMut(&[u8])>(&mut self, words: &mut dyn Iterator<Item = &[u8]>, mut f: F) {
+        let path = path_arg(words);
+        let mut file = match report_fs_error(self.vfs.lock().open(&path, 0), &mut f) {
+            Some(file) => file,
+            None => return,
+        };
+        let mut data = vec![0u8; 4096];
         loop {
-            self.blk.map(|blk| blk.read(sector, &mut data));
-            if len > 512 {
-                f(&data);
-                len -= 512;
+            match report_fs_error(file.read(&mut data), &mut f) {
+                Some(0) | None => return,
+                Some(len) => f(&data[..len]),
+            }
+        }
+    }
+
+    /// Replaces the contents of a file with the rest of the line, creating
+    /// it if needed
+    fn write_file<F: FnMut(&[u8])>(&mut self, words: &mut dyn Iterator<Item = &[u8]>, mut f: F) {
+        let path = path_arg(words);
+        let text = words.collect::<Vec<_>>().join(&b' ');
+        let written = self
+            .vfs
+            .lock()
+            .open(path, O_WRITE | O_CREATE | O_TRUNCATE)
+            .and_then(|mut file| file.write_all(&text));
+        if report_fs_error(written, &mut f).is_some() {
+            f(b"done");
+        }
+    }
+
+    fn mkdir<F: FnMut(&[u8])>(&mut self, words: &mut dyn Iterator<Item = &[u8]>, mut f: F) {
+        let path = path_arg(words);
+        let made = self.vfs.lock().mkdir(path);
+        if report_fs_error(made, &mut f).is_some() {
+            f(b"done");
+        }
+    }
+
+    fn rm<F: FnMut(&[u8])>(&mut self, words: &mut dyn Iterator<Item = &[u8]>, mut f: F) {
+        let path = path_arg(words);
+        let removed = self.vfs.lock().remove(path);
+        if report_fs_error(removed, &mut f).is_some() {
+            f(b"done");
+        }
+    }
+
+    /// Makes a symbolic link: `ln target path`
+    fn ln<F: FnMut(&[u8])>(&mut self, words: &mut dyn Iterator<Item = &[u8]>, mut f: F) {
+        let target = path_arg(words);
+        let path = path_arg(words);
+        let linked = self.vfs.lock().symlink(path, target);
+        if report_fs_error(linked, &mut f).is_some() {
+            f(b"done");
+        }
+    }
+
+    /// Lists what's mounted where
+    fn mounts<F: FnMut(&[u8])>(&mut self, mut f: F) {
+        let vfs = self.vfs.lock();
+        for (i, (path, fs)) in vfs.mounts().enumerate() {
+            if i > 0 {
+                f(b"\n");
+            }
+            f(format!("{} on {}", fs.name(), path).as_bytes());
+        }
+    }
+
+    /// `kv get KEY`, `kv set KEY VALUE...`, `kv del KEY`, `kv list` and
+    /// `kv compact`
+    fn kv<F: FnMut(&[u8])>(&mut self, words: &mut dyn Iterator<Item = &[u8]>, mut f: F) {
+        let mut kv = self.kv.lock();
+        let kv = match kv.as_mut() {
+            Some(kv) => kv,
+            None => return f(b"Error: no key-value store"),
+        };
+        let command = words.next().unwrap_or(b"list");
+        let key = words.next().unwrap_or(b"");
+        match command {
+            b"get" => match report_fs_error(kv.get(key), &mut f) {
+                Some(Some(value)) => f(&value),
+                Some(None) => f(b"Error: no such key"),
+                None => {}
+            },
+            b"set" => {
+                let value = words.collect::<Vec<_>>().join(&b' ');
+                if report_fs_error(kv.put(key, &value), &mut f).is_some() {
+                    f(b"done");
+                }
+            }
+            b"del" => match report_fs_error(kv.delete(key), &mut f) {
+                Some(true) => f(b"done"),
+                Some(false) => f(b"Error: no such key"),
+                None => {}
+            },
+            b"list" => {
+                for (i, key) in kv.keys().enumerate() {
+                    if i > 0 {
+                        f(b"\n");
+                    }
+                    f(key);
+                }
+            }
+            b"compact" => {
+                if report_fs_error(kv.compact(), &mut f).is_some() {
+                    let usage = kv.usage();
+                    f(format!(
+                        "{} keys, {} of {} bytes used",
+                        usage.keys, usage.used, usage.capacity
+                    )
+                    .as_bytes());
+                }
+            }
+            _ => f(b"Usage: kv get|set|del|list|compact [KEY] [VALUE]"),
+        }
+    }
+
+    /// Prompts on the console for a passphrase and reads it without echoing
+    /// it. Returns its length in `buf`, or None if there's no console.
+    fn passphrase(&self, prompt: &[u8], buf: &mut [u8]) -> Option<usize> {
+        self.console?.map(|uart| {
+            uart.write_bytes(prompt);
+            let len = uart.read_line(buf, false).len();
+            uart.write_byte(b'\n');
+            len
+        })
+    }
+
+    /// `cryptsetup format DEV` and `cryptsetup open DEV`, which set up
+    /// `crypt0` on a disk or partition, and `cryptsetup close`
+    fn cryptsetup<F: FnMut(&[u8])>(&mut self, words: &mut dyn Iterator<Item = &[u8]>, mut f: F) {
+        let command = words.next().unwrap_or(b"");
+        if command == b"close" {
+            let crypt = self.crypt.lock().take();
+            return match crypt {
+                Some(mut crypt) => {
+                    if report_blk_error(Some(crypt.flush()), &mut f).is_some() {
+                        f(b"done");
+                    }
+                }
+                None => f(b"Error: crypt0 isn't open"),
+            };
+        }
+        let name = match words.next() {
+            Some(name) if command == b"format" || command == b"open" => name,
+            _ => return f(b"Usage: cryptsetup format|open DEV, cryptsetup close"),
+        };
+        // Stacking crypt0 on itself would deadlock on its lock
+        if !name.starts_with(b"blk") {
+            return f(b"Error: no such device");
+        }
+        if self.crypt.lock().is_some() {
+            return f(b"Error: crypt0 is already open");
+        }
+        let device = match report_blk_error(Some(self.open(name)), &mut f) {
+            Some(device) => device,
+            None => return,
+        };
+
+        let mut passphrase = [0u8; 256];
+        let len = match self.passphrase(b"Passphrase: ", &mut passphrase) {
+            Some(len) => len,
+            None => return f(b"Error: passphrases can only be entered on the console"),
+        };
+        let opened = if command == b"format" {
+            let mut again = [0u8; 256];
+            let matches = self.passphrase(b"Again: ", &mut again) == Some(len)
+                && passphrase[..len] == again[..len];
+            again.iter_mut().for_each(|b| *b = 0);
+            let mut salt = [0u8; SALT_SIZE];
+            if !matches {
+                Err("passphrases don't match")
+            } else if self.entropy.map(|e| e.read(&mut salt)).is_none() {
+                Err("no entropy device for the salt")
             } else {
-                f(&data[..len]);
-                break;
+                CryptDevice::format(device, &passphrase[..len], &salt, DEFAULT_ITERATIONS)
+                    .map_err(|err| err.as_str())
+            }
+        } else {
+            CryptDevice::open(device, &passphrase[..len]).map_err(|err| err.as_str())
+        };
+        passphrase.iter_mut().for_each(|b| *b = 0);
+
+        match opened {
+            Ok(crypt) => {
+                f(format!(
+                    "crypt0: {} blocks, AES-256-XTS in {}",
+                    crypt.block_count(),
+                    if crypt.hardware() {
+                        "hardware"
+                    } else {
+                        "software"
+                    }
+                )
+                .as_bytes());
+                *self.crypt.lock() = Some(crypt);
+            }
+            Err(err) => {
+                f(b"Error: ");
+                f(err.as_bytes());
             }
         }
     }
 
-    /*fn write<F: FnMut(&[u8])>(&mut self, words: &mut dyn Iterator<Item = &[u8]>, mut f: F) {
-        let mut sector = words
-            .next()
-            .and_then(|sec| from_utf8(sec).ok())
-            .and_then(|sec| sec.parse::<u64>().ok())
-            .unwrap_or(0);
-        let mut len = words
+    /// A file if `words` starts with a path, or else a device and a block
+    fn target(&self, words: &mut dyn Iterator<Item = &[u8]>) -> Result<Target<'a>, BlkError> {
+        let mut words = words.peekable();
+        match words.peek().and_then(|word| from_utf8(word).ok()) {
+            Some(path) if path.starts_with('/') => {
+                let path = path.into();
+                words.next();
+                Ok(Target::File(path))
+            }
+            _ => {
+                let (device, block) = self.device_and_block(&mut words)?;
+                Ok(Target::Device(device, block))
+            }
+        }
+    }
+
+    /// Runs a transfer over the console, which has to be where the shell is
+    fn over_console<R>(&self, transfer: impl FnOnce(&mut dyn Serial) -> R) -> Option<R> {
+        self.console?.map(|uart| transfer(uart))
+    }
+
+    /// `rx PATH` or `rx [DEV] [BLOCK]`: receives a file by XMODEM
+    fn xmodem_receive<F: FnMut(&[u8])>(
+        &mut self,
+        words: &mut dyn Iterator<Item = &[u8]>,
+        mut f: F,
+    ) {
+        let target = match report_blk_error(Some(self.target(words)), &mut f) {
+            Some(target) => target,
+            None => return,
+        };
+        let received = match target {
+            Target::File(path) => {
+                let file = self.vfs.lock().open(&path, O_WRITE | O_CREATE | O_TRUNCATE);
+                let mut file = match report_fs_error(file, &mut f) {
+                    Some(file) => file,
+                    None => return,
+                };
+                f(b"Ready to receive by XMODEM\n");
+                self.over_console(|serial| {
+                    Receiver::new(serial)
+                        .xmodem(&mut |data| file.write_all(data).map_err(|err| err.as_str()))
+                })
+            }
+            Target::Device(device, block) => {
+                let mut writer = BlockWriter {
+                    device,
+                    next: block,
+                    pending: Vec::new(),
+                };
+                f(b"Ready to receive by XMODEM\n");
+                self.over_console(|serial| {
+                    let received = Receiver::new(serial).xmodem(&mut |data| writer.write(data))?;
+                    writer.finish().map_err(TransferError::Local)?;
+                    Ok(received)
+                })
+            }
+        };
+        match received {
+            Some(Ok(len)) => f(format!("Received {} bytes", len).as_bytes()),
+            Some(Err(err)) => report_transfer_error(err, f),
+            None => f(b"Error: transfers only run over the console"),
+        }
+    }
+
+    /// `sx PATH` or `sx [DEV] [BLOCK] [COUNT]`: sends a file, or blocks up to
+    /// the end of the device, by XMODEM
+    fn xmodem_send<F: FnMut(&[u8])>(&mut self, words: &mut dyn Iterator<Item = &[u8]>, mut f: F) {
+        let target = match report_blk_error(Some(self.target(words)), &mut f) {
+            Some(target) => target,
+            None => return,
+        };
+        let sent = match target {
+            Target::File(path) => {
+                let mut file = match report_fs_error(self.vfs.lock().open(&path, 0), &mut f) {
+                    Some(file) => file,
+                    None => return,
+                };
+                f(b"Ready to send by XMODEM\n");
+                self.over_console(|serial| {
+                    Sender::new(serial)
+                        .xmodem(&mut |data| file.read(data).map_err(|err| err.as_str()))
+                })
+            }
+            Target::Device(device, block) => {
+                let count = words
+                    .next()
+                    .and_then(|count| from_utf8(count).ok())
+                    .and_then(|count| count.parse::<u64>().ok());
+                let mut reader = BlockReader::new(device, block, count);
+                f(b"Ready to send by XMODEM\n");
+                self.over_console(|serial| {
+                    Sender::new(serial).xmodem(&mut |data| reader.read(data))
+                })
+            }
+        };
+        match sent {
+            Some(Ok(len)) => f(format!("Sent {} bytes", len).as_bytes()),
+            Some(Err(err)) => report_transfer_error(err, f),
+            None => f(b"Error: transfers only run over the console"),
+        }
+    }
+
+    /// `rb [DIR]` receives a batch of files by YMODEM into a directory, and
+    /// `rb DEV [BLOCK]` one file onto a device
+    fn ymodem_receive<F: FnMut(&[u8])>(
+        &mut self,
+        words: &mut dyn Iterator<Item = &[u8]>,
+        mut f: F,
+    ) {
+        let mut words = words.peekable();
+        let target = match words.peek() {
+            None | Some(&b"") => Ok(Target::File("/".into())),
+            Some(_) => self.target(&mut words),
+        };
+        let target = match report_blk_error(Some(target), &mut f) {
+            Some(target) => target,
+            None => return,
+        };
+        let vfs = self.vfs;
+        f(b"Ready to receive by YMODEM\n");
+        let received = self.over_console(|serial| {
+            let mut receiver = Receiver::new(serial);
+            let mut received = Vec::new();
+            match target {
+                Target::File(dir) => {
+                    while let Some(info) = receiver.next_file()? {
+                        let path = format!("{}/{}", dir.trim_end_matches('/'), info.name);
+                        let file = vfs.lock().open(&path, O_WRITE | O_CREATE | O_TRUNCATE);
+                        let mut file = match file {
+                            Ok(file) => file,
+                            Err(err) => {
+                                receiver.cancel();
+                                return Err(TransferError::Local(err.as_str()));
+                            }
+                        };
+                        let len = receiver.receive_file(&info, &mut |data| {
+                            file.write_all(data).map_err(|err| err.as_str())
+                        })?;
+                        received.push((info.name, len));
+                    }
+                }
+                Target::Device(device, block) => {
+                    let mut writer = BlockWriter {
+                        device,
+                        next: block,
+                        pending: Vec::new(),
+                    };
+                    if let Some(info) = receiver.next_file()? {
+                        let len = receiver.receive_file(&info, &mut |data| writer.write(data))?;
+                        writer.finish().map_err(TransferError::Local)?;
+                        received.push((info.name, len));
+                        if receiver.next_file()?.is_some() {
+                            receiver.cancel();
+                            return Err(TransferError::Local("only one file fits on a device"));
+                        }
+                    }
+                }
+            }
+            Ok(received)
+        });
+        match received {
+            Some(Ok(received)) => {
+                for (i, (name, len)) in received.iter().enumerate() {
+                    if i > 0 {
+                        f(b"\n");
+                    }
+                    f(format!("Received {}: {} bytes", name, len).as_bytes());
+                }
+            }
+            Some(Err(err)) => report_transfer_error(err, f),
+            None => f(b"Error: transfers only run over the console"),
+        }
+    }
+
+    /// `sb PATH...` sends files by YMODEM, and `sb [DEV] [BLOCK] [COUNT]`
+    /// blocks up to the end of a device as one named after it
+    fn ymodem_send<F: FnMut(&[u8])>(&mut self, words: &mut dyn Iterator<Item = &[u8]>, mut f: F) {
+        let words: Vec<&[u8]> = words.collect();
+        let is_path = |word: &&[u8]| word.starts_with(b"/");
+        let sent = if !words.is_empty() && words.iter().all(is_path) {
+            let vfs = self.vfs;
+            f(b"Ready to send by YMODEM\n");
+            self.over_console(|serial| {
+                let mut sender = Sender::new(serial);
+                let mut sent = Vec::new();
+                for path in words.iter().filter_map(|path| from_utf8(path).ok()) {
+                    let opened = vfs.lock().open(path, 0).and_then(|file| {
+                        let size = file.size()?;
+                        Ok((file, size))
+                    });
+                    let (mut file, size) = match opened {
+                        Ok(opened) => opened,
+                        Err(err) => {
+                            sender.cancel();
+                            return Err(TransferError::Local(err.as_str()));
+                        }
+                    };
+                    let name = path.rsplit('/').next().unwrap_or(path);
+                    let len = sender.ymodem_file(name, size, &mut |data| {
+                        file.read(data).map_err(|err| err.as_str())
+                    })?;
+                    sent.push((String::from(name), len));
+                }
+                sender.ymodem_end()?;
+                Ok(sent)
+            })
+        } else {
+            let device_name = words
+                .first()
+                .and_then(|name| from_utf8(name).ok())
+                .filter(|name| name.starts_with("blk") || name.starts_with("crypt"))
+                .unwrap_or("blk0");
+            let name = format!("{}.img", device_name);
+            let mut words = words.iter().copied();
+            let (device, block) =
+                match report_blk_error(Some(self.device_and_block(&mut words)), &mut f) {
+                    Some(device) => device,
+                    None => return,
+                };
+            let count = words
+                .next()
+                .and_then(|count| from_utf8(count).ok())
+                .and_then(|count| count.parse::<u64>().ok());
+            let mut reader = BlockReader::new(device, block, count);
+            f(b"Ready to send by YMODEM\n");
+            self.over_console(|serial| {
+                let mut sender = Sender::new(serial);
+                let len = reader.len();
+                let len = sender.ymodem_file(&name, len, &mut |data| reader.read(data))?;
+                sender.ymodem_end()?;
+                Ok(vec![(name, len)])
+            })
+        };
+        match sent {
+            Some(Ok(sent)) => {
+                for (i, (name, len)) in sent.iter().enumerate() {
+                    if i > 0 {
+                        f(b"\n");
+                    }
+                    f(format!("Sent {}: {} bytes", name, len).as_bytes());
+                }
+            }
+            Some(Err(err)) => report_transfer_error(err, f),
+            None => f(b"Error: transfers only run over the console"),
+        }
+    }
+
+    /// Lists the partitions on the disk
+    fn parts<F: FnMut(&[u8])>(&mut self, mut f: F) {
+        let mut disk = Sharded(self.cache);
+        if disk.block_count() == 0 {
+            return f(b"Error: no block device");
+        }
+        let block_size = disk.block_size() as u64;
+        let partitions = match report_blk_error(Some(read_partitions(&mut disk)), &mut f) {
+            Some(partitions) => partitions,
+            None => return,
+        };
+        if partitions.is_empty() {
+            return f(b"No partitions");
+        }
+        for (i, partition) in partitions.iter().enumerate() {
+            if i > 0 {
+                f(b"\n");
+            }
+            f(format!(
+                "blk0p{:<3} {:>10} KiB  {:<36}  {}",
+                partition.number,
+                partition.count * block_size / 1024,
+                partition.kind.to_string(),
+                partition.name
+            )
+            .as_bytes());
+        }
+    }
+
+    fn blkid<F: FnMut(&[u8])>(&mut self, mut f: F) {
+        if let Some(id) = report_blk_error(self.blk.map(|blk| blk.id()), &mut f) {
+            let len = id.iter().position(|b| *b == 0).unwrap_or(id.len());
+            f(b"Serial: ");
+            f(&id[..len]);
+        }
+        if let Some((capacity, block_size)) = self.blk.map(|blk| (blk.capacity(), blk.block_size()))
+        {
+            f(format!(
+                "\nCapacity: {} sectors, block size {}",
+                capacity, block_size
+            )
+            .as_bytes());
+        }
+    }
+
+    fn cache_stats<F: FnMut(&[u8])>(&mut self, mut f: F) {
+        let stats = Sharded(self.cache).stats();
+        match stats {
+            Some((stats, (cached, dirty))) => f(format!(
+                "Hits: {}, misses: {}, read ahead: {}, written back: {}\n\
+                 Cached: {} blocks, {} dirty",
+                stats.hits, stats.misses, stats.readahead, stats.writebacks, cached, dirty
+            )
+            .as_bytes()),
+            None => f(b"Error: no block device"),
+        }
+    }
+
+    fn net_stats<F: FnMut(&[u8])>(&mut self, mut f: F) {
+        let stats = match self.net_stats.lock().clone() {
+            Some(stats) => stats,
+            None => return f(b"Error: no network device"),
+        };
+        let count = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        f(format!(
+            "Link: {}\n\
+             RX: {} packets, {} bytes, {} dropped\n\
+             TX: {} packets, {} bytes",
+            if stats.link_up.load(Ordering::Relaxed) {
+                "up"
+            } else {
+                "down"
+            },
+            count(&stats.rx_packets),
+            count(&stats.rx_bytes),
+            count(&stats.rx_dropped),
+            count(&stats.tx_packets),
+            count(&stats.tx_bytes)
+        )
+        .as_bytes());
+    }
+
+    /// Measures sequential read IOPS and throughput at queue depths 1, 8 and 32
+    fn bench<F: FnMut(&[u8])>(&mut self, words: &mut dyn Iterator<Item = &[u8]>, mut f: F) {
+        let requests = words
             .next()
-            .and_then(|len| from_utf8(len).ok())
-            .and_then(|len| len.parse::<usize>().ok())
-            .unwrap_or(0);
-        while len > 0 {
-            let mut outdata: [u8; 512] = [0; 512];
-            let curlen = core::cmp::min(512, len);
-            {
-                let curbuf = &mut outdata[..curlen];
-                for b in curbuf.iter_mut() {
-                    *b = self.uart.read_byte();
-                    if *b == b'\r' {
-                        *b = b'\n';
+            .and_then(|count| from_utf8(count).ok())
+            .and_then(|count| count.parse::<usize>().ok())
+            .unwrap_or(1024);
+        let done = self.blk.map(|blk| {
+            let block_size = blk.block_size() as usize;
+            let blocks_per_request = (4096 / block_size).max(1);
+            let request_size = blocks_per_request * block_size;
+            let span = blk.capacity() as usize * SECTOR_SIZE / request_size;
+            if span == 0 {
+                return f(b"Error: disk too small");
+            }
+            for &depth in [1, 8, 32].iter() {
+                let completed = Arc::new(AtomicUsize::new(0));
+                let failed = Arc::new(AtomicUsize::new(0));
+                let start = counter();
+                let mut issued = 0;
+                while completed.load(Ordering::Relaxed) < requests {
+                    while issued < requests && issued - completed.load(Ordering::Relaxed) < depth {
+                        let (completed, failed) = (completed.clone(), failed.clone());
+                        let block = (issued % span * blocks_per_request) as u64;
+                        blk.read_with(block, vec![0; request_size], move |result, _| {
+                            if result.is_err() {
+                                failed.fetch_add(1, Ordering::Relaxed);
+                            }
+                            completed.fetch_add(1, Ordering::Relaxed);
+                        });
+                        issued += 1;
                     }
-                    self.uart.write_byte(*b);
+                    blk.handle_interrupt();
+                }
+                let ticks = (counter() - start).max(1) as u128;
+                let frequency = counter_frequency() as u128;
+                let iops = requests as u128 * frequency / ticks;
+                // Hundredths of a megabyte per second
+                let throughput = (requests * request_size) as u128 * frequency * 100 / ticks;
+                f(format!(
+                    "QD {:>2}: {} IOPS, {}.{:02} MB/s",
+                    depth,
+                    iops,
+                    throughput / 1_000_000 / 100,
+                    throughput / 1_000_000 % 100,
+                )
+                .as_bytes());
+                match failed.load(Ordering::Relaxed) {
+                    0 => f(b"\n"),
+                    failed => f(format!(" ({} failed)\n", failed).as_bytes()),
                 }
             }
-            self.blk.write(sector, &outdata);
-            sector += 1;
-            len -= curlen;
+        });
+        if done.is_none() {
+            f(b"Error: no block device");
         }
-    }*/
+    }
 
     pub fn do_line<F>(&mut self, line: &[u8], mut f: F) -> bool
     where
@@ -118,9 +937,66 @@ impl<'a, 'b> Shell<'a, 'b> {
             Some(b"read") => {
                 self.read(&mut words, f);
             }
-            /*Some(b"write") => {
-                self.write(&mut words, f);
-            }*/
+            Some(b"sync") => {
+                self.sync(f);
+            }
+            Some(b"trim") => {
+                self.trim(&mut words, f);
+            }
+            Some(b"parts") => {
+                self.parts(f);
+            }
+            Some(b"ls") => {
+                self.ls(&mut words, f);
+            }
+            Some(b"cat") => {
+                self.cat(&mut words, f);
+            }
+            Some(b"write") => {
+                self.write_file(&mut words, f);
+            }
+            Some(b"mkdir") => {
+                self.mkdir(&mut words, f);
+            }
+            Some(b"rm") => {
+                self.rm(&mut words, f);
+            }
+            Some(b"ln") => {
+                self.ln(&mut words, f);
+            }
+            Some(b"mount") => {
+                self.mounts(f);
+            }
+            Some(b"rx") => {
+                self.xmodem_receive(&mut words, f);
+            }
+            Some(b"sx") => {
+                self.xmodem_send(&mut words, f);
+            }
+            Some(b"rb") => {
+                self.ymodem_receive(&mut words, f);
+            }
+            Some(b"sb") => {
+                self.ymodem_send(&mut words, f);
+            }
+            Some(b"kv") => {
+                self.kv(&mut words, f);
+            }
+            Some(b"cryptsetup") => {
+                self.cryptsetup(&mut words, f);
+            }
+            Some(b"blkid") => {
+                self.blkid(f);
+            }
+            Some(b"cache") => {
+                self.cache_stats(f);
+            }
+            Some(b"netstat") => {
+                self.net_stats(f);
+            }
+            Some(b"bench") => {
+                self.bench(&mut words, f);
+            }
             Some(b"exit") => {
                 return true;
             }
@@ -135,8 +1011,9 @@ impl<'a, 'b> Shell<'a, 'b> {
 }
 
 pub fn main(uart: &Mutex<Option<UART>>, app: &mut Shell) {
+    let config = Config::load(app.vfs);
     loop {
-        uart.map(|u| u.write_bytes(b"$> "));
+        uart.map(|u| u.write_bytes(config.prompt.as_bytes()));
         let mut buf = [0; 1024];
         let line = uart.map(|u| u.read_line(&mut buf, true)).unwrap_or(b"");
         if app.do_line(line, |output| {
@@ -0,0 +1,255 @@
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::mutex::Mutex;
+
+mod cache;
+mod crypt;
+mod partition;
+
+pub use crate::virtio::BlkError;
+pub use cache::{BufferCache, CacheStats, Sharded, STRIPE_BLOCKS};
+pub use crypt::{CryptDevice, CryptError, DEFAULT_ITERATIONS, SALT_SIZE};
+pub use partition::{read_partitions, Guid, Partition, PartitionEntry, PartitionType};
+
+/// A disk addressed in fixed-size blocks. Filesystems, partition tables and
+/// caches are written against this rather than a particular driver, so they
+/// can be stacked on each other and tested on a RAM disk.
+pub trait BlockDevice: Send {
+    /// Size of a block in bytes
+    fn block_size(&self) -> usize;
+
+    /// Number of blocks on the device
+    fn block_count(&self) -> u64;
+
+    /// Reads whole blocks starting at block `start`. The length of `data`
+    /// must be a multiple of the block size.
+    fn read(&mut self, start: u64, data: &mut [u8]) -> Result<(), BlkError>;
+
+    /// Writes whole blocks starting at block `start`. The length of `data`
+    /// must be a multiple of the block size.
+    fn write(&mut self, start: u64, data: &[u8]) -> Result<(), BlkError>;
+
+    /// Makes completed writes durable
+    fn flush(&mut self) -> Result<(), BlkError> {
+        Ok(())
+    }
+
+    /// Tells the device `count` blocks from `start` are no longer in use.
+    /// This is only a hint, so ignoring it is correct.
+    fn discard(&mut self, _start: u64, _count: u64) -> Result<(), BlkError> {
+        Ok(())
+    }
+}
+
+impl<T: BlockDevice + ?Sized> BlockDevice for Box<T> {
+    fn block_size(&self) -> usize {
+        (**self).block_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        (**self).block_count()
+    }
+
+    fn read(&mut self, start: u64, data: &mut [u8]) -> Result<(), BlkError> {
+        (**self).read(start, data)
+    }
+
+    fn write(&mut self, start: u64, data: &[u8]) -> Result<(), BlkError> {
+        (**self).write(start, data)
+    }
+
+    fn flush(&mut self) -> Result<(), BlkError> {
+        (**self).flush()
+    }
+
+    fn discard(&mut self, start: u64, count: u64) -> Result<(), BlkError> {
+        (**self).discard(start, count)
+    }
+}
+
+/// A device shared behind one of the kernel's `Mutex<Option<_>>` statics,
+/// locked for each operation
+pub struct Locked<'a, T>(pub &'a Mutex<Option<T>>);
+
+impl<'a, T: BlockDevice> Locked<'a, T> {
+    fn with<R>(&self, f: impl FnOnce(&mut T) -> Result<R, BlkError>) -> Result<R, BlkError> {
+        self.0.map(f).unwrap_or(Err(BlkError::NoDevice))
+    }
+}
+
+impl<'a, T: BlockDevice + Sync> BlockDevice for Locked<'a, T> {
+    fn block_size(&self) -> usize {
+        self.0.map(|device| device.block_size()).unwrap_or(1)
+    }
+
+    fn block_count(&self) -> u64 {
+        self.0.map(|device| device.block_count()).unwrap_or(0)
+    }
+
+    fn read(&mut self, start: u64, data: &mut [u8]) -> Result<(), BlkError> {
+        self.with(|device| device.read(start, data))
+    }
+
+    fn write(&mut self, start: u64, data: &[u8]) -> Result<(), BlkError> {
+        self.with(|device| device.write(start, data))
+    }
+
+    fn flush(&mut self) -> Result<(), BlkError> {
+        self.with(|device| device.flush())
+    }
+
+    fn discard(&mut self, start: u64, count: u64) -> Result<(), BlkError> {
+        self.with(|device| device.discard(start, count))
+    }
+}
+
+/// A device with a queue per core, such as a multiqueue virtio-blk disk,
+/// behind the kernel's statics. Each operation goes to the calling core's
+/// queue, so it completes on the core its interrupt is routed to, and cores
+/// don't contend on one queue.
+pub struct PerCore<'a, T>(pub &'a [Mutex<Option<T>>]);
+
+impl<'a, T: BlockDevice> PerCore<'a, T> {
+    fn queue(&self) -> Locked<'a, T> {
+        Locked(&self.0[crate::utils::current_core() % self.0.len()])
+    }
+}
+
+impl<'a, T: BlockDevice + Sync> BlockDevice for PerCore<'a, T> {
+    fn block_size(&self) -> usize {
+        self.queue().block_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        self.queue().block_count()
+    }
+
+    fn read(&mut self, start: u64, data: &mut [u8]) -> Result<(), BlkError> {
+        self.queue().read(start, data)
+    }
+
+    fn write(&mut self, start: u64, data: &[u8]) -> Result<(), BlkError> {
+        self.queue().write(start, data)
+    }
+
+    fn flush(&mut self) -> Result<(), BlkError> {
+        self.queue().flush()
+    }
+
+    fn discard(&mut self, start: u64, count: u64) -> Result<(), BlkError> {
+        self.queue().discard(start, count)
+    }
+}
+
+/// Checks that `len` bytes from block `start` are whole blocks within a
+/// device, returning the byte range they cover
+pub fn byte_range(
+    device: &dyn BlockDevice,
+    start: u64,
+    len: usize,
+) -> Result<core::ops::Range<usize>, BlkError> {
+    let block_size = device.block_size();
+    if len % block_size != 0 {
+        return Err(BlkError::Misaligned);
+    }
+    let end = start
+        .checked_add((len / block_size) as u64)
+        .filter(|end| *end <= device.block_count())
+        .ok_or(BlkError::OutOfRange)?;
+    Ok(start as usize * block_size..end as usize * block_size)
+}
+
+/// A disk held in heap memory
+pub struct RamDisk {
+    block_size: usize,
+    data: Vec<u8>,
+}
+
+impl RamDisk {
+    pub fn new(block_size: usize, blocks: usize) -> RamDisk {
+        RamDisk {
+            block_size,
+            data: vec![0; block_size * blocks],
+        }
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn block_count(&self) -> u64 {
+        (self.data.len() / self.block_size) as u64
+    }
+
+    fn read(&mut self, start: u64, data: &mut [u8]) -> Result<(), BlkError> {
+        let range = byte_range(self, start, data.len())?;
+        data.copy_from_slice(&self.data[range]);
+        Ok(())
+    }
+
+    fn write(&mut self, start: u64, data: &[u8]) -> Result<(), BlkError> {
+        let range = byte_range(self, start, data.len())?;
+        self.data[range].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn discard(&mut self, start: u64, count: u64) -> Result<(), BlkError> {
+        let len = (count as usize)
+            .checked_mul(self.block_size)
+            .ok_or(BlkError::OutOfRange)?;
+        let range = byte_range(self, start, len)?;
+        self.data[range].iter_mut().for_each(|b| *b = 0);
+        Ok(())
+    }
+}
+
+/// A read-only disk over a region of memory, such as an initrd the bootloader
+/// loaded. A partial block at the end of the region is left out.
+pub struct MemoryDisk {
+    block_size: usize,
+    data: &'static [u8],
+}
+
+impl MemoryDisk {
+    pub fn new(block_size: usize, data: &'static [u8]) -> MemoryDisk {
+        MemoryDisk { block_size, data }
+    }
+
+    /// # Safety
+    /// `len` bytes from `base` must be memory nothing else writes to for the
+    /// rest of the kernel's life.
+    pub unsafe fn from_raw(block_size: usize, base: usize, len: usize) -> MemoryDisk {
+        MemoryDisk::new(
+            block_size,
+            core::slice::from_raw_parts(base as *const u8, len),
+        )
+    }
+}
+
+impl BlockDevice for MemoryDisk {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn block_count(&self) -> u64 {
+        (self.data.len() / self.block_size) as u64
+    }
+
+    fn read(&mut self, start: u64, data: &mut [u8]) -> Result<(), BlkError> {
+        let range = byte_range(self, start, data.len())?;
+        data.copy_from_slice(&self.data[range]);
+        Ok(())
+    }
+
+    fn write(&mut self, _start: u64, _data: &[u8]) -> Result<(), BlkError> {
+        Err(BlkError::ReadOnly)
+    }
+
+    fn discard(&mut self, _start: u64, _count: u64) -> Result<(), BlkError> {
+        Err(BlkError::ReadOnly)
+    }
+}
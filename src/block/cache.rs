@@ -0,0 +1,568 @@
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::{BlkError, BlockDevice, Locked};
+use crate::mutex::Mutex;
+use crate::utils::{counter, counter_frequency};
+
+/// Buffers cached by default
+pub const DEFAULT_CAPACITY: usize = 256;
+/// Blocks read ahead once reads look sequential
+pub const DEFAULT_READAHEAD: usize = 16;
+/// Longest a dirty buffer waits to be written back, in milliseconds
+pub const DEFAULT_WRITEBACK_MS: u64 = 5000;
+/// Blocks in each stripe a `Sharded` cache hands to one of its shards
+pub const STRIPE_BLOCKS: u64 = 64;
+
+#[derive(Clone, Copy, Default, Debug)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// Blocks read ahead of a request
+    pub readahead: u64,
+    /// Dirty blocks written back to the device
+    pub writebacks: u64,
+}
+
+struct Buffer {
+    block: u64,
+    data: Vec<u8>,
+    /// Counter value when the buffer first became dirty since its last
+    /// write-back
+    dirty_since: Option<u64>,
+    pins: usize,
+    last_used: u64,
+}
+
+/// A write-back LRU cache of blocks on a `BlockDevice`.
+///
+/// Dirty buffers are written back by `sync`, on eviction, and once the
+/// oldest has waited longer than the write-back interval. That is checked by
+/// every cache operation, and by `write_back_due`, which an idle loop should
+/// call periodically so a cache nobody uses doesn't hold on to dirty data.
+/// Pinned buffers are never evicted, so the cache can grow past its capacity
+/// while they are held.
+pub struct BufferCache<D: BlockDevice> {
+    device: D,
+    capacity: usize,
+    buffers: Vec<Buffer>,
+    /// Index into `buffers` of each cached block
+    index: BTreeMap<u64, usize>,
+    /// Logical clock for LRU ordering
+    clock: u64,
+    readahead: usize,
+    /// Block after the last one missed, to spot sequential reads
+    next_sequential: Option<u64>,
+    /// Readahead stops at multiples of this, see `set_stripe`
+    stripe: Option<u64>,
+    writeback_ticks: u64,
+    stats: CacheStats,
+}
+
+impl<D: BlockDevice> BufferCache<D> {
+    pub fn new(device: D) -> BufferCache<D> {
+        BufferCache::with_capacity(device, DEFAULT_CAPACITY)
+    }
+
+    pub fn with_capacity(device: D, capacity: usize) -> BufferCache<D> {
+        BufferCache {
+            device,
+            capacity: capacity.max(1),
+            buffers: Vec::new(),
+            index: BTreeMap::new(),
+            clock: 0,
+            readahead: DEFAULT_READAHEAD,
+            next_sequential: None,
+            stripe: None,
+            writeback_ticks: DEFAULT_WRITEBACK_MS * counter_frequency() / 1000,
+            stats: CacheStats::default(),
+        }
+    }
+
+    /// Sets how many blocks to read ahead on sequential reads, 0 to disable
+    pub fn set_readahead(&mut self, blocks: usize) {
+        self.readahead = blocks;
+    }
+
+    /// Stops readahead at multiples of `blocks`, so that a cache which is
+    /// only asked for some stripes of the device never caches blocks of the
+    /// others. `Sharded` relies on this to keep its shards coherent.
+    pub fn set_stripe(&mut self, blocks: u64) {
+        self.stripe = Some(blocks.max(1));
+    }
+
+    /// Sets how long a dirty buffer may wait before being written back
+    pub fn set_writeback_interval(&mut self, ms: u64) {
+        self.writeback_ticks = ms * counter_frequency() / 1000;
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.stats
+    }
+
+    pub fn device(&mut self) -> &mut D {
+        &mut self.device
+    }
+
+    /// Number of buffers cached and how many of those are dirty
+    pub fn usage(&self) -> (usize, usize) {
+        let dirty = self
+            .buffers
+            .iter()
+            .filter(|buffer| buffer.dirty_since.is_some())
+            .count();
+        (self.index.len(), dirty)
+    }
+
+    fn touch(&mut self, slot: usize) {
+        self.clock += 1;
+        self.buffers[slot].last_used = self.clock;
+    }
+
+    fn write_back(&mut self, slot: usize) -> Result<(), BlkError> {
+        let buffer = &mut self.buffers[slot];
+        if buffer.dirty_since.is_some() {
+            self.device.write(buffer.block, &buffer.data)?;
+            buffer.dirty_since = None;
+            self.stats.writebacks += 1;
+        }
+        Ok(())
+    }
+
+    /// Writes every dirty buffer back if the oldest has waited too long
+    pub fn write_back_due(&mut self) -> Result<(), BlkError> {
+        let now = counter();
+        let due = self
+            .buffers
+            .iter()
+            .filter_map(|buffer| buffer.dirty_since)
+            .any(|since| now.wrapping_sub(since) >= self.writeback_ticks);
+        if due {
+            self.write_back_all()?;
+        }
+        Ok(())
+    }
+
+    fn write_back_all(&mut self) -> Result<(), BlkError> {
+        // In block order, so the device sees sequential writes
+        let slots: Vec<usize> = self.index.values().copied().collect();
+        for slot in slots {
+            self.write_back(slot)?;
+        }
+        Ok(())
+    }
+
+    /// Writes all dirty buffers back and flushes the device
+    pub fn sync(&mut self) -> Result<(), BlkError> {
+        self.write_back_all()?;
+        self.device.flush()
+    }
+
+    /// A buffer for `block`, reusing the least recently used unpinned one
+    /// once the cache is full. Its contents are stale.
+    fn allocate(&mut self, block: u64) -> Result<usize, BlkError> {
+        let victim = if self.buffers.len() < self.capacity {
+            None
+        } else {
+            self.buffers
+                .iter()
+                .enumerate()
+                .filter(|(_, buffer)| buffer.pins == 0)
+                .min_by_key(|(_, buffer)| buffer.last_used)
+                .map(|(slot, _)| slot)
+        };
+        let slot = match victim {
+            Some(slot) => {
+                self.write_back(slot)?;
+                self.index.remove(&self.buffers[slot].block);
+                slot
+            }
+            None => {
+                self.buffers.push(Buffer {
+                    block,
+                    data: vec![0; self.device.block_size()],
+                    dirty_since: None,
+                    pins: 0,
+                    last_used: 0,
+                });
+                self.buffers.len() - 1
+            }
+        };
+        self.buffers[slot].block = block;
+        self.index.insert(block, slot);
+        self.touch(slot);
+        Ok(slot)
+    }
+
+    /// The buffer holding `block`, reading it (and any readahead) on a miss
+    fn lookup(&mut self, block: u64) -> Result<usize, BlkError> {
+        self.write_back_due()?;
+        if let Some(&slot) = self.index.get(&block) {
+            self.stats.hits += 1;
+            self.touch(slot);
+            return Ok(slot);
+        }
+        self.stats.misses += 1;
+        if block >= self.device.block_count() {
+            return Err(BlkError::OutOfRange);
+        }
+
+        // Read following blocks too if this continues the last miss, stopping
+        // at the first one already cached
+        let mut count = 1;
+        if self.next_sequential == Some(block) {
+            let limit = (self.readahead + 1).min(self.capacity / 2).max(1) as u64;
+            let mut end = (block + limit).min(self.device.block_count());
+            if let Some(stripe) = self.stripe {
+                end = end.min((block / stripe + 1) * stripe);
+            }
+            while block + count < end && !self.index.contains_key(&(block + count)) {
+                count += 1;
+            }
+        }
+        self.next_sequential = Some(block + count);
+
+        let block_size = self.device.block_size();
+        let mut data = vec![0; count as usize * block_size];
+        self.device.read(block, &mut data)?;
+        self.stats.readahead += count - 1;
+
+        // Allocate the readahead blocks first so they are evicted before the
+        // block actually asked for
+        let mut slot = 0;
+        for (i, chunk) in data.chunks(block_size).enumerate().rev() {
+            let buffer = self.allocate(block + i as u64)?;
+            self.buffers[buffer].data.copy_from_slice(chunk);
+            if i == 0 {
+                slot = buffer;
+            }
+        }
+        Ok(slot)
+    }
+
+    /// Reads `block` into the cache if needed and keeps it there until
+    /// `unpin`
+    pub fn pin(&mut self, block: u64) -> Result<(), BlkError> {
+        let slot = self.lookup(block)?;
+        self.buffers[slot].pins += 1;
+        Ok(())
+    }
+
+    pub fn unpin(&mut self, block: u64) {
+        if let Some(&slot) = self.index.get(&block) {
+            let buffer = &mut self.buffers[slot];
+            buffer.pins = buffer.pins.saturating_sub(1);
+        }
+    }
+
+    /// The cached contents of `block`
+    pub fn get(&mut self, block: u64) -> Result<&[u8], BlkError> {
+        let slot = self.lookup(block)?;
+        Ok(&self.buffers[slot].data)
+    }
+
+    /// The cached contents of `block`, for modification. The buffer is
+    /// marked dirty.
+    pub fn get_mut(&mut self, block: u64) -> Result<&mut [u8], BlkError> {
+        let slot = self.lookup(block)?;
+        let buffer = &mut self.buffers[slot];
+        buffer.dirty_since = buffer.dirty_since.or_else(|| Some(counter()));
+        Ok(&mut buffer.data)
+    }
+
+    /// Forgets cached copies of `count` blocks from `start` without writing
+    /// them back. Pinned buffers are kept.
+    fn invalidate(&mut self, start: u64, count: u64) {
+        let blocks: Vec<(u64, usize)> = self
+            .index
+            .range(start..start.saturating_add(count))
+            .map(|(block, slot)| (*block, *slot))
+            .collect();
+        for (block, slot) in blocks {
+            let buffer = &mut self.buffers[slot];
+            if buffer.pins == 0 {
+                buffer.dirty_since = None;
+                buffer.last_used = 0;
+                self.index.remove(&block);
+                // Keep the buffer for reuse, under a block nobody asks for
+                buffer.block = u64::max_value();
+            }
+        }
+    }
+}
+
+impl<D: BlockDevice> BlockDevice for BufferCache<D> {
+    fn block_size(&self) -> usize {
+        self.device.block_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        self.device.block_count()
+    }
+
+    fn read(&mut self, start: u64, data: &mut [u8]) -> Result<(), BlkError> {
+        super::byte_range(self, start, data.len())?;
+        let block_size = self.block_size();
+        for (i, chunk) in data.chunks_mut(block_size).enumerate() {
+            chunk.copy_from_slice(self.get(start + i as u64)?);
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, start: u64, data: &[u8]) -> Result<(), BlkError> {
+        super::byte_range(self, start, data.len())?;
+        let block_size = self.block_size();
+        for (i, chunk) in data.chunks(block_size).enumerate() {
+            let block = start + i as u64;
+            // Whole blocks are overwritten, so a miss needn't read them
+            let slot = match self.index.get(&block) {
+                Some(&slot) => {
+                    self.stats.hits += 1;
+                    self.touch(slot);
+                    slot
+                }
+                None => self.allocate(block)?,
+            };
+            let buffer = &mut self.buffers[slot];
+            buffer.data.copy_from_slice(chunk);
+            buffer.dirty_since = buffer.dirty_since.or_else(|| Some(counter()));
+        }
+        self.write_back_due()
+    }
+
+    fn flush(&mut self) -> Result<(), BlkError> {
+        self.sync()
+    }
+
+    fn discard(&mut self, start: u64, count: u64) -> Result<(), BlkError> {
+        self.invalidate(start, count);
+        self.device.discard(start, count)
+    }
+}
+
+/// A buffer cache split into shards behind locks of their own, so cores
+/// using different parts of a disk don't contend on one lock. Blocks are
+/// dealt out to the shards a stripe of `STRIPE_BLOCKS` at a time; each shard
+/// must be set up with `set_stripe(STRIPE_BLOCKS)` and only ever sees its
+/// own stripes, so no block is cached twice. An empty slice is a missing
+/// device.
+pub struct Sharded<'a, D: BlockDevice>(pub &'a [Mutex<Option<BufferCache<D>>>]);
+
+impl<'a, D: BlockDevice> Sharded<'a, D> {
+    /// The shard holding `block`, and how many blocks from it on are in the
+    /// same stripe
+    fn shard(&self, block: u64) -> (Locked<'a, BufferCache<D>>, u64) {
+        let stripe = block / STRIPE_BLOCKS;
+        let shard = &self.0[(stripe % self.0.len() as u64) as usize];
+        (Locked(shard), (stripe + 1) * STRIPE_BLOCKS - block)
+    }
+
+    /// Writes back every shard's dirty buffers if its oldest has waited too
+    /// long
+    pub fn write_back_due(&self) -> Result<(), BlkError> {
+        for shard in self.0 {
+            shard
+                .map(|cache| cache.write_back_due())
+                .unwrap_or(Ok(()))?;
+        }
+        Ok(())
+    }
+
+    /// The shards' statistics and usage added up, or `None` if there's no
+    /// device
+    pub fn stats(&self) -> Option<(CacheStats, (usize, usize))> {
+        let mut total: Option<(CacheStats, (usize, usize))> = None;
+        for shard in self.0 {
+            if let Some((stats, (cached, dirty))) =
+                shard.map(|cache| (cache.stats(), cache.usage()))
+            {
+                let (sum, usage) = total.get_or_insert_with(Default::default);
+                sum.hits += stats.hits;
+                sum.misses += stats.misses;
+                sum.readahead += stats.readahead;
+                sum.writebacks += stats.writebacks;
+                usage.0 += cached;
+                usage.1 += dirty;
+            }
+        }
+        total
+    }
+}
+
+impl<'a, D: BlockDevice + Sync> BlockDevice for Sharded<'a, D> {
+    fn block_size(&self) -> usize {
+        match self.0.first() {
+            Some(shard) => Locked(shard).block_size(),
+            None => 1,
+        }
+    }
+
+    fn block_count(&self) -> u64 {
+        match self.0.first() {
+            Some(shard) => Locked(shard).block_count(),
+            None => 0,
+        }
+    }
+
+    fn read(&mut self, start: u64, mut data: &mut [u8]) -> Result<(), BlkError> {
+        if self.0.is_empty() {
+            return Err(BlkError::NoDevice);
+        }
+        super::byte_range(self, start, data.len())?;
+        let block_size = self.block_size();
+        let mut block = start;
+        while !data.is_empty() {
+            let (mut shard, count) = self.shard(block);
+            let len = data.len().min(count as usize * block_size);
+            let (head, rest) = core::mem::take(&mut data).split_at_mut(len);
+            shard.read(block, head)?;
+            data = rest;
+            block += (len / block_size) as u64;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, start: u64, mut data: &[u8]) -> Result<(), BlkError> {
+        if self.0.is_empty() {
+            return Err(BlkError::NoDevice);
+        }
+        super::byte_range(self, start, data.len())?;
+        let block_size = self.block_size();
+        let mut block = start;
+        while !data.is_empty() {
+            let (mut shard, count) = self.shard(block);
+            let (head, rest) = data.split_at(data.len().min(count as usize * block_size));
+            shard.write(block, head)?;
+            data = rest;
+            block += (head.len() / block_size) as u64;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), BlkError> {
+        if self.0.is_empty() {
+            return Err(BlkError::NoDevice);
+        }
+        for shard in self.0 {
+            Locked(shard).flush()?;
+        }
+        Ok(())
+    }
+
+    fn discard(&mut self, start: u64, count: u64) -> Result<(), BlkError> {
+        if self.0.is_empty() {
+            return Err(BlkError::NoDevice);
+        }
+        let end = start.saturating_add(count);
+        let mut block = start;
+        while block < end {
+            let (mut shard, stripe_left) = self.shard(block);
+            let count = stripe_left.min(end - block);
+            shard.discard(block, count)?;
+            block += count;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::RamDisk;
+
+    #[test]
+    fn buffer_cache() {
+        let mut cache = BufferCache::with_capacity(RamDisk::new(512, 64), 8);
+        cache.set_readahead(2);
+        let mut block = [0u8; 512];
+
+        cache.write(0, &[3u8; 512]).expect("write failed");
+        cache.device().read(0, &mut block).expect("read failed");
+        assert!(block == [0u8; 512], "write went straight to the disk");
+        cache.sync().expect("sync failed");
+        cache.device().read(0, &mut block).expect("read failed");
+        assert!(block == [3u8; 512], "sync didn't write back");
+        assert!(cache.stats().writebacks == 1, "wrong write-back count");
+
+        cache.pin(0).expect("pin failed");
+        for i in 10..14 {
+            cache.read(i, &mut block).expect("read failed");
+        }
+        let stats = cache.stats();
+        assert!(stats.readahead == 2, "sequential reads not read ahead");
+        assert!(
+            stats.hits == 3 && stats.misses == 2,
+            "wrong hit/miss counts"
+        );
+
+        for i in 20..30 {
+            cache.read(i, &mut block).expect("read failed");
+        }
+        assert!(cache.usage().0 <= 8, "cache grew past its capacity");
+        let hits = cache.stats().hits;
+        assert!(
+            cache.get(0).map(|data| data[0]) == Ok(3),
+            "pinned block lost"
+        );
+        assert!(cache.stats().hits == hits + 1, "pinned block evicted");
+    }
+
+    #[test]
+    fn write_back_due() {
+        let mut cache = BufferCache::new(RamDisk::new(512, 4));
+        let mut block = [0u8; 512];
+        cache.write(1, &[4u8; 512]).expect("write failed");
+        cache.write_back_due().expect("write-back failed");
+        cache.device().read(1, &mut block).expect("read failed");
+        assert!(block == [0u8; 512], "written back before it was due");
+
+        // Due without any further cache operation
+        cache.set_writeback_interval(1);
+        let deadline = counter() + counter_frequency() / 1000;
+        while counter() <= deadline {}
+        cache.write_back_due().expect("write-back failed");
+        cache.device().read(1, &mut block).expect("read failed");
+        assert!(block == [4u8; 512], "due buffer not written back");
+    }
+
+    #[test]
+    fn sharded() {
+        let disk = Mutex::new(Some(RamDisk::new(512, 4 * STRIPE_BLOCKS as usize)));
+        let shards = [
+            Mutex::new(Some(BufferCache::new(Locked(&disk)))),
+            Mutex::new(Some(BufferCache::new(Locked(&disk)))),
+        ];
+        for shard in shards.iter() {
+            shard.map(|cache| cache.set_stripe(STRIPE_BLOCKS));
+        }
+        let mut cache = Sharded(&shards);
+        let data: Vec<u8> = (0..2 * STRIPE_BLOCKS as usize * 512)
+            .map(|i| (i / 512) as u8)
+            .collect();
+        let start = STRIPE_BLOCKS / 2;
+        cache.write(start, &data).expect("write failed");
+        let mut read = vec![0; data.len()];
+        cache.read(start, &mut read).expect("read failed");
+        assert!(read == data, "wrong data read back");
+        cache.flush().expect("flush failed");
+        Locked(&disk).read(start, &mut read).expect("read failed");
+        assert!(read == data, "flush didn't write every shard back");
+
+        // Sequential reads from the start of the disk, which read ahead
+        let mut block = [0u8; 512];
+        for i in 0..3 * STRIPE_BLOCKS {
+            cache.read(i, &mut block).expect("read failed");
+        }
+        let stripe = |block: &u64| (block / STRIPE_BLOCKS % 2) as usize;
+        for (i, shard) in shards.iter().enumerate() {
+            let cached = shard.map(|cache| cache.index.keys().all(|block| stripe(block) == i));
+            assert!(cached == Some(true), "shard cached another's block");
+        }
+        assert!(
+            cache.stats().map(|(stats, _)| stats.readahead > 0) == Some(true),
+            "sequential reads not read ahead"
+        );
+        assert!(Sharded::<RamDisk>(&[]).read(0, &mut block) == Err(BlkError::NoDevice));
+    }
+}
@@ -0,0 +1,265 @@
+use alloc::vec;
+use core::convert::TryInto;
+
+use super::{byte_range, BlkError, BlockDevice};
+use crate::crypto::aes::{self, Xts};
+use crate::crypto::sha256;
+use crate::utils::crc32;
+
+const MAGIC: &[u8; 8] = b"AlloraCR";
+const VERSION: u32 = 1;
+/// Magic, version, iterations, salt, key check and CRC
+const HEADER_SIZE: usize = 84;
+/// Bytes set aside for the header, so data blocks stay 4KiB aligned
+const HEADER_AREA: usize = 4096;
+pub const SALT_SIZE: usize = 32;
+/// PBKDF2 rounds for new headers. Each costs two SHA-256 compressions, so
+/// this takes around a second on one core without the crypto extensions.
+pub const DEFAULT_ITERATIONS: u32 = 20_000;
+/// Bounds on the PBKDF2 rounds a header may ask for: fewer make guessing the
+/// passphrase cheap, and more would tie up a core for minutes
+const MIN_ITERATIONS: u32 = 1_000;
+const MAX_ITERATIONS: u32 = 1_000_000;
+const KEY_CHECK_LABEL: &[u8] = b"AlloraCR key check";
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CryptError {
+    Io(BlkError),
+    /// There's no encryption header on the device
+    NotEncrypted,
+    /// The header is there but its CRC doesn't match
+    Corrupt,
+    /// The passphrase doesn't derive the key the header was written with
+    WrongPassphrase,
+    /// Blocks aren't a whole number of AES blocks, or are too small for the
+    /// header
+    Unsupported,
+    /// The PBKDF2 iteration count is outside `MIN_ITERATIONS..=MAX_ITERATIONS`
+    BadIterations,
+}
+
+impl CryptError {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CryptError::Io(err) => err.as_str(),
+            CryptError::NotEncrypted => "not an encrypted device",
+            CryptError::Corrupt => "encryption header corrupt",
+            CryptError::WrongPassphrase => "wrong passphrase",
+            CryptError::Unsupported => "unsupported block size",
+            CryptError::BadIterations => "PBKDF2 iteration count out of range",
+        }
+    }
+}
+
+impl From<BlkError> for CryptError {
+    fn from(err: BlkError) -> CryptError {
+        CryptError::Io(err)
+    }
+}
+
+/// A block device whose contents are encrypted with AES-256-XTS on another.
+///
+/// The first 4KiB of the underlying device hold a header with the salt and
+/// iteration count the key is derived from a passphrase with, by
+/// PBKDF2-HMAC-SHA256, and an HMAC of a fixed label under the key to tell a
+/// wrong passphrase from garbage. Each block after that is an XTS data unit
+/// numbered from 0, so identical plaintext blocks encrypt differently and
+/// moving ciphertext between blocks garbles it. There's no authentication:
+/// tampering goes undetected, as with dm-crypt.
+pub struct CryptDevice<D: BlockDevice> {
+    device: D,
+    xts: Xts,
+    /// Blocks taken by the header
+    offset: u64,
+}
+
+impl<D: BlockDevice> CryptDevice<D> {
+    /// Writes a new header to `device`, making whatever was on it
+    /// unreadable, and opens it
+    pub fn format(
+        mut device: D,
+        passphrase: &[u8],
+        salt: &[u8; SALT_SIZE],
+        iterations: u32,
+    ) -> Result<CryptDevice<D>, CryptError> {
+        let offset = Self::header_blocks(&device)?;
+        if !(MIN_ITERATIONS..=MAX_ITERATIONS).contains(&iterations) {
+            return Err(CryptError::BadIterations);
+        }
+        let key = derive_key(passphrase, salt, iterations);
+        let mut header = vec![0; offset as usize * device.block_size()];
+        header[..8].copy_from_slice(MAGIC);
+        header[8..12].copy_from_slice(&VERSION.to_le_bytes());
+        header[12..16].copy_from_slice(&iterations.to_le_bytes());
+        header[16..48].copy_from_slice(salt);
+        header[48..80].copy_from_slice(&sha256::hmac(&key, KEY_CHECK_LABEL));
+        let crc = crc32(&header[..80]);
+        header[80..84].copy_from_slice(&crc.to_le_bytes());
+        device.write(0, &header)?;
+        device.flush()?;
+        Ok(CryptDevice {
+            device,
+            xts: Xts::new(&key),
+            offset,
+        })
+    }
+
+    /// Opens an encrypted device with the key `passphrase` derives
+    pub fn open(mut device: D, passphrase: &[u8]) -> Result<CryptDevice<D>, CryptError> {
+        let offset = Self::header_blocks(&device)?;
+        let mut header = vec![0; device.block_size()];
+        device.read(0, &mut header)?;
+        if &header[..8] != MAGIC || le32(&header, 8) != VERSION {
+            return Err(CryptError::NotEncrypted);
+        }
+        if crc32(&header[..80]) != le32(&header, 80) {
+            return Err(CryptError::Corrupt);
+        }
+        let iterations = le32(&header, 12);
+        if !(MIN_ITERATIONS..=MAX_ITERATIONS).contains(&iterations) {
+            return Err(CryptError::BadIterations);
+        }
+        let key = derive_key(passphrase, &header[16..48], iterations);
+        let check = sha256::hmac(&key, KEY_CHECK_LABEL);
+        // Compared without an early exit, so timing doesn't say how close
+        let difference = check
+            .iter()
+            .zip(&header[48..80])
+            .fold(0, |acc, (a, b)| acc | (a ^ b));
+        if difference != 0 {
+            return Err(CryptError::WrongPassphrase);
+        }
+        Ok(CryptDevice {
+            device,
+            xts: Xts::new(&key),
+            offset,
+        })
+    }
+
+    /// Whether `device` has an encryption header
+    pub fn detect(device: &mut D) -> Result<bool, CryptError> {
+        Self::header_blocks(device)?;
+        let mut header = vec![0; device.block_size()];
+        device.read(0, &mut header)?;
+        Ok(&header[..8] == MAGIC)
+    }
+
+    /// Whether the ARMv8 crypto extensions are doing the work
+    pub fn hardware(&self) -> bool {
+        self.xts.hardware()
+    }
+
+    pub fn into_inner(self) -> D {
+        self.device
+    }
+
+    fn header_blocks(device: &D) -> Result<u64, CryptError> {
+        let block_size = device.block_size();
+        if block_size < HEADER_SIZE || block_size % aes::BLOCK_SIZE != 0 {
+            return Err(CryptError::Unsupported);
+        }
+        let blocks = ((HEADER_AREA + block_size - 1) / block_size) as u64;
+        if device.block_count() <= blocks {
+            return Err(CryptError::Io(BlkError::OutOfRange));
+        }
+        Ok(blocks)
+    }
+}
+
+impl<D: BlockDevice> BlockDevice for CryptDevice<D> {
+    fn block_size(&self) -> usize {
+        self.device.block_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        self.device.block_count() - self.offset
+    }
+
+    fn read(&mut self, start: u64, data: &mut [u8]) -> Result<(), BlkError> {
+        byte_range(self, start, data.len())?;
+        self.device.read(self.offset + start, data)?;
+        let block_size = self.block_size();
+        for (i, block) in data.chunks_exact_mut(block_size).enumerate() {
+            self.xts.decrypt(start + i as u64, block);
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, start: u64, data: &[u8]) -> Result<(), BlkError> {
+        byte_range(self, start, data.len())?;
+        let block_size = self.block_size();
+        let mut ciphertext = data.to_vec();
+        for (i, block) in ciphertext.chunks_exact_mut(block_size).enumerate() {
+            self.xts.encrypt(start + i as u64, block);
+        }
+        self.device.write(self.offset + start, &ciphertext)
+    }
+
+    fn flush(&mut self) -> Result<(), BlkError> {
+        self.device.flush()
+    }
+
+    // Discards aren't passed down, since the blocks they free would show
+    // through as zeros and give away how much of the device is in use
+}
+
+fn le32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+/// The XTS key, data key then tweak key, for `passphrase`
+fn derive_key(passphrase: &[u8], salt: &[u8], iterations: u32) -> [u8; 2 * aes::KEY_SIZE] {
+    let mut key = [0; 2 * aes::KEY_SIZE];
+    sha256::pbkdf2(passphrase, salt, iterations, &mut key);
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::{Locked, RamDisk};
+    use crate::mutex::Mutex;
+
+    #[test]
+    fn encrypted_disk() {
+        let disk = Mutex::new(Some(RamDisk::new(512, 64)));
+        assert!(
+            CryptDevice::open(Locked(&disk), b"secret").err() == Some(CryptError::NotEncrypted),
+            "blank disk opened"
+        );
+        let mut crypt = CryptDevice::format(Locked(&disk), b"secret", &[7; 32], MIN_ITERATIONS)
+            .expect("format failed");
+        assert!(crypt.block_count() == 56, "header not set aside");
+        let plaintext = [0x41; 1024];
+        crypt.write(2, &plaintext).expect("write failed");
+        let mut raw = [0; 1024];
+        disk.map(|disk| disk.read(10, &mut raw));
+        assert!(raw[..] != plaintext[..], "plaintext reached the disk");
+        assert!(raw[..512] != raw[512..], "equal blocks encrypted the same");
+        assert!(
+            CryptDevice::open(Locked(&disk), b"Secret").err() == Some(CryptError::WrongPassphrase),
+            "wrong passphrase accepted"
+        );
+        let mut crypt = CryptDevice::open(Locked(&disk), b"secret").expect("open failed");
+        let mut data = [0; 1024];
+        crypt.read(2, &mut data).expect("read failed");
+        assert!(data[..] == plaintext[..], "data didn't decrypt");
+
+        // A header asking for no PBKDF2 rounds at all, with its CRC fixed up
+        let mut header = [0; 512];
+        disk.map(|disk| disk.read(0, &mut header));
+        header[12..16].copy_from_slice(&0u32.to_le_bytes());
+        let crc = crc32(&header[..80]);
+        header[80..84].copy_from_slice(&crc.to_le_bytes());
+        disk.map(|disk| disk.write(0, &header));
+        assert!(
+            CryptDevice::open(Locked(&disk), b"secret").err() == Some(CryptError::BadIterations),
+            "header without PBKDF2 rounds accepted"
+        );
+        assert!(
+            CryptDevice::format(Locked(&disk), b"secret", &[7; 32], u32::MAX).err()
+                == Some(CryptError::BadIterations),
+            "endless PBKDF2 accepted"
+        );
+    }
+}
@@ -0,0 +1,486 @@
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::convert::TryInto;
+use core::fmt;
+
+use super::{byte_range, BlkError, BlockDevice};
+use crate::utils::crc32;
+
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xaa];
+const MBR_ENTRIES: usize = 446;
+const MBR_TYPE_PROTECTIVE: u8 = 0xee;
+const MBR_TYPES_EXTENDED: [u8; 3] = [0x05, 0x0f, 0x85];
+/// Logical partitions followed before giving up on a looping EBR chain
+const MAX_LOGICAL: u32 = 128;
+
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+const GPT_HEADER_SIZE: usize = 92;
+/// Entries, and their size to a block, are capped so a corrupt header can't
+/// make us allocate the world
+const GPT_MAX_ENTRIES: usize = 1024;
+const GPT_ENTRY_SIZE: usize = 128;
+
+/// A GUID, stored as it is on disk with the first three fields little-endian
+#[derive(Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub struct Guid(pub [u8; 16]);
+
+impl Guid {
+    pub fn is_zero(&self) -> bool {
+        self.0 == [0; 16]
+    }
+}
+
+impl fmt::Display for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let g = &self.0;
+        write!(
+            f,
+            "{:08X}-{:04X}-{:04X}-{:02X}{:02X}-",
+            u32::from_le_bytes([g[0], g[1], g[2], g[3]]),
+            u16::from_le_bytes([g[4], g[5]]),
+            u16::from_le_bytes([g[6], g[7]]),
+            g[8],
+            g[9]
+        )?;
+        g[10..].iter().try_for_each(|b| write!(f, "{:02X}", b))
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PartitionType {
+    /// MBR system ID
+    Mbr(u8),
+    Gpt(Guid),
+}
+
+impl fmt::Display for PartitionType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PartitionType::Mbr(id) => write!(f, "{:#04x}", id),
+            PartitionType::Gpt(guid) => write!(f, "{}", guid),
+        }
+    }
+}
+
+/// A partition found in a partition table. `start` and `count` are in blocks
+/// of the disk it was read from.
+#[derive(Clone, Debug)]
+pub struct PartitionEntry {
+    /// Number in the partition's device name, from 1. MBR logical partitions
+    /// start at 5, as in Linux.
+    pub number: u32,
+    pub start: u64,
+    pub count: u64,
+    pub kind: PartitionType,
+    /// The GPT partition name, empty for MBR
+    pub name: String,
+}
+
+fn le16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+}
+
+fn le32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn le64(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+fn guid(data: &[u8], offset: usize) -> Guid {
+    Guid(data[offset..offset + 16].try_into().unwrap())
+}
+
+fn read_block(disk: &mut dyn BlockDevice, block: u64) -> Result<Vec<u8>, BlkError> {
+    let mut data = vec![0; disk.block_size()];
+    disk.read(block, &mut data)?;
+    Ok(data)
+}
+
+/// Reads the partition table of `disk`: a GPT if there is a valid one,
+/// otherwise an MBR. A disk with neither has no partitions.
+pub fn read_partitions(disk: &mut dyn BlockDevice) -> Result<Vec<PartitionEntry>, BlkError> {
+    // Tables are laid out in 512-byte sectors, which smaller blocks can't hold
+    if disk.block_size() < 512 || disk.block_count() == 0 {
+        return Ok(Vec::new());
+    }
+    let mbr = read_block(disk, 0)?;
+    if mbr[510..512] != MBR_SIGNATURE {
+        return Ok(Vec::new());
+    }
+    // Boot flags other than 0x00 and 0x80 mean this is a boot sector with
+    // code where the table would be, such as a FAT volume's
+    if (0..4).any(|i| mbr[MBR_ENTRIES + i * 16] & 0x7f != 0) {
+        return Ok(Vec::new());
+    }
+    let protective = (0..4).any(|i| mbr[MBR_ENTRIES + i * 16 + 4] == MBR_TYPE_PROTECTIVE);
+    if protective {
+        if let Some(partitions) = read_gpt(disk)? {
+            return Ok(partitions);
+        }
+        // Neither GPT header is valid, so all there is left is the protective
+        // partition itself
+    }
+    read_mbr(disk, &mbr)
+}
+
+/// One of the four entries of an MBR or EBR as (type, start, count), or
+/// `None` if it's unused
+fn mbr_entry(sector: &[u8], index: usize) -> Option<(u8, u64, u64)> {
+    let entry = &sector[MBR_ENTRIES + index * 16..MBR_ENTRIES + (index + 1) * 16];
+    let (kind, start, count) = (entry[4], le32(entry, 8), le32(entry, 12));
+    if kind == 0 || count == 0 {
+        None
+    } else {
+        Some((kind, start as u64, count as u64))
+    }
+}
+
+fn read_mbr(disk: &mut dyn BlockDevice, mbr: &[u8]) -> Result<Vec<PartitionEntry>, BlkError> {
+    let mut partitions = Vec::new();
+    let mut extended = None;
+    for i in 0..4 {
+        if let Some((kind, start, count)) = mbr_entry(mbr, i) {
+            if MBR_TYPES_EXTENDED.contains(&kind) {
+                extended = extended.or(Some(start));
+            }
+            partitions.push(PartitionEntry {
+                number: i as u32 + 1,
+                start,
+                count,
+                kind: PartitionType::Mbr(kind),
+                name: String::new(),
+            });
+        }
+    }
+
+    // Each EBR describes one logical partition, relative to itself, and links
+    // to the next EBR, relative to the extended partition
+    if let Some(base) = extended {
+        let mut ebr = base;
+        for number in 5..5 + MAX_LOGICAL {
+            if ebr >= disk.block_count() {
+                break;
+            }
+            let sector = read_block(disk, ebr)?;
+            if sector[510..512] != MBR_SIGNATURE {
+                break;
+            }
+            if let Some((kind, start, count)) = mbr_entry(&sector, 0) {
+                partitions.push(PartitionEntry {
+                    number,
+                    start: ebr + start,
+                    count,
+                    kind: PartitionType::Mbr(kind),
+                    name: String::new(),
+                });
+            }
+            match mbr_entry(&sector, 1) {
+                Some((_, next, _)) if base + next > ebr => ebr = base + next,
+                _ => break,
+            }
+        }
+    }
+    Ok(partitions)
+}
+
+/// The GPT from the primary header, or the backup one at the end of the disk
+/// if the primary is damaged. `None` if neither is valid.
+fn read_gpt(disk: &mut dyn BlockDevice) -> Result<Option<Vec<PartitionEntry>>, BlkError> {
+    let last = disk.block_count() - 1;
+    for &lba in [1, last].iter() {
+        let header = read_block(disk, lba)?;
+        if let Some(partitions) = parse_gpt(disk, &header, lba)? {
+            return Ok(Some(partitions));
+        }
+    }
+    Ok(None)
+}
+
+fn parse_gpt(
+    disk: &mut dyn BlockDevice,
+    header: &[u8],
+    lba: u64,
+) -> Result<Option<Vec<PartitionEntry>>, BlkError> {
+    let header_size = le32(header, 12) as usize;
+    if &header[..8] != GPT_SIGNATURE
+        || header_size < GPT_HEADER_SIZE
+        || header_size > header.len()
+        || le64(header, 24) != lba
+    {
+        return Ok(None);
+    }
+    // The CRC covers the header with its own CRC field zeroed
+    let mut copy = header[..header_size].to_vec();
+    copy[16..20].copy_from_slice(&[0; 4]);
+    if crc32(&copy) != le32(header, 16) {
+        return Ok(None);
+    }
+
+    let entries_lba = le64(header, 72);
+    let entries = le32(header, 80) as usize;
+    let entry_size = le32(header, 84) as usize;
+    let block_size = disk.block_size();
+    if entries > GPT_MAX_ENTRIES
+        || entry_size < GPT_ENTRY_SIZE
+        || entry_size > block_size
+        || entry_size % 8 != 0
+    {
+        return Ok(None);
+    }
+    let blocks = (entries * entry_size + block_size - 1) / block_size;
+    if byte_range(disk, entries_lba, blocks * block_size).is_err() {
+        return Ok(None);
+    }
+    let mut table = vec![0; blocks * block_size];
+    disk.read(entries_lba, &mut table)?;
+    if crc32(&table[..entries * entry_size]) != le32(header, 88) {
+        return Ok(None);
+    }
+
+    let disk_blocks = disk.block_count();
+    let partitions = table[..entries * entry_size]
+        .chunks(entry_size)
+        .enumerate()
+        .filter(|(_, entry)| !guid(entry, 0).is_zero())
+        .filter_map(|(i, entry)| {
+            // The range is inclusive. One that ends before it starts or runs
+            // off the disk is damaged, so the entry is left out.
+            let (first, last) = (le64(entry, 32), le64(entry, 40));
+            let end = last
+                .checked_add(1)
+                .filter(|end| last >= first && *end <= disk_blocks)?;
+            let name = (0..36)
+                .map(|c| le16(entry, 56 + c * 2))
+                .take_while(|c| *c != 0);
+            Some(PartitionEntry {
+                number: i as u32 + 1,
+                start: first,
+                count: end - first,
+                kind: PartitionType::Gpt(guid(entry, 0)),
+                name: core::char::decode_utf16(name)
+                    .map(|c| c.unwrap_or(core::char::REPLACEMENT_CHARACTER))
+                    .collect(),
+            })
+        })
+        .collect();
+    Ok(Some(partitions))
+}
+
+/// A window of blocks on another device. Block 0 is the partition's first
+/// block, and nothing outside the partition can be reached through it.
+pub struct Partition<D: BlockDevice> {
+    device: D,
+    start: u64,
+    count: u64,
+}
+
+impl<D: BlockDevice> Partition<D> {
+    /// Exposes `entry` of a table read from `device`. Fails if the partition
+    /// extends past the end of the device.
+    pub fn new(device: D, entry: &PartitionEntry) -> Result<Partition<D>, BlkError> {
+        entry
+            .start
+            .checked_add(entry.count)
+            .filter(|end| *end <= device.block_count())
+            .ok_or(BlkError::OutOfRange)?;
+        Ok(Partition {
+            device,
+            start: entry.start,
+            count: entry.count,
+        })
+    }
+}
+
+impl<D: BlockDevice> BlockDevice for Partition<D> {
+    fn block_size(&self) -> usize {
+        self.device.block_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        self.count
+    }
+
+    fn read(&mut self, start: u64, data: &mut [u8]) -> Result<(), BlkError> {
+        byte_range(self, start, data.len())?;
+        self.device.read(self.start + start, data)
+    }
+
+    fn write(&mut self, start: u64, data: &[u8]) -> Result<(), BlkError> {
+        byte_range(self, start, data.len())?;
+        self.device.write(self.start + start, data)
+    }
+
+    fn flush(&mut self) -> Result<(), BlkError> {
+        self.device.flush()
+    }
+
+    fn discard(&mut self, start: u64, count: u64) -> Result<(), BlkError> {
+        match start.checked_add(count) {
+            Some(end) if end <= self.count => self.device.discard(self.start + start, count),
+            _ => Err(BlkError::OutOfRange),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::{Locked, RamDisk};
+    use crate::mutex::Mutex;
+
+    /// Sets MBR or EBR entry `index` in `sector`
+    fn set_mbr_entry(sector: &mut [u8], index: usize, kind: u8, start: u32, count: u32) {
+        let entry = &mut sector[446 + index * 16..446 + (index + 1) * 16];
+        entry[4] = kind;
+        entry[8..12].copy_from_slice(&start.to_le_bytes());
+        entry[12..16].copy_from_slice(&count.to_le_bytes());
+        sector[510..512].copy_from_slice(&[0x55, 0xaa]);
+    }
+
+    /// A GPT header at `lba` whose entries are at `entries`
+    fn gpt_header(lba: u64, alternate: u64, entries: u64, entries_crc: u32) -> [u8; 512] {
+        let mut header = [0u8; 512];
+        header[..8].copy_from_slice(b"EFI PART");
+        header[8..12].copy_from_slice(&0x10000u32.to_le_bytes());
+        header[12..16].copy_from_slice(&92u32.to_le_bytes());
+        header[24..32].copy_from_slice(&lba.to_le_bytes());
+        header[32..40].copy_from_slice(&alternate.to_le_bytes());
+        header[72..80].copy_from_slice(&entries.to_le_bytes());
+        header[80..84].copy_from_slice(&4u32.to_le_bytes());
+        header[84..88].copy_from_slice(&128u32.to_le_bytes());
+        header[88..92].copy_from_slice(&entries_crc.to_le_bytes());
+        let crc = crc32(&header[..92]);
+        header[16..20].copy_from_slice(&crc.to_le_bytes());
+        header
+    }
+
+    #[test]
+    fn partitions() {
+        // An MBR with a primary partition and two logical ones
+        let mut disk = RamDisk::new(512, 64);
+        let mut sector = [0u8; 512];
+        set_mbr_entry(&mut sector, 0, 0x83, 2, 10);
+        set_mbr_entry(&mut sector, 1, 0x05, 20, 40);
+        disk.write(0, &sector).expect("write failed");
+        let mut sector = [0u8; 512];
+        set_mbr_entry(&mut sector, 0, 0x83, 1, 5);
+        set_mbr_entry(&mut sector, 1, 0x05, 10, 20);
+        disk.write(20, &sector).expect("write failed");
+        let mut sector = [0u8; 512];
+        set_mbr_entry(&mut sector, 0, 0x0c, 1, 8);
+        disk.write(30, &sector).expect("write failed");
+        let found = read_partitions(&mut disk).expect("MBR read failed");
+        let numbers: Vec<(u32, u64)> = found.iter().map(|p| (p.number, p.start)).collect();
+        assert!(
+            numbers == [(1, 2), (2, 20), (5, 21), (6, 31)],
+            "wrong MBR partitions"
+        );
+        assert!(
+            found[3].kind == PartitionType::Mbr(0x0c),
+            "wrong MBR partition type"
+        );
+
+        let disk = Mutex::new(Some(disk));
+        let mut partition = Partition::new(Locked(&disk), &found[0]).expect("open failed");
+        let mut block = [0u8; 512];
+        assert!(
+            partition.read(10, &mut block) == Err(BlkError::OutOfRange),
+            "read past the end of a partition"
+        );
+        partition.write(0, &[1u8; 512]).expect("write failed");
+        disk.map(|disk| disk.read(2, &mut block));
+        assert!(block == [1u8; 512], "partition not offset");
+
+        // A GPT with one partition, then with its primary header damaged
+        let mut disk = RamDisk::new(512, 64);
+        let mut sector = [0u8; 512];
+        set_mbr_entry(&mut sector, 0, 0xee, 1, 63);
+        disk.write(0, &sector).expect("write failed");
+        let mut entries = [0u8; 512];
+        entries[..16].copy_from_slice(&[0xaf; 16]);
+        entries[32..40].copy_from_slice(&34u64.to_le_bytes());
+        entries[40..48].copy_from_slice(&43u64.to_le_bytes());
+        for (i, c) in "boot".encode_utf16().enumerate() {
+            entries[56 + i * 2..58 + i * 2].copy_from_slice(&c.to_le_bytes());
+        }
+        let entries_crc = crc32(&entries);
+        disk.write(2, &entries).expect("write failed");
+        disk.write(62, &entries).expect("write failed");
+        disk.write(1, &gpt_header(1, 63, 2, entries_crc))
+            .expect("write failed");
+        disk.write(63, &gpt_header(63, 1, 62, entries_crc))
+            .expect("write failed");
+        for damaged in [None, Some(1)].iter() {
+            if let Some(lba) = *damaged {
+                let mut header = [0u8; 512];
+                disk.read(lba, &mut header).expect("read failed");
+                header[40] ^= 1;
+                disk.write(lba, &header).expect("write failed");
+            }
+            let found = read_partitions(&mut disk).expect("GPT read failed");
+            assert!(found.len() == 1, "wrong number of GPT partitions");
+            assert!(
+                found[0].start == 34 && found[0].count == 10,
+                "wrong GPT partition extent"
+            );
+            assert!(found[0].name == "boot", "wrong GPT partition name");
+        }
+
+        // Damaging the backup too leaves the protective MBR
+        let mut header = [0u8; 512];
+        disk.read(63, &mut header).expect("read failed");
+        header[40] ^= 1;
+        disk.write(63, &header).expect("write failed");
+        let found = read_partitions(&mut disk).expect("MBR read failed");
+        assert!(
+            found.len() == 1 && found[0].kind == PartitionType::Mbr(0xee),
+            "corrupt GPT accepted"
+        );
+    }
+
+    #[test]
+    fn damaged_gpt_entries() {
+        let mut disk = RamDisk::new(512, 64);
+        let mut sector = [0u8; 512];
+        set_mbr_entry(&mut sector, 0, 0xee, 1, 63);
+        disk.write(0, &sector).expect("write failed");
+        // A good entry, one whose end wraps around, one that ends before it
+        // starts and one past the end of the disk
+        let mut entries = [0u8; 512];
+        for (i, &(first, last)) in [(34u64, 43), (0, u64::MAX), (40, 30), (50, 99)]
+            .iter()
+            .enumerate()
+        {
+            let entry = &mut entries[i * 128..(i + 1) * 128];
+            entry[..16].copy_from_slice(&[0xaf; 16]);
+            entry[32..40].copy_from_slice(&first.to_le_bytes());
+            entry[40..48].copy_from_slice(&last.to_le_bytes());
+        }
+        disk.write(2, &entries).expect("write failed");
+        disk.write(1, &gpt_header(1, 63, 2, crc32(&entries)))
+            .expect("write failed");
+        let found = read_partitions(&mut disk).expect("GPT read failed");
+        assert!(
+            found.len() == 1 && found[0].number == 1 && found[0].count == 10,
+            "damaged GPT entries accepted"
+        );
+
+        // A header whose entries would each be a gigabyte
+        let mut header = gpt_header(1, 63, 2, crc32(&entries));
+        header[84..88].copy_from_slice(&(1u32 << 30).to_le_bytes());
+        header[16..20].copy_from_slice(&[0; 4]);
+        let crc = crc32(&header[..92]);
+        header[16..20].copy_from_slice(&crc.to_le_bytes());
+        disk.write(1, &header).expect("write failed");
+        let found = read_partitions(&mut disk).expect("GPT read failed");
+        assert!(
+            found
+                .iter()
+                .all(|entry| entry.kind == PartitionType::Mbr(0xee)),
+            "oversized GPT entries accepted"
+        );
+    }
+}
@@ -0,0 +1,14 @@
+pub mod aes;
+pub mod sha256;
+
+/// Bytes from a string of hex digits, for test vectors
+#[cfg(test)]
+pub fn unhex(hex: &str) -> Vec<u8> {
+    hex.as_bytes()
+        .chunks(2)
+        .map(|pair| {
+            let digit = |c: u8| (c as char).to_digit(16).unwrap_or(0) as u8;
+            digit(pair[0]) << 4 | digit(pair[1])
+        })
+        .collect()
+}
@@ -0,0 +1,365 @@
+use core::convert::TryInto;
+
+pub const BLOCK_SIZE: usize = 16;
+pub const KEY_SIZE: usize = 32;
+const ROUNDS: usize = 14;
+
+/// Multiplication in AES's GF(2^8)
+const fn mul(mut a: u8, mut b: u8) -> u8 {
+    let mut product = 0;
+    while b != 0 {
+        if b & 1 != 0 {
+            product ^= a;
+        }
+        a = (a << 1) ^ if a & 0x80 != 0 { 0x1b } else { 0 };
+        b >>= 1;
+    }
+    product
+}
+
+const fn sbox() -> [u8; 256] {
+    // Powers of the generator 3 and their logarithms, to find inverses
+    let mut exp = [0u8; 255];
+    let mut log = [0u8; 256];
+    let mut power = 1u8;
+    let mut i = 0;
+    while i < 255 {
+        exp[i] = power;
+        log[power as usize] = i as u8;
+        power = mul(power, 3);
+        i += 1;
+    }
+    let mut sbox = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        // 0 has no inverse, and maps to 0
+        let b = if i == 0 {
+            0
+        } else {
+            exp[(255 - log[i] as usize) % 255]
+        };
+        sbox[i] =
+            b ^ b.rotate_left(1) ^ b.rotate_left(2) ^ b.rotate_left(3) ^ b.rotate_left(4) ^ 0x63;
+        i += 1;
+    }
+    sbox
+}
+
+const fn inverse_sbox(sbox: &[u8; 256]) -> [u8; 256] {
+    let mut inverse = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        inverse[sbox[i] as usize] = i as u8;
+        i += 1;
+    }
+    inverse
+}
+
+static SBOX: [u8; 256] = sbox();
+static INV_SBOX: [u8; 256] = inverse_sbox(&SBOX);
+
+type Block = [u8; BLOCK_SIZE];
+
+fn sub_bytes(state: &mut Block, sbox: &[u8; 256]) {
+    state.iter_mut().for_each(|b| *b = sbox[*b as usize]);
+}
+
+/// Rotates row `r` of the column-major state left by `r`
+fn shift_rows(state: &mut Block) {
+    let old = *state;
+    for c in 0..4 {
+        for r in 0..4 {
+            state[r + 4 * c] = old[r + 4 * ((c + r) % 4)];
+        }
+    }
+}
+
+fn inv_shift_rows(state: &mut Block) {
+    let old = *state;
+    for c in 0..4 {
+        for r in 0..4 {
+            state[r + 4 * ((c + r) % 4)] = old[r + 4 * c];
+        }
+    }
+}
+
+/// Multiplies each column by the matrix whose first row is `m`
+fn mix(state: &mut Block, m: [u8; 4]) {
+    for column in state.chunks_mut(4) {
+        let a = [column[0], column[1], column[2], column[3]];
+        for r in 0..4 {
+            column[r] = mul(a[0], m[(4 - r) % 4])
+                ^ mul(a[1], m[(5 - r) % 4])
+                ^ mul(a[2], m[(6 - r) % 4])
+                ^ mul(a[3], m[(7 - r) % 4]);
+        }
+    }
+}
+
+fn mix_columns(state: &mut Block) {
+    mix(state, [2, 3, 1, 1]);
+}
+
+fn inv_mix_columns(state: &mut Block) {
+    mix(state, [14, 11, 13, 9]);
+}
+
+fn xor(state: &mut Block, key: &Block) {
+    state.iter_mut().zip(key.iter()).for_each(|(s, k)| *s ^= k);
+}
+
+/// Whether the CPU has the ARMv8 AES instructions
+#[cfg(target_arch = "aarch64")]
+fn has_crypto_extensions() -> bool {
+    let isar0: u64;
+    unsafe { core::arch::asm!("mrs {}, ID_AA64ISAR0_EL1", out(reg) isar0) };
+    (isar0 >> 4) & 0xf != 0
+}
+
+#[cfg(not(target_arch = "aarch64"))]
+fn has_crypto_extensions() -> bool {
+    false
+}
+
+/// AES-256 with an expanded key, using the ARMv8 crypto extensions if the
+/// CPU has them. The software fallback looks up S-boxes by secret-dependent
+/// index, so isn't constant-time.
+#[derive(Clone)]
+pub struct Aes256 {
+    round_keys: [Block; ROUNDS + 1],
+    /// Round keys for the equivalent inverse cipher that AESD/AESIMC
+    /// implement: in reverse, with InvMixColumns applied to the middle ones
+    decrypt_keys: [Block; ROUNDS + 1],
+    hardware: bool,
+}
+
+impl Aes256 {
+    pub fn new(key: &[u8; KEY_SIZE]) -> Aes256 {
+        let mut words = [[0u8; 4]; 4 * (ROUNDS + 1)];
+        for (word, chunk) in words.iter_mut().zip(key.chunks(4)) {
+            word.copy_from_slice(chunk);
+        }
+        let mut rcon = 1u8;
+        for i in 8..words.len() {
+            let mut word = words[i - 1];
+            if i % 8 == 0 {
+                word = [
+                    SBOX[word[1] as usize] ^ rcon,
+                    SBOX[word[2] as usize],
+                    SBOX[word[3] as usize],
+                    SBOX[word[0] as usize],
+                ];
+                rcon = mul(rcon, 2);
+            } else if i % 8 == 4 {
+                word = word.map(|b| SBOX[b as usize]);
+            }
+            for j in 0..4 {
+                words[i][j] = words[i - 8][j] ^ word[j];
+            }
+        }
+        let mut round_keys = [[0; BLOCK_SIZE]; ROUNDS + 1];
+        for (round, key) in round_keys.iter_mut().enumerate() {
+            for (j, word) in words[round * 4..round * 4 + 4].iter().enumerate() {
+                key[j * 4..j * 4 + 4].copy_from_slice(word);
+            }
+        }
+        let mut decrypt_keys = [[0; BLOCK_SIZE]; ROUNDS + 1];
+        for (i, key) in decrypt_keys.iter_mut().enumerate() {
+            *key = round_keys[ROUNDS - i];
+            if i != 0 && i != ROUNDS {
+                inv_mix_columns(key);
+            }
+        }
+        Aes256 {
+            round_keys,
+            decrypt_keys,
+            hardware: has_crypto_extensions(),
+        }
+    }
+
+    /// Whether the ARMv8 crypto extensions are doing the work
+    pub fn hardware(&self) -> bool {
+        self.hardware
+    }
+
+    pub fn encrypt(&self, block: &mut Block) {
+        #[cfg(target_arch = "aarch64")]
+        {
+            if self.hardware {
+                return unsafe { self.encrypt_hardware(block) };
+            }
+        }
+        xor(block, &self.round_keys[0]);
+        for round in 1..=ROUNDS {
+            sub_bytes(block, &SBOX);
+            shift_rows(block);
+            if round != ROUNDS {
+                mix_columns(block);
+            }
+            xor(block, &self.round_keys[round]);
+        }
+    }
+
+    pub fn decrypt(&self, block: &mut Block) {
+        #[cfg(target_arch = "aarch64")]
+        {
+            if self.hardware {
+                return unsafe { self.decrypt_hardware(block) };
+            }
+        }
+        xor(block, &self.round_keys[ROUNDS]);
+        for round in (0..ROUNDS).rev() {
+            inv_shift_rows(block);
+            sub_bytes(block, &INV_SBOX);
+            xor(block, &self.round_keys[round]);
+            if round != 0 {
+                inv_mix_columns(block);
+            }
+        }
+    }
+
+    /// AESE does AddRoundKey, ShiftRows and SubBytes, and AESMC MixColumns
+    #[cfg(target_arch = "aarch64")]
+    #[target_feature(enable = "aes")]
+    unsafe fn encrypt_hardware(&self, block: &mut Block) {
+        use core::arch::aarch64::*;
+        let mut state = vld1q_u8(block.as_ptr());
+        for key in self.round_keys[..ROUNDS - 1].iter() {
+            state = vaesmcq_u8(vaeseq_u8(state, vld1q_u8(key.as_ptr())));
+        }
+        state = vaeseq_u8(state, vld1q_u8(self.round_keys[ROUNDS - 1].as_ptr()));
+        state = veorq_u8(state, vld1q_u8(self.round_keys[ROUNDS].as_ptr()));
+        vst1q_u8(block.as_mut_ptr(), state);
+    }
+
+    /// AESD does AddRoundKey, InvShiftRows and InvSubBytes, and AESIMC
+    /// InvMixColumns
+    #[cfg(target_arch = "aarch64")]
+    #[target_feature(enable = "aes")]
+    unsafe fn decrypt_hardware(&self, block: &mut Block) {
+        use core::arch::aarch64::*;
+        let mut state = vld1q_u8(block.as_ptr());
+        for key in self.decrypt_keys[..ROUNDS - 1].iter() {
+            state = vaesimcq_u8(vaesdq_u8(state, vld1q_u8(key.as_ptr())));
+        }
+        state = vaesdq_u8(state, vld1q_u8(self.decrypt_keys[ROUNDS - 1].as_ptr()));
+        state = veorq_u8(state, vld1q_u8(self.decrypt_keys[ROUNDS].as_ptr()));
+        vst1q_u8(block.as_mut_ptr(), state);
+    }
+}
+
+impl Drop for Aes256 {
+    /// Keeps the key from lingering in freed memory
+    fn drop(&mut self) {
+        for key in self
+            .round_keys
+            .iter_mut()
+            .chain(self.decrypt_keys.iter_mut())
+        {
+            unsafe { core::ptr::write_volatile(key, [0; BLOCK_SIZE]) };
+        }
+    }
+}
+
+/// AES-256-XTS, as in IEEE 1619, over data units whose tweak is their
+/// number. Units must be whole AES blocks, so there's no ciphertext
+/// stealing.
+#[derive(Clone)]
+pub struct Xts {
+    data: Aes256,
+    tweak: Aes256,
+}
+
+impl Xts {
+    /// `key` is the data key followed by the tweak key
+    pub fn new(key: &[u8; 2 * KEY_SIZE]) -> Xts {
+        let (data, tweak) = key.split_at(KEY_SIZE);
+        Xts {
+            data: Aes256::new(data.try_into().unwrap()),
+            tweak: Aes256::new(tweak.try_into().unwrap()),
+        }
+    }
+
+    pub fn hardware(&self) -> bool {
+        self.data.hardware()
+    }
+
+    pub fn encrypt(&self, unit: u64, data: &mut [u8]) {
+        self.crypt(unit, data, |block| self.data.encrypt(block));
+    }
+
+    pub fn decrypt(&self, unit: u64, data: &mut [u8]) {
+        self.crypt(unit, data, |block| self.data.decrypt(block));
+    }
+
+    fn crypt(&self, unit: u64, data: &mut [u8], cipher: impl Fn(&mut Block)) {
+        debug_assert!(data.len() % BLOCK_SIZE == 0);
+        let mut tweak = [0; BLOCK_SIZE];
+        tweak[..8].copy_from_slice(&unit.to_le_bytes());
+        self.tweak.encrypt(&mut tweak);
+        for chunk in data.chunks_exact_mut(BLOCK_SIZE) {
+            let block: &mut Block = chunk.try_into().unwrap();
+            xor(block, &tweak);
+            cipher(block);
+            xor(block, &tweak);
+            // Multiply the tweak by x in GF(2^128), little-endian
+            let carry = tweak[BLOCK_SIZE - 1] >> 7;
+            for i in (1..BLOCK_SIZE).rev() {
+                tweak[i] = tweak[i] << 1 | tweak[i - 1] >> 7;
+            }
+            tweak[0] = (tweak[0] << 1) ^ (carry * 0x87);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::unhex;
+
+    #[test]
+    fn aes_256() {
+        // FIPS-197 appendix C.3
+        let mut key = [0; 32];
+        key.iter_mut().enumerate().for_each(|(i, b)| *b = i as u8);
+        let aes = Aes256::new(&key);
+        let mut block = [0; 16];
+        block
+            .iter_mut()
+            .enumerate()
+            .for_each(|(i, b)| *b = i as u8 * 0x11);
+        aes.encrypt(&mut block);
+        assert!(
+            block[..] == unhex("8ea2b7ca516745bfeafc49904b496089")[..],
+            "wrong AES-256 ciphertext"
+        );
+        aes.decrypt(&mut block);
+        assert!(
+            block[..] == unhex("00112233445566778899aabbccddeeff")[..],
+            "AES-256 didn't decrypt"
+        );
+    }
+
+    #[test]
+    fn xts() {
+        // IEEE 1619 XTS-AES-256 vector 10
+        let mut key = [0; 64];
+        key.copy_from_slice(&unhex(
+            "27182818284590452353602874713526624977572470936999595749669676273141592653589793238462643383279502884197169399375105820974944592",
+        ));
+        let xts = Xts::new(&key);
+        let mut data = [0; 512];
+        data.iter_mut().enumerate().for_each(|(i, b)| *b = i as u8);
+        xts.encrypt(0xff, &mut data);
+        assert!(
+            data[..32]
+                == unhex("1c3b3a102f770386e4836c99e370cf9bea00803f5e482357a4ae12d414a3e63b")[..],
+            "wrong XTS ciphertext"
+        );
+        xts.decrypt(0xff, &mut data);
+        assert!(
+            data.iter().enumerate().all(|(i, b)| *b == i as u8),
+            "XTS didn't decrypt"
+        );
+    }
+}
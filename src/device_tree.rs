@@ -185,6 +185,22 @@ impl<'a> Node<'a> {
         self.children().find(|node| node.name == name)
     }
 
+    /// Finds the node anywhere below this one whose `phandle` is `phandle`
+    pub fn descendant_by_phandle(&self, phandle: u32) -> Option<Node<'a>> {
+        for child in self.children() {
+            if child
+                .props()
+                .any(|prop| prop.name == b"phandle" && prop.value == &phandle.to_be_bytes()[..])
+            {
+                return Some(child);
+            }
+            if let Some(node) = child.descendant_by_phandle(phandle) {
+                return Some(node);
+            }
+        }
+        None
+    }
+
     fn child_by_path_helper<'b, I: Iterator<Item = &'b [u8]>>(
         self,
         mut path: I,
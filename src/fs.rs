@@ -0,0 +1,92 @@
+use crate::block::BlkError;
+
+pub mod ext2;
+pub mod fat;
+pub mod kv;
+pub mod tmpfs;
+pub mod vfs;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FileType {
+    File,
+    Dir,
+    Symlink,
+    /// Devices, FIFOs and sockets, which there's nothing to read from
+    Other,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FsError {
+    /// The underlying block device failed
+    Io(BlkError),
+    /// No such file or directory
+    NotFound,
+    /// A path component that should be a directory isn't one
+    NotADirectory,
+    /// The operation needs a file, not a directory
+    IsADirectory,
+    AlreadyExists,
+    /// A directory to be removed still has entries
+    NotEmpty,
+    /// No free clusters or directory entries left
+    NoSpace,
+    /// The name can't be stored on this filesystem
+    InvalidName,
+    /// The on-disk structures don't make sense
+    Corrupt,
+    /// The filesystem uses a feature this driver doesn't handle
+    Unsupported,
+    ReadOnly,
+    /// Too many symbolic links were followed resolving a path
+    Loop,
+    /// A mount point can't be removed or mounted over
+    Busy,
+    /// A bad seek position, or an operation that makes no sense for the file
+    InvalidArgument,
+}
+
+impl FsError {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FsError::Io(err) => err.as_str(),
+            FsError::NotFound => "no such file or directory",
+            FsError::NotADirectory => "not a directory",
+            FsError::IsADirectory => "is a directory",
+            FsError::AlreadyExists => "file exists",
+            FsError::NotEmpty => "directory not empty",
+            FsError::NoSpace => "no space left on device",
+            FsError::InvalidName => "invalid file name",
+            FsError::Corrupt => "filesystem is corrupt",
+            FsError::Unsupported => "unsupported filesystem feature",
+            FsError::ReadOnly => "read-only filesystem",
+            FsError::Loop => "too many levels of symbolic links",
+            FsError::Busy => "device or resource busy",
+            FsError::InvalidArgument => "invalid argument",
+        }
+    }
+}
+
+impl From<BlkError> for FsError {
+    fn from(err: BlkError) -> FsError {
+        match err {
+            BlkError::ReadOnly => FsError::ReadOnly,
+            err => FsError::Io(err),
+        }
+    }
+}
+
+/// The components of `path`, ignoring empty ones so that `/a//b/` is `a/b`
+pub fn components(path: &str) -> impl DoubleEndedIterator<Item = &str> {
+    path.split('/').filter(|component| !component.is_empty())
+}
+
+/// Splits `path` into its parent directory and final component. `None` for
+/// a path with no components, such as `/`.
+pub fn split_last(path: &str) -> Option<(&str, &str)> {
+    let path = path.trim_end_matches('/');
+    match path.rfind('/') {
+        Some(slash) => Some((&path[..slash], &path[slash + 1..])),
+        None if path.is_empty() => None,
+        None => Some(("", path)),
+    }
+}
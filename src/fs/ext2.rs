@@ -0,0 +1,853 @@
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::convert::TryInto;
+
+use super::{components, FileType, FsError};
+use crate::block::BlockDevice;
+
+mod vfs;
+
+pub use vfs::Ext2Volume;
+
+const SUPERBLOCK_OFFSET: u64 = 1024;
+const SUPERBLOCK_SIZE: usize = 1024;
+const MAGIC: u16 = 0xef53;
+const ROOT_INODE: u32 = 2;
+
+const COMPAT_HAS_JOURNAL: u32 = 0x4;
+
+const INCOMPAT_FILETYPE: u32 = 0x2;
+/// The journal needs replaying. Reading without doing so only risks seeing
+/// the last few transactions missing.
+const INCOMPAT_RECOVER: u32 = 0x4;
+const INCOMPAT_EXTENTS: u32 = 0x40;
+const INCOMPAT_64BIT: u32 = 0x80;
+const INCOMPAT_MMP: u32 = 0x100;
+const INCOMPAT_FLEX_BG: u32 = 0x200;
+const INCOMPAT_CSUM_SEED: u32 = 0x2000;
+const INCOMPAT_LARGEDIR: u32 = 0x4000;
+/// Features that change nothing a reader has to know about
+const INCOMPAT_SUPPORTED: u32 = INCOMPAT_FILETYPE
+    | INCOMPAT_RECOVER
+    | INCOMPAT_EXTENTS
+    | INCOMPAT_64BIT
+    | INCOMPAT_MMP
+    | INCOMPAT_FLEX_BG
+    | INCOMPAT_CSUM_SEED
+    | INCOMPAT_LARGEDIR;
+/// Features only ext4 has
+const INCOMPAT_EXT4: u32 = INCOMPAT_EXTENTS
+    | INCOMPAT_64BIT
+    | INCOMPAT_MMP
+    | INCOMPAT_FLEX_BG
+    | INCOMPAT_CSUM_SEED
+    | INCOMPAT_LARGEDIR;
+
+const MODE_TYPE: u16 = 0xf000;
+const MODE_DIR: u16 = 0x4000;
+const MODE_FILE: u16 = 0x8000;
+const MODE_SYMLINK: u16 = 0xa000;
+
+const FLAG_EXTENTS: u32 = 0x80000;
+const FLAG_INLINE_DATA: u32 = 0x1000_0000;
+
+const EXTENT_MAGIC: u16 = 0xf30a;
+/// Deeper extent trees than this can't describe a 2^32 block file, so must
+/// be corrupt
+const MAX_EXTENT_DEPTH: u16 = 5;
+/// Lengths above this mark an extent that is allocated but unwritten
+const EXTENT_INIT_MAX: u16 = 32768;
+
+/// Blocks mapped directly by an inode before its indirect blocks
+const DIRECT_BLOCKS: usize = 12;
+/// Size of `i_block`, which holds block pointers, an extent tree or a short
+/// symlink target
+const INODE_BLOCK_SIZE: usize = 60;
+const MAX_SYMLINKS: u32 = 8;
+
+fn le16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+}
+
+fn le32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+/// An inode as read from the inode table
+#[derive(Clone, Debug)]
+pub struct Inode {
+    pub number: u32,
+    pub mode: u16,
+    pub size: u64,
+    pub links: u16,
+    flags: u32,
+    block: [u8; INODE_BLOCK_SIZE],
+}
+
+impl Inode {
+    pub fn file_type(&self) -> FileType {
+        match self.mode & MODE_TYPE {
+            MODE_FILE => FileType::File,
+            MODE_DIR => FileType::Dir,
+            MODE_SYMLINK => FileType::Symlink,
+            _ => FileType::Other,
+        }
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.file_type() == FileType::Dir
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct DirEntry {
+    pub name: String,
+    pub inode: u32,
+}
+
+/// A read-only ext2, ext3 or ext4 filesystem on a block device
+pub struct Ext2Fs<D: BlockDevice> {
+    device: D,
+    block_size: usize,
+    /// Device blocks per filesystem block
+    device_blocks: u64,
+    blocks: u64,
+    inodes: u32,
+    inodes_per_group: u32,
+    inode_size: usize,
+    filetype: bool,
+    /// `ext2`, `ext3` or `ext4`
+    kind: &'static str,
+    /// Where each group's inode table starts
+    inode_tables: Vec<u64>,
+}
+
+impl<D: BlockDevice> Ext2Fs<D> {
+    /// Reads the superblock and group descriptors of the filesystem on
+    /// `device`. Filesystems with features that change how files are found
+    /// or stored are refused.
+    pub fn mount(mut device: D) -> Result<Ext2Fs<D>, FsError> {
+        let device_block_size = device.block_size() as u64;
+        if device_block_size > SUPERBLOCK_OFFSET || SUPERBLOCK_OFFSET % device_block_size != 0 {
+            return Err(FsError::Unsupported);
+        }
+        let mut superblock = vec![0; SUPERBLOCK_SIZE];
+        device.read(SUPERBLOCK_OFFSET / device_block_size, &mut superblock)?;
+        if le16(&superblock, 56) != MAGIC {
+            return Err(FsError::Unsupported);
+        }
+        let incompat = le32(&superblock, 96);
+        if incompat & !INCOMPAT_SUPPORTED != 0 {
+            return Err(FsError::Unsupported);
+        }
+
+        let log_block_size = le32(&superblock, 24);
+        if log_block_size > 6 {
+            return Err(FsError::Corrupt);
+        }
+        let block_size = 1024usize << log_block_size;
+        if block_size as u64 % device_block_size != 0 {
+            return Err(FsError::Unsupported);
+        }
+        let wide = incompat & INCOMPAT_64BIT != 0;
+        let mut blocks = le32(&superblock, 4) as u64;
+        if wide {
+            blocks |= (le32(&superblock, 0x150) as u64) << 32;
+        }
+        let first_data_block = le32(&superblock, 20) as u64;
+        let blocks_per_group = le32(&superblock, 32) as u64;
+        let inodes = le32(&superblock, 0);
+        let inodes_per_group = le32(&superblock, 40);
+        let inode_size = match le32(&superblock, 76) {
+            0 => 128,
+            _ => le16(&superblock, 88) as usize,
+        };
+        let desc_size = match le16(&superblock, 254) as usize {
+            size if wide && size >= 64 => size,
+            _ => 32,
+        };
+        if blocks_per_group == 0
+            || inodes_per_group == 0
+            || inode_size < 128
+            || !inode_size.is_power_of_two()
+            || inode_size > block_size
+            || first_data_block >= blocks
+        {
+            return Err(FsError::Corrupt);
+        }
+        let device_blocks = block_size as u64 / device_block_size;
+        if blocks * device_blocks > device.block_count() {
+            return Err(FsError::Corrupt);
+        }
+
+        let mut fs = Ext2Fs {
+            device,
+            block_size,
+            device_blocks,
+            blocks,
+            inodes,
+            inodes_per_group,
+            inode_size,
+            filetype: incompat & INCOMPAT_FILETYPE != 0,
+            kind: if incompat & INCOMPAT_EXT4 != 0 {
+                "ext4"
+            } else if le32(&superblock, 92) & COMPAT_HAS_JOURNAL != 0 {
+                "ext3"
+            } else {
+                "ext2"
+            },
+            inode_tables: Vec::new(),
+        };
+
+        // The descriptor table starts in the block after the superblock
+        let groups = (blocks - first_data_block + blocks_per_group - 1) / blocks_per_group;
+        let table_blocks = (groups as usize * desc_size + block_size - 1) / block_size;
+        let mut table = vec![0; table_blocks * block_size];
+        for (i, block) in table.chunks_mut(block_size).enumerate() {
+            fs.read_block(first_data_block + 1 + i as u64, block)?;
+        }
+        fs.inode_tables = table
+            .chunks(desc_size)
+            .take(groups as usize)
+            .map(|desc| {
+                let mut inode_table = le32(desc, 8) as u64;
+                if desc_size >= 64 {
+                    inode_table |= (le32(desc, 0x28) as u64) << 32;
+                }
+                inode_table
+            })
+            .collect();
+        Ok(fs)
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    /// Which of ext2, ext3 or ext4 the features in use make this
+    pub fn kind(&self) -> &'static str {
+        self.kind
+    }
+
+    pub fn device(&mut self) -> &mut D {
+        &mut self.device
+    }
+
+    fn read_block(&mut self, block: u64, data: &mut [u8]) -> Result<(), FsError> {
+        if block >= self.blocks {
+            return Err(FsError::Corrupt);
+        }
+        Ok(self.device.read(block * self.device_blocks, data)?)
+    }
+
+    pub fn inode(&mut self, number: u32) -> Result<Inode, FsError> {
+        if number == 0 || number > self.inodes {
+            return Err(FsError::Corrupt);
+        }
+        let index = (number - 1) as u64;
+        let group = (index / self.inodes_per_group as u64) as usize;
+        let offset = (index % self.inodes_per_group as u64) * self.inode_size as u64;
+        let table = *self.inode_tables.get(group).ok_or(FsError::Corrupt)?;
+        let mut data = vec![0; self.block_size];
+        self.read_block(table + offset / self.block_size as u64, &mut data)?;
+        let raw = &data[(offset % self.block_size as u64) as usize..][..self.inode_size];
+        let mode = le16(raw, 0);
+        Ok(Inode {
+            number,
+            mode,
+            size: le32(raw, 4) as u64 | (le32(raw, 108) as u64) << 32,
+            links: le16(raw, 26),
+            flags: le32(raw, 32),
+            block: raw[40..40 + INODE_BLOCK_SIZE].try_into().unwrap(),
+        })
+    }
+
+    pub fn root(&mut self) -> Result<Inode, FsError> {
+        self.inode(ROOT_INODE)
+    }
+
+    /// The filesystem block holding block `logical` of a file, or `None` for
+    /// a hole, which reads as zeros
+    fn map(&mut self, inode: &Inode, logical: u64) -> Result<Option<u64>, FsError> {
+        if inode.flags & FLAG_INLINE_DATA != 0 {
+            return Err(FsError::Unsupported);
+        }
+        if logical > u32::max_value() as u64 {
+            return Ok(None);
+        }
+        if inode.flags & FLAG_EXTENTS != 0 {
+            self.map_extent(&inode.block, logical as u32, MAX_EXTENT_DEPTH)
+        } else {
+            self.map_indirect(&inode.block, logical)
+        }
+    }
+
+    /// Searches the extent tree node `node` for `logical`
+    fn map_extent(
+        &mut self,
+        node: &[u8],
+        logical: u32,
+        max_depth: u16,
+    ) -> Result<Option<u64>, FsError> {
+        let entries = le16(node, 2) as usize;
+        let depth = le16(node, 6);
+        if le16(node, 0) != EXTENT_MAGIC || 12 * (entries + 1) > node.len() || depth > max_depth {
+            return Err(FsError::Corrupt);
+        }
+        let entry = |i: usize| &node[12 * (i + 1)..12 * (i + 2)];
+        if depth == 0 {
+            for i in 0..entries {
+                let extent = entry(i);
+                let start = le32(extent, 0);
+                let (len, initialized) = match le16(extent, 4) {
+                    len if len > EXTENT_INIT_MAX => (len - EXTENT_INIT_MAX, false),
+                    len => (len, true),
+                };
+                if logical >= start && logical - start < len as u32 {
+                    if !initialized {
+                        return Ok(None);
+                    }
+                    let physical = (le16(extent, 6) as u64) << 32 | le32(extent, 8) as u64;
+                    return Ok(Some(physical + (logical - start) as u64));
+                }
+            }
+            return Ok(None);
+        }
+        // Index entries are sorted, and each covers blocks up to the next
+        let index = (0..entries)
+            .take_while(|i| le32(entry(*i), 0) <= logical)
+            .last();
+        let index = match index {
+            Some(index) => entry(index),
+            None => return Ok(None),
+        };
+        let child = (le16(index, 8) as u64) << 32 | le32(index, 4) as u64;
+        let mut data = vec![0; self.block_size];
+        self.read_block(child, &mut data)?;
+        self.map_extent(&data, logical, depth - 1)
+    }
+
+    /// Follows the direct and indirect block pointers of ext2/3
+    fn map_indirect(&mut self, pointers: &[u8], logical: u64) -> Result<Option<u64>, FsError> {
+        let per_block = (self.block_size / 4) as u64;
+        // The slot in `i_block` to start from, and the indices into each
+        // level of indirect block below it
+        let mut path = [0u64; 3];
+        let (slot, levels) = if logical < DIRECT_BLOCKS as u64 {
+            (logical as usize, 0)
+        } else {
+            let mut rest = logical - DIRECT_BLOCKS as u64;
+            let mut span = per_block;
+            let mut level = 1;
+            while rest >= span {
+                rest -= span;
+                span *= per_block;
+                level += 1;
+                if level > 3 {
+                    return Ok(None);
+                }
+            }
+            for i in (0..level).rev() {
+                path[i] = rest % per_block;
+                rest /= per_block;
+            }
+            (DIRECT_BLOCKS + level - 1, level)
+        };
+        let mut block = le32(pointers, slot * 4) as u64;
+        let mut data = vec![0; self.block_size];
+        for index in path[..levels].iter() {
+            if block == 0 {
+                return Ok(None);
+            }
+            self.read_block(block, &mut data)?;
+            block = le32(&data, *index as usize * 4) as u64;
+        }
+        Ok(Some(block).filter(|block| *block != 0))
+    }
+
+    /// Reads from `offset` in a file, returning how much was read, which is
+    /// less than asked for at the end of the file
+    pub fn read(&mut self, inode: &Inode, offset: u64, data: &mut [u8]) -> Result<usize, FsError> {
+        if inode.is_dir() {
+            return Err(FsError::IsADirectory);
+        }
+        self.read_data(inode, offset, data)
+    }
+
+    /// Reads from the blocks of any kind of inode
+    fn read_data(&mut self, inode: &Inode, offset: u64, data: &mut [u8]) -> Result<usize, FsError> {
+        if offset >= inode.size {
+            return Ok(0);
+        }
+        let len = data.len().min((inode.size - offset) as usize);
+        let block_size = self.block_size as u64;
+        let mut block_data = vec![0; self.block_size];
+        let mut done = 0;
+        while done < len {
+            let position = offset + done as u64;
+            let start = (position % block_size) as usize;
+            let chunk = (self.block_size - start).min(len - done);
+            match self.map(inode, position / block_size)? {
+                Some(block) if chunk == self.block_size => {
+                    self.read_block(block, &mut data[done..done + chunk])?
+                }
+                Some(block) => {
+                    self.read_block(block, &mut block_data)?;
+                    data[done..done + chunk].copy_from_slice(&block_data[start..start + chunk]);
+                }
+                None => data[done..done + chunk].iter_mut().for_each(|b| *b = 0),
+            }
+            done += chunk;
+        }
+        Ok(len)
+    }
+
+    /// The entries of a directory, without `.` and `..`
+    pub fn read_dir(&mut self, dir: &Inode) -> Result<Vec<DirEntry>, FsError> {
+        Ok(self
+            .dir_entries(dir)?
+            .into_iter()
+            .filter(|entry| entry.name != "." && entry.name != "..")
+            .collect())
+    }
+
+    /// Every entry of a directory. Hashed directories are read as plain ones,
+    /// as their index blocks look like empty entries.
+    fn dir_entries(&mut self, dir: &Inode) -> Result<Vec<DirEntry>, FsError> {
+        if !dir.is_dir() {
+            return Err(FsError::NotADirectory);
+        }
+        let mut entries = Vec::new();
+        let mut block = vec![0; self.block_size];
+        let mut offset = 0;
+        while offset < dir.size {
+            let len = self.read_data(dir, offset, &mut block)?;
+            let mut position = 0;
+            while position + 8 <= len {
+                let entry = &block[position..len];
+                let inode = le32(entry, 0);
+                let rec_len = le16(entry, 4) as usize;
+                let name_len = if self.filetype {
+                    entry[6] as usize
+                } else {
+                    le16(entry, 6) as usize
+                };
+                if rec_len < 8 || rec_len > entry.len() || 8 + name_len > rec_len {
+                    return Err(FsError::Corrupt);
+                }
+                if inode != 0 {
+                    entries.push(DirEntry {
+                        name: String::from_utf8_lossy(&entry[8..8 + name_len]).into(),
+                        inode,
+                    });
+                }
+                position += rec_len;
+            }
+            offset += self.block_size as u64;
+        }
+        Ok(entries)
+    }
+
+    /// The entry called `name` in `dir`, without following symlinks
+    pub fn find(&mut self, dir: &Inode, name: &str) -> Result<Inode, FsError> {
+        let entry = self
+            .dir_entries(dir)?
+            .into_iter()
+            .find(|entry| entry.name == name)
+            .ok_or(FsError::NotFound)?;
+        self.inode(entry.inode)
+    }
+
+    /// The target of a symlink
+    pub fn read_link(&mut self, inode: &Inode) -> Result<String, FsError> {
+        if inode.file_type() != FileType::Symlink {
+            return Err(FsError::InvalidArgument);
+        }
+        // Short targets are kept in place of the block pointers
+        if inode.size < INODE_BLOCK_SIZE as u64 && inode.flags & FLAG_EXTENTS == 0 {
+            return Ok(String::from_utf8_lossy(&inode.block[..inode.size as usize]).into());
+        }
+        let mut target = vec![0; inode.size.min(4096) as usize];
+        let len = self.read_data(inode, 0, &mut target)?;
+        Ok(String::from_utf8_lossy(&target[..len]).into())
+    }
+
+    /// Follows `path` from the root, following symlinks along the way
+    pub fn lookup(&mut self, path: &str) -> Result<Inode, FsError> {
+        let root = self.root()?;
+        let mut links = 0;
+        self.resolve(root, path, &mut links)
+    }
+
+    /// Follows `path` from `dir`, or from the root if it's absolute
+    fn resolve(&mut self, dir: Inode, path: &str, links: &mut u32) -> Result<Inode, FsError> {
+        let mut inode = if path.starts_with('/') {
+            self.root()?
+        } else {
+            dir
+        };
+        for name in components(path) {
+            let parent = inode;
+            inode = self.find(&parent, name)?;
+            if inode.file_type() == FileType::Symlink {
+                *links += 1;
+                if *links > MAX_SYMLINKS {
+                    return Err(FsError::Loop);
+                }
+                let target = self.read_link(&inode)?;
+                inode = self.resolve(parent, &target, links)?;
+            }
+        }
+        Ok(inode)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::RamDisk;
+
+    const BLOCK: usize = 1024;
+    const BLOCKS: usize = 128;
+    const GROUP_BLOCKS: usize = 64;
+    const GROUP_INODES: u32 = 16;
+
+    const DIR: u16 = MODE_DIR | 0o755;
+    const FILE: u16 = MODE_FILE | 0o644;
+    const SYMLINK: u16 = MODE_SYMLINK | 0o777;
+
+    fn put16(data: &mut [u8], offset: usize, value: u16) {
+        data[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn put32(data: &mut [u8], offset: usize, value: u32) {
+        data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    /// A filesystem of 128 1KiB blocks in two groups, put together by hand.
+    /// Each group starts with its inode table, and data blocks are handed
+    /// out from the first group.
+    struct Image {
+        data: Vec<u8>,
+        inode_size: usize,
+        next_free: usize,
+    }
+
+    impl Image {
+        /// With INCOMPAT_64BIT the group descriptors are 64 bytes and the
+        /// inodes 256, otherwise 32 and 128
+        fn new(incompat: u32) -> Image {
+            let (inode_size, desc_size) = match incompat & INCOMPAT_64BIT {
+                0 => (128, 32),
+                _ => (256, 64),
+            };
+            let mut data = vec![0; BLOCKS * BLOCK];
+            let superblock = &mut data[BLOCK..2 * BLOCK];
+            put32(superblock, 0, 2 * GROUP_INODES);
+            put32(superblock, 4, BLOCKS as u32);
+            put32(superblock, 20, 1);
+            put32(superblock, 32, GROUP_BLOCKS as u32);
+            put32(superblock, 40, GROUP_INODES);
+            put16(superblock, 56, MAGIC);
+            put32(superblock, 76, 1);
+            put16(superblock, 88, inode_size as u16);
+            put32(superblock, 96, incompat);
+            put16(superblock, 254, desc_size as u16);
+            // The descriptors follow the superblock. Group 1's inode table is
+            // only found if they're stepped through at the right size.
+            for group in 0..2 {
+                let desc = &mut data[2 * BLOCK + group * desc_size..];
+                put32(desc, 8, Self::inode_table(group) as u32);
+            }
+            Image {
+                data,
+                inode_size,
+                next_free: 16,
+            }
+        }
+
+        fn inode_table(group: usize) -> usize {
+            1 + group * GROUP_BLOCKS + 4
+        }
+
+        /// Stores `contents` in a free block, returning its number
+        fn alloc(&mut self, contents: &[u8]) -> u32 {
+            let block = self.next_free;
+            self.next_free += 1;
+            assert!(block < GROUP_BLOCKS, "image out of space");
+            self.data[block * BLOCK..block * BLOCK + contents.len()].copy_from_slice(contents);
+            block as u32
+        }
+
+        /// Writes inode `number`, with `block` in place of its block pointers
+        fn inode(&mut self, number: u32, mode: u16, size: u64, flags: u32, block: &[u8]) {
+            let index = (number - 1) as usize;
+            let table = Self::inode_table(index / GROUP_INODES as usize);
+            let offset = table * BLOCK + index % GROUP_INODES as usize * self.inode_size;
+            let raw = &mut self.data[offset..offset + self.inode_size];
+            put16(raw, 0, mode);
+            put32(raw, 4, size as u32);
+            put32(raw, 108, (size >> 32) as u32);
+            put16(raw, 26, 1);
+            put32(raw, 32, flags);
+            raw[40..40 + block.len()].copy_from_slice(block);
+        }
+
+        fn disk(&self) -> RamDisk {
+            let mut disk = RamDisk::new(512, self.data.len() / 512);
+            disk.write(0, &self.data).expect("write failed");
+            disk
+        }
+    }
+
+    /// A directory block holding `entries`, the last stretched to its end
+    fn dir_block(entries: &[(u32, &str)]) -> Vec<u8> {
+        let mut block = vec![0; BLOCK];
+        let mut position = 0;
+        for (i, &(inode, name)) in entries.iter().enumerate() {
+            let rec_len = if i + 1 == entries.len() {
+                BLOCK - position
+            } else {
+                (8 + name.len() + 3) & !3
+            };
+            let entry = &mut block[position..position + rec_len];
+            put32(entry, 0, inode);
+            put16(entry, 4, rec_len as u16);
+            entry[6] = name.len() as u8;
+            entry[8..8 + name.len()].copy_from_slice(name.as_bytes());
+            position += rec_len;
+        }
+        block
+    }
+
+    /// Block pointers, or an indirect block, from `pointers`
+    fn pointers(pointers: &[u32], len: usize) -> Vec<u8> {
+        let mut data = vec![0; len];
+        for (i, &pointer) in pointers.iter().enumerate() {
+            put32(&mut data, i * 4, pointer);
+        }
+        data
+    }
+
+    /// An extent tree node of `depth` with `entries`, each given as its
+    /// first logical block, length (leaves only) and physical block
+    fn extent_node(depth: u16, entries: &[(u32, u16, u32)], len: usize) -> Vec<u8> {
+        let mut node = vec![0; len];
+        put16(&mut node, 0, EXTENT_MAGIC);
+        put16(&mut node, 2, entries.len() as u16);
+        put16(&mut node, 4, (len / 12 - 1) as u16);
+        put16(&mut node, 6, depth);
+        for (i, &(logical, count, physical)) in entries.iter().enumerate() {
+            let entry = &mut node[12 * (i + 1)..12 * (i + 2)];
+            put32(entry, 0, logical);
+            if depth == 0 {
+                put16(entry, 4, count);
+                put32(entry, 8, physical);
+            } else {
+                put32(entry, 4, physical);
+            }
+        }
+        node
+    }
+
+    fn names(fs: &mut Ext2Fs<RamDisk>, path: &str) -> Vec<String> {
+        let dir = fs.lookup(path).expect("lookup failed");
+        let entries = fs.read_dir(&dir).expect("read_dir failed");
+        entries.into_iter().map(|entry| entry.name).collect()
+    }
+
+    /// The block of a file at `logical`, read back whole
+    fn file_block(fs: &mut Ext2Fs<RamDisk>, inode: &Inode, logical: u64) -> Vec<u8> {
+        let mut data = vec![0xee; BLOCK];
+        let len = fs
+            .read(inode, logical * BLOCK as u64, &mut data)
+            .expect("read failed");
+        assert!(len == BLOCK, "short read");
+        data
+    }
+
+    #[test]
+    fn indirect_blocks() {
+        let mut image = Image::new(INCOMPAT_FILETYPE);
+
+        // A sparse file with a block at the start and one through each level
+        // of indirection
+        let per_block = (BLOCK / 4) as u64;
+        let single = DIRECT_BLOCKS as u64;
+        let double = single + per_block;
+        let triple = double + per_block * per_block;
+        let mut direct = [0u32; 15];
+        direct[0] = image.alloc(&[1; BLOCK]);
+        let data = image.alloc(&[2; BLOCK]);
+        direct[12] = image.alloc(&pointers(&[data], BLOCK));
+        let data = image.alloc(&[3; BLOCK]);
+        let level = image.alloc(&pointers(&[data], BLOCK));
+        direct[13] = image.alloc(&pointers(&[level], BLOCK));
+        let data = image.alloc(&[4; BLOCK]);
+        let mut level = image.alloc(&pointers(&[data], BLOCK));
+        for _ in 0..2 {
+            level = image.alloc(&pointers(&[level], BLOCK));
+        }
+        direct[14] = level;
+        let size = (triple + 1) * BLOCK as u64;
+        image.inode(12, FILE, size, 0, &pointers(&direct, 60));
+
+        let root = image.alloc(&dir_block(&[(2, "."), (2, ".."), (12, "sparse")]));
+        image.inode(2, DIR, BLOCK as u64, 0, &pointers(&[root], 60));
+
+        let mut fs = Ext2Fs::mount(image.disk()).expect("mount failed");
+        assert!(fs.kind() == "ext2", "ext2 not recognised");
+        let file = fs.lookup("/sparse").expect("lookup failed");
+        assert!(file.size == size, "wrong size");
+        for &(logical, fill) in [(0, 1), (single, 2), (double, 3), (triple, 4)].iter() {
+            let data = file_block(&mut fs, &file, logical);
+            assert!(data.iter().all(|&b| b == fill), "wrong block mapped");
+        }
+        for &logical in [1, single + 1, double + per_block, triple - 1].iter() {
+            let data = file_block(&mut fs, &file, logical);
+            assert!(data.iter().all(|&b| b == 0), "hole not read as zeros");
+        }
+        let mut data = [0; 2 * BLOCK];
+        assert!(
+            fs.read(&file, size - 10, &mut data) == Ok(10),
+            "read past the end of the file"
+        );
+    }
+
+    #[test]
+    fn directories_and_symlinks() {
+        let mut image = Image::new(INCOMPAT_FILETYPE);
+
+        let data = image.alloc(b"hello\n");
+        image.inode(12, FILE, 6, 0, &pointers(&[data], 60));
+        // A fast symlink keeps its target in place of the block pointers,
+        // while a slow one, too long for that, has a block of its own
+        image.inode(13, SYMLINK, 5, 0, b"hello");
+        let target = format!("/{}hello", "sub/../".repeat(10));
+        assert!(target.len() >= INODE_BLOCK_SIZE, "target fits in the inode");
+        let data = image.alloc(target.as_bytes());
+        image.inode(14, SYMLINK, target.len() as u64, 0, &pointers(&[data], 60));
+        // In the second group
+        let sub = image.alloc(&dir_block(&[(17, "."), (2, ".."), (14, "up")]));
+        image.inode(17, DIR, BLOCK as u64, 0, &pointers(&[sub], 60));
+        image.inode(18, SYMLINK, 4, 0, b"loop");
+
+        // The root spans two blocks, the second with a deleted entry
+        let first = image.alloc(&dir_block(&[
+            (2, "."),
+            (2, ".."),
+            (12, "hello"),
+            (13, "fast"),
+        ]));
+        let second = image.alloc(&dir_block(&[
+            (14, "slow"),
+            (0, "deleted"),
+            (17, "sub"),
+            (18, "loop"),
+        ]));
+        image.inode(2, DIR, 2 * BLOCK as u64, 0, &pointers(&[first, second], 60));
+
+        let mut fs = Ext2Fs::mount(image.disk()).expect("mount failed");
+        assert!(
+            names(&mut fs, "/") == ["hello", "fast", "slow", "sub", "loop"],
+            "wrong root listing"
+        );
+        assert!(names(&mut fs, "/sub") == ["up"], "wrong listing in group 1");
+
+        let root = fs.root().expect("root failed");
+        let fast = fs.find(&root, "fast").expect("find failed");
+        assert!(
+            fs.read_link(&fast).as_deref() == Ok("hello"),
+            "wrong fast target"
+        );
+        let slow = fs.find(&root, "slow").expect("find failed");
+        assert!(fs.read_link(&slow) == Ok(target), "wrong slow target");
+        for path in ["/hello", "/fast", "/slow", "/sub/up", "sub/../slow"].iter() {
+            let file = fs.lookup(path).expect("lookup failed");
+            let mut data = [0; 16];
+            assert!(
+                file.number == 12 && fs.read(&file, 0, &mut data) == Ok(6),
+                "symlink not followed"
+            );
+        }
+        assert!(
+            fs.lookup("/loop").err() == Some(FsError::Loop),
+            "loop followed"
+        );
+        assert!(
+            fs.lookup("/hello/x").err() == Some(FsError::NotADirectory),
+            "file searched as a directory"
+        );
+        assert!(
+            fs.lookup("/sub/x").err() == Some(FsError::NotFound),
+            "missing file found"
+        );
+    }
+
+    /// An ext4 image: extent-mapped files and 64-byte group descriptors
+    #[test]
+    fn extents() {
+        let mut image = Image::new(INCOMPAT_FILETYPE | INCOMPAT_EXTENTS | INCOMPAT_64BIT);
+
+        // Blocks 0-1 in one extent, 2-4 a hole, 5 preallocated but not
+        // written, and 6-7 a hole, through an index node
+        let mut contiguous = vec![1; BLOCK];
+        contiguous.extend_from_slice(&[2; BLOCK]);
+        let start = image.alloc(&contiguous[..BLOCK]);
+        image.alloc(&contiguous[BLOCK..]);
+        let unwritten = image.alloc(&[3; BLOCK]);
+        let leaf = image.alloc(&extent_node(
+            0,
+            &[(0, 2, start), (5, 1 + EXTENT_INIT_MAX, unwritten)],
+            BLOCK,
+        ));
+        let index = extent_node(1, &[(0, 0, leaf)], INODE_BLOCK_SIZE);
+        image.inode(17, FILE, 8 * BLOCK as u64, FLAG_EXTENTS, &index);
+
+        // A short symlink whose target is in a block, mapped by an extent
+        let data = image.alloc(b"file");
+        let node = extent_node(0, &[(0, 1, data)], INODE_BLOCK_SIZE);
+        image.inode(18, SYMLINK, 4, FLAG_EXTENTS, &node);
+
+        let root = image.alloc(&dir_block(&[
+            (2, "."),
+            (2, ".."),
+            (17, "file"),
+            (18, "link"),
+        ]));
+        let node = extent_node(0, &[(0, 1, root)], INODE_BLOCK_SIZE);
+        image.inode(2, DIR, BLOCK as u64, FLAG_EXTENTS, &node);
+
+        let mut fs = Ext2Fs::mount(image.disk()).expect("mount failed");
+        assert!(fs.kind() == "ext4", "ext4 not recognised");
+        assert!(
+            names(&mut fs, "/") == ["file", "link"],
+            "wrong root listing"
+        );
+        let file = fs.lookup("/link").expect("lookup failed");
+        assert!(file.number == 17, "symlink in an extent not followed");
+        let mut data = vec![0xee; 2 * BLOCK];
+        assert!(
+            fs.read(&file, 0, &mut data) == Ok(2 * BLOCK) && data == contiguous,
+            "extent read wrong"
+        );
+        for logical in 2..8 {
+            let data = file_block(&mut fs, &file, logical);
+            assert!(
+                data.iter().all(|&b| b == 0),
+                "hole or unwritten extent not read as zeros"
+            );
+        }
+
+        // A depth the tree can't have
+        let node = extent_node(MAX_EXTENT_DEPTH + 1, &[(0, 0, leaf)], INODE_BLOCK_SIZE);
+        image.inode(17, FILE, 8 * BLOCK as u64, FLAG_EXTENTS, &node);
+        let mut fs = Ext2Fs::mount(image.disk()).expect("mount failed");
+        let file = fs.lookup("/file").expect("lookup failed");
+        assert!(
+            fs.read(&file, 0, &mut data) == Err(FsError::Corrupt),
+            "corrupt extent tree read"
+        );
+    }
+}
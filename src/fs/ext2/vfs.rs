@@ -0,0 +1,84 @@
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use super::Ext2Fs;
+use crate::block::BlockDevice;
+use crate::fs::vfs::{DirEntry, Filesystem, Inode, InodeRef};
+use crate::fs::{FileType, FsError};
+use crate::mutex::Mutex;
+
+/// An ext2, ext3 or ext4 filesystem mounted read-only in the VFS
+pub struct Ext2Volume<D: BlockDevice> {
+    fs: Arc<Mutex<Ext2Fs<D>>>,
+}
+
+impl<D: BlockDevice> Ext2Volume<D> {
+    pub fn new(fs: Ext2Fs<D>) -> Ext2Volume<D> {
+        Ext2Volume {
+            fs: Arc::new(Mutex::new(fs)),
+        }
+    }
+}
+
+impl<D: BlockDevice + Sync + 'static> Filesystem for Ext2Volume<D> {
+    fn name(&self) -> &'static str {
+        self.fs.lock().kind()
+    }
+
+    fn root(&self) -> Result<InodeRef, FsError> {
+        let root = self.fs.lock().root()?;
+        Ok(Arc::new(Ext2Inode {
+            fs: self.fs.clone(),
+            inode: root,
+        }))
+    }
+}
+
+/// Nothing changes an ext2 inode behind our back, so unlike FAT entries
+/// they can be kept
+struct Ext2Inode<D: BlockDevice> {
+    fs: Arc<Mutex<Ext2Fs<D>>>,
+    inode: super::Inode,
+}
+
+impl<D: BlockDevice + Sync + 'static> Inode for Ext2Inode<D> {
+    fn kind(&self) -> FileType {
+        self.inode.file_type()
+    }
+
+    fn size(&self) -> Result<u64, FsError> {
+        Ok(self.inode.size)
+    }
+
+    fn lookup(&self, name: &str) -> Result<InodeRef, FsError> {
+        let inode = self.fs.lock().find(&self.inode, name)?;
+        Ok(Arc::new(Ext2Inode {
+            fs: self.fs.clone(),
+            inode,
+        }))
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, FsError> {
+        let mut fs = self.fs.lock();
+        fs.read_dir(&self.inode)?
+            .into_iter()
+            .map(|entry| {
+                let inode = fs.inode(entry.inode)?;
+                Ok(DirEntry {
+                    name: entry.name,
+                    kind: inode.file_type(),
+                    size: inode.size,
+                })
+            })
+            .collect()
+    }
+
+    fn read_at(&self, offset: u64, data: &mut [u8]) -> Result<usize, FsError> {
+        self.fs.lock().read(&self.inode, offset, data)
+    }
+
+    fn read_link(&self) -> Result<String, FsError> {
+        self.fs.lock().read_link(&self.inode)
+    }
+}
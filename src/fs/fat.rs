@@ -0,0 +1,775 @@
+use alloc::vec;
+use alloc::vec::Vec;
+use core::convert::TryInto;
+
+use super::{components, split_last, FsError};
+use crate::block::BlockDevice;
+
+mod dir;
+mod vfs;
+
+pub use dir::DirEntry;
+use dir::{names_match, Dir};
+pub use vfs::FatVolume;
+
+pub const ATTR_READ_ONLY: u8 = 0x01;
+pub const ATTR_HIDDEN: u8 = 0x02;
+pub const ATTR_SYSTEM: u8 = 0x04;
+pub const ATTR_VOLUME_ID: u8 = 0x08;
+pub const ATTR_DIRECTORY: u8 = 0x10;
+pub const ATTR_ARCHIVE: u8 = 0x20;
+/// The attributes marking a long name entry
+const ATTR_LONG_NAME: u8 = ATTR_READ_ONLY | ATTR_HIDDEN | ATTR_SYSTEM | ATTR_VOLUME_ID;
+
+const FSINFO_LEAD_SIGNATURE: u32 = 0x4161_5252;
+const FSINFO_STRUCT_SIGNATURE: u32 = 0x6141_7272;
+/// FSInfo free count and next free hint when they aren't known
+const FSINFO_UNKNOWN: u32 = 0xffff_ffff;
+
+/// Files can't reach 4GiB, as their size is stored in 32 bits
+const MAX_FILE_SIZE: u64 = 0xffff_ffff;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FatType {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+impl FatType {
+    /// Entries from this value up end a cluster chain
+    fn end_of_chain(self) -> u32 {
+        match self {
+            FatType::Fat12 => 0xff8,
+            FatType::Fat16 => 0xfff8,
+            FatType::Fat32 => 0x0fff_fff8,
+        }
+    }
+}
+
+fn le16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+}
+
+fn le32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+/// A FAT12, FAT16 or FAT32 filesystem on a block device.
+///
+/// Files are handled through the `DirEntry` they were looked up or created
+/// as. Writes change the entry passed in and its copy on disk together, so a
+/// stale `DirEntry` of the same file shouldn't be written through.
+pub struct FatFs<D: BlockDevice> {
+    device: D,
+    fat_type: FatType,
+    bytes_per_sector: usize,
+    sectors_per_cluster: u32,
+    /// Device blocks per filesystem sector
+    blocks_per_sector: u64,
+    fat_start: u32,
+    fat_sectors: u32,
+    fats: u32,
+    /// The fixed root directory of FAT12/16
+    root_start: u32,
+    root_sectors: u32,
+    data_start: u32,
+    /// Number of data clusters, which are numbered from 2
+    clusters: u32,
+    /// First cluster of the FAT32 root directory
+    root_cluster: u32,
+    fsinfo_sector: Option<u32>,
+    free_clusters: Option<u32>,
+    next_free: u32,
+    /// FSInfo needs writing back
+    fsinfo_dirty: bool,
+}
+
+impl<D: BlockDevice> FatFs<D> {
+    /// Reads the boot sector of the filesystem on `device`, and its FSInfo
+    /// sector for FAT32
+    pub fn mount(mut device: D) -> Result<FatFs<D>, FsError> {
+        let block_size = device.block_size();
+        if block_size < 512 || device.block_count() == 0 {
+            return Err(FsError::Unsupported);
+        }
+        let mut boot = vec![0; block_size];
+        device.read(0, &mut boot)?;
+        if boot[510..512] != [0x55, 0xaa] || (boot[0] != 0xeb && boot[0] != 0xe9) {
+            return Err(FsError::Unsupported);
+        }
+
+        let bytes_per_sector = le16(&boot, 11) as usize;
+        let sectors_per_cluster = boot[13] as u32;
+        let reserved = le16(&boot, 14) as u32;
+        let fats = boot[16] as u32;
+        let root_entries = le16(&boot, 17) as u32;
+        let total_sectors = match le16(&boot, 19) {
+            0 => le32(&boot, 32),
+            total => total as u32,
+        };
+        let fat_sectors = match le16(&boot, 22) {
+            0 => le32(&boot, 36),
+            sectors => sectors as u32,
+        };
+        if ![512, 1024, 2048, 4096].contains(&bytes_per_sector)
+            || !sectors_per_cluster.is_power_of_two()
+            || reserved == 0
+            || fats == 0
+            || fat_sectors == 0
+        {
+            return Err(FsError::Unsupported);
+        }
+        if bytes_per_sector % block_size != 0 {
+            return Err(FsError::Unsupported);
+        }
+        let blocks_per_sector = (bytes_per_sector / block_size) as u64;
+        if total_sectors as u64 * blocks_per_sector > device.block_count() {
+            return Err(FsError::Corrupt);
+        }
+
+        let root_sectors =
+            (root_entries * 32 + bytes_per_sector as u32 - 1) / bytes_per_sector as u32;
+        let fat_start = reserved;
+        let root_start = fat_start + fats * fat_sectors;
+        let data_start = root_start + root_sectors;
+        if data_start >= total_sectors {
+            return Err(FsError::Corrupt);
+        }
+        let clusters = (total_sectors - data_start) / sectors_per_cluster;
+        // The type comes from the cluster count alone
+        let fat_type = match clusters {
+            0..=4084 => FatType::Fat12,
+            4085..=65524 => FatType::Fat16,
+            _ => FatType::Fat32,
+        };
+        let entry_bits = match fat_type {
+            FatType::Fat12 => 12,
+            FatType::Fat16 => 16,
+            FatType::Fat32 => 32,
+        };
+        if (fat_sectors as u64 * bytes_per_sector as u64 * 8 / entry_bits) < clusters as u64 + 2 {
+            return Err(FsError::Corrupt);
+        }
+
+        let mut fs = FatFs {
+            device,
+            fat_type,
+            bytes_per_sector,
+            sectors_per_cluster,
+            blocks_per_sector,
+            fat_start,
+            fat_sectors,
+            fats,
+            root_start,
+            root_sectors,
+            data_start,
+            clusters,
+            root_cluster: 0,
+            fsinfo_sector: None,
+            free_clusters: None,
+            next_free: 2,
+            fsinfo_dirty: false,
+        };
+        if fat_type == FatType::Fat32 {
+            if root_entries != 0 {
+                return Err(FsError::Corrupt);
+            }
+            fs.root_cluster = le32(&boot, 44);
+            fs.check_cluster(fs.root_cluster)?;
+            match le16(&boot, 48) as u32 {
+                0 | 0xffff => {}
+                sector => fs.read_fsinfo(sector)?,
+            }
+        }
+        Ok(fs)
+    }
+
+    pub fn fat_type(&self) -> FatType {
+        self.fat_type
+    }
+
+    pub fn cluster_size(&self) -> usize {
+        self.sectors_per_cluster as usize * self.bytes_per_sector
+    }
+
+    pub fn device(&mut self) -> &mut D {
+        &mut self.device
+    }
+
+    fn read_sector(&mut self, sector: u32, data: &mut [u8]) -> Result<(), FsError> {
+        Ok(self
+            .device
+            .read(sector as u64 * self.blocks_per_sector, data)?)
+    }
+
+    fn write_sector(&mut self, sector: u32, data: &[u8]) -> Result<(), FsError> {
+        Ok(self
+            .device
+            .write(sector as u64 * self.blocks_per_sector, data)?)
+    }
+
+    fn read_fsinfo(&mut self, sector: u32) -> Result<(), FsError> {
+        let mut data = vec![0; self.bytes_per_sector];
+        self.read_sector(sector, &mut data)?;
+        if le32(&data, 0) != FSINFO_LEAD_SIGNATURE || le32(&data, 484) != FSINFO_STRUCT_SIGNATURE {
+            // Without a valid FSInfo sector we just don't keep one
+            return Ok(());
+        }
+        self.fsinfo_sector = Some(sector);
+        self.free_clusters = Some(le32(&data, 488)).filter(|free| *free <= self.clusters);
+        let next_free = le32(&data, 492);
+        if self.check_cluster(next_free).is_ok() {
+            self.next_free = next_free;
+        }
+        Ok(())
+    }
+
+    /// Writes the free cluster count and next free hint back to FSInfo if
+    /// they changed
+    fn write_fsinfo(&mut self) -> Result<(), FsError> {
+        let sector = match self.fsinfo_sector {
+            Some(sector) if self.fsinfo_dirty => sector,
+            _ => return Ok(()),
+        };
+        let mut data = vec![0; self.bytes_per_sector];
+        self.read_sector(sector, &mut data)?;
+        let free = self.free_clusters.unwrap_or(FSINFO_UNKNOWN);
+        data[488..492].copy_from_slice(&free.to_le_bytes());
+        data[492..496].copy_from_slice(&self.next_free.to_le_bytes());
+        self.write_sector(sector, &data)?;
+        self.fsinfo_dirty = false;
+        Ok(())
+    }
+
+    /// Number of free clusters, counting them if FSInfo doesn't say
+    pub fn free_clusters(&mut self) -> Result<u32, FsError> {
+        if let Some(free) = self.free_clusters {
+            return Ok(free);
+        }
+        let mut free = 0;
+        for cluster in 2..self.clusters + 2 {
+            if self.fat_entry(cluster)? == 0 {
+                free += 1;
+            }
+        }
+        self.free_clusters = Some(free);
+        self.fsinfo_dirty = true;
+        self.write_fsinfo()?;
+        Ok(free)
+    }
+
+    fn check_cluster(&self, cluster: u32) -> Result<(), FsError> {
+        if cluster >= 2 && cluster - 2 < self.clusters {
+            Ok(())
+        } else {
+            Err(FsError::Corrupt)
+        }
+    }
+
+    fn cluster_sector(&self, cluster: u32) -> u32 {
+        self.data_start + (cluster - 2) * self.sectors_per_cluster
+    }
+
+    /// Byte offset of a cluster's entry in the FAT
+    fn fat_offset(&self, cluster: u32) -> usize {
+        let cluster = cluster as usize;
+        match self.fat_type {
+            FatType::Fat12 => cluster + cluster / 2,
+            FatType::Fat16 => cluster * 2,
+            FatType::Fat32 => cluster * 4,
+        }
+    }
+
+    /// Reads bytes at `offset` in the first FAT, which FAT12 entries can
+    /// straddle sectors with
+    fn read_fat_bytes(&mut self, offset: usize, bytes: &mut [u8]) -> Result<(), FsError> {
+        let mut sector = vec![0; self.bytes_per_sector];
+        let mut loaded = None;
+        for (i, b) in bytes.iter_mut().enumerate() {
+            let index = (offset + i) / self.bytes_per_sector;
+            if loaded != Some(index) {
+                self.read_sector(self.fat_start + index as u32, &mut sector)?;
+                loaded = Some(index);
+            }
+            *b = sector[(offset + i) % self.bytes_per_sector];
+        }
+        Ok(())
+    }
+
+    /// Writes bytes at `offset` in every copy of the FAT
+    fn write_fat_bytes(&mut self, offset: usize, bytes: &[u8]) -> Result<(), FsError> {
+        let mut sector = vec![0; self.bytes_per_sector];
+        for fat in 0..self.fats {
+            let start = self.fat_start + fat * self.fat_sectors;
+            let mut i = 0;
+            while i < bytes.len() {
+                let index = (offset + i) / self.bytes_per_sector;
+                let within = (offset + i) % self.bytes_per_sector;
+                let len = (self.bytes_per_sector - within).min(bytes.len() - i);
+                self.read_sector(start + index as u32, &mut sector)?;
+                sector[within..within + len].copy_from_slice(&bytes[i..i + len]);
+                self.write_sector(start + index as u32, &sector)?;
+                i += len;
+            }
+        }
+        Ok(())
+    }
+
+    fn fat_entry(&mut self, cluster: u32) -> Result<u32, FsError> {
+        let offset = self.fat_offset(cluster);
+        Ok(match self.fat_type {
+            FatType::Fat12 => {
+                let mut bytes = [0; 2];
+                self.read_fat_bytes(offset, &mut bytes)?;
+                let entry = u16::from_le_bytes(bytes) as u32;
+                if cluster & 1 != 0 {
+                    entry >> 4
+                } else {
+                    entry & 0xfff
+                }
+            }
+            FatType::Fat16 => {
+                let mut bytes = [0; 2];
+                self.read_fat_bytes(offset, &mut bytes)?;
+                u16::from_le_bytes(bytes) as u32
+            }
+            FatType::Fat32 => {
+                let mut bytes = [0; 4];
+                self.read_fat_bytes(offset, &mut bytes)?;
+                u32::from_le_bytes(bytes) & 0x0fff_ffff
+            }
+        })
+    }
+
+    fn set_fat_entry(&mut self, cluster: u32, value: u32) -> Result<(), FsError> {
+        let offset = self.fat_offset(cluster);
+        match self.fat_type {
+            FatType::Fat12 => {
+                let mut bytes = [0; 2];
+                self.read_fat_bytes(offset, &mut bytes)?;
+                let entry = u16::from_le_bytes(bytes);
+                let value = value as u16 & 0xfff;
+                let entry = if cluster & 1 != 0 {
+                    entry & 0x000f | value << 4
+                } else {
+                    entry & 0xf000 | value
+                };
+                self.write_fat_bytes(offset, &entry.to_le_bytes())
+            }
+            FatType::Fat16 => self.write_fat_bytes(offset, &(value as u16).to_le_bytes()),
+            FatType::Fat32 => {
+                // The top four bits are reserved and kept as they are
+                let mut bytes = [0; 4];
+                self.read_fat_bytes(offset, &mut bytes)?;
+                let entry = u32::from_le_bytes(bytes) & 0xf000_0000 | value & 0x0fff_ffff;
+                self.write_fat_bytes(offset, &entry.to_le_bytes())
+            }
+        }
+    }
+
+    /// The clusters of the chain starting at `first`, none if it's 0
+    fn chain(&mut self, first: u32) -> Result<Vec<u32>, FsError> {
+        let mut chain = Vec::new();
+        let mut cluster = first;
+        while cluster != 0 {
+            self.check_cluster(cluster)?;
+            // A chain longer than the disk must loop
+            if chain.len() as u32 >= self.clusters {
+                return Err(FsError::Corrupt);
+            }
+            chain.push(cluster);
+            cluster = match self.fat_entry(cluster)? {
+                next if next >= self.fat_type.end_of_chain() => 0,
+                0 => return Err(FsError::Corrupt),
+                next => next,
+            };
+        }
+        Ok(chain)
+    }
+
+    /// Takes a free cluster, linking it after `previous` if given. Directory
+    /// clusters are `zeroed` so they read as empty.
+    fn allocate(&mut self, previous: Option<u32>, zeroed: bool) -> Result<u32, FsError> {
+        let start = self.next_free.max(2).min(self.clusters + 1) - 2;
+        let mut found = None;
+        for i in 0..self.clusters {
+            let cluster = 2 + (start + i) % self.clusters;
+            if self.fat_entry(cluster)? == 0 {
+                found = Some(cluster);
+                break;
+            }
+        }
+        let cluster = found.ok_or(FsError::NoSpace)?;
+        self.set_fat_entry(cluster, 0x0fff_ffff)?;
+        if let Some(previous) = previous {
+            self.set_fat_entry(previous, cluster)?;
+        }
+        self.free_clusters = self.free_clusters.map(|free| free.saturating_sub(1));
+        self.next_free = cluster + 1;
+        self.fsinfo_dirty = true;
+        if zeroed {
+            let zeros = vec![0; self.bytes_per_sector];
+            let first = self.cluster_sector(cluster);
+            for sector in first..first + self.sectors_per_cluster {
+                self.write_sector(sector, &zeros)?;
+            }
+        }
+        Ok(cluster)
+    }
+
+    fn free_chain(&mut self, clusters: &[u32]) -> Result<(), FsError> {
+        for cluster in clusters {
+            self.set_fat_entry(*cluster, 0)?;
+        }
+        self.free_clusters = self.free_clusters.map(|free| free + clusters.len() as u32);
+        self.fsinfo_dirty = true;
+        Ok(())
+    }
+
+    /// The root directory, which has no entry of its own
+    pub fn root(&self) -> DirEntry {
+        DirEntry {
+            name: "".into(),
+            attributes: ATTR_DIRECTORY,
+            size: 0,
+            cluster: self.root_cluster,
+            location: None,
+        }
+    }
+
+    fn dir_of(&self, entry: &DirEntry) -> Result<Dir, FsError> {
+        if !entry.is_dir() {
+            return Err(FsError::NotADirectory);
+        }
+        // `..` entries point to the root as cluster 0, even on FAT32
+        Ok(match entry.cluster {
+            0 if self.fat_type == FatType::Fat32 => Dir::Chain(self.root_cluster),
+            0 => Dir::FixedRoot,
+            cluster => Dir::Chain(cluster),
+        })
+    }
+
+    /// The entry in directory `dir` called `name`
+    pub fn find(&mut self, dir: &DirEntry, name: &str) -> Result<DirEntry, FsError> {
+        if dir.is_root() && (name == "." || name == "..") {
+            // The root has no entries for itself
+            return Ok(self.root());
+        }
+        let dir = self.dir_of(dir)?;
+        self.find_in(dir, name)
+    }
+
+    /// Follows `path` from the root. `.` and `..` are the directories' own
+    /// entries, so a path can't climb above the root.
+    pub fn lookup(&mut self, path: &str) -> Result<DirEntry, FsError> {
+        let mut entry = self.root();
+        for name in components(path) {
+            entry = self.find(&entry, name)?;
+        }
+        Ok(entry)
+    }
+
+    /// The entries of the directory `dir`, without `.` and `..`
+    pub fn read_dir(&mut self, dir: &DirEntry) -> Result<Vec<DirEntry>, FsError> {
+        let dir = self.dir_of(dir)?;
+        Ok(self
+            .dir_entries(dir)?
+            .into_iter()
+            .filter(|entry| entry.name != "." && entry.name != "..")
+            .collect())
+    }
+
+    /// Reads from `offset` in a file, returning how much was read, which is
+    /// less than asked for at the end of the file
+    pub fn read(
+        &mut self,
+        file: &DirEntry,
+        offset: u64,
+        data: &mut [u8],
+    ) -> Result<usize, FsError> {
+        if file.is_dir() {
+            return Err(FsError::IsADirectory);
+        }
+        if offset >= file.size as u64 {
+            return Ok(0);
+        }
+        let len = data.len().min((file.size as u64 - offset) as usize);
+        let chain = self.chain(file.cluster)?;
+        self.read_at(&chain, offset, &mut data[..len])?;
+        Ok(len)
+    }
+
+    /// The sector holding byte `position` of a chain of clusters, where in
+    /// it that byte is, and how many of the `remaining` bytes are in it
+    fn locate(
+        &self,
+        chain: &[u32],
+        position: u64,
+        remaining: usize,
+    ) -> Result<(u32, usize, usize), FsError> {
+        let cluster_size = self.cluster_size() as u64;
+        let cluster = *chain
+            .get((position / cluster_size) as usize)
+            .ok_or(FsError::Corrupt)?;
+        let within = (position % cluster_size) as usize;
+        let sector = self.cluster_sector(cluster) + (within / self.bytes_per_sector) as u32;
+        let start = within % self.bytes_per_sector;
+        Ok((
+            sector,
+            start,
+            (self.bytes_per_sector - start).min(remaining),
+        ))
+    }
+
+    fn read_at(&mut self, chain: &[u32], offset: u64, data: &mut [u8]) -> Result<(), FsError> {
+        let mut sector_data = vec![0; self.bytes_per_sector];
+        let mut done = 0;
+        while done < data.len() {
+            let (sector, start, len) =
+                self.locate(chain, offset + done as u64, data.len() - done)?;
+            let chunk = &mut data[done..done + len];
+            if len == self.bytes_per_sector {
+                self.read_sector(sector, chunk)?;
+            } else {
+                self.read_sector(sector, &mut sector_data)?;
+                chunk.copy_from_slice(&sector_data[start..start + len]);
+            }
+            done += len;
+        }
+        Ok(())
+    }
+
+    fn write_at(&mut self, chain: &[u32], offset: u64, data: &[u8]) -> Result<(), FsError> {
+        let mut sector_data = vec![0; self.bytes_per_sector];
+        let mut done = 0;
+        while done < data.len() {
+            let (sector, start, len) =
+                self.locate(chain, offset + done as u64, data.len() - done)?;
+            let chunk = &data[done..done + len];
+            if len == self.bytes_per_sector {
+                self.write_sector(sector, chunk)?;
+            } else {
+                self.read_sector(sector, &mut sector_data)?;
+                sector_data[start..start + len].copy_from_slice(chunk);
+                self.write_sector(sector, &sector_data)?;
+            }
+            done += len;
+        }
+        Ok(())
+    }
+
+    /// Makes a file's chain at least `clusters` long. The entry's first
+    /// cluster is updated, but not written back.
+    fn extend(
+        &mut self,
+        file: &mut DirEntry,
+        chain: &mut Vec<u32>,
+        clusters: usize,
+    ) -> Result<(), FsError> {
+        while chain.len() < clusters {
+            let cluster = self.allocate(chain.last().copied(), false)?;
+            if chain.is_empty() {
+                file.cluster = cluster;
+            }
+            chain.push(cluster);
+        }
+        Ok(())
+    }
+
+    /// Writes `data` at `offset` in a file, growing it as needed. A gap
+    /// between the old end of the file and `offset` reads as zeros.
+    pub fn write(&mut self, file: &mut DirEntry, offset: u64, data: &[u8]) -> Result<(), FsError> {
+        if file.is_dir() {
+            return Err(FsError::IsADirectory);
+        }
+        if file.attributes & ATTR_READ_ONLY != 0 {
+            return Err(FsError::ReadOnly);
+        }
+        let end = offset + data.len() as u64;
+        if end > MAX_FILE_SIZE {
+            return Err(FsError::NoSpace);
+        }
+        let size = file.size as u64;
+        let cluster_size = self.cluster_size() as u64;
+        let mut chain = self.chain(file.cluster)?;
+        let clusters = ((end.max(size) + cluster_size - 1) / cluster_size) as usize;
+        let extended = self.extend(file, &mut chain, clusters);
+
+        // Record whatever got allocated even if the disk filled up
+        let written = extended.and_then(|_| {
+            if offset > size {
+                let zeros = vec![0; (cluster_size as usize).min((offset - size) as usize)];
+                let mut position = size;
+                while position < offset {
+                    let len = zeros.len().min((offset - position) as usize);
+                    self.write_at(&chain, position, &zeros[..len])?;
+                    position += len as u64;
+                }
+            }
+            self.write_at(&chain, offset, data)?;
+            file.size = end.max(size) as u32;
+            Ok(())
+        });
+        self.update_entry(file)?;
+        self.write_fsinfo()?;
+        written
+    }
+
+    /// Changes the size of a file, freeing clusters past its new end or
+    /// zero-filling up to it
+    pub fn truncate(&mut self, file: &mut DirEntry, len: u64) -> Result<(), FsError> {
+        if file.is_dir() {
+            return Err(FsError::IsADirectory);
+        }
+        if len > file.size as u64 {
+            return self.write(file, len, &[]);
+        }
+        let cluster_size = self.cluster_size() as u64;
+        let keep = ((len + cluster_size - 1) / cluster_size) as usize;
+        let chain = self.chain(file.cluster)?;
+        if keep < chain.len() {
+            if keep == 0 {
+                file.cluster = 0;
+            } else {
+                self.set_fat_entry(chain[keep - 1], 0x0fff_ffff)?;
+            }
+            self.free_chain(&chain[keep..])?;
+        }
+        file.size = len as u32;
+        self.update_entry(file)?;
+        self.write_fsinfo()
+    }
+
+    /// The directory `path` is in, and its last component
+    fn parent<'p>(&mut self, path: &'p str) -> Result<(Dir, DirEntry, &'p str), FsError> {
+        let (parent, name) = split_last(path).ok_or(FsError::AlreadyExists)?;
+        let parent = self.lookup(parent)?;
+        Ok((self.dir_of(&parent)?, parent, name))
+    }
+
+    /// Creates an empty file
+    pub fn create(&mut self, path: &str) -> Result<DirEntry, FsError> {
+        let (dir, _, name) = self.parent(path)?;
+        self.add_entry(dir, name, ATTR_ARCHIVE, 0)
+    }
+
+    pub fn mkdir(&mut self, path: &str) -> Result<DirEntry, FsError> {
+        let (dir, parent, name) = self.parent(path)?;
+        if self.find_in(dir, name).is_ok() {
+            return Err(FsError::AlreadyExists);
+        }
+        let cluster = self.allocate(None, true)?;
+        let parent_cluster = if parent.is_root() { 0 } else { parent.cluster };
+        let created = self
+            .init_dir(cluster, parent_cluster)
+            .and_then(|_| self.add_entry(dir, name, ATTR_DIRECTORY, cluster));
+        if created.is_err() {
+            self.free_chain(&[cluster])?;
+        }
+        self.write_fsinfo()?;
+        created
+    }
+
+    /// Removes a file, or a directory if it's empty
+    pub fn remove(&mut self, path: &str) -> Result<(), FsError> {
+        let entry = self.lookup(path)?;
+        if entry.is_root() || entry.name == "." || entry.name == ".." {
+            return Err(FsError::InvalidName);
+        }
+        if entry.is_dir() && !self.read_dir(&entry)?.is_empty() {
+            return Err(FsError::NotEmpty);
+        }
+        let chain = self.chain(entry.cluster)?;
+        self.remove_entry(&entry)?;
+        self.free_chain(&chain)?;
+        self.write_fsinfo()
+    }
+
+    fn find_in(&mut self, dir: Dir, name: &str) -> Result<DirEntry, FsError> {
+        self.dir_entries(dir)?
+            .into_iter()
+            .find(|entry| names_match(&entry.name, name))
+            .ok_or(FsError::NotFound)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::block::RamDisk;
+
+    /// An empty FAT12 filesystem of 256 512-byte sectors, one per cluster
+    pub(crate) fn fat12_disk() -> RamDisk {
+        let mut disk = RamDisk::new(512, 256);
+        let mut boot = [0u8; 512];
+        boot[..3].copy_from_slice(&[0xeb, 0x3c, 0x90]);
+        boot[11..13].copy_from_slice(&512u16.to_le_bytes());
+        boot[13] = 1;
+        boot[14..16].copy_from_slice(&1u16.to_le_bytes());
+        boot[16] = 2;
+        boot[17..19].copy_from_slice(&16u16.to_le_bytes());
+        boot[19..21].copy_from_slice(&256u16.to_le_bytes());
+        boot[21] = 0xf8;
+        boot[22..24].copy_from_slice(&1u16.to_le_bytes());
+        boot[510..512].copy_from_slice(&[0x55, 0xaa]);
+        disk.write(0, &boot).expect("write failed");
+        let mut fat = [0u8; 512];
+        fat[..3].copy_from_slice(&[0xf8, 0xff, 0xff]);
+        disk.write(1, &fat).expect("write failed");
+        disk.write(2, &fat).expect("write failed");
+        disk
+    }
+
+    #[test]
+    fn fat() {
+        let mut fs = FatFs::mount(fat12_disk()).expect("mount failed");
+        let free = fs.free_clusters().expect("FAT read failed");
+
+        let mut file = fs.create("/A Long File Name.txt").expect("create failed");
+        let data: Vec<u8> = (0..1500u32).map(|i| i as u8).collect();
+        fs.write(&mut file, 0, &data).expect("write failed");
+        let file = fs
+            .lookup("/a long file name.TXT")
+            .expect("long name lookup failed");
+        let mut read = [0u8; 2048];
+        assert!(
+            fs.read(&file, 0, &mut read) == Ok(1500) && read[..1500] == data[..],
+            "read didn't return written data"
+        );
+
+        fs.mkdir("/dir").expect("mkdir failed");
+        let mut config = fs.create("/dir/config.txt").expect("create failed");
+        fs.write(&mut config, 4, b"x").expect("write failed");
+        let dir = fs.lookup("/dir/../dir").expect("lookup failed");
+        let entries = fs.read_dir(&dir).expect("read_dir failed");
+        assert!(
+            entries.len() == 1 && entries[0].name == "config.txt" && entries[0].size == 5,
+            "wrong directory listing"
+        );
+        assert!(
+            fs.read(&entries[0], 0, &mut read) == Ok(5) && read[..5] == *b"\0\0\0\0x",
+            "gap not zero-filled"
+        );
+        assert!(
+            fs.remove("/dir") == Err(FsError::NotEmpty),
+            "non-empty directory removed"
+        );
+        assert!(
+            fs.create("/DIR/CONFIG.TXT").err() == Some(FsError::AlreadyExists),
+            "duplicate name created"
+        );
+
+        let mut file = fs.lookup("/a long file name.txt").expect("lookup failed");
+        fs.truncate(&mut file, 0).expect("truncate failed");
+        for path in ["/dir/config.txt", "/dir", "/a long file name.txt"].iter() {
+            fs.remove(path).expect("remove failed");
+        }
+        let root = fs.root();
+        assert!(
+            fs.read_dir(&root).map(|entries| entries.is_empty()) == Ok(true),
+            "removed entries still listed"
+        );
+        assert!(fs.free_clusters() == Ok(free), "clusters leaked");
+    }
+}
@@ -0,0 +1,513 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use super::{FatFs, ATTR_DIRECTORY, ATTR_LONG_NAME, ATTR_VOLUME_ID};
+use crate::block::BlockDevice;
+use crate::fs::FsError;
+
+pub(super) const ENTRY_SIZE: usize = 32;
+/// First name byte of a deleted entry
+const DELETED: u8 = 0xe5;
+/// First name byte standing for 0xe5, which would read as deleted
+const KANJI_E5: u8 = 0x05;
+/// Set in the sequence number of the last (first stored) long name entry
+const LFN_LAST: u8 = 0x40;
+const LFN_CHARS: usize = 13;
+/// Offsets of the UTF-16 characters in a long name entry
+const LFN_OFFSETS: [usize; LFN_CHARS] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+const MAX_NAME: usize = 255;
+/// Case flags Windows NT keeps in an entry, for names like `config.txt`
+/// that fit in 8.3 apart from being lower case
+const CASE_LOWER_BASE: u8 = 0x08;
+const CASE_LOWER_EXT: u8 = 0x10;
+/// 1980-01-01, the earliest FAT date. There's no real-time clock to stamp
+/// files with.
+const DATE: u16 = 0x0021;
+
+/// A directory: the fixed root region of FAT12/16, or a cluster chain
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(super) enum Dir {
+    FixedRoot,
+    Chain(u32),
+}
+
+/// Where a directory entry is stored
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(super) struct Location {
+    dir: Dir,
+    /// Slot of the 8.3 entry
+    index: usize,
+    /// Slots used, counting the long name entries before the 8.3 one
+    slots: usize,
+}
+
+/// A file or directory as stored in its parent directory
+#[derive(Clone, Debug)]
+pub struct DirEntry {
+    pub name: String,
+    pub attributes: u8,
+    pub size: u32,
+    pub(super) cluster: u32,
+    /// `None` for the root directory, which has no entry
+    pub(super) location: Option<Location>,
+}
+
+impl DirEntry {
+    pub fn is_dir(&self) -> bool {
+        self.attributes & ATTR_DIRECTORY != 0
+    }
+
+    pub(super) fn is_root(&self) -> bool {
+        self.location.is_none()
+    }
+}
+
+fn short_name_checksum(short: &[u8]) -> u8 {
+    short[..11]
+        .iter()
+        .fold(0u8, |sum, b| sum.rotate_right(1).wrapping_add(*b))
+}
+
+/// Whether `c` may appear in an 8.3 name as is
+fn short_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "!#$%&'()-@^_`{}~".contains(c)
+}
+
+/// `part` as an 8.3 name part with its case flag, if it fits without any
+/// changes but case
+fn fit_short(part: &str, len: usize, lower_flag: u8) -> Option<u8> {
+    if part.len() > len || !part.chars().all(short_char) {
+        return None;
+    }
+    if part.chars().any(|c| c.is_ascii_lowercase()) {
+        if part.chars().any(|c| c.is_ascii_uppercase()) {
+            return None;
+        }
+        return Some(lower_flag);
+    }
+    Some(0)
+}
+
+/// `name` as an 8.3 name and case flags if it can be stored without a long
+/// name
+fn exact_short_name(name: &str) -> Option<([u8; 11], u8)> {
+    let (base, ext) = match name.rfind('.') {
+        Some(dot) => (&name[..dot], &name[dot + 1..]),
+        None => (name, ""),
+    };
+    if base.is_empty() {
+        return None;
+    }
+    let flags = fit_short(base, 8, CASE_LOWER_BASE)? | fit_short(ext, 3, CASE_LOWER_EXT)?;
+    let mut short = [b' '; 11];
+    short[..base.len()].copy_from_slice(base.to_ascii_uppercase().as_bytes());
+    short[8..8 + ext.len()].copy_from_slice(ext.to_ascii_uppercase().as_bytes());
+    if short[0] == DELETED {
+        short[0] = KANJI_E5;
+    }
+    Some((short, flags))
+}
+
+/// The `n`th generated 8.3 alias for a long name, like `LONGFI~1.TXT`
+fn generated_short_name(name: &str, n: u32) -> [u8; 11] {
+    fn clean(part: &str) -> impl Iterator<Item = u8> + '_ {
+        part.chars().filter(|c| *c != ' ' && *c != '.').map(|c| {
+            if short_char(c) {
+                c.to_ascii_uppercase() as u8
+            } else {
+                b'_'
+            }
+        })
+    }
+    let (base, ext) = match name.rfind('.') {
+        Some(dot) if dot > 0 => (&name[..dot], &name[dot + 1..]),
+        _ => (name, ""),
+    };
+    let mut tail = [0u8; 8];
+    let mut tail_len = 0;
+    let mut digits = n;
+    loop {
+        tail[7 - tail_len] = b'0' + (digits % 10) as u8;
+        tail_len += 1;
+        digits /= 10;
+        if digits == 0 {
+            break;
+        }
+    }
+    tail[7 - tail_len] = b'~';
+    tail_len += 1;
+
+    let mut short = [b' '; 11];
+    let mut len = 0;
+    for b in clean(base).take(8 - tail_len) {
+        short[len] = b;
+        len += 1;
+    }
+    short[len..len + tail_len].copy_from_slice(&tail[8 - tail_len..]);
+    for (i, b) in clean(ext).take(3).enumerate() {
+        short[8 + i] = b;
+    }
+    short
+}
+
+/// The name stored in an 8.3 entry, lowercased as its case flags say
+fn short_entry_name(slot: &[u8]) -> String {
+    let part = |bytes: &[u8], lower: bool| -> String {
+        bytes
+            .iter()
+            .enumerate()
+            .map(|(i, b)| match *b {
+                KANJI_E5 if i == 0 => DELETED,
+                b => b,
+            })
+            // Code page characters are taken as Latin-1
+            .map(|b| char::from(b))
+            .map(|c| if lower { c.to_ascii_lowercase() } else { c })
+            .collect::<String>()
+            .trim_end_matches(' ')
+            .into()
+    };
+    let mut name = part(&slot[..8], slot[12] & CASE_LOWER_BASE != 0);
+    let ext = part(&slot[8..11], slot[12] & CASE_LOWER_EXT != 0);
+    if !ext.is_empty() {
+        name.push('.');
+        name.push_str(&ext);
+    }
+    name
+}
+
+fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name != "."
+        && name != ".."
+        && !name.ends_with(' ')
+        && !name.ends_with('.')
+        && name.encode_utf16().count() <= MAX_NAME
+        && !name
+            .chars()
+            .any(|c| c < ' ' || "\"*/:<>?\\|".contains(c) || c == '\u{7f}')
+}
+
+/// An 8.3 directory entry
+pub(super) fn short_entry(
+    short: &[u8; 11],
+    flags: u8,
+    attributes: u8,
+    cluster: u32,
+    size: u32,
+) -> [u8; ENTRY_SIZE] {
+    let mut slot = [0u8; ENTRY_SIZE];
+    slot[..11].copy_from_slice(short);
+    slot[11] = attributes;
+    slot[12] = flags;
+    slot[16..18].copy_from_slice(&DATE.to_le_bytes());
+    slot[18..20].copy_from_slice(&DATE.to_le_bytes());
+    slot[24..26].copy_from_slice(&DATE.to_le_bytes());
+    set_cluster(&mut slot, cluster);
+    slot[28..32].copy_from_slice(&size.to_le_bytes());
+    slot
+}
+
+fn set_cluster(slot: &mut [u8], cluster: u32) {
+    slot[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+    slot[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+}
+
+/// The long name entries for `name`, in the order they are stored
+fn long_entries(name: &str, checksum: u8) -> Vec<[u8; ENTRY_SIZE]> {
+    let mut chars: Vec<u16> = name.encode_utf16().collect();
+    // Terminated if there's room, then padded with 0xffff
+    if chars.len() % LFN_CHARS != 0 {
+        chars.push(0);
+    }
+    while chars.len() % LFN_CHARS != 0 {
+        chars.push(0xffff);
+    }
+    let count = chars.len() / LFN_CHARS;
+    (0..count)
+        .rev()
+        .map(|i| {
+            let mut slot = [0u8; ENTRY_SIZE];
+            slot[0] = (i + 1) as u8 | if i + 1 == count { LFN_LAST } else { 0 };
+            slot[11] = ATTR_LONG_NAME;
+            slot[13] = checksum;
+            for (c, offset) in chars[i * LFN_CHARS..(i + 1) * LFN_CHARS]
+                .iter()
+                .zip(LFN_OFFSETS.iter())
+            {
+                slot[*offset..*offset + 2].copy_from_slice(&c.to_le_bytes());
+            }
+            slot
+        })
+        .collect()
+}
+
+/// A long name being put together from its entries, which are stored last
+/// part first
+struct LongName {
+    chars: Vec<u16>,
+    checksum: u8,
+    /// Sequence number of the entry expected next
+    next: u8,
+    first: usize,
+}
+
+impl<D: BlockDevice> FatFs<D> {
+    /// Absolute sectors holding `dir`, in order
+    fn dir_sectors(&mut self, dir: Dir) -> Result<Vec<u32>, FsError> {
+        match dir {
+            Dir::FixedRoot => Ok((self.root_start..self.root_start + self.root_sectors).collect()),
+            Dir::Chain(cluster) => {
+                let spc = self.sectors_per_cluster;
+                Ok(self
+                    .chain(cluster)?
+                    .into_iter()
+                    .flat_map(|cluster| {
+                        let first = self.cluster_sector(cluster);
+                        first..first + spc
+                    })
+                    .collect())
+            }
+        }
+    }
+
+    /// The raw contents of `dir` and the sectors they came from
+    fn read_dir_slots(&mut self, dir: Dir) -> Result<(Vec<u32>, Vec<u8>), FsError> {
+        let sectors = self.dir_sectors(dir)?;
+        let mut data = alloc::vec![0; sectors.len() * self.bytes_per_sector];
+        for (sector, chunk) in sectors.iter().zip(data.chunks_mut(self.bytes_per_sector)) {
+            self.read_sector(*sector, chunk)?;
+        }
+        Ok((sectors, data))
+    }
+
+    fn write_slot(&mut self, sectors: &[u32], index: usize, slot: &[u8]) -> Result<(), FsError> {
+        let offset = index * ENTRY_SIZE;
+        let sector = sectors[offset / self.bytes_per_sector];
+        let mut data = alloc::vec![0; self.bytes_per_sector];
+        self.read_sector(sector, &mut data)?;
+        let start = offset % self.bytes_per_sector;
+        data[start..start + ENTRY_SIZE].copy_from_slice(slot);
+        self.write_sector(sector, &data)
+    }
+
+    /// Every entry in `dir`, including `.` and `..`
+    pub(super) fn dir_entries(&mut self, dir: Dir) -> Result<Vec<DirEntry>, FsError> {
+        let (_, data) = self.read_dir_slots(dir)?;
+        let mut entries = Vec::new();
+        let mut long: Option<LongName> = None;
+        for (index, slot) in data.chunks(ENTRY_SIZE).enumerate() {
+            match slot[0] {
+                0 => break,
+                DELETED => {
+                    long = None;
+                    continue;
+                }
+                _ => {}
+            }
+            if slot[11] & 0x3f == ATTR_LONG_NAME {
+                let sequence = slot[0] & !LFN_LAST;
+                if slot[0] & LFN_LAST != 0 {
+                    long = Some(LongName {
+                        chars: alloc::vec![0xffff; sequence as usize * LFN_CHARS],
+                        checksum: slot[13],
+                        next: sequence,
+                        first: index,
+                    });
+                }
+                long = long.filter(|long| {
+                    sequence != 0 && long.next == sequence && long.checksum == slot[13]
+                });
+                if let Some(long) = long.as_mut() {
+                    let start = (sequence as usize - 1) * LFN_CHARS;
+                    for (i, offset) in LFN_OFFSETS.iter().enumerate() {
+                        long.chars[start + i] =
+                            u16::from_le_bytes([slot[*offset], slot[*offset + 1]]);
+                    }
+                    long.next -= 1;
+                }
+                continue;
+            }
+            let long = long.take();
+            if slot[11] & ATTR_VOLUME_ID != 0 {
+                continue;
+            }
+            let long =
+                long.filter(|long| long.next == 0 && long.checksum == short_name_checksum(slot));
+            let (name, first) = match long {
+                Some(long) => {
+                    let chars = long
+                        .chars
+                        .iter()
+                        .copied()
+                        .take_while(|c| *c != 0 && *c != 0xffff);
+                    let name = core::char::decode_utf16(chars)
+                        .map(|c| c.unwrap_or(core::char::REPLACEMENT_CHARACTER))
+                        .collect();
+                    (name, long.first)
+                }
+                None => (short_entry_name(slot), index),
+            };
+            let cluster_high = u16::from_le_bytes([slot[20], slot[21]]) as u32;
+            let cluster_low = u16::from_le_bytes([slot[26], slot[27]]) as u32;
+            entries.push(DirEntry {
+                name,
+                attributes: slot[11],
+                size: u32::from_le_bytes([slot[28], slot[29], slot[30], slot[31]]),
+                cluster: cluster_high << 16 | cluster_low,
+                location: Some(Location {
+                    dir,
+                    index,
+                    slots: index - first + 1,
+                }),
+            });
+        }
+        Ok(entries)
+    }
+
+    /// Adds an entry for a new file or directory to `dir`, growing it if it
+    /// has no room
+    pub(super) fn add_entry(
+        &mut self,
+        dir: Dir,
+        name: &str,
+        attributes: u8,
+        cluster: u32,
+    ) -> Result<DirEntry, FsError> {
+        if !valid_name(name) {
+            return Err(FsError::InvalidName);
+        }
+        let existing = self.dir_entries(dir)?;
+        if existing.iter().any(|entry| names_match(&entry.name, name)) {
+            return Err(FsError::AlreadyExists);
+        }
+
+        // 8.3 names must be unique too, including the aliases of long names
+        let (mut sectors, data) = self.read_dir_slots(dir)?;
+        let taken = |short: &[u8; 11]| {
+            data.chunks(ENTRY_SIZE)
+                .take_while(|slot| slot[0] != 0)
+                .any(|slot| slot[0] != DELETED && slot[..11] == short[..])
+        };
+        let (short, flags, long) = match exact_short_name(name).filter(|(short, _)| !taken(short)) {
+            Some((short, flags)) => (short, flags, Vec::new()),
+            None => {
+                let short = (1..1_000_000)
+                    .map(|n| generated_short_name(name, n))
+                    .find(|short| !taken(short))
+                    .ok_or(FsError::NoSpace)?;
+                (short, 0, long_entries(name, short_name_checksum(&short)))
+            }
+        };
+        let mut slots = long;
+        slots.push(short_entry(&short, flags, attributes, cluster, 0));
+
+        let mut run = 0;
+        let mut start = None;
+        for (index, slot) in data.chunks(ENTRY_SIZE).enumerate() {
+            if slot[0] == 0 || slot[0] == DELETED {
+                run += 1;
+                if run == slots.len() {
+                    start = Some(index + 1 - run);
+                    break;
+                }
+            } else {
+                run = 0;
+            }
+        }
+        let start = match (start, dir) {
+            (Some(start), _) => start,
+            (None, Dir::FixedRoot) => return Err(FsError::NoSpace),
+            (None, Dir::Chain(first)) => {
+                // Append zeroed clusters until the run of free slots at the
+                // end is long enough
+                let slots_per_cluster =
+                    self.sectors_per_cluster as usize * self.bytes_per_sector / ENTRY_SIZE;
+                let mut last = *self.chain(first)?.last().ok_or(FsError::Corrupt)?;
+                let mut free = run;
+                while free < slots.len() {
+                    last = self.allocate(Some(last), true)?;
+                    free += slots_per_cluster;
+                }
+                sectors = self.dir_sectors(dir)?;
+                data.len() / ENTRY_SIZE - run
+            }
+        };
+        for (i, slot) in slots.iter().enumerate() {
+            self.write_slot(&sectors, start + i, slot)?;
+        }
+        Ok(DirEntry {
+            name: name.into(),
+            attributes,
+            size: 0,
+            cluster,
+            location: Some(Location {
+                dir,
+                index: start + slots.len() - 1,
+                slots: slots.len(),
+            }),
+        })
+    }
+
+    /// Writes an entry's size and first cluster back to its directory
+    pub(super) fn update_entry(&mut self, entry: &DirEntry) -> Result<(), FsError> {
+        let location = match entry.location {
+            Some(location) => location,
+            None => return Ok(()),
+        };
+        let sectors = self.dir_sectors(location.dir)?;
+        let offset = location.index * ENTRY_SIZE;
+        let sector = *sectors
+            .get(offset / self.bytes_per_sector)
+            .ok_or(FsError::Corrupt)?;
+        let mut data = alloc::vec![0; self.bytes_per_sector];
+        self.read_sector(sector, &mut data)?;
+        let slot = &mut data[offset % self.bytes_per_sector..][..ENTRY_SIZE];
+        set_cluster(slot, entry.cluster);
+        if !entry.is_dir() {
+            slot[28..32].copy_from_slice(&entry.size.to_le_bytes());
+        }
+        slot[24..26].copy_from_slice(&DATE.to_le_bytes());
+        self.write_sector(sector, &data)
+    }
+
+    /// Marks an entry and its long name entries deleted
+    pub(super) fn remove_entry(&mut self, entry: &DirEntry) -> Result<(), FsError> {
+        let location = entry.location.ok_or(FsError::InvalidName)?;
+        let sectors = self.dir_sectors(location.dir)?;
+        for index in location.index + 1 - location.slots..=location.index {
+            let offset = index * ENTRY_SIZE;
+            let sector = sectors[offset / self.bytes_per_sector];
+            let mut data = alloc::vec![0; self.bytes_per_sector];
+            self.read_sector(sector, &mut data)?;
+            data[offset % self.bytes_per_sector] = DELETED;
+            self.write_sector(sector, &data)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the `.` and `..` entries of a new directory
+    pub(super) fn init_dir(&mut self, cluster: u32, parent: u32) -> Result<(), FsError> {
+        let sectors = self.dir_sectors(Dir::Chain(cluster))?;
+        let mut dot = [b' '; 11];
+        dot[0] = b'.';
+        self.write_slot(
+            &sectors,
+            0,
+            &short_entry(&dot, 0, ATTR_DIRECTORY, cluster, 0),
+        )?;
+        dot[1] = b'.';
+        self.write_slot(
+            &sectors,
+            1,
+            &short_entry(&dot, 0, ATTR_DIRECTORY, parent, 0),
+        )
+    }
+}
+
+pub(super) fn names_match(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.chars()
+            .zip(b.chars())
+            .all(|(a, b)| a.to_lowercase().eq(b.to_lowercase()))
+}
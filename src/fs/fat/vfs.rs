@@ -0,0 +1,147 @@
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use super::{FatFs, FatType};
+use crate::block::BlockDevice;
+use crate::fs::vfs::{DirEntry, Filesystem, Inode, InodeRef};
+use crate::fs::{FileType, FsError};
+use crate::mutex::Mutex;
+
+/// A FAT filesystem mounted in the VFS
+pub struct FatVolume<D: BlockDevice> {
+    fs: Arc<Mutex<FatFs<D>>>,
+    fat_type: FatType,
+}
+
+impl<D: BlockDevice> FatVolume<D> {
+    pub fn new(fs: FatFs<D>) -> FatVolume<D> {
+        FatVolume {
+            fat_type: fs.fat_type(),
+            fs: Arc::new(Mutex::new(fs)),
+        }
+    }
+}
+
+impl<D: BlockDevice + Sync + 'static> Filesystem for FatVolume<D> {
+    fn name(&self) -> &'static str {
+        match self.fat_type {
+            FatType::Fat12 => "fat12",
+            FatType::Fat16 => "fat16",
+            FatType::Fat32 => "fat32",
+        }
+    }
+
+    fn root(&self) -> Result<InodeRef, FsError> {
+        Ok(Arc::new(FatInode {
+            fs: self.fs.clone(),
+            path: String::new(),
+            dir: true,
+        }))
+    }
+
+    fn sync(&self) -> Result<(), FsError> {
+        let mut fs = self.fs.lock();
+        Ok(fs.device().flush()?)
+    }
+}
+
+/// A file or directory, known by its path. FAT keeps a file's size and first
+/// cluster in its directory entry, so each operation looks the entry up
+/// again rather than trusting a copy another handle may have changed.
+struct FatInode<D: BlockDevice> {
+    fs: Arc<Mutex<FatFs<D>>>,
+    path: String,
+    dir: bool,
+}
+
+impl<D: BlockDevice> FatInode<D> {
+    fn child(&self, name: &str) -> String {
+        format!("{}/{}", self.path, name)
+    }
+}
+
+impl<D: BlockDevice + Sync + 'static> Inode for FatInode<D> {
+    fn kind(&self) -> FileType {
+        if self.dir {
+            FileType::Dir
+        } else {
+            FileType::File
+        }
+    }
+
+    fn size(&self) -> Result<u64, FsError> {
+        let mut fs = self.fs.lock();
+        Ok(fs.lookup(&self.path)?.size as u64)
+    }
+
+    fn lookup(&self, name: &str) -> Result<InodeRef, FsError> {
+        let path = self.child(name);
+        let entry = self.fs.lock().lookup(&path)?;
+        Ok(Arc::new(FatInode {
+            fs: self.fs.clone(),
+            path,
+            dir: entry.is_dir(),
+        }))
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, FsError> {
+        let mut fs = self.fs.lock();
+        let dir = fs.lookup(&self.path)?;
+        Ok(fs
+            .read_dir(&dir)?
+            .into_iter()
+            .map(|entry| DirEntry {
+                kind: if entry.is_dir() {
+                    FileType::Dir
+                } else {
+                    FileType::File
+                },
+                size: entry.size as u64,
+                name: entry.name,
+            })
+            .collect())
+    }
+
+    fn read_at(&self, offset: u64, data: &mut [u8]) -> Result<usize, FsError> {
+        let mut fs = self.fs.lock();
+        let file = fs.lookup(&self.path)?;
+        fs.read(&file, offset, data)
+    }
+
+    fn write_at(&self, offset: u64, data: &[u8]) -> Result<usize, FsError> {
+        let mut fs = self.fs.lock();
+        let mut file = fs.lookup(&self.path)?;
+        fs.write(&mut file, offset, data)?;
+        Ok(data.len())
+    }
+
+    fn truncate(&self, len: u64) -> Result<(), FsError> {
+        let mut fs = self.fs.lock();
+        let mut file = fs.lookup(&self.path)?;
+        fs.truncate(&mut file, len)
+    }
+
+    fn create(&self, name: &str, kind: FileType) -> Result<InodeRef, FsError> {
+        let path = self.child(name);
+        match kind {
+            FileType::File => self.fs.lock().create(&path)?,
+            FileType::Dir => self.fs.lock().mkdir(&path)?,
+            _ => return Err(FsError::Unsupported),
+        };
+        Ok(Arc::new(FatInode {
+            fs: self.fs.clone(),
+            path,
+            dir: kind == FileType::Dir,
+        }))
+    }
+
+    fn symlink(&self, _name: &str, _target: &str) -> Result<InodeRef, FsError> {
+        Err(FsError::Unsupported)
+    }
+
+    fn remove(&self, name: &str) -> Result<(), FsError> {
+        self.fs.lock().remove(&self.child(name))
+    }
+}
@@ -0,0 +1,518 @@
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::convert::TryInto;
+
+use super::FsError;
+use crate::block::BlockDevice;
+use crate::utils::crc32;
+
+const MAGIC: &[u8; 8] = b"AlloraKV";
+const VERSION: u32 = 1;
+/// Magic, version, generation, newest generation handed out and CRC
+const HEADER_SIZE: usize = 24;
+/// Generation, kind, key length, padding, value length and CRC
+const RECORD_HEADER_SIZE: usize = 16;
+const KIND_PUT: u8 = 1;
+const KIND_DELETE: u8 = 2;
+pub const MAX_KEY: usize = 255;
+pub const MAX_VALUE: usize = 64 << 10;
+
+fn le32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+/// Where a live value is in the active log
+#[derive(Clone, Copy)]
+struct Value {
+    /// Offset of its record from the start of the half
+    record: u64,
+    len: u32,
+}
+
+impl Value {
+    fn record_len(&self, key: &[u8]) -> u64 {
+        (RECORD_HEADER_SIZE + key.len()) as u64 + self.len as u64
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub struct KvUsage {
+    pub keys: usize,
+    /// Bytes of records in the log, live or not
+    pub used: u64,
+    /// Bytes of records superseded by later ones, which compaction frees
+    pub garbage: u64,
+    /// Bytes the log can hold
+    pub capacity: u64,
+}
+
+/// A key-value store kept as a log of records on a block device.
+///
+/// The device is split in two halves, each starting with a header block
+/// naming its generation. The half with the newest valid header is the
+/// active one, and puts and deletes are appended to it as records stamped
+/// with that generation and a CRC, then flushed. Replaying the log at open
+/// rebuilds the index, stopping at the first record that doesn't check out,
+/// which after a crash is the one that was being written. When the active
+/// half fills up the live records are copied to the other half under the
+/// next generation, and only once they're durable is its header written,
+/// so a crash during compaction leaves the old half in charge.
+///
+/// Records left behind in a half, by older generations or by a compaction
+/// that was cut short, don't match its header's generation, so replay stops
+/// at them. That relies on no generation being used twice: the newest one
+/// handed out is kept in the active header, which is rewritten before a
+/// compaction starts. Sector writes are assumed atomic, so rewriting the
+/// block that holds the end of the log can't damage the records already in
+/// it.
+pub struct KvStore<D: BlockDevice> {
+    device: D,
+    block_size: usize,
+    /// Bytes in each half, header block included
+    half: u64,
+    /// Which half is active
+    active: u64,
+    generation: u32,
+    /// Newest generation a compaction has taken, even one that didn't finish
+    newest: u32,
+    /// Where the next record goes, from the start of the active half
+    end: u64,
+    index: BTreeMap<Vec<u8>, Value>,
+    garbage: u64,
+    torn: bool,
+}
+
+impl<D: BlockDevice> KvStore<D> {
+    /// Opens the store on `device`, replaying its log. A device with no
+    /// store header on it yet gets an empty store.
+    pub fn open(device: D) -> Result<KvStore<D>, FsError> {
+        let block_size = device.block_size();
+        let half_blocks = device.block_count() / 2;
+        if half_blocks < 2 || block_size < HEADER_SIZE {
+            return Err(FsError::NoSpace);
+        }
+        let mut store = KvStore {
+            device,
+            block_size,
+            half: half_blocks * block_size as u64,
+            active: 0,
+            generation: 0,
+            newest: 0,
+            end: block_size as u64,
+            index: BTreeMap::new(),
+            garbage: 0,
+            torn: false,
+        };
+
+        let mut formatted = false;
+        for half in 0..2 {
+            let mut header = vec![0; block_size];
+            store.device.read(half * half_blocks, &mut header)?;
+            formatted |= &header[..8] == MAGIC;
+            if &header[..8] != MAGIC
+                || le32(&header, 8) != VERSION
+                || crc32(&header[..20]) != le32(&header, 20)
+            {
+                continue;
+            }
+            let generation = le32(&header, 12);
+            if store.generation == 0 || generation.wrapping_sub(store.generation) as i32 > 0 {
+                store.generation = generation;
+                store.newest = le32(&header, 16);
+                store.active = half;
+            }
+        }
+        if store.generation == 0 {
+            if formatted {
+                return Err(FsError::Corrupt);
+            }
+            store.generation = 1;
+            store.newest = 1;
+            store.write_header(0)?;
+        } else {
+            store.replay()?;
+        }
+        Ok(store)
+    }
+
+    /// Whether opening found a partly written record at the end of the log
+    /// and discarded it
+    pub fn recovered_torn(&self) -> bool {
+        self.torn
+    }
+
+    pub fn usage(&self) -> KvUsage {
+        KvUsage {
+            keys: self.index.len(),
+            used: self.end - self.block_size as u64,
+            garbage: self.garbage,
+            capacity: self.half - self.block_size as u64,
+        }
+    }
+
+    /// The keys in the store, in order
+    pub fn keys(&self) -> impl Iterator<Item = &[u8]> {
+        self.index.keys().map(|key| key.as_slice())
+    }
+
+    pub fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>, FsError> {
+        let value = match self.index.get(key) {
+            Some(value) => *value,
+            None => return Ok(None),
+        };
+        let mut data = vec![0; value.len as usize];
+        let start =
+            self.active * self.half + value.record + (RECORD_HEADER_SIZE + key.len()) as u64;
+        self.read_bytes(start, &mut data)?;
+        Ok(Some(data))
+    }
+
+    /// Sets `key` to `value`, compacting the log first if it's full
+    pub fn put(&mut self, key: &[u8], value: &[u8]) -> Result<(), FsError> {
+        if key.is_empty() || key.len() > MAX_KEY {
+            return Err(FsError::InvalidName);
+        }
+        if value.len() > MAX_VALUE {
+            return Err(FsError::NoSpace);
+        }
+        let record = self.append(KIND_PUT, key, value)?;
+        let new = Value {
+            record,
+            len: value.len() as u32,
+        };
+        if let Some(old) = self.index.insert(key.into(), new) {
+            self.garbage += old.record_len(key);
+        }
+        Ok(())
+    }
+
+    /// Removes `key`, returning whether it was there
+    pub fn delete(&mut self, key: &[u8]) -> Result<bool, FsError> {
+        if !self.index.contains_key(key) {
+            return Ok(false);
+        }
+        self.append(KIND_DELETE, key, &[])?;
+        // Compaction may have moved the record, so look it up afterwards
+        if let Some(old) = self.index.remove(key) {
+            self.garbage += old.record_len(key) + (RECORD_HEADER_SIZE + key.len()) as u64;
+        }
+        Ok(true)
+    }
+
+    /// Copies the live records to the other half, dropping everything else
+    pub fn compact(&mut self) -> Result<(), FsError> {
+        let target = 1 - self.active;
+        // Records an earlier attempt left in the target half must not pass
+        // for this one's, so take a generation no attempt has had
+        let generation = self.newest.wrapping_add(1).max(1);
+        self.newest = generation;
+        self.write_header(self.active)?;
+        let mut index = BTreeMap::new();
+        let mut end = self.block_size as u64;
+        let keys: Vec<Vec<u8>> = self.index.keys().cloned().collect();
+        for key in keys {
+            let value = self.get(&key)?.ok_or(FsError::Corrupt)?;
+            let record = encode(generation, KIND_PUT, &key, &value);
+            self.write_bytes(target * self.half + end, &record)?;
+            index.insert(
+                key,
+                Value {
+                    record: end,
+                    len: value.len() as u32,
+                },
+            );
+            end += record.len() as u64;
+        }
+        // The records must be down before the header that vouches for them
+        self.device.flush()?;
+        self.generation = generation;
+        self.write_header(target)?;
+        self.active = target;
+        self.end = end;
+        self.index = index;
+        self.garbage = 0;
+        Ok(())
+    }
+
+    fn write_header(&mut self, half: u64) -> Result<(), FsError> {
+        let mut header = vec![0; self.block_size];
+        header[..8].copy_from_slice(MAGIC);
+        header[8..12].copy_from_slice(&VERSION.to_le_bytes());
+        header[12..16].copy_from_slice(&self.generation.to_le_bytes());
+        header[16..20].copy_from_slice(&self.newest.to_le_bytes());
+        let crc = crc32(&header[..20]);
+        header[20..24].copy_from_slice(&crc.to_le_bytes());
+        self.device
+            .write(half * self.half / self.block_size as u64, &header)?;
+        Ok(self.device.flush()?)
+    }
+
+    /// Writes a record at the end of the log, compacting first if it doesn't
+    /// fit, and returns where it went
+    fn append(&mut self, kind: u8, key: &[u8], value: &[u8]) -> Result<u64, FsError> {
+        let len = (RECORD_HEADER_SIZE + key.len() + value.len()) as u64;
+        if self.end + len > self.half {
+            self.compact()?;
+            if self.end + len > self.half {
+                return Err(FsError::NoSpace);
+            }
+        }
+        let record = encode(self.generation, kind, key, value);
+        let offset = self.end;
+        self.write_bytes(self.active * self.half + offset, &record)?;
+        self.device.flush()?;
+        self.end += len;
+        Ok(offset)
+    }
+
+    /// Rebuilds the index from the active half's records
+    fn replay(&mut self) -> Result<(), FsError> {
+        let base = self.active * self.half;
+        let mut offset = self.block_size as u64;
+        let mut header = [0; RECORD_HEADER_SIZE];
+        while offset + RECORD_HEADER_SIZE as u64 <= self.half {
+            self.read_bytes(base + offset, &mut header)?;
+            if le32(&header, 0) != self.generation {
+                // Zeros, or what an older generation left
+                break;
+            }
+            let (kind, key_len, value_len) = (header[4], header[5] as usize, le32(&header, 8));
+            let len = (RECORD_HEADER_SIZE + key_len) as u64 + value_len as u64;
+            let valid = (kind == KIND_PUT || kind == KIND_DELETE)
+                && key_len > 0
+                && value_len as usize <= MAX_VALUE
+                && offset + len <= self.half;
+            let mut body = vec![0; if valid { len as usize } else { 0 }];
+            if valid {
+                self.read_bytes(base + offset, &mut body)?;
+                body[12..16].copy_from_slice(&[0; 4]);
+            }
+            if !valid || crc32(&body) != le32(&header, 12) {
+                self.torn = true;
+                break;
+            }
+            let key = &body[RECORD_HEADER_SIZE..RECORD_HEADER_SIZE + key_len];
+            let old = match kind {
+                KIND_PUT => self.index.insert(
+                    key.into(),
+                    Value {
+                        record: offset,
+                        len: value_len,
+                    },
+                ),
+                _ => {
+                    self.garbage += len;
+                    self.index.remove(key)
+                }
+            };
+            if let Some(old) = old {
+                self.garbage += old.record_len(key);
+            }
+            offset += len;
+        }
+        self.end = offset;
+        Ok(())
+    }
+
+    fn read_bytes(&mut self, start: u64, data: &mut [u8]) -> Result<(), FsError> {
+        let block_size = self.block_size as u64;
+        let mut block = vec![0; self.block_size];
+        let mut done = 0;
+        while done < data.len() {
+            let position = start + done as u64;
+            let within = (position % block_size) as usize;
+            let len = (self.block_size - within).min(data.len() - done);
+            self.device.read(position / block_size, &mut block)?;
+            data[done..done + len].copy_from_slice(&block[within..within + len]);
+            done += len;
+        }
+        Ok(())
+    }
+
+    /// Writes bytes anywhere, reading back the blocks they only partly cover
+    fn write_bytes(&mut self, start: u64, data: &[u8]) -> Result<(), FsError> {
+        let block_size = self.block_size as u64;
+        let mut block = vec![0; self.block_size];
+        let mut done = 0;
+        while done < data.len() {
+            let position = start + done as u64;
+            let within = (position % block_size) as usize;
+            let len = (self.block_size - within).min(data.len() - done);
+            if len < self.block_size {
+                self.device.read(position / block_size, &mut block)?;
+            }
+            block[within..within + len].copy_from_slice(&data[done..done + len]);
+            self.device.write(position / block_size, &block)?;
+            done += len;
+        }
+        Ok(())
+    }
+}
+
+/// A record as stored, with its CRC computed over it with the CRC field
+/// zeroed
+fn encode(generation: u32, kind: u8, key: &[u8], value: &[u8]) -> Vec<u8> {
+    let mut record = vec![0; RECORD_HEADER_SIZE];
+    record[0..4].copy_from_slice(&generation.to_le_bytes());
+    record[4] = kind;
+    record[5] = key.len() as u8;
+    record[8..12].copy_from_slice(&(value.len() as u32).to_le_bytes());
+    record.extend_from_slice(key);
+    record.extend_from_slice(value);
+    let crc = crc32(&record);
+    record[12..16].copy_from_slice(&crc.to_le_bytes());
+    record
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::{BlkError, Locked, RamDisk};
+    use crate::mutex::Mutex;
+
+    #[test]
+    fn kv() {
+        let disk = Mutex::new(Some(RamDisk::new(512, 64)));
+        let open = || KvStore::open(Locked(&disk)).expect("open failed");
+        let mut kv = open();
+        kv.put(b"boots", b"1").expect("put failed");
+        kv.put(b"ip", b"10.0.2.15").expect("put failed");
+        kv.put(b"boots", b"2").expect("put failed");
+        assert!(kv.delete(b"ip") == Ok(true), "delete failed");
+        assert!(kv.delete(b"ip") == Ok(false), "deleted a missing key");
+
+        // A record whose last sector never made it to the disk
+        kv.put(b"torn", &[0x55; 600]).expect("put failed");
+        let end = kv.usage().used as usize + 512;
+        disk.map(|disk| {
+            let mut block = [0; 512];
+            let _ = disk.read((end / 512) as u64, &mut block);
+            block[..end % 512].iter_mut().for_each(|b| *b = 0);
+            let _ = disk.write((end / 512) as u64, &block);
+        });
+        let mut kv = open();
+        assert!(kv.recovered_torn(), "torn record not noticed");
+        assert!(
+            kv.keys().eq([&b"boots"[..]].iter().copied()),
+            "wrong keys after recovery"
+        );
+        assert!(
+            kv.get(b"boots") == Ok(Some(b"2".to_vec())),
+            "value lost in recovery"
+        );
+        kv.put(b"after", b"crash")
+            .expect("put after recovery failed");
+        let mut kv = open();
+        assert!(
+            !kv.recovered_torn() && kv.get(b"after") == Ok(Some(b"crash".to_vec())),
+            "record written over a torn one lost"
+        );
+
+        // Writing a few times what the log holds only works if filling it up
+        // compacts it
+        for i in 0..40u8 {
+            kv.put(b"big", &[i; 1000])
+                .expect("put while compacting failed");
+        }
+        let mut kv = open();
+        assert!(
+            kv.get(b"big") == Ok(Some(vec![39; 1000]))
+                && kv.get(b"boots") == Ok(Some(b"2".to_vec())),
+            "values lost in compaction"
+        );
+        assert!(
+            kv.put(b"huge", &[0; 16 << 10]) == Err(FsError::NoSpace),
+            "record bigger than the log accepted"
+        );
+
+        // Until the new half's header is down, the old half is still the store
+        kv.put(b"boots", b"3").expect("put failed");
+        kv.compact().expect("compact failed");
+        kv.put(b"boots", b"4").expect("put failed");
+        let header = disk.map(|disk| {
+            let mut blocks = [[0; 512]; 2];
+            let _ = disk.read(0, &mut blocks[0]);
+            let _ = disk.read(32, &mut blocks[1]);
+            blocks
+        });
+        let generation =
+            |block: &[u8; 512]| u32::from_le_bytes([block[12], block[13], block[14], block[15]]);
+        let newer = match header {
+            Some(blocks) if generation(&blocks[0]) > generation(&blocks[1]) => 0,
+            Some(_) => 32,
+            None => panic!("disk gone"),
+        };
+        disk.map(|disk| {
+            let mut block = [0; 512];
+            let _ = disk.read(newer, &mut block);
+            block[16] ^= 1;
+            let _ = disk.write(newer, &block);
+        });
+        let mut kv = open();
+        assert!(
+            kv.get(b"boots") == Ok(Some(b"3".to_vec())),
+            "didn't fall back to the old half"
+        );
+    }
+
+    /// A disk that fails writes to `block`, as if the machine went down
+    /// before they got there
+    struct FailingDisk<'a> {
+        disk: Locked<'a, RamDisk>,
+        block: u64,
+    }
+
+    impl<'a> BlockDevice for FailingDisk<'a> {
+        fn block_size(&self) -> usize {
+            self.disk.block_size()
+        }
+
+        fn block_count(&self) -> u64 {
+            self.disk.block_count()
+        }
+
+        fn read(&mut self, start: u64, data: &mut [u8]) -> Result<(), BlkError> {
+            self.disk.read(start, data)
+        }
+
+        fn write(&mut self, start: u64, data: &[u8]) -> Result<(), BlkError> {
+            let blocks = (data.len() / self.block_size()) as u64;
+            if (start..start + blocks).contains(&self.block) {
+                return Err(BlkError::IoError);
+            }
+            self.disk.write(start, data)
+        }
+    }
+
+    #[test]
+    fn interrupted_compaction() {
+        let disk = Mutex::new(Some(RamDisk::new(512, 64)));
+        let mut kv = KvStore::open(Locked(&disk)).expect("open failed");
+        for key in b'a'..b'i' {
+            kv.put(&[key], &[key; 100]).expect("put failed");
+        }
+
+        // A compaction into the other half that stops short of its header
+        let failing = FailingDisk {
+            disk: Locked(&disk),
+            block: 32,
+        };
+        let mut kv = KvStore::open(failing).expect("open failed");
+        assert!(kv.compact().is_err(), "header written");
+        let mut kv = KvStore::open(Locked(&disk)).expect("open failed");
+        assert!(kv.keys().count() == 8, "keys lost to the failed compaction");
+
+        // Compacting again with fewer keys must not bring back the records
+        // the first attempt left past the end of the new ones
+        for key in b'c'..b'i' {
+            kv.delete(&[key]).expect("delete failed");
+        }
+        kv.compact().expect("compact failed");
+        let kv = KvStore::open(Locked(&disk)).expect("open failed");
+        assert!(
+            kv.keys().eq([&b"a"[..], &b"b"[..]].iter().copied()),
+            "deleted keys came back"
+        );
+    }
+}
@@ -0,0 +1,206 @@
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use super::vfs::{DirEntry, Filesystem, Inode, InodeRef};
+use super::{FileType, FsError};
+use crate::mutex::Mutex;
+
+/// Files larger than this would take a good part of the heap
+const MAX_FILE_SIZE: u64 = 16 << 20;
+
+enum Node {
+    File(Vec<u8>),
+    Dir(BTreeMap<String, Arc<TmpInode>>),
+    Symlink(String),
+}
+
+/// A filesystem kept entirely in memory, and lost when unmounted
+pub struct TmpFs {
+    root: Arc<TmpInode>,
+}
+
+impl TmpFs {
+    pub fn new() -> TmpFs {
+        TmpFs {
+            root: TmpInode::new(Node::Dir(BTreeMap::new())),
+        }
+    }
+}
+
+impl Default for TmpFs {
+    fn default() -> TmpFs {
+        TmpFs::new()
+    }
+}
+
+impl Filesystem for TmpFs {
+    fn name(&self) -> &'static str {
+        "tmpfs"
+    }
+
+    fn root(&self) -> Result<InodeRef, FsError> {
+        Ok(self.root.clone())
+    }
+}
+
+pub struct TmpInode {
+    node: Mutex<Node>,
+}
+
+impl TmpInode {
+    fn new(node: Node) -> Arc<TmpInode> {
+        Arc::new(TmpInode {
+            node: Mutex::new(node),
+        })
+    }
+
+    /// Adds `node` to this directory as `name`
+    fn add(&self, name: &str, node: Node) -> Result<InodeRef, FsError> {
+        if name.is_empty() || name.contains('/') {
+            return Err(FsError::InvalidName);
+        }
+        let mut dir = self.node.lock();
+        let entries = match &mut *dir {
+            Node::Dir(entries) => entries,
+            _ => return Err(FsError::NotADirectory),
+        };
+        if entries.contains_key(name) {
+            return Err(FsError::AlreadyExists);
+        }
+        let inode = TmpInode::new(node);
+        entries.insert(name.into(), inode.clone());
+        Ok(inode)
+    }
+}
+
+impl Inode for TmpInode {
+    fn kind(&self) -> FileType {
+        match &*self.node.lock() {
+            Node::File(_) => FileType::File,
+            Node::Dir(_) => FileType::Dir,
+            Node::Symlink(_) => FileType::Symlink,
+        }
+    }
+
+    fn size(&self) -> Result<u64, FsError> {
+        let node = self.node.lock();
+        Ok(match &*node {
+            Node::File(data) => data.len() as u64,
+            Node::Dir(entries) => entries.len() as u64,
+            Node::Symlink(target) => target.len() as u64,
+        })
+    }
+
+    fn lookup(&self, name: &str) -> Result<InodeRef, FsError> {
+        let node = self.node.lock();
+        match &*node {
+            Node::Dir(entries) => match entries.get(name) {
+                Some(inode) => Ok(inode.clone()),
+                None => Err(FsError::NotFound),
+            },
+            _ => Err(FsError::NotADirectory),
+        }
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, FsError> {
+        let entries = match &*self.node.lock() {
+            Node::Dir(entries) => entries.clone(),
+            _ => return Err(FsError::NotADirectory),
+        };
+        // Each entry is locked for its type, so not while holding this one
+        Ok(entries
+            .into_iter()
+            .map(|(name, inode)| DirEntry {
+                name,
+                kind: inode.kind(),
+                size: inode.size().unwrap_or(0),
+            })
+            .collect())
+    }
+
+    fn read_at(&self, offset: u64, data: &mut [u8]) -> Result<usize, FsError> {
+        let node = self.node.lock();
+        let file = match &*node {
+            Node::File(file) => file,
+            Node::Dir(_) => return Err(FsError::IsADirectory),
+            Node::Symlink(_) => return Err(FsError::InvalidArgument),
+        };
+        if offset >= file.len() as u64 {
+            return Ok(0);
+        }
+        let start = offset as usize;
+        let len = data.len().min(file.len() - start);
+        data[..len].copy_from_slice(&file[start..start + len]);
+        Ok(len)
+    }
+
+    fn write_at(&self, offset: u64, data: &[u8]) -> Result<usize, FsError> {
+        let mut node = self.node.lock();
+        let file = match &mut *node {
+            Node::File(file) => file,
+            Node::Dir(_) => return Err(FsError::IsADirectory),
+            Node::Symlink(_) => return Err(FsError::InvalidArgument),
+        };
+        let end = offset
+            .checked_add(data.len() as u64)
+            .filter(|end| *end <= MAX_FILE_SIZE)
+            .ok_or(FsError::NoSpace)?;
+        if end as usize > file.len() {
+            file.resize(end as usize, 0);
+        }
+        file[offset as usize..end as usize].copy_from_slice(data);
+        Ok(data.len())
+    }
+
+    fn truncate(&self, len: u64) -> Result<(), FsError> {
+        if len > MAX_FILE_SIZE {
+            return Err(FsError::NoSpace);
+        }
+        let mut node = self.node.lock();
+        match &mut *node {
+            Node::File(file) => {
+                file.resize(len as usize, 0);
+                Ok(())
+            }
+            Node::Dir(_) => Err(FsError::IsADirectory),
+            Node::Symlink(_) => Err(FsError::InvalidArgument),
+        }
+    }
+
+    fn create(&self, name: &str, kind: FileType) -> Result<InodeRef, FsError> {
+        match kind {
+            FileType::File => self.add(name, Node::File(Vec::new())),
+            FileType::Dir => self.add(name, Node::Dir(BTreeMap::new())),
+            _ => Err(FsError::InvalidArgument),
+        }
+    }
+
+    fn symlink(&self, name: &str, target: &str) -> Result<InodeRef, FsError> {
+        self.add(name, Node::Symlink(target.into()))
+    }
+
+    fn remove(&self, name: &str) -> Result<(), FsError> {
+        let mut dir = self.node.lock();
+        let entries = match &mut *dir {
+            Node::Dir(entries) => entries,
+            _ => return Err(FsError::NotADirectory),
+        };
+        let inode = entries.get(name).ok_or(FsError::NotFound)?;
+        if let Node::Dir(children) = &*inode.node.lock() {
+            if !children.is_empty() {
+                return Err(FsError::NotEmpty);
+            }
+        }
+        entries.remove(name);
+        Ok(())
+    }
+
+    fn read_link(&self) -> Result<String, FsError> {
+        match &*self.node.lock() {
+            Node::Symlink(target) => Ok(target.clone()),
+            _ => Err(FsError::InvalidArgument),
+        }
+    }
+}
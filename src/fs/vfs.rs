@@ -0,0 +1,510 @@
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use alloc::{format, vec};
+
+use super::ext2::{Ext2Fs, Ext2Volume};
+use super::fat::{FatFs, FatVolume};
+use super::{components, split_last, FileType, FsError};
+use crate::block::BlockDevice;
+
+/// Opens a file for writing as well as reading
+pub const O_WRITE: u32 = 0x1;
+/// Creates the file if it doesn't exist
+pub const O_CREATE: u32 = 0x2;
+/// Empties the file when it's opened
+pub const O_TRUNCATE: u32 = 0x4;
+/// Makes every write go to the end of the file
+pub const O_APPEND: u32 = 0x8;
+
+const MAX_SYMLINKS: u32 = 8;
+
+pub type InodeRef = Arc<dyn Inode>;
+
+/// A mounted filesystem
+pub trait Filesystem: Send + Sync {
+    /// The kind of filesystem, such as `fat32` or `tmpfs`
+    fn name(&self) -> &'static str;
+
+    fn root(&self) -> Result<InodeRef, FsError>;
+
+    /// Writes out anything the filesystem or its device is holding back
+    fn sync(&self) -> Result<(), FsError> {
+        Ok(())
+    }
+}
+
+/// An entry of a directory, as listed by [`Inode::read_dir`]
+#[derive(Clone, Debug)]
+pub struct DirEntry {
+    pub name: String,
+    pub kind: FileType,
+    pub size: u64,
+}
+
+/// A file, directory or symbolic link on a mounted filesystem. Names passed
+/// in are single path components, never `.` or `..`, which the VFS handles.
+/// Filesystems that can't be written to leave the modifying methods to
+/// their defaults.
+pub trait Inode: Send + Sync {
+    fn kind(&self) -> FileType;
+
+    fn size(&self) -> Result<u64, FsError>;
+
+    /// The entry called `name` in this directory
+    fn lookup(&self, name: &str) -> Result<InodeRef, FsError>;
+
+    /// The entries of this directory, without `.` and `..`
+    fn read_dir(&self) -> Result<Vec<DirEntry>, FsError>;
+
+    /// Reads from `offset`, returning how much was read, which is less than
+    /// asked for at the end of the file
+    fn read_at(&self, offset: u64, data: &mut [u8]) -> Result<usize, FsError>;
+
+    /// Writes at `offset`, growing the file as needed, and returns how much
+    /// was written
+    fn write_at(&self, _offset: u64, _data: &[u8]) -> Result<usize, FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn truncate(&self, _len: u64) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    /// Creates an empty file or directory called `name` in this directory
+    fn create(&self, _name: &str, _kind: FileType) -> Result<InodeRef, FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn symlink(&self, _name: &str, _target: &str) -> Result<InodeRef, FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    /// Removes the file or empty directory called `name` from this directory
+    fn remove(&self, _name: &str) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    /// Where this symbolic link points
+    fn read_link(&self) -> Result<String, FsError> {
+        Err(FsError::InvalidArgument)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SeekFrom {
+    Start(u64),
+    End(i64),
+    Current(i64),
+}
+
+/// Something that can be read, written and seeked like an open file
+pub trait File {
+    fn read(&mut self, data: &mut [u8]) -> Result<usize, FsError>;
+
+    fn write(&mut self, data: &[u8]) -> Result<usize, FsError>;
+
+    /// Moves to a new position, returning it as an offset from the start.
+    /// Seeking past the end is allowed, and writing there leaves a gap that
+    /// reads as zeros.
+    fn seek(&mut self, position: SeekFrom) -> Result<u64, FsError>;
+
+    /// Reads everything from the current position to the end
+    fn read_to_end(&mut self) -> Result<Vec<u8>, FsError> {
+        let mut data = Vec::new();
+        let mut chunk = vec![0; 4096];
+        loop {
+            let len = self.read(&mut chunk)?;
+            if len == 0 {
+                return Ok(data);
+            }
+            data.extend_from_slice(&chunk[..len]);
+        }
+    }
+
+    /// Writes all of `data`, which a single `write` may not
+    fn write_all(&mut self, mut data: &[u8]) -> Result<(), FsError> {
+        while !data.is_empty() {
+            match self.write(data)? {
+                0 => return Err(FsError::NoSpace),
+                len => data = &data[len..],
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A file opened through the VFS, with its own position
+pub struct OpenFile {
+    inode: InodeRef,
+    position: u64,
+    flags: u32,
+}
+
+impl OpenFile {
+    pub fn inode(&self) -> &InodeRef {
+        &self.inode
+    }
+
+    pub fn size(&self) -> Result<u64, FsError> {
+        self.inode.size()
+    }
+}
+
+impl File for OpenFile {
+    fn read(&mut self, data: &mut [u8]) -> Result<usize, FsError> {
+        let len = self.inode.read_at(self.position, data)?;
+        self.position += len as u64;
+        Ok(len)
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<usize, FsError> {
+        if self.flags & O_WRITE == 0 {
+            return Err(FsError::ReadOnly);
+        }
+        if self.flags & O_APPEND != 0 {
+            self.position = self.inode.size()?;
+        }
+        let len = self.inode.write_at(self.position, data)?;
+        self.position += len as u64;
+        Ok(len)
+    }
+
+    fn seek(&mut self, position: SeekFrom) -> Result<u64, FsError> {
+        let (base, delta) = match position {
+            SeekFrom::Start(offset) => (offset, 0),
+            SeekFrom::End(delta) => (self.inode.size()?, delta),
+            SeekFrom::Current(delta) => (self.position, delta),
+        };
+        let position = if delta < 0 {
+            base.checked_sub(delta.unsigned_abs())
+        } else {
+            base.checked_add(delta as u64)
+        };
+        self.position = position.ok_or(FsError::InvalidArgument)?;
+        Ok(self.position)
+    }
+}
+
+struct Mount {
+    /// Canonical path of the mount point
+    path: String,
+    fs: Arc<dyn Filesystem>,
+}
+
+/// The tree of mounted filesystems. Paths are all taken from the root, so
+/// `a/b` is `/a/b`.
+pub struct Vfs {
+    mounts: Vec<Mount>,
+}
+
+impl Vfs {
+    pub const fn new() -> Vfs {
+        Vfs { mounts: Vec::new() }
+    }
+
+    fn mounted(&self, path: &str) -> Option<&Arc<dyn Filesystem>> {
+        self.mounts
+            .iter()
+            .find(|mount| mount.path == path)
+            .map(|mount| &mount.fs)
+    }
+
+    /// Attaches `fs` at `path`, which must be an existing directory, or `/`
+    /// for the first filesystem mounted
+    pub fn mount(&mut self, path: &str, fs: Arc<dyn Filesystem>) -> Result<(), FsError> {
+        let path = if self.mounts.is_empty() {
+            if components(path).next().is_some() {
+                return Err(FsError::NotFound);
+            }
+            String::from("/")
+        } else {
+            let (path, inode) = self.resolve(path, true)?;
+            if inode.kind() != FileType::Dir {
+                return Err(FsError::NotADirectory);
+            }
+            path
+        };
+        if self.mounted(&path).is_some() {
+            return Err(FsError::Busy);
+        }
+        self.mounts.push(Mount { path, fs });
+        Ok(())
+    }
+
+    /// Detaches the filesystem at `path`, which can't have others mounted
+    /// inside it
+    pub fn unmount(&mut self, path: &str) -> Result<Arc<dyn Filesystem>, FsError> {
+        let (path, _) = self.resolve(path, true)?;
+        let index = self
+            .mounts
+            .iter()
+            .position(|mount| mount.path == path)
+            .ok_or(FsError::InvalidArgument)?;
+        let inside = |other: &Mount| {
+            other.path != path && (path == "/" || other.path.starts_with(&format!("{}/", path)))
+        };
+        if self.mounts.iter().any(inside) {
+            return Err(FsError::Busy);
+        }
+        Ok(self.mounts.remove(index).fs)
+    }
+
+    /// The mount points and what's mounted on them, in the order they were
+    /// mounted
+    pub fn mounts(&self) -> impl Iterator<Item = (&str, &Arc<dyn Filesystem>)> {
+        self.mounts
+            .iter()
+            .map(|mount| (mount.path.as_str(), &mount.fs))
+    }
+
+    /// Follows `path` from the root, returning the canonical path of what it
+    /// ends at along with it. `..` goes back the way the path came, so it
+    /// climbs out of a mounted filesystem and never above the root. A
+    /// symbolic link at the end is only followed if `follow` is set.
+    fn resolve(&self, path: &str, follow: bool) -> Result<(String, InodeRef), FsError> {
+        let root = self.mounted("/").ok_or(FsError::NotFound)?.root()?;
+        let mut pending: Vec<String> = components(path).rev().map(String::from).collect();
+        let mut names: Vec<String> = Vec::new();
+        let mut inodes = vec![root];
+        let mut links = 0;
+        while let Some(name) = pending.pop() {
+            match name.as_str() {
+                "." => continue,
+                ".." => {
+                    if names.pop().is_some() {
+                        inodes.pop();
+                    }
+                    continue;
+                }
+                _ => {}
+            }
+            let dir = inodes.last().unwrap();
+            if dir.kind() != FileType::Dir {
+                return Err(FsError::NotADirectory);
+            }
+            let path = join(&names, &name);
+            let inode = match self.mounted(&path) {
+                Some(fs) => fs.root()?,
+                None => dir.lookup(&name)?,
+            };
+            if inode.kind() == FileType::Symlink && (follow || !pending.is_empty()) {
+                links += 1;
+                if links > MAX_SYMLINKS {
+                    return Err(FsError::Loop);
+                }
+                let target = inode.read_link()?;
+                if target.starts_with('/') {
+                    names.clear();
+                    inodes.truncate(1);
+                }
+                pending.extend(components(&target).rev().map(String::from));
+                continue;
+            }
+            names.push(name);
+            inodes.push(inode);
+        }
+        Ok((join(&names, ""), inodes.pop().unwrap()))
+    }
+
+    /// What `path` names, following symbolic links
+    pub fn lookup(&self, path: &str) -> Result<InodeRef, FsError> {
+        Ok(self.resolve(path, true)?.1)
+    }
+
+    /// `path` with `.`, `..` and symbolic links resolved
+    pub fn canonical(&self, path: &str) -> Result<String, FsError> {
+        Ok(self.resolve(path, true)?.0)
+    }
+
+    /// The directory `path` is in, its canonical path and the last component
+    /// of `path`, which mustn't be `.` or `..`
+    fn parent<'p>(&self, path: &'p str) -> Result<(InodeRef, String, &'p str), FsError> {
+        let (parent, name) = split_last(path).ok_or(FsError::Busy)?;
+        if name == "." || name == ".." {
+            return Err(FsError::InvalidName);
+        }
+        let (parent_path, parent) = self.resolve(parent, true)?;
+        if parent.kind() != FileType::Dir {
+            return Err(FsError::NotADirectory);
+        }
+        Ok((parent, parent_path, name))
+    }
+
+    /// Opens the file at `path` with `O_*` flags
+    pub fn open(&self, path: &str, flags: u32) -> Result<OpenFile, FsError> {
+        let inode = match self.lookup(path) {
+            Err(FsError::NotFound) if flags & O_CREATE != 0 => {
+                let (parent, _, name) = self.parent(path)?;
+                parent.create(name, FileType::File)?
+            }
+            inode => inode?,
+        };
+        match inode.kind() {
+            FileType::Dir if flags & O_WRITE != 0 => return Err(FsError::IsADirectory),
+            FileType::File if flags & O_TRUNCATE != 0 => inode.truncate(0)?,
+            _ => {}
+        }
+        Ok(OpenFile {
+            inode,
+            position: 0,
+            flags,
+        })
+    }
+
+    pub fn mkdir(&self, path: &str) -> Result<(), FsError> {
+        let (parent, _, name) = self.parent(path)?;
+        parent.create(name, FileType::Dir)?;
+        Ok(())
+    }
+
+    /// Creates a symbolic link at `path` pointing to `target`
+    pub fn symlink(&self, path: &str, target: &str) -> Result<(), FsError> {
+        let (parent, _, name) = self.parent(path)?;
+        parent.symlink(name, target)?;
+        Ok(())
+    }
+
+    /// Removes a file, symbolic link or empty directory. Mount points can't
+    /// be removed.
+    pub fn remove(&self, path: &str) -> Result<(), FsError> {
+        let (parent, parent_path, name) = self.parent(path)?;
+        let inside = self
+            .mounts
+            .iter()
+            .any(|mount| components(&mount.path).eq(components(&parent_path).chain(Some(name))));
+        if inside {
+            return Err(FsError::Busy);
+        }
+        parent.remove(name)
+    }
+
+    /// The entries of the directory at `path`
+    pub fn read_dir(&self, path: &str) -> Result<Vec<DirEntry>, FsError> {
+        self.lookup(path)?.read_dir()
+    }
+
+    /// Where the symbolic link at `path` points
+    pub fn read_link(&self, path: &str) -> Result<String, FsError> {
+        self.resolve(path, false)?.1.read_link()
+    }
+
+    /// Syncs every mounted filesystem, carrying on past failures and
+    /// returning the first
+    pub fn sync(&self) -> Result<(), FsError> {
+        let mut result = Ok(());
+        for mount in self.mounts.iter() {
+            let synced = mount.fs.sync();
+            if result.is_ok() {
+                result = synced;
+            }
+        }
+        result
+    }
+}
+
+/// The FAT or ext2 filesystem on a device, whichever it has. `open` gives
+/// the device once for each filesystem tried.
+pub fn probe<D, F>(mut open: F) -> Result<Arc<dyn Filesystem>, FsError>
+where
+    D: BlockDevice + Sync + 'static,
+    F: FnMut() -> Result<D, FsError>,
+{
+    if let Ok(fs) = FatFs::mount(open()?) {
+        return Ok(Arc::new(FatVolume::new(fs)));
+    }
+    Ok(Arc::new(Ext2Volume::new(Ext2Fs::mount(open()?)?)))
+}
+
+/// The absolute path of `names` followed by `last`, if it isn't empty
+fn join(names: &[String], last: &str) -> String {
+    let mut path = String::new();
+    for name in names.iter().map(String::as_str).chain(Some(last)) {
+        if !name.is_empty() {
+            path.push('/');
+            path.push_str(name);
+        }
+    }
+    if path.is_empty() {
+        path.push('/');
+    }
+    path
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::fat::tests::fat12_disk;
+    use crate::fs::tmpfs::TmpFs;
+
+    #[test]
+    fn vfs() {
+        let mut vfs = Vfs::new();
+        vfs.mount("/", Arc::new(TmpFs::new()))
+            .expect("tmpfs mount failed");
+        vfs.mkdir("/disk").expect("mkdir failed");
+        let fs = FatFs::mount(fat12_disk()).expect("FAT mount failed");
+        vfs.mount("/disk", Arc::new(FatVolume::new(fs)))
+            .expect("FAT mount failed");
+
+        let mut file = vfs
+            .open("/disk/config.txt", O_WRITE | O_CREATE | O_TRUNCATE)
+            .expect("open failed");
+        file.write_all(b"ip=10.0.2.15\n").expect("write failed");
+        let mut read = [0u8; 16];
+        assert!(
+            file.seek(SeekFrom::Start(3)) == Ok(3)
+                && file.read(&mut read) == Ok(10)
+                && read[..10] == *b"10.0.2.15\n",
+            "seek and read returned wrong data"
+        );
+        assert!(
+            file.seek(SeekFrom::Current(-20)) == Err(FsError::InvalidArgument),
+            "seeked before the start"
+        );
+
+        // Another handle sees the first one's writes, through `.`, `..` and a
+        // symbolic link out of the tmpfs
+        vfs.symlink("/etc", "disk/./").expect("symlink failed");
+        let mut other = vfs
+            .open("/disk/../etc/CONFIG.TXT", O_WRITE | O_APPEND)
+            .expect("open through symlink failed");
+        other.write_all(b"port=7\n").expect("append failed");
+        file.seek(SeekFrom::Start(0)).expect("seek failed");
+        assert!(
+            file.read_to_end().as_deref() == Ok(&b"ip=10.0.2.15\nport=7\n"[..]),
+            "append not seen by the other handle"
+        );
+        assert!(
+            vfs.canonical("/etc/..//disk/config.txt").as_deref() == Ok("/disk/config.txt"),
+            "wrong canonical path"
+        );
+
+        assert!(
+            vfs.remove("/disk") == Err(FsError::Busy),
+            "mount point removed"
+        );
+        assert!(
+            vfs.unmount("/").err() == Some(FsError::Busy),
+            "root unmounted with /disk still mounted"
+        );
+        let root = vfs.read_dir("/").expect("read_dir failed");
+        assert!(
+            root.len() == 2
+                && root[0].name == "disk"
+                && root[0].kind == FileType::Dir
+                && root[1].kind == FileType::Symlink,
+            "wrong tmpfs listing"
+        );
+        vfs.remove("/disk/config.txt").expect("remove failed");
+        assert!(
+            vfs.open("/etc/config.txt", 0).err() == Some(FsError::NotFound),
+            "removed file still opens"
+        );
+        vfs.unmount("/disk").expect("unmount failed");
+        assert!(
+            vfs.read_dir("/disk").map(|entries| entries.is_empty()) == Ok(true),
+            "mount point still shows the unmounted filesystem"
+        );
+    }
+}
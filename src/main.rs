@@ -1,29 +1,42 @@
-#![no_main]
-#![no_std]
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(not(test), no_std)]
+// Host-side tests don't build the kernel's entry point, so most of the
+// kernel is unreachable from them
+#![cfg_attr(test, allow(dead_code, unused_imports))]
 
-use core::arch::{asm, global_asm};
+use core::arch::global_asm;
 
 extern crate alloc;
 use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
 
+pub mod block;
+pub mod crypto;
 pub mod device_tree;
+pub mod fs;
 pub mod gic;
 pub mod mutex;
+pub mod pci;
 pub mod thread;
 pub mod uart;
 pub mod utils;
 pub mod virtio;
+pub mod xmodem;
 
 mod apps;
 
-use virtio::VirtIORegs;
+use virtio::{Transport, VirtIORegs};
 
 #[cfg(target_arch = "aarch64")]
 global_asm!(include_str!("boot.S"));
 
 use core::fmt::Write;
 use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
+#[cfg(not(test))]
 extern "C" {
     static HEAP_START: usize;
     fn system_off() -> !;
@@ -48,32 +61,119 @@ fn regs_to_usize(regs: &[u8], cell_size: usize) -> (usize, &[u8]) {
     (result, rest)
 }
 
+fn interrupt_id(irq_type: usize, irq: usize) -> u32 {
+    if irq_type == 0 {
+        // IRQ
+        32 + (irq as u32)
+    } else {
+        // irq_type == 1, SPI
+        16 + (irq as u32)
+    }
+}
+
 fn interrupt_for_node(node: &device_tree::Node) -> Option<u32> {
     node.prop_by_name("interrupts").map(|interrupt| {
         let (irq_type, rest) = regs_to_usize(interrupt.value, 1);
         let (irq, _rest) = regs_to_usize(rest, 1);
-        if irq_type == 0 {
-            // IRQ
-            32 + (irq as u32)
-        } else {
-            // irq_type == 1, SPI
-            16 + (irq as u32)
-        }
+        interrupt_id(irq_type, irq)
     })
 }
 
+#[cfg(not(test))]
 #[global_allocator]
 static ALLOCATOR: linked_list_allocator::LockedHeap = linked_list_allocator::LockedHeap::empty();
 
+/// A shard of the buffer cache over the virtio-blk queues, reading and
+/// writing through the calling core's
+type CacheShard =
+    mutex::Mutex<Option<block::BufferCache<block::PerCore<'static, virtio::VirtIOBlk<'static>>>>>;
+type DiskCache = [CacheShard];
+type Kv = mutex::Mutex<Option<fs::kv::KvStore<Box<dyn block::BlockDevice + Sync>>>>;
+type Crypt = mutex::Mutex<Option<block::CryptDevice<Box<dyn block::BlockDevice + Sync>>>>;
+
+/// Mounts the filesystem on the disk at `/disk`: the whole disk's, or else
+/// that of the first partition with one. Returns the device it was on and
+/// the kind of filesystem.
+fn mount_disk(
+    vfs: &mutex::Mutex<fs::vfs::Vfs>,
+    cache: &'static DiskCache,
+) -> Result<(String, &'static str), fs::FsError> {
+    let disk = || block::Sharded(cache);
+    let mut found = fs::vfs::probe(|| Ok(disk())).map(|fs| (String::from("blk0"), fs));
+    if found.is_err() {
+        for entry in block::read_partitions(&mut disk())? {
+            if let Ok(fs) = fs::vfs::probe(|| Ok(block::Partition::new(disk(), &entry)?)) {
+                found = Ok((format!("blk0p{}", entry.number), fs));
+                break;
+            }
+        }
+    }
+    let (device, fs) = found?;
+    let name = fs.name();
+    vfs.lock().mount("/disk", fs)?;
+    Ok((device, name))
+}
+
+/// Opens the key-value store on the partition set aside for it: a GPT
+/// partition named `kv`, or an MBR one of type 0xda, for non-filesystem
+/// data. Returns the partition's number and whether a torn record was
+/// discarded.
+fn open_kv(kv: &Kv, cache: &'static DiskCache) -> Result<(u32, bool), fs::FsError> {
+    let entry = block::read_partitions(&mut block::Sharded(cache))?
+        .into_iter()
+        .find(|entry| match entry.kind {
+            block::PartitionType::Gpt(_) => entry.name == "kv",
+            block::PartitionType::Mbr(kind) => kind == 0xda,
+        })
+        .ok_or(fs::FsError::NotFound)?;
+    let partition = block::Partition::new(block::Sharded(cache), &entry)?;
+    let store = fs::kv::KvStore::open(Box::new(partition) as Box<dyn block::BlockDevice + Sync>)?;
+    let torn = store.recovered_torn();
+    *kv.lock() = Some(store);
+    Ok((entry.number, torn))
+}
+
+#[cfg(not(test))]
 #[no_mangle]
 pub extern "C" fn kernel_main(dtb: &device_tree::DeviceTree) {
     gic::init();
 
     static UART: mutex::Mutex<Option<uart::UART>> = mutex::Mutex::new(None);
 
-    static BLK: mutex::Mutex<Option<virtio::VirtIOBlk>> = mutex::Mutex::new(None);
+    // One virtio-blk queue per core, when the device supports that many
+    const NO_BLK: mutex::Mutex<Option<virtio::VirtIOBlk>> = mutex::Mutex::new(None);
+    static BLK: [mutex::Mutex<Option<virtio::VirtIOBlk>>; thread::MAX_CORES] =
+        [NO_BLK; thread::MAX_CORES];
+    static BLK_QUEUES: AtomicUsize = AtomicUsize::new(1);
+    /// The block queue for the current core
+    fn blk() -> &'static mutex::Mutex<Option<virtio::VirtIOBlk<'static>>> {
+        &BLK[utils::current_core() % BLK_QUEUES.load(Ordering::Relaxed)]
+    }
+    // Shared by every core, so cached blocks stay coherent, and split into a
+    // shard per block queue so cores seldom wait for each other
+    const NO_CACHE: CacheShard = mutex::Mutex::new(None);
+    static CACHE: [CacheShard; thread::MAX_CORES] = [NO_CACHE; thread::MAX_CORES];
+    static CACHE_SHARDS: AtomicUsize = AtomicUsize::new(0);
+    fn cache() -> &'static DiskCache {
+        &CACHE[..CACHE_SHARDS.load(Ordering::Relaxed)]
+    }
+    static INITRD: mutex::Mutex<Option<block::MemoryDisk>> = mutex::Mutex::new(None);
     static ENTROPY: mutex::Mutex<Option<virtio::VirtIOEntropy>> = mutex::Mutex::new(None);
-    static NET: mutex::Mutex<Option<virtio::VirtIONet>> = mutex::Mutex::new(None);
+    // One virtio-net queue pair per network thread
+    const NO_NET: mutex::Mutex<Option<virtio::VirtIONet>> = mutex::Mutex::new(None);
+    static NET: [mutex::Mutex<Option<virtio::VirtIONet>>; thread::MAX_CORES] =
+        [NO_NET; thread::MAX_CORES];
+    static NET_QUEUES: AtomicUsize = AtomicUsize::new(0);
+    static NET_STATS: mutex::Mutex<Option<Arc<virtio::NetStats>>> = mutex::Mutex::new(None);
+    static VFS: mutex::Mutex<fs::vfs::Vfs> = mutex::Mutex::new(fs::vfs::Vfs::new());
+    static KV: Kv = mutex::Mutex::new(None);
+    static CRYPT: Crypt = mutex::Mutex::new(None);
+
+    {
+        let mut vfs = VFS.lock();
+        let _ = vfs.mount("/", Arc::new(fs::tmpfs::TmpFs::new()));
+        let _ = vfs.mkdir("/disk");
+    }
 
     if let Some(root) = dtb.root() {
         let size_cell = root
@@ -93,6 +193,16 @@ pub extern "C" fn kernel_main(dtb: &device_tree::DeviceTree) {
             })
             .unwrap_or(2);
 
+        // Where the bootloader loaded an initrd, if it did. The cell count
+        // follows from the property length.
+        let initrd = root.child_by_name("chosen").and_then(|chosen| {
+            let start = chosen.prop_by_name("linux,initrd-start")?.value;
+            let end = chosen.prop_by_name("linux,initrd-end")?.value;
+            let (start, _) = regs_to_usize(start, start.len() / 4);
+            let (end, _) = regs_to_usize(end, end.len() / 4);
+            Some((start, end)).filter(|(start, end)| start < end)
+        });
+
         for memory in root.children_by_prop("device_type", |prop| prop.value == b"memory\0") {
             if let Some(reg) = memory.prop_by_name("reg") {
                 let (addr, rest) = regs_to_usize(reg.value, address_cell);
@@ -100,6 +210,11 @@ pub extern "C" fn kernel_main(dtb: &device_tree::DeviceTree) {
                 unsafe {
                     let heap_start = &HEAP_START as *const _ as usize;
                     if heap_start >= addr {
+                        // Keep the heap clear of the initrd
+                        let size = match initrd {
+                            Some((start, _)) if start > heap_start => size.min(start - heap_start),
+                            _ => size,
+                        };
                         ALLOCATOR.lock().init(heap_start as *mut u8, size);
                         break;
                     } else {
@@ -109,6 +224,10 @@ pub extern "C" fn kernel_main(dtb: &device_tree::DeviceTree) {
             }
         }
 
+        if let Some((start, end)) = initrd {
+            *INITRD.lock() = Some(unsafe { block::MemoryDisk::from_raw(512, start, end - start) });
+        }
+
         if let Some(chosen) = root.child_by_name("chosen") {
             chosen
                 .prop_by_name("stdout-path")
@@ -130,87 +249,221 @@ pub extern "C" fn kernel_main(dtb: &device_tree::DeviceTree) {
                 });
         }
 
+        let cores = root
+            .child_by_name("cpus")
+            .map(|cpus| {
+                cpus.children_by_prop("device_type", |prop| prop.value == b"cpu\0")
+                    .count()
+            })
+            .unwrap_or(1)
+            .clamp(1, thread::MAX_CORES);
+        thread::init(cores);
+
+        let probe = |virtio: &'static mut dyn Transport, irq: gic::GIC| match virtio.device_id() {
+            virtio::DeviceId::Blk => {
+                let queues = (0..cores)
+                    .map(|_| Box::leak(virtio::Queue::boxed()))
+                    .collect();
+                match virtio::VirtIOBlk::new_multiqueue(virtio, queues, irq) {
+                    Ok(queues) => {
+                        let count = queues.len();
+                        BLK_QUEUES.store(count, Ordering::Relaxed);
+                        for (slot, blk) in BLK.iter().zip(queues) {
+                            *slot.lock() = Some(blk);
+                        }
+                        for shard in CACHE[..count].iter() {
+                            let mut cache = block::BufferCache::new(block::PerCore(&BLK[..count]));
+                            cache.set_stripe(block::STRIPE_BLOCKS);
+                            *shard.lock() = Some(cache);
+                        }
+                        CACHE_SHARDS.store(count, Ordering::Relaxed);
+                        let partitions = block::read_partitions(&mut block::Sharded(cache()));
+                        UART.map(|uart| {
+                            let _ = write!(uart, "blk0:");
+                            match partitions {
+                                Ok(partitions) => {
+                                    for partition in partitions.iter() {
+                                        let _ = write!(uart, " p{}", partition.number);
+                                    }
+                                }
+                                Err(err) => {
+                                    let _ = write!(uart, " {}", err.as_str());
+                                }
+                            }
+                            let _ = write!(uart, "\n");
+                        });
+                    }
+                    Err(err) => {
+                        UART.map(|uart| {
+                            let _ = write!(uart, "virtio-blk: {}\n", err.as_str());
+                        });
+                    }
+                }
+            }
+            virtio::DeviceId::Entropy => {
+                let mut virtio_entropy = ENTROPY.lock();
+                *virtio_entropy = Some(virtio::VirtIOEntropy::new(
+                    virtio,
+                    Box::leak(Box::new(virtio::Queue::new())),
+                    irq,
+                ));
+            }
+            virtio::DeviceId::Net => {
+                // The cores kernel_main and the console shell don't take,
+                // though the device needs at least one pair
+                let pairs = (0..cores.saturating_sub(2).max(1))
+                    .map(|_| {
+                        (
+                            Box::leak(virtio::Queue::boxed()),
+                            Box::leak(virtio::Queue::boxed()),
+                        )
+                    })
+                    .collect();
+                let queues = virtio::VirtIONet::new_multiqueue(
+                    virtio,
+                    pairs,
+                    Box::leak(virtio::Queue::boxed()),
+                    irq,
+                );
+                *NET_STATS.lock() = Some(queues[0].stats());
+                NET_QUEUES.store(queues.len(), Ordering::Relaxed);
+                for (slot, net) in NET.iter().zip(queues) {
+                    *slot.lock() = Some(net);
+                }
+            }
+            _ => {}
+        };
+
         for child in root.children_by_prop("compatible", |prop| prop.value == b"virtio,mmio\0") {
             if let Some(reg) = child.prop_by_name("reg") {
                 let (addr, _rest) = regs_to_usize(reg.value, address_cell);
                 let irq =
                     unsafe { crate::gic::GIC::new(interrupt_for_node(&child).unwrap_or(0) as u32) };
                 if let Some(virtio) = unsafe { VirtIORegs::new(addr as *mut VirtIORegs<()>) } {
-                    match virtio.device_id() {
-                        virtio::DeviceId::Blk => {
-                            let mut virtio_blk = BLK.lock();
-                            *virtio_blk = unsafe {
-                                Some(virtio::VirtIOBlk::new(
-                                    &mut *(virtio as *mut _ as *mut _),
-                                    Box::leak(Box::new(virtio::Queue::new())),
-                                    irq,
-                                ))
-                            };
-                        }
-                        virtio::DeviceId::Entropy => {
-                            let mut virtio_entropy = ENTROPY.lock();
-                            *virtio_entropy = unsafe {
-                                Some(virtio::VirtIOEntropy::new(
-                                    &mut *(virtio as *mut _ as *mut _),
-                                    Box::leak(Box::new(virtio::Queue::new())),
-                                    irq,
-                                ))
-                            };
-                        }
-                        virtio::DeviceId::Net => {
-                            let mut virtio_net = NET.lock();
-                            *virtio_net = unsafe {
-                                Some(virtio::VirtIONet::new(
-                                    &mut *(virtio as *mut _ as *mut _),
-                                    Box::leak(Box::new(virtio::Queue::new())),
-                                    Box::leak(Box::new(virtio::Queue::new())),
-                                    irq,
-                                ))
-                            };
-                        }
-                        _ => {}
+                    probe(virtio, irq);
+                }
+            }
+        }
+
+        for child in root.children_by_prop("compatible", |prop| {
+            prop.value == b"pci-host-ecam-generic\0"
+        }) {
+            if let Some(mut bridge) =
+                pci::HostBridge::from_node(&root, &child, address_cell, size_cell)
+            {
+                for device in bridge.enumerate() {
+                    if let Some(virtio) = virtio::VirtIOPci::new(device) {
+                        let irq = unsafe { gic::GIC::new(virtio.irq().unwrap_or(0)) };
+                        probe(Box::leak(Box::new(virtio)), irq);
                     }
                 }
             }
         }
+
+        if !cache().is_empty() {
+            let mounted = mount_disk(&VFS, cache());
+            UART.map(|uart| {
+                let _ = match mounted {
+                    Ok((device, fs)) => write!(uart, "{}: {} on /disk\n", device, fs),
+                    Err(err) => write!(uart, "blk0: {}\n", err.as_str()),
+                };
+            });
+            match open_kv(&KV, cache()) {
+                Ok((number, torn)) => UART.map(|uart| {
+                    let _ = write!(uart, "blk0p{}: key-value store\n", number);
+                    if torn {
+                        let _ = write!(uart, "blk0p{}: discarded a torn record\n", number);
+                    }
+                }),
+                Err(fs::FsError::NotFound) => None,
+                Err(err) => UART.map(|uart| {
+                    let _ = write!(uart, "kv: {}\n", err.as_str());
+                }),
+            };
+        }
     }
 
-    thread::spawn(|| {
+    let shell = thread::spawn(|| {
         UART.map(|uart| {
             let _ = write!(uart, "Running from core {}\n", utils::current_core());
         });
 
         let mut shell = apps::shell::Shell {
-            blk: &BLK,
+            blk: blk(),
             entropy: &ENTROPY,
+            cache: cache(),
+            vfs: &VFS,
+            kv: &KV,
+            crypt: &CRYPT,
+            console: Some(&UART),
+            net_stats: &NET_STATS,
         };
         apps::shell::main(&UART, &mut shell);
     });
+    if shell.is_err() {
+        UART.map(|uart| {
+            let _ = write!(uart, "shell: no free core\n");
+        });
+    }
 
     UART.lock()
         .as_mut()
         .map(|uart| uart.write_bytes(b"Booting Allora...\n"));
 
-    thread::spawn(|| {
-        UART.map(|uart| {
-            let _ = write!(uart, "Running from core {}\n", utils::current_core());
-        });
-        NET.map(|mut net| {
-            let mut shell = apps::shell::Shell {
-                blk: &BLK,
-                entropy: &ENTROPY,
-            };
-            apps::net::Net { net: &mut net }.run(&mut shell)
+    for index in 0..NET_QUEUES.load(Ordering::Relaxed) {
+        let spawned = thread::spawn(move || {
+            UART.map(|uart| {
+                let _ = write!(uart, "Running from core {}\n", utils::current_core());
+            });
+            NET[index].map(|mut net| {
+                net.set_core(utils::current_core() as u32);
+                let mut shell = apps::shell::Shell {
+                    blk: blk(),
+                    entropy: &ENTROPY,
+                    cache: cache(),
+                    vfs: &VFS,
+                    kv: &KV,
+                    crypt: &CRYPT,
+                    console: None,
+                    net_stats: &NET_STATS,
+                };
+                let config = apps::config::Config::load(&VFS);
+                apps::net::Net {
+                    net: &mut net,
+                    ip: config.ip,
+                    port: config.port,
+                }
+                .run(&mut shell)
+            });
         });
-    });
+        if spawned.is_err() {
+            UART.map(|uart| {
+                let _ = write!(uart, "net{}: no free core\n", index);
+            });
+        }
+    }
 
+    // Core 0 is left to write back dirty cache buffers that have waited too
+    // long. There's no timer interrupt to sleep until then, so it polls.
+    let period = utils::counter_frequency() / 10;
+    let mut failing = false;
     loop {
-        unsafe {
-            asm!("wfi");
+        let result = block::Sharded(cache()).write_back_due();
+        if let Err(err) = result {
+            // Reported once per run of failures rather than on every retry
+            if !failing {
+                UART.map(|uart| {
+                    let _ = write!(uart, "blk0: write-back failed: {}\n", err.as_str());
+                });
+            }
         }
+        failing = result.is_err();
+        let deadline = utils::counter() + period;
+        while utils::counter() < deadline {}
     }
 }
 
+#[cfg(not(test))]
 #[panic_handler]
 fn panic(panic_info: &PanicInfo<'_>) -> ! {
     let mut uart = unsafe { uart::UART::new(0x0900_0000 as _, gic::GIC::new(uart::IRQ)) };
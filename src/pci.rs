@@ -0,0 +1,542 @@
+use alloc::vec::Vec;
+use core::ptr::{read_volatile, write_volatile};
+
+use crate::device_tree::Node;
+use crate::regs_to_usize;
+
+// Configuration space header (type 0)
+const PCI_VENDOR_ID: usize = 0x00;
+const PCI_DEVICE_ID: usize = 0x02;
+const PCI_COMMAND: usize = 0x04;
+const PCI_STATUS: usize = 0x06;
+const PCI_HEADER_TYPE: usize = 0x0e;
+const PCI_BAR0: usize = 0x10;
+const PCI_SUBSYSTEM_ID: usize = 0x2e;
+const PCI_CAPABILITY_LIST: usize = 0x34;
+const PCI_INTERRUPT_PIN: usize = 0x3d;
+
+const PCI_COMMAND_IO: u16 = 1 << 0;
+const PCI_COMMAND_MEMORY: u16 = 1 << 1;
+const PCI_COMMAND_MASTER: u16 = 1 << 2;
+const PCI_COMMAND_INTX_DISABLE: u16 = 1 << 10;
+
+const PCI_STATUS_CAP_LIST: u16 = 1 << 4;
+
+const PCI_HEADER_TYPE_MULTI_FUNCTION: u8 = 0x80;
+
+pub const PCI_CAP_ID_MSI: u8 = 0x05;
+pub const PCI_CAP_ID_VNDR: u8 = 0x09;
+pub const PCI_CAP_ID_MSIX: u8 = 0x11;
+
+const PCI_MSI_FLAGS_ENABLE: u16 = 1 << 0;
+const PCI_MSI_FLAGS_64BIT: u16 = 1 << 7;
+const PCI_MSIX_FLAGS_MASKALL: u16 = 1 << 14;
+const PCI_MSIX_FLAGS_ENABLE: u16 = 1 << 15;
+/// SPIs given to one function's MSI-X vectors: enough for a configuration
+/// change vector plus one per core
+const MAX_MSIX_VECTORS: usize = 1 + crate::thread::MAX_CORES;
+
+// GICv2m MSI frame
+const V2M_MSI_TYPER: usize = 0x008;
+const V2M_MSI_SETSPI_NS: usize = 0x040;
+
+/// Address spaces a `ranges` entry can describe (bits 24-25 of phys.hi)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Space {
+    Config = 0,
+    Io = 1,
+    Mem32 = 2,
+    Mem64 = 3,
+}
+
+/// A window of PCI address space the host bridge forwards from the CPU, out of
+/// which BARs are assigned
+#[derive(Clone, Copy, Debug)]
+struct Window {
+    space: Space,
+    pci_addr: u64,
+    cpu_addr: u64,
+    size: u64,
+    next: u64,
+}
+
+impl Window {
+    /// Allocates a naturally aligned region of `size` bytes, returning its PCI
+    /// address
+    fn alloc(&mut self, size: u64) -> Option<u64> {
+        let start = (self.next + size - 1) & !(size - 1);
+        if start + size > self.pci_addr + self.size {
+            return None;
+        }
+        self.next = start + size;
+        Some(start)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct InterruptMapEntry {
+    /// phys.hi of the child unit address and the INTx pin
+    child: [u32; 2],
+    irq: u32,
+}
+
+/// A GICv2m frame, which turns MSI writes into SPIs
+struct GicV2m {
+    base: usize,
+    next_spi: u32,
+    end_spi: u32,
+}
+
+impl GicV2m {
+    unsafe fn new(base: usize) -> GicV2m {
+        let typer = read_volatile((base + V2M_MSI_TYPER) as *const u32);
+        let first = (typer >> 16) & 0x3ff;
+        GicV2m {
+            base,
+            next_spi: first,
+            end_spi: first + (typer & 0x3ff),
+        }
+    }
+
+    fn alloc(&mut self) -> Option<u32> {
+        if self.next_spi < self.end_spi {
+            self.next_spi += 1;
+            Some(self.next_spi - 1)
+        } else {
+            None
+        }
+    }
+
+    fn doorbell(&self) -> u64 {
+        (self.base + V2M_MSI_SETSPI_NS) as u64
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Bar {
+    /// CPU physical address the BAR is mapped at
+    pub address: usize,
+    pub size: u64,
+    pub io: bool,
+}
+
+/// How a function's interrupts reach the GIC. Each carries the GIC interrupt id.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Interrupt {
+    None,
+    IntX(u32),
+    Msi(u32),
+    /// MSI-X vector `i` signals interrupt `first + i`, and any vectors past
+    /// `count` signal the last one
+    MsiX {
+        first: u32,
+        count: u32,
+    },
+}
+
+impl Interrupt {
+    pub fn irq(&self) -> Option<u32> {
+        match *self {
+            Interrupt::None => None,
+            Interrupt::IntX(irq) | Interrupt::Msi(irq) | Interrupt::MsiX { first: irq, .. } => {
+                Some(irq)
+            }
+        }
+    }
+}
+
+/// A configured PCI function
+pub struct Device {
+    config: usize,
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub bars: [Option<Bar>; 6],
+    pub interrupt: Interrupt,
+}
+
+unsafe impl Send for Device {}
+unsafe impl Sync for Device {}
+
+impl Device {
+    pub fn read8(&self, offset: usize) -> u8 {
+        unsafe { read_volatile((self.config + offset) as *const u8) }
+    }
+
+    pub fn read16(&self, offset: usize) -> u16 {
+        u16::from_le(unsafe { read_volatile((self.config + offset) as *const u16) })
+    }
+
+    pub fn read32(&self, offset: usize) -> u32 {
+        u32::from_le(unsafe { read_volatile((self.config + offset) as *const u32) })
+    }
+
+    pub fn write16(&mut self, offset: usize, value: u16) {
+        unsafe { write_volatile((self.config + offset) as *mut u16, value.to_le()) }
+    }
+
+    pub fn write32(&mut self, offset: usize, value: u32) {
+        unsafe { write_volatile((self.config + offset) as *mut u32, value.to_le()) }
+    }
+
+    pub fn subsystem_id(&self) -> u16 {
+        self.read16(PCI_SUBSYSTEM_ID)
+    }
+
+    /// Iterates over the capability list as `(id, offset)` pairs
+    pub fn capabilities(&self) -> impl Iterator<Item = (u8, usize)> + '_ {
+        let mut next = if self.read16(PCI_STATUS) & PCI_STATUS_CAP_LIST != 0 {
+            self.read8(PCI_CAPABILITY_LIST) as usize & !3
+        } else {
+            0
+        };
+        core::iter::from_fn(move || {
+            if next == 0 {
+                return None;
+            }
+            let cap = next;
+            next = self.read8(cap + 1) as usize & !3;
+            Some((self.read8(cap), cap))
+        })
+    }
+
+    pub fn capability(&self, id: u8) -> Option<usize> {
+        self.capabilities()
+            .find(|(cap_id, _)| *cap_id == id)
+            .map(|(_, offset)| offset)
+    }
+}
+
+/// A generic ECAM host bridge (`pci-host-ecam-generic`), as found on QEMU's
+/// `virt` machine
+pub struct HostBridge {
+    ecam: usize,
+    bus_start: u8,
+    bus_end: u8,
+    windows: Vec<Window>,
+    interrupt_map_mask: [u32; 2],
+    interrupt_map: Vec<InterruptMapEntry>,
+    msi: Option<GicV2m>,
+}
+
+fn cell(bytes: &[u8], index: usize) -> u32 {
+    let mut c = [0; 4];
+    c.copy_from_slice(&bytes[index * 4..index * 4 + 4]);
+    u32::from_be_bytes(c)
+}
+
+fn cell_count(node: &Node, name: &str, default: usize) -> usize {
+    node.prop_by_name(name)
+        .map(|prop| cell(prop.value, 0) as usize)
+        .unwrap_or(default)
+}
+
+impl HostBridge {
+    /// Parses a `pci-host-ecam-generic` node. `address_cells`/`size_cells` are
+    /// those of its parent (the root node).
+    pub fn from_node(
+        root: &Node,
+        node: &Node,
+        address_cells: usize,
+        size_cells: usize,
+    ) -> Option<HostBridge> {
+        let reg = node.prop_by_name("reg")?;
+        let (ecam, rest) = regs_to_usize(reg.value, address_cells);
+        let (ecam_size, _) = regs_to_usize(rest, size_cells);
+
+        let (bus_start, bus_end) = node
+            .prop_by_name("bus-range")
+            .map(|range| (cell(range.value, 0) as u8, cell(range.value, 1) as u8))
+            .unwrap_or((0, ((ecam_size >> 20).max(1) - 1).min(255) as u8));
+
+        let child_address_cells = cell_count(node, "#address-cells", 3);
+        let child_size_cells = cell_count(node, "#size-cells", 2);
+        let child_interrupt_cells = cell_count(node, "#interrupt-cells", 1);
+
+        let mut windows = Vec::new();
+        if let Some(ranges) = node.prop_by_name("ranges") {
+            let entry_len = (child_address_cells + address_cells + child_size_cells) * 4;
+            for entry in ranges.value.chunks_exact(entry_len) {
+                let space = match (cell(entry, 0) >> 24) & 0x3 {
+                    0 => Space::Config,
+                    1 => Space::Io,
+                    2 => Space::Mem32,
+                    _ => Space::Mem64,
+                };
+                let (pci_addr, rest) = regs_to_usize(&entry[4..], child_address_cells - 1);
+                let (cpu_addr, rest) = regs_to_usize(rest, address_cells);
+                let (size, _) = regs_to_usize(rest, child_size_cells);
+                windows.push(Window {
+                    space,
+                    pci_addr: pci_addr as u64,
+                    cpu_addr: cpu_addr as u64,
+                    size: size as u64,
+                    // Keep PCI address 0 unassigned, it reads as "BAR disabled"
+                    next: (pci_addr as u64).max(0x1000),
+                });
+            }
+        }
+
+        let mut interrupt_map_mask = [!0, !0];
+        if let Some(mask) = node.prop_by_name("interrupt-map-mask") {
+            interrupt_map_mask = [cell(mask.value, 0), cell(mask.value, child_address_cells)];
+        }
+
+        let mut interrupt_map = Vec::new();
+        if let Some(map) = node.prop_by_name("interrupt-map") {
+            let mut i = 0;
+            let cells = map.value.len() / 4;
+            while i < cells {
+                let child = [cell(map.value, i), cell(map.value, i + child_address_cells)];
+                i += child_address_cells + child_interrupt_cells;
+                let parent = root.descendant_by_phandle(cell(map.value, i))?;
+                i += 1;
+                i += cell_count(&parent, "#address-cells", 0);
+                let parent_interrupt_cells = cell_count(&parent, "#interrupt-cells", 3);
+                let irq = crate::interrupt_id(
+                    cell(map.value, i) as usize,
+                    cell(map.value, i + 1) as usize,
+                );
+                i += parent_interrupt_cells;
+                interrupt_map.push(InterruptMapEntry { child, irq });
+            }
+        }
+
+        let msi = node
+            .prop_by_name("msi-parent")
+            .and_then(|parent| root.descendant_by_phandle(cell(parent.value, 0)))
+            .filter(|parent| {
+                parent
+                    .prop_by_name("compatible")
+                    .map(|compatible| compatible.value == b"arm,gic-v2m-frame\0")
+                    .unwrap_or(false)
+            })
+            .and_then(|frame| {
+                frame.prop_by_name("reg").map(|reg| {
+                    let (base, _) = regs_to_usize(
+                        reg.value,
+                        cell_count(&frame, "#address-cells", address_cells),
+                    );
+                    unsafe { GicV2m::new(base) }
+                })
+            });
+
+        Some(HostBridge {
+            ecam,
+            bus_start,
+            bus_end,
+            windows,
+            interrupt_map_mask,
+            interrupt_map,
+            msi,
+        })
+    }
+
+    /// Scans every bus behind the bridge and configures the functions found:
+    /// BARs are assigned and enabled, and the function's interrupt is routed
+    /// through MSI-X or MSI when the bridge has an MSI controller, legacy INTx
+    /// otherwise.
+    pub fn enumerate(&mut self) -> Vec<Device> {
+        let mut devices = Vec::new();
+        for bus in self.bus_start..=self.bus_end {
+            for device in 0..32u8 {
+                for function in 0..8u8 {
+                    let config = self.ecam
+                        + ((bus as usize) << 20
+                            | (device as usize) << 15
+                            | (function as usize) << 12);
+                    let mut dev = Device {
+                        config,
+                        bus,
+                        device,
+                        function,
+                        vendor_id: 0,
+                        device_id: 0,
+                        bars: [None; 6],
+                        interrupt: Interrupt::None,
+                    };
+                    dev.vendor_id = dev.read16(PCI_VENDOR_ID);
+                    if dev.vendor_id == 0xffff {
+                        if function == 0 {
+                            break;
+                        }
+                        continue;
+                    }
+                    dev.device_id = dev.read16(PCI_DEVICE_ID);
+                    let header_type = dev.read8(PCI_HEADER_TYPE);
+                    // Bridges (header type 1) aren't supported
+                    if header_type & !PCI_HEADER_TYPE_MULTI_FUNCTION == 0 {
+                        self.configure(&mut dev);
+                        devices.push(dev);
+                    }
+                    if function == 0 && header_type & PCI_HEADER_TYPE_MULTI_FUNCTION == 0 {
+                        break;
+                    }
+                }
+            }
+        }
+        devices
+    }
+
+    fn configure(&mut self, dev: &mut Device) {
+        let command = dev.read16(PCI_COMMAND);
+        dev.write16(
+            PCI_COMMAND,
+            command & !(PCI_COMMAND_IO | PCI_COMMAND_MEMORY | PCI_COMMAND_MASTER),
+        );
+
+        let mut i = 0;
+        while i < 6 {
+            let offset = PCI_BAR0 + i * 4;
+            let orig = dev.read32(offset);
+            dev.write32(offset, !0);
+            let probe = dev.read32(offset);
+            if probe == 0 {
+                dev.write32(offset, orig);
+                i += 1;
+                continue;
+            }
+
+            let io = orig & 1 != 0;
+            let is64 = !io && (orig >> 1) & 0x3 == 2;
+            let size = if io {
+                (!(probe & !0x3) & 0xffff) as u64 + 1
+            } else if is64 {
+                dev.write32(offset + 4, !0);
+                let high = dev.read32(offset + 4) as u64;
+                !(high << 32 | (probe & !0xf) as u64) + 1
+            } else {
+                (!(probe & !0xf)) as u64 + 1
+            };
+
+            let spaces: &[Space] = if io {
+                &[Space::Io]
+            } else if is64 {
+                &[Space::Mem32, Space::Mem64]
+            } else {
+                &[Space::Mem32]
+            };
+            let assigned = spaces.iter().find_map(|space| {
+                self.windows
+                    .iter_mut()
+                    .filter(|window| window.space == *space)
+                    .find_map(|window| {
+                        window.alloc(size).map(|pci_addr| {
+                            (pci_addr, window.cpu_addr + (pci_addr - window.pci_addr))
+                        })
+                    })
+            });
+
+            match assigned {
+                Some((pci_addr, cpu_addr)) => {
+                    dev.write32(offset, pci_addr as u32);
+                    if is64 {
+                        dev.write32(offset + 4, (pci_addr >> 32) as u32);
+                    }
+                    dev.bars[i] = Some(Bar {
+                        address: cpu_addr as usize,
+                        size,
+                        io,
+                    });
+                }
+                None => {
+                    dev.write32(offset, 0);
+                    if is64 {
+                        dev.write32(offset + 4, 0);
+                    }
+                }
+            }
+            i += if is64 { 2 } else { 1 };
+        }
+
+        // The MSI-X table lives in a BAR, so memory decode has to be on while
+        // it's programmed. Bus mastering stays off until the function is
+        // fully set up.
+        dev.write16(
+            PCI_COMMAND,
+            (command | PCI_COMMAND_IO | PCI_COMMAND_MEMORY) & !PCI_COMMAND_MASTER,
+        );
+        dev.interrupt = self.route_interrupt(dev);
+
+        let mut command = command | PCI_COMMAND_IO | PCI_COMMAND_MEMORY | PCI_COMMAND_MASTER;
+        if let Interrupt::IntX(_) = dev.interrupt {
+            command &= !PCI_COMMAND_INTX_DISABLE;
+        } else {
+            command |= PCI_COMMAND_INTX_DISABLE;
+        }
+        dev.write16(PCI_COMMAND, command);
+    }
+
+    fn route_interrupt(&mut self, dev: &mut Device) -> Interrupt {
+        if let Some(msi) = self.msi.as_mut() {
+            if let Some(cap) = dev.capability(PCI_CAP_ID_MSIX) {
+                let table = dev.read32(cap + 4);
+                // Checking the table's BAR first means no SPIs are taken for
+                // a function whose table couldn't be mapped
+                let bar = dev.bars.get((table & 0x7) as usize).copied().flatten();
+                if let Some((bar, first)) = bar.and_then(|bar| Some((bar, msi.alloc()?))) {
+                    let control = dev.read16(cap + 2);
+                    let table_size = (control & 0x7ff) as usize + 1;
+                    // SPIs come out of the frame consecutively
+                    let mut count = 1;
+                    let wanted = table_size.min(MAX_MSIX_VECTORS) as u32;
+                    while count < wanted && msi.alloc().is_some() {
+                        count += 1;
+                    }
+                    let table = bar.address + (table & !0x7) as usize;
+                    for entry in 0..table_size {
+                        let spi = first + (entry as u32).min(count - 1);
+                        let entry = (table + entry * 16) as *mut u32;
+                        unsafe {
+                            write_volatile(entry, (msi.doorbell() as u32).to_le());
+                            write_volatile(entry.add(1), ((msi.doorbell() >> 32) as u32).to_le());
+                            write_volatile(entry.add(2), spi.to_le());
+                            write_volatile(entry.add(3), 0);
+                        }
+                    }
+                    dev.write16(
+                        cap + 2,
+                        (control | PCI_MSIX_FLAGS_ENABLE) & !PCI_MSIX_FLAGS_MASKALL,
+                    );
+                    for spi in first..first + count {
+                        crate::gic::set_config(spi, crate::gic::ICFGR_EDGE);
+                    }
+                    return Interrupt::MsiX { first, count };
+                }
+            } else if let Some(cap) = dev.capability(PCI_CAP_ID_MSI) {
+                if let Some(spi) = msi.alloc() {
+                    let control = dev.read16(cap + 2);
+                    dev.write32(cap + 4, msi.doorbell() as u32);
+                    let data = if control & PCI_MSI_FLAGS_64BIT != 0 {
+                        dev.write32(cap + 8, (msi.doorbell() >> 32) as u32);
+                        cap + 12
+                    } else {
+                        cap + 8
+                    };
+                    dev.write16(data, spi as u16);
+                    // Single message: Multiple Message Enable stays 0
+                    dev.write16(cap + 2, (control & !(0x7 << 4)) | PCI_MSI_FLAGS_ENABLE);
+                    crate::gic::set_config(spi, crate::gic::ICFGR_EDGE);
+                    return Interrupt::Msi(spi);
+                }
+            }
+        }
+
+        let pin = dev.read8(PCI_INTERRUPT_PIN);
+        if pin == 0 {
+            return Interrupt::None;
+        }
+        let child = [
+            ((dev.bus as u32) << 16 | (dev.device as u32) << 11 | (dev.function as u32) << 8)
+                & self.interrupt_map_mask[0],
+            pin as u32 & self.interrupt_map_mask[1],
+        ];
+        self.interrupt_map
+            .iter()
+            .find(|entry| entry.child == child)
+            .map(|entry| Interrupt::IntX(entry.irq))
+            .unwrap_or(Interrupt::None)
+    }
+}
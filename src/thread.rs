@@ -19,30 +19,43 @@ extern "C" fn thread_start(mut conf: Box<Thread<Box<dyn FnMut()>>>) {
     (conf.userdata)()
 }
 
+/// Cores `USED_CPUS` can track
+pub const MAX_CORES: usize = 16;
+
+/// A bit per core, set while it's busy. Core 0 runs `kernel_main` and cores
+/// past those `init` was told about don't exist, so those are always set.
 static USED_CPUS: AtomicU16 = AtomicU16::new(!0b110);
 
-pub fn spawn<F: 'static + FnMut()>(mut f: F) {
-    // Wait until there is a free CPU in the bit map
+/// Every core is busy running a thread already
+#[derive(Debug)]
+pub struct NoFreeCore;
+
+/// Makes the first `cores` cores, apart from core 0, available to `spawn`
+pub fn init(cores: usize) {
+    let cores = cores.min(MAX_CORES);
+    let free = (((1u32 << cores) - 1) & !1) as u16;
+    USED_CPUS.store(!free, Ordering::SeqCst);
+}
+
+/// Runs `f` on a free core, which is given back when `f` returns. Threads
+/// don't wait for each other, so there must be a core free already.
+pub fn spawn<F: 'static + FnMut()>(mut f: F) -> Result<(), NoFreeCore> {
     let mut used_cpus = USED_CPUS.load(Ordering::Relaxed);
-    let mut next_cpu;
-    loop {
-        loop {
-            if used_cpus != !0 {
-                break;
-            }
-            used_cpus = USED_CPUS.load(Ordering::Relaxed);
+    let next_cpu = loop {
+        if used_cpus == !0 {
+            return Err(NoFreeCore);
         }
-        next_cpu = used_cpus.trailing_ones() as usize;
-        let new_used_cpus = used_cpus | (used_cpus << next_cpu);
-        if let Err(uc) =
-            USED_CPUS.compare_exchange(used_cpus, new_used_cpus, Ordering::SeqCst, Ordering::SeqCst)
-        {
-            used_cpus = uc;
-        } else {
-            used_cpus = new_used_cpus;
-            break;
+        let next_cpu = used_cpus.trailing_ones() as usize;
+        match USED_CPUS.compare_exchange(
+            used_cpus,
+            used_cpus | (1 << next_cpu),
+            Ordering::SeqCst,
+            Ordering::SeqCst,
+        ) {
+            Ok(_) => break next_cpu,
+            Err(uc) => used_cpus = uc,
         }
-    }
+    };
 
     let conf = Box::into_raw(Box::new(Thread {
         main: thread_start,
@@ -50,23 +63,12 @@ pub fn spawn<F: 'static + FnMut()>(mut f: F) {
         userdata: Box::new(move || {
             gic::init();
             f();
-            loop {
-                let new_used_cpus = used_cpus & !(used_cpus << next_cpu);
-                if let Err(uc) = USED_CPUS.compare_exchange(
-                    used_cpus,
-                    new_used_cpus,
-                    Ordering::SeqCst,
-                    Ordering::SeqCst,
-                ) {
-                    used_cpus = uc;
-                } else {
-                    break;
-                }
-            }
+            USED_CPUS.fetch_and(!(1 << next_cpu), Ordering::SeqCst);
             unsafe { cpu_off(next_cpu) };
         }),
     }));
     unsafe {
         cpu_on(next_cpu, conf as *mut _);
     }
+    Ok(())
 }
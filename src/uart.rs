@@ -4,6 +4,7 @@ use core::ptr;
 use core::str;
 
 use crate::gic::GIC;
+use crate::utils::{counter, counter_frequency};
 
 pub struct UART(*mut u32, GIC);
 unsafe impl Send for UART {}
@@ -52,6 +53,22 @@ impl UART {
         }
     }
 
+    /// Reads a byte if one arrives within `timeout_ms` milliseconds. This
+    /// polls, since there's no timer interrupt to wake a `wfi` at the
+    /// deadline.
+    pub fn read_byte_timeout(&mut self, timeout_ms: u64) -> Option<u8> {
+        let deadline = counter() + counter_frequency() * timeout_ms / 1000;
+        unsafe {
+            while ptr::read_volatile(self.0.offset(0x18 / 4)) & (1 << 4) != 0 {
+                if counter() >= deadline {
+                    return None;
+                }
+                core::hint::spin_loop();
+            }
+            Some(ptr::read_volatile(self.0) as u8)
+        }
+    }
+
     pub fn read_line<'a>(&mut self, buf: &'a mut [u8], echo: bool) -> &'a [u8] {
         let mut max_len = buf.len();
         let mut count = 0;
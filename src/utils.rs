@@ -116,12 +116,14 @@ impl<T: HasEndianness + fmt::LowerHex, D: Endianness> fmt::LowerHex for Endian<T
 }
 
 /// Memory barrier
+#[cfg(not(test))]
 pub fn mb() {
     unsafe {
         asm!("dsb 0");
     }
 }
 
+#[cfg(not(test))]
 pub fn current_core() -> usize {
     let core: usize;
     unsafe {
@@ -131,3 +133,116 @@ pub fn current_core() -> usize {
     }
     core
 }
+
+/// Current value of the virtual counter
+#[cfg(not(test))]
+pub fn counter() -> u64 {
+    let count: u64;
+    unsafe {
+        asm!("isb
+              mrs {0}, CNTVCT_EL0", out(reg) count);
+    }
+    count
+}
+
+/// Ticks per second of `counter`
+#[cfg(not(test))]
+pub fn counter_frequency() -> u64 {
+    let frequency: u64;
+    unsafe {
+        asm!("mrs {0}, CNTFRQ_EL0", out(reg) frequency);
+    }
+    frequency
+}
+
+// Host-side tests run as a single core, counting time in nanoseconds.
+#[cfg(test)]
+pub fn mb() {
+    core::sync::atomic::fence(core::sync::atomic::Ordering::SeqCst);
+}
+
+#[cfg(test)]
+pub fn current_core() -> usize {
+    0
+}
+
+#[cfg(test)]
+pub fn counter() -> u64 {
+    static START: std::sync::OnceLock<std::time::Instant> = std::sync::OnceLock::new();
+    START
+        .get_or_init(std::time::Instant::now)
+        .elapsed()
+        .as_nanos() as u64
+}
+
+#[cfg(test)]
+pub fn counter_frequency() -> u64 {
+    1_000_000_000
+}
+
+fn noop_raw_waker() -> core::task::RawWaker {
+    fn clone(_: *const ()) -> core::task::RawWaker {
+        noop_raw_waker()
+    }
+    fn noop(_: *const ()) {}
+    static VTABLE: core::task::RawWakerVTable =
+        core::task::RawWakerVTable::new(clone, noop, noop, noop);
+    core::task::RawWaker::new(core::ptr::null(), &VTABLE)
+}
+
+/// Runs `future` to completion on the current core, calling `idle` whenever it
+/// isn't ready. Nothing drives interrupts for us, so `idle` is where whatever
+/// the future waits on should be polled.
+pub fn block_on<F: core::future::Future>(future: F, mut idle: impl FnMut()) -> F::Output {
+    let waker = unsafe { core::task::Waker::from_raw(noop_raw_waker()) };
+    let mut context = core::task::Context::from_waker(&waker);
+    let mut future = core::pin::pin!(future);
+    loop {
+        if let core::task::Poll::Ready(output) = future.as_mut().poll(&mut context) {
+            return output;
+        }
+        idle();
+    }
+}
+
+const fn crc32_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                crc >> 1 ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+static CRC32_TABLE: [u32; 256] = crc32_table();
+
+/// The CRC-32 used by zlib, Ethernet and GPT
+pub fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0, |crc: u32, b| {
+        CRC32_TABLE[((crc ^ *b as u32) & 0xff) as usize] ^ crc >> 8
+    })
+}
+
+/// The 16-bit one's complement sum of `data`, as big-endian words, added to
+/// `initial`. The Internet checksum (RFC 1071) is its complement.
+pub fn ones_complement_sum(initial: u32, data: &[u8]) -> u16 {
+    let mut sum = initial as u64;
+    for word in data.chunks(2) {
+        sum += (word[0] as u64) << 8 | word.get(1).copied().unwrap_or(0) as u64;
+    }
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    sum as u16
+}
@@ -1,12 +1,21 @@
+use alloc::boxed::Box;
+use core::ptr::{read_volatile, write_volatile};
+
 use crate::utils::*;
 
 mod blk;
 mod entropy;
+#[cfg(test)]
+mod mock;
 mod net;
+mod packed;
+mod pci;
 
-pub use blk::VirtIOBlk;
+pub use blk::{BlkError, BlkFuture, VirtIOBlk, VirtIOBlkConfig, SECTOR_SIZE};
 pub use entropy::VirtIOEntropy;
-pub use net::VirtIONet;
+pub use net::{GsoType, NetError, NetStats, Offload, RxInfo, Segmentation, VirtIONet};
+pub use packed::PackedQueue;
+pub use pci::VirtIOPci;
 
 #[derive(Debug)]
 pub enum Status {
@@ -31,7 +40,7 @@ impl Into<Endian<u32, Little>> for Status {
     }
 }
 
-#[derive(PartialEq, Eq, Debug)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum DeviceId {
     Invalid = 0,
     Net = 1,
@@ -45,6 +54,12 @@ pub enum DeviceId {
     NinePTransport = 9,
 }
 
+/// Device-independent feature bits
+pub const VIRTIO_F_INDIRECT_DESC: u64 = 1 << 28;
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+pub const VIRTIO_F_RING_PACKED: u64 = 1 << 34;
+
+type LEU16 = Endian<u16, Little>;
 type LEU32 = Endian<u32, Little>;
 type LEU64 = Endian<u64, Little>;
 
@@ -59,11 +74,16 @@ pub struct VirtIORegs<C = LEU64> {
     _reserved0: [u32; 2],
     pub driver_features: LEU32,
     pub driver_features_sel: LEU32,
-    _reserved1: [u32; 2],
+    /// Legacy (version 1) only
+    pub guest_page_size: LEU32,
+    _reserved1: u32,
     pub queue_sel: LEU32,
     pub queue_num_max: LEU32,
     pub queue_num: LEU32,
-    _reserved2: [u32; 2],
+    /// Legacy (version 1) only
+    pub queue_align: LEU32,
+    /// Legacy (version 1) only
+    pub queue_pfn: LEU32,
     pub queue_ready: LEU32,
     _reserved3: [u32; 2],
     pub queue_notify: LEU32,
@@ -88,13 +108,25 @@ pub struct VirtIORegs<C = LEU64> {
 
 const MAGIC: u32 = 0x74726976;
 
+/// Page size legacy devices use to interpret `queue_pfn`
+const LEGACY_PAGE_SIZE: u32 = 4096;
+/// Alignment of the used ring within a legacy queue. The used ring is only
+/// 4-byte aligned in `SplitQueue`, so it must directly follow the available ring.
+const LEGACY_QUEUE_ALIGN: u32 = 4;
+
+const VIRTQ_DESC_F_NEXT: u16 = 1;
+const VIRTQ_DESC_F_WRITE: u16 = 2;
+const VIRTQ_DESC_F_INDIRECT: u16 = 4;
+
+const VIRTQ_USED_F_NO_NOTIFY: u16 = 1;
+
 #[derive(Copy, Clone)]
 #[repr(C, align(16))]
 pub struct VirtQDesc {
     addr: LEU64,
     len: LEU32,
-    flags: Endian<u16, Little>,
-    next: Endian<u16, Little>,
+    flags: LEU16,
+    next: LEU16,
 }
 
 impl VirtQDesc {
@@ -110,29 +142,29 @@ impl VirtQDesc {
 
 #[derive(Copy, Clone)]
 #[repr(C, align(2))]
-pub struct VirtqAvailable {
+pub struct VirtqAvailable<const S: usize> {
     flags: Endian<u16, Little>,
     idx: Endian<u16, Little>,
-    ring: [Endian<u16, Little>; 128],
+    ring: [Endian<u16, Little>; S],
     used_event: Endian<u16, Little>,
 }
 
-impl VirtqAvailable {
-    pub const fn empty() -> VirtqAvailable {
+impl<const S: usize> VirtqAvailable<S> {
+    pub const fn empty() -> Self {
         VirtqAvailable {
             flags: Endian::from_raw(0),
             idx: Endian::from_raw(0),
-            ring: [Endian::from_raw(0); 128],
+            ring: [Endian::from_raw(0); S],
             used_event: Endian::from_raw(0),
         }
     }
 }
 
 #[derive(Copy, Clone, Default)]
-#[repr(C, packed)]
+#[repr(C)]
 struct VirtQUsedElement {
-    id: Endian<u16, Little>,
-    len: Endian<u16, Little>,
+    id: LEU32,
+    len: LEU32,
 }
 
 impl VirtQUsedElement {
@@ -146,55 +178,353 @@ impl VirtQUsedElement {
 
 #[derive(Copy, Clone)]
 #[repr(C, align(4))]
-pub struct VirtQUsed {
+pub struct VirtQUsed<const S: usize> {
     flags: Endian<u16, Little>,
     idx: Endian<u16, Little>,
-    ring: [VirtQUsedElement; 128],
+    ring: [VirtQUsedElement; S],
     avail_event: Endian<u16, Little>,
 }
 
-impl VirtQUsed {
-    pub const fn empty() -> VirtQUsed {
+impl<const S: usize> VirtQUsed<S> {
+    pub const fn empty() -> Self {
         VirtQUsed {
             flags: Endian::from_raw(0),
             idx: Endian::from_raw(0),
-            ring: [VirtQUsedElement::empty(); 128],
+            ring: [VirtQUsedElement::empty(); S],
             avail_event: Endian::from_raw(0),
         }
     }
 }
 
-pub struct Queue<const S: usize> {
+/// One buffer in a request's descriptor chain
+#[derive(Copy, Clone)]
+pub struct Segment {
+    addr: u64,
+    len: u32,
+    write: bool,
+}
+
+impl Segment {
+    /// A buffer the device only reads from
+    pub fn readable<T: ?Sized>(data: &T) -> Segment {
+        Segment {
+            addr: data as *const T as *const u8 as u64,
+            len: core::mem::size_of_val(data) as u32,
+            write: false,
+        }
+    }
+
+    /// A buffer the device writes into
+    pub fn writable<T: ?Sized>(data: &mut T) -> Segment {
+        Segment {
+            addr: data as *mut T as *mut u8 as u64,
+            len: core::mem::size_of_val(data) as u32,
+            write: true,
+        }
+    }
+
+    fn flags(&self, has_next: bool) -> u16 {
+        let mut flags = 0;
+        if self.write {
+            flags |= VIRTQ_DESC_F_WRITE;
+        }
+        if has_next {
+            flags |= VIRTQ_DESC_F_NEXT;
+        }
+        flags
+    }
+
+    fn descriptor(&self, next: Option<u16>) -> VirtQDesc {
+        VirtQDesc {
+            addr: self.addr.into(),
+            len: self.len.into(),
+            flags: self.flags(next.is_some()).into(),
+            next: next.unwrap_or(0).into(),
+        }
+    }
+}
+
+/// A split virtqueue: descriptor table, available ring and used ring.
+///
+/// The three parts are laid out back to back from a page boundary, which is
+/// the contiguous layout legacy devices expect with a queue alignment of
+/// `LEGACY_QUEUE_ALIGN`.
+#[repr(C, align(4096))]
+pub struct SplitQueue<const S: usize> {
     pub descriptors: [VirtQDesc; S],
-    pub available: VirtqAvailable,
-    pub used: VirtQUsed,
+    pub available: VirtqAvailable<S>,
+    pub used: VirtQUsed<S>,
+    free_head: u16,
+    num_free: u16,
+    last_used: u16,
+    use_indirect: bool,
+    /// Heap-allocated descriptor tables of in-flight indirect chains, indexed by head
+    indirect: [Option<Box<[VirtQDesc]>>; S],
+    /// Heads of the chains the device holds, so a completion naming any
+    /// other descriptor can be told apart
+    in_flight: [bool; S],
 }
 
-impl<const S: usize> Queue<S> {
+impl<const S: usize> SplitQueue<S> {
     pub const fn new() -> Self {
-        Queue {
-            descriptors: [VirtQDesc::empty(); S],
+        const NO_TABLE: Option<Box<[VirtQDesc]>> = None;
+        let mut descriptors = [VirtQDesc::empty(); S];
+        let mut i = 0;
+        while i + 1 < S {
+            descriptors[i].next = Endian::from_raw((i as u16 + 1).to_le());
+            i += 1;
+        }
+        SplitQueue {
+            descriptors,
             available: VirtqAvailable::empty(),
             used: VirtQUsed::empty(),
+            free_head: 0,
+            num_free: S as u16,
+            last_used: 0,
+            use_indirect: false,
+            indirect: [NO_TABLE; S],
+            in_flight: [false; S],
         }
     }
-}
 
-impl<C> VirtIORegs<C> {
-    pub unsafe fn new<'a>(base: *mut Self) -> Option<&'a mut Self> {
-        let candidate = &mut *base;
-        if candidate.magic.native() == MAGIC
-            && candidate.version.native() == 2
-            && candidate.device_id.native() != DeviceId::Invalid as u32
-        {
-            Some(candidate)
+    pub fn set_indirect(&mut self, enabled: bool) {
+        self.use_indirect = enabled;
+    }
+
+    pub fn add(&mut self, segments: &[Segment]) -> Option<u16> {
+        let head = if self.use_indirect && segments.len() > 1 {
+            if self.num_free < 1 {
+                return None;
+            }
+            let table: Box<[VirtQDesc]> = segments
+                .iter()
+                .enumerate()
+                .map(|(i, segment)| {
+                    let next = if i + 1 < segments.len() {
+                        Some(i as u16 + 1)
+                    } else {
+                        None
+                    };
+                    segment.descriptor(next)
+                })
+                .collect();
+            let head = self.alloc_descriptor();
+            self.descriptors[head as usize] = VirtQDesc {
+                addr: (table.as_ptr() as u64).into(),
+                len: (core::mem::size_of_val(&*table) as u32).into(),
+                flags: VIRTQ_DESC_F_INDIRECT.into(),
+                next: 0.into(),
+            };
+            self.indirect[head as usize] = Some(table);
+            head
         } else {
-            None
+            if (self.num_free as usize) < segments.len() || segments.is_empty() {
+                return None;
+            }
+            let head = self.free_head;
+            for (i, segment) in segments.iter().enumerate() {
+                let desc = self.alloc_descriptor();
+                let next = if i + 1 < segments.len() {
+                    Some(self.free_head)
+                } else {
+                    None
+                };
+                self.descriptors[desc as usize] = segment.descriptor(next);
+            }
+            head
+        };
+
+        self.in_flight[head as usize] = true;
+        let idx = self.available.idx.native();
+        self.available.ring[idx as usize % self.available.ring.len()] = head.into();
+        crate::utils::mb();
+        unsafe {
+            core::ptr::write_volatile(&mut self.available.idx, idx.wrapping_add(1).into());
+        }
+        crate::utils::mb();
+        Some(head)
+    }
+
+    pub fn has_used(&self) -> bool {
+        unsafe { core::ptr::read_volatile(&self.used.idx).native() != self.last_used }
+    }
+
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        let (head, len) = loop {
+            if !self.has_used() {
+                return None;
+            }
+            crate::utils::mb();
+            let element = unsafe {
+                core::ptr::read_volatile(
+                    &self.used.ring[self.last_used as usize % self.used.ring.len()],
+                )
+            };
+            self.last_used = self.last_used.wrapping_add(1);
+            // A buggy device may name a descriptor that isn't the head of a
+            // chain it holds. There's nothing to complete, so drop it.
+            let head = element.id.native() as usize;
+            if head < S && self.in_flight[head] {
+                break (head as u16, element.len.native());
+            }
+        };
+
+        self.in_flight[head as usize] = false;
+        self.indirect[head as usize] = None;
+        let mut cur = head;
+        loop {
+            let desc = self.descriptors[cur as usize];
+            let next = desc.next.native();
+            self.free_descriptor(cur);
+            if desc.flags.native() & VIRTQ_DESC_F_NEXT == 0 || next as usize >= S {
+                break;
+            }
+            cur = next;
         }
+        Some((head, len))
+    }
+
+    pub fn should_notify(&self) -> bool {
+        unsafe { core::ptr::read_volatile(&self.used.flags).native() & VIRTQ_USED_F_NO_NOTIFY == 0 }
+    }
+
+    pub fn addresses(&self) -> (u64, u64, u64) {
+        (
+            self.descriptors.as_ptr() as u64,
+            &self.available as *const _ as u64,
+            &self.used as *const _ as u64,
+        )
+    }
+
+    fn alloc_descriptor(&mut self) -> u16 {
+        let desc = self.free_head;
+        self.free_head = self.descriptors[desc as usize].next.native();
+        self.num_free -= 1;
+        desc
+    }
+
+    fn free_descriptor(&mut self, desc: u16) {
+        self.descriptors[desc as usize] = VirtQDesc::empty();
+        self.descriptors[desc as usize].next = self.free_head.into();
+        self.free_head = desc;
+        self.num_free += 1;
     }
+}
+
+/// A virtqueue in whichever ring layout was negotiated with the device.
+/// Starts out as a split ring; `negotiated` switches to a packed ring when
+/// VIRTIO_F_RING_PACKED was accepted.
+pub enum Queue<const S: usize> {
+    Split(SplitQueue<S>),
+    Packed(PackedQueue<S>),
+}
+
+impl<const S: usize> Queue<S> {
+    const EMPTY_PACKED: Self = Queue::Packed(PackedQueue::EMPTY);
+
+    pub const fn new() -> Self {
+        Queue::Split(SplitQueue::new())
+    }
+
+    /// Adapts the queue to the features accepted during negotiation. Must be
+    /// called before the queue is handed to the device.
+    pub fn negotiated(&mut self, features: u64) {
+        if features & VIRTIO_F_RING_PACKED != 0 {
+            // Like `boxed`, copy the empty ring straight from a constant so
+            // it's never built on the caller's stack.
+            let empty: &'static Self = &Self::EMPTY_PACKED;
+            unsafe {
+                core::ptr::drop_in_place(self);
+                // `EMPTY_PACKED` holds no heap pointers, so a bitwise copy is
+                // an independent queue.
+                core::ptr::copy_nonoverlapping(empty, self, 1);
+            }
+        }
+        let indirect = features & VIRTIO_F_INDIRECT_DESC != 0;
+        match self {
+            Queue::Split(q) => q.set_indirect(indirect),
+            Queue::Packed(q) => q.set_indirect(indirect),
+        }
+    }
+
+    /// Number of descriptors in the ring
+    pub fn len(&self) -> usize {
+        S
+    }
+
+    /// Whether the ring has no descriptors at all
+    pub fn is_empty(&self) -> bool {
+        S == 0
+    }
+
+    /// Makes `segments` available to the device as a single request. Returns
+    /// a token identifying the request (the head descriptor for split rings,
+    /// the buffer ID for packed rings), or `None` if the ring is full.
+    ///
+    /// Multi-segment requests take a single ring slot pointing at an indirect
+    /// table when VIRTIO_F_INDIRECT_DESC was negotiated.
+    ///
+    /// The device isn't notified; the caller does that through the transport.
+    pub fn add(&mut self, segments: &[Segment]) -> Option<u16> {
+        match self {
+            Queue::Split(q) => q.add(segments),
+            Queue::Packed(q) => q.add(segments),
+        }
+    }
+
+    /// Whether the device has completed requests that haven't been popped yet
+    pub fn has_used(&self) -> bool {
+        match self {
+            Queue::Split(q) => q.has_used(),
+            Queue::Packed(q) => q.has_used(),
+        }
+    }
+
+    /// Takes the next request the device has completed, returning its token
+    /// and the number of bytes the device wrote into it.
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        match self {
+            Queue::Split(q) => q.pop_used(),
+            Queue::Packed(q) => q.pop_used(),
+        }
+    }
+
+    /// Whether the device asked to be notified of new requests
+    pub fn should_notify(&self) -> bool {
+        match self {
+            Queue::Split(q) => q.should_notify(),
+            Queue::Packed(q) => q.should_notify(),
+        }
+    }
+
+    /// Physical addresses of the descriptor area, driver area and device area
+    pub fn addresses(&self) -> (u64, u64, u64) {
+        match self {
+            Queue::Split(q) => q.addresses(),
+            Queue::Packed(q) => q.addresses(),
+        }
+    }
+}
 
-    pub fn device_id(&self) -> DeviceId {
-        match self.device_id.native() {
+impl Queue<128> {
+    /// Allocates an empty queue directly on the heap. Queues are several
+    /// pages, too large to build on a thread's stack and then move.
+    pub fn boxed() -> Box<Self> {
+        static EMPTY: Queue<128> = Queue::new();
+        unsafe {
+            let queue = alloc::alloc::alloc(core::alloc::Layout::new::<Self>()) as *mut Self;
+            assert!(!queue.is_null(), "out of memory allocating virtqueue");
+            // `EMPTY` holds no heap pointers, so a bitwise copy is an
+            // independent queue.
+            core::ptr::copy_nonoverlapping(&EMPTY, queue, 1);
+            Box::from_raw(queue)
+        }
+    }
+}
+
+impl From<u32> for DeviceId {
+    fn from(id: u32) -> DeviceId {
+        match id {
             1 => DeviceId::Net,
             2 => DeviceId::Blk,
             3 => DeviceId::Console,
@@ -208,3 +538,263 @@ impl<C> VirtIORegs<C> {
         }
     }
 }
+
+/// The register interface a device is reached through (virtio-mmio,
+/// virtio-pci). Drivers only talk to their device through this trait.
+pub trait Transport: Send + Sync {
+    fn device_id(&self) -> DeviceId;
+
+    fn device_features(&mut self) -> u64;
+
+    fn set_driver_features(&mut self, features: u64);
+
+    fn status(&self) -> u32;
+
+    fn set_status(&mut self, status: Status);
+
+    /// Largest queue size the device supports for queue `index`, 0 if the
+    /// queue doesn't exist
+    fn max_queue_size(&mut self, index: u16) -> u16;
+
+    /// Hands a queue of `size` descriptors to the device, given the addresses
+    /// of its descriptor, driver and device areas
+    fn setup_queue(&mut self, index: u16, size: u16, addresses: (u64, u64, u64));
+
+    fn notify(&mut self, index: u16);
+    /// GIC interrupt the device signals for queue `index`, when it has one of
+    /// its own rather than sharing the device's
+    fn queue_irq(&self, _index: u16) -> Option<u32> {
+        None
+    }
+
+    /// Reads and acknowledges the pending interrupt causes (bit 0: used
+    /// buffer, bit 1: configuration change)
+    fn ack_interrupt(&mut self) -> u32;
+
+    fn config_generation(&self) -> u32;
+
+    fn read_config(&self, offset: usize, data: &mut [u8]);
+
+    fn write_config(&mut self, offset: usize, data: &[u8]);
+}
+
+/// Reads the device-specific configuration space as a `T`.
+///
+/// Fields wider than 32 bits aren't read atomically, so the read is retried
+/// until the config generation is the same before and after it.
+pub fn read_config<T: Default>(transport: &dyn Transport) -> T {
+    let mut config = T::default();
+    let bytes = unsafe {
+        core::slice::from_raw_parts_mut(&mut config as *mut T as *mut u8, core::mem::size_of::<T>())
+    };
+    loop {
+        let generation = transport.config_generation();
+        transport.read_config(0, bytes);
+        if transport.config_generation() == generation {
+            break config;
+        }
+    }
+}
+
+impl<C> VirtIORegs<C> {
+    pub unsafe fn new<'a>(base: *mut Self) -> Option<&'a mut Self> {
+        let candidate = &mut *base;
+        if candidate.magic.native() == MAGIC
+            && (candidate.version.native() == 1 || candidate.version.native() == 2)
+            && candidate.device_id.native() != DeviceId::Invalid as u32
+        {
+            Some(candidate)
+        } else {
+            None
+        }
+    }
+
+    /// Whether this is a legacy (version 1) virtio-mmio device
+    pub fn is_legacy(&self) -> bool {
+        unsafe { read_volatile(&self.version).native() == 1 }
+    }
+}
+
+impl<C: Send + Sync> Transport for VirtIORegs<C> {
+    fn device_id(&self) -> DeviceId {
+        DeviceId::from(self.device_id.native())
+    }
+
+    fn device_features(&mut self) -> u64 {
+        unsafe {
+            write_volatile(&mut self.device_features_sel, 0.into());
+            let low = read_volatile(&self.device_features).native() as u64;
+            write_volatile(&mut self.device_features_sel, 1.into());
+            let high = read_volatile(&self.device_features).native() as u64;
+            if self.is_legacy() {
+                // Legacy devices only have 32 feature bits
+                low
+            } else {
+                high << 32 | low
+            }
+        }
+    }
+
+    fn set_driver_features(&mut self, features: u64) {
+        unsafe {
+            write_volatile(&mut self.driver_features_sel, 0.into());
+            write_volatile(&mut self.driver_features, (features as u32).into());
+            write_volatile(&mut self.driver_features_sel, 1.into());
+            write_volatile(&mut self.driver_features, ((features >> 32) as u32).into());
+        }
+    }
+
+    fn status(&self) -> u32 {
+        unsafe { read_volatile(&self.status).native() }
+    }
+
+    fn set_status(&mut self, status: Status) {
+        unsafe { write_volatile(&mut self.status, status.into()) }
+    }
+
+    fn max_queue_size(&mut self, index: u16) -> u16 {
+        unsafe {
+            write_volatile(&mut self.queue_sel, (index as u32).into());
+            read_volatile(&self.queue_num_max).native() as u16
+        }
+    }
+
+    fn setup_queue(&mut self, index: u16, size: u16, (desc, driver, device): (u64, u64, u64)) {
+        if self.is_legacy() {
+            // Legacy devices locate the rings themselves from the page number
+            // of the descriptor table.
+            let size = size as u64;
+            assert!(
+                desc % LEGACY_PAGE_SIZE as u64 == 0
+                    && driver == desc + 16 * size
+                    && device
+                        == (driver + 6 + 2 * size + LEGACY_QUEUE_ALIGN as u64 - 1)
+                            & !(LEGACY_QUEUE_ALIGN as u64 - 1),
+                "queue layout not usable by a legacy device"
+            );
+            unsafe {
+                write_volatile(&mut self.guest_page_size, LEGACY_PAGE_SIZE.into());
+                write_volatile(&mut self.queue_sel, (index as u32).into());
+                write_volatile(&mut self.queue_num, (size as u32).into());
+                write_volatile(&mut self.queue_align, LEGACY_QUEUE_ALIGN.into());
+                write_volatile(
+                    &mut self.queue_pfn,
+                    ((desc / LEGACY_PAGE_SIZE as u64) as u32).into(),
+                );
+            }
+            return;
+        }
+        unsafe {
+            write_volatile(&mut self.queue_sel, (index as u32).into());
+            write_volatile(&mut self.queue_num, (size as u32).into());
+            write_volatile(&mut self.queue_desc_low, (desc as u32).into());
+            write_volatile(&mut self.queue_desc_high, ((desc >> 32) as u32).into());
+            write_volatile(&mut self.queue_avail_low, (driver as u32).into());
+            write_volatile(&mut self.queue_avail_high, ((driver >> 32) as u32).into());
+            write_volatile(&mut self.queue_used_low, (device as u32).into());
+            write_volatile(&mut self.queue_used_high, ((device >> 32) as u32).into());
+            write_volatile(&mut self.queue_ready, 1.into());
+        }
+    }
+
+    fn notify(&mut self, index: u16) {
+        mb();
+        unsafe { write_volatile(&mut self.queue_notify, (index as u32).into()) }
+    }
+
+    fn ack_interrupt(&mut self) -> u32 {
+        unsafe {
+            let status = read_volatile(&self.interrupt_status);
+            if status.native() != 0 {
+                write_volatile(&mut self.interrupt_ack, status);
+            }
+            status.native()
+        }
+    }
+
+    fn config_generation(&self) -> u32 {
+        if self.is_legacy() {
+            0
+        } else {
+            unsafe { read_volatile(&self.config_generation).native() }
+        }
+    }
+
+    fn read_config(&self, offset: usize, data: &mut [u8]) {
+        let base = &self.config as *const C as *const u8;
+        for (i, b) in data.iter_mut().enumerate() {
+            *b = unsafe { read_volatile(base.add(offset + i)) };
+        }
+    }
+
+    fn write_config(&mut self, offset: usize, data: &[u8]) {
+        let base = &mut self.config as *mut C as *mut u8;
+        for (i, b) in data.iter().enumerate() {
+            unsafe { write_volatile(base.add(offset + i), *b) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::mock::{echo_handler, MockDevice};
+    use super::*;
+
+    #[test]
+    fn out_of_order_completion() {
+        let mut queue = Queue::boxed();
+        let mut device = MockDevice::new(DeviceId::Entropy, 0, echo_handler());
+        device.setup_queue(0, queue.len() as u16, queue.addresses());
+
+        let data = [[1u8; 4], [2u8; 4], [3u8; 4]];
+        let mut out = [[0u8; 4]; 3];
+        device.hold_completions(3);
+        let mut heads = Vec::new();
+        for (data, out) in data.iter().zip(out.iter_mut()) {
+            let head = queue.add(&[Segment::readable(data), Segment::writable(out)]);
+            heads.push(head.expect("ring full"));
+        }
+        device.notify(0);
+
+        for expected in heads.iter().rev() {
+            let (head, len) = queue.pop_used().expect("completion missing");
+            assert!(
+                head == *expected,
+                "completions not returned in device order"
+            );
+            assert!(len == 4, "wrong used length");
+        }
+        assert!(queue.pop_used().is_none(), "spurious completion");
+        assert!(out == data, "data not written back");
+
+        // Every descriptor must have been returned to the free list
+        let mut byte = 0u8;
+        for _ in 0..queue.len() {
+            queue
+                .add(&[Segment::writable(&mut byte)])
+                .expect("descriptors leaked");
+        }
+    }
+
+    #[test]
+    fn bogus_completions() {
+        let mut queue = SplitQueue::<4>::new();
+        let mut byte = 0u8;
+        let head = queue
+            .add(&[Segment::writable(&mut byte)])
+            .expect("ring full");
+        // A descriptor out of range, one the device doesn't hold, the real
+        // completion and then that again
+        let ids = [9, head as u32 + 1, head as u32, head as u32];
+        for (element, &id) in queue.used.ring.iter_mut().zip(ids.iter()) {
+            *element = VirtQUsedElement {
+                id: id.into(),
+                len: 1.into(),
+            };
+        }
+        queue.used.idx = 3.into();
+        assert!(queue.pop_used() == Some((head, 1)), "completion lost");
+        queue.used.idx = 4.into();
+        assert!(queue.pop_used().is_none(), "bogus completion returned");
+    }
+}
@@ -1,12 +1,155 @@
-use crate::utils::*;
-use core::ptr::{read_volatile, write_volatile};
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
 
-use super::{Queue, Status, VirtIORegs, VirtQDesc, LEU32, LEU64};
+use crate::block::BlockDevice;
+use crate::mutex::Mutex;
 
+use super::{
+    Queue, Segment, Status, Transport, LEU16, LEU32, LEU64, VIRTIO_F_INDIRECT_DESC,
+    VIRTIO_F_RING_PACKED,
+};
+
+mod requests;
+
+pub use requests::BlkFuture;
+use requests::{status_result, Slot};
+
+/// Size of the sectors `capacity` and request headers count in, whatever the
+/// device's block size
+pub const SECTOR_SIZE: usize = 512;
+
+const VIRTIO_BLK_F_SIZE_MAX: u64 = 1 << 1;
+const VIRTIO_BLK_F_SEG_MAX: u64 = 1 << 2;
+const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+const VIRTIO_BLK_F_BLK_SIZE: u64 = 1 << 6;
+const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+const VIRTIO_BLK_F_TOPOLOGY: u64 = 1 << 10;
+const VIRTIO_BLK_F_MQ: u64 = 1 << 12;
+const VIRTIO_BLK_F_DISCARD: u64 = 1 << 13;
+const VIRTIO_BLK_F_WRITE_ZEROES: u64 = 1 << 14;
+
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_T_FLUSH: u32 = 4;
+const VIRTIO_BLK_T_GET_ID: u32 = 8;
+const VIRTIO_BLK_T_DISCARD: u32 = 11;
+const VIRTIO_BLK_T_WRITE_ZEROES: u32 = 13;
+
+const VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP: u32 = 1;
+
+/// Length of the serial number GET_ID returns
+pub const VIRTIO_BLK_ID_BYTES: usize = 20;
+
+/// Cap on the segments in one DISCARD or WRITE_ZEROES request, whatever the
+/// device allows, to bound the table we allocate
+const MAX_RANGE_SEGMENTS: usize = 256;
+
+const VIRTIO_BLK_S_OK: u8 = 0;
+const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum BlkError {
+    /// The device reported VIRTIO_BLK_S_IOERR, or no valid status at all
+    IoError,
+    /// The device reported VIRTIO_BLK_S_UNSUPP
+    Unsupported,
+    /// The request reaches past the end of the disk
+    OutOfRange,
+    /// The device set DEVICE_NEEDS_RESET and won't process requests
+    NeedsReset,
+    /// The disk is read-only
+    ReadOnly,
+    /// The request needs more descriptors than the queue has
+    TooLarge,
+    /// The buffer isn't a whole number of blocks
+    Misaligned,
+    /// There is no device to send the request to
+    NoDevice,
+    /// The device didn't accept the negotiated features
+    FeaturesRejected,
+}
+
+impl BlkError {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BlkError::IoError => "I/O error",
+            BlkError::Unsupported => "unsupported operation",
+            BlkError::OutOfRange => "sector out of range",
+            BlkError::NeedsReset => "device needs reset",
+            BlkError::ReadOnly => "disk is read-only",
+            BlkError::TooLarge => "request too large",
+            BlkError::Misaligned => "not a multiple of the block size",
+            BlkError::NoDevice => "no block device",
+            BlkError::FeaturesRejected => "features rejected",
+        }
+    }
+}
+
+/// Driver for one virtqueue of a virtio-blk device. With VIRTIO_BLK_F_MQ
+/// there is one per core, so cores only contend on the transport when they
+/// notify the device.
+///
+/// Completion is polled. The kernel installs no exception vectors, so the
+/// device's interrupt is never taken: blocking calls poll `handle_interrupt`
+/// while they wait, and asynchronous requests only complete when their
+/// owner calls it.
 pub struct VirtIOBlk<'a> {
-    regs: &'a mut VirtIORegs,
+    regs: Arc<Mutex<&'a mut dyn Transport>>,
     queue: &'a mut Queue<128>,
+    /// Index of `queue` on the device
+    queue_index: u16,
     irq: crate::gic::GIC,
+    features: u64,
+    config: VirtIOBlkConfig,
+    /// What's waiting on each in-flight request, indexed by descriptor head
+    slots: Vec<Slot>,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct VirtIOBlkGeometry {
+    pub cylinders: LEU16,
+    pub heads: u8,
+    pub sectors: u8,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct VirtIOBlkTopology {
+    /// log2 of the number of logical blocks per physical block
+    pub physical_block_exp: u8,
+    /// Offset of the first aligned logical block
+    pub alignment_offset: u8,
+    /// Suggested minimum I/O size in blocks
+    pub min_io_size: LEU16,
+    /// Optimal (suggested maximum) I/O size in blocks
+    pub opt_io_size: LEU32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct VirtIOBlkConfig {
+    /// In 512-byte sectors
+    pub capacity: LEU64,
+    /// Maximum size of any single segment (SIZE_MAX)
+    pub size_max: LEU32,
+    /// Maximum number of segments in a request (SEG_MAX)
+    pub seg_max: LEU32,
+    pub geometry: VirtIOBlkGeometry,
+    /// Logical block size (BLK_SIZE)
+    pub blk_size: LEU32,
+    pub topology: VirtIOBlkTopology,
+    pub writeback: u8,
+    _unused0: u8,
+    pub num_queues: LEU16,
+    pub max_discard_sectors: LEU32,
+    pub max_discard_seg: LEU32,
+    pub discard_sector_alignment: LEU32,
+    pub max_write_zeroes_sectors: LEU32,
+    pub max_write_zeroes_seg: LEU32,
+    pub write_zeroes_may_unmap: u8,
+    _unused1: [u8; 3],
 }
 
 #[repr(C)]
@@ -16,193 +159,923 @@ pub struct BlkReqHdr {
     pub sector: LEU64,
 }
 
-const BLK_DEVICE_FEATURES: u32 = 0;
+/// One range of a DISCARD or WRITE_ZEROES request
+#[repr(C)]
+pub struct BlkDiscardWriteZeroes {
+    pub sector: LEU64,
+    pub num_sectors: LEU32,
+    pub flags: LEU32,
+}
+
+const BLK_DEVICE_FEATURES: u64 = VIRTIO_BLK_F_SIZE_MAX
+    | VIRTIO_BLK_F_SEG_MAX
+    | VIRTIO_BLK_F_RO
+    | VIRTIO_BLK_F_BLK_SIZE
+    | VIRTIO_BLK_F_FLUSH
+    | VIRTIO_BLK_F_TOPOLOGY
+    | VIRTIO_BLK_F_DISCARD
+    | VIRTIO_BLK_F_WRITE_ZEROES
+    | VIRTIO_F_INDIRECT_DESC
+    | VIRTIO_F_RING_PACKED;
 
 impl<'a> VirtIOBlk<'a> {
-    pub fn new(regs: &'a mut VirtIORegs, queue: &'a mut Queue<128>, irq: crate::gic::GIC) -> Self {
-        unsafe {
-            write_volatile(&mut regs.status, Status::Reset.into());
-            write_volatile(&mut regs.status, Status::Acknowledge.into());
-            write_volatile(&mut regs.status, Status::Driver.into());
-
-            write_volatile(&mut regs.device_features_sel, 0.into());
-            let device_features = read_volatile(&mut regs.device_features).native();
-            write_volatile(&mut regs.driver_features_sel, 0.into());
-            write_volatile(
-                &mut regs.driver_features,
-                (BLK_DEVICE_FEATURES & device_features).into(),
-            );
+    pub fn new(
+        regs: &'a mut dyn Transport,
+        queue: &'a mut Queue<128>,
+        irq: crate::gic::GIC,
+    ) -> Result<Self, BlkError> {
+        let mut queues = Self::new_multiqueue(regs, vec![queue], irq)?;
+        Ok(queues.remove(0))
+    }
 
-            write_volatile(&mut regs.status, Status::FeaturesOk.into());
-            if read_volatile(&mut regs.status).native() & (Status::FeaturesOk as u32) == 0 {
-                panic!("Coudln't set blk features");
-            }
+    /// Sets the device up with as many of `queues` as it supports, returning a
+    /// driver for each. Queue `i` is meant to be used from core `i`, which its
+    /// interrupt is routed to if the transport gives it one of its own (an
+    /// MSI-X vector). Otherwise the queues share `irq` and its routing is
+    /// left alone.
+    pub fn new_multiqueue(
+        regs: &'a mut dyn Transport,
+        mut queues: Vec<&'a mut Queue<128>>,
+        irq: crate::gic::GIC,
+    ) -> Result<Vec<Self>, BlkError> {
+        regs.set_status(Status::Reset);
+        regs.set_status(Status::Acknowledge);
+        regs.set_status(Status::Driver);
 
-            write_volatile(&mut regs.queue_sel, 0.into());
-            write_volatile(&mut regs.queue_num, (queue.descriptors.len() as u32).into());
-            write_volatile(
-                &mut regs.queue_desc_low,
-                ((queue.descriptors.as_ptr() as usize) as u32).into(),
-            );
-            write_volatile(
-                &mut regs.queue_desc_high,
-                ((queue.descriptors.as_ptr() as usize >> 32) as u32).into(),
-            );
-            write_volatile(
-                &mut regs.queue_avail_low,
-                (&queue.available as *const _ as u32).into(),
-            );
-            write_volatile(
-                &mut regs.queue_avail_high,
-                ((&queue.available as *const _ as usize >> 32) as u32).into(),
-            );
-            write_volatile(
-                &mut regs.queue_used_low,
-                (&queue.used as *const _ as u32).into(),
-            );
-            write_volatile(
-                &mut regs.queue_used_high,
-                ((&queue.used as *const _ as usize >> 32) as u32).into(),
-            );
-            write_volatile(&mut regs.queue_ready, 1.into());
+        let mut wanted = BLK_DEVICE_FEATURES;
+        if queues.len() > 1 {
+            wanted |= VIRTIO_BLK_F_MQ;
+        }
+        let driver_features = wanted & regs.device_features();
+        regs.set_driver_features(driver_features);
+
+        regs.set_status(Status::FeaturesOk);
+        if regs.status() & (Status::FeaturesOk as u32) == 0 {
+            regs.set_status(Status::Failed);
+            return Err(BlkError::FeaturesRejected);
+        }
+
+        let config: VirtIOBlkConfig = super::read_config(&*regs);
+        let num_queues = match driver_features & VIRTIO_BLK_F_MQ {
+            0 => 1,
+            _ => (config.num_queues.native() as usize).max(1),
+        };
+        queues.truncate(num_queues);
 
-            write_volatile(&mut regs.status, Status::DriverOk.into());
+        for (index, queue) in queues.iter_mut().enumerate() {
+            queue.negotiated(driver_features);
+            regs.setup_queue(index as u16, queue.len() as u16, queue.addresses());
         }
-        VirtIOBlk { regs, queue, irq }
+        let irqs: Vec<Option<u32>> = (0..queues.len() as u16)
+            .map(|index| regs.queue_irq(index))
+            .collect();
+
+        regs.set_status(Status::DriverOk);
+        let regs = Arc::new(Mutex::new(regs));
+        Ok(queues
+            .into_iter()
+            .zip(irqs)
+            .enumerate()
+            .map(|(index, (queue, queue_irq))| {
+                if let Some(queue_irq) = queue_irq {
+                    crate::gic::set_core(queue_irq, index as u32);
+                }
+                let irq = queue_irq.unwrap_or(irq.irq());
+                let slots = (0..queue.len()).map(|_| Slot::Free).collect();
+                VirtIOBlk {
+                    regs: regs.clone(),
+                    queue,
+                    queue_index: index as u16,
+                    irq: unsafe { crate::gic::GIC::new(irq) },
+                    features: driver_features,
+                    config,
+                    slots,
+                }
+            })
+            .collect())
     }
 }
 
 impl<'a> VirtIOBlk<'a> {
-    pub fn read(&mut self, sector: u64, data: &mut [u8; 512]) {
-        unsafe {
-            let mut status: u8 = 0;
-            let blkreq_hdr = BlkReqHdr {
-                req_type: 0.into(),
-                reserved: 0,
-                sector: sector.into(),
-            };
-
-            write_volatile(&mut status, 0);
-
-            write_volatile(
-                &mut self.queue.descriptors[0],
-                VirtQDesc {
-                    addr: (&blkreq_hdr as *const _ as u64).into(),
-                    len: (core::mem::size_of::<BlkReqHdr>() as u32).into(),
-                    flags: (1).into(),
-                    next: 1.into(),
-                },
-            );
+    pub fn config(&self) -> &VirtIOBlkConfig {
+        &self.config
+    }
 
-            write_volatile(
-                &mut self.queue.descriptors[1],
-                VirtQDesc {
-                    addr: (data.as_ptr() as *const _ as u64).into(),
-                    len: (512).into(),
-                    flags: (3).into(),
-                    next: 2.into(),
-                },
-            );
+    /// Number of 512-byte sectors on the disk
+    pub fn capacity(&self) -> u64 {
+        self.config.capacity.native()
+    }
 
-            write_volatile(
-                &mut self.queue.descriptors[2],
-                VirtQDesc {
-                    addr: (&status as *const _ as u64).into(),
-                    len: (1).into(),
-                    flags: 2.into(),
-                    next: 0.into(),
-                },
-            );
+    /// Logical block size in bytes. A size that isn't a power of two of at
+    /// least a sector is bogus, so sectors are used instead.
+    pub fn block_size(&self) -> u32 {
+        match self.features & VIRTIO_BLK_F_BLK_SIZE {
+            0 => SECTOR_SIZE as u32,
+            _ => match self.config.blk_size.native() {
+                size if size.is_power_of_two() && size >= SECTOR_SIZE as u32 => size,
+                _ => SECTOR_SIZE as u32,
+            },
+        }
+    }
 
-            write_volatile(
-                &mut self.queue.available.ring[self.queue.available.idx.native() as usize],
-                0.into(),
-            );
-            mb();
-            write_volatile(
-                &mut self.queue.available.idx,
-                (self.queue.available.idx.native() + 1).into(),
-            );
-            mb();
-            write_volatile(&mut self.regs.queue_notify, 0.into());
-            mb();
-            self.irq.enable();
-            while read_volatile(&self.queue.used.idx).native()
-                != read_volatile(&self.queue.available.idx).native()
-            {
-                //asm!("wfi");
-                let status = read_volatile(&self.regs.interrupt_status);
-                if status.native() != 0 {
-                    write_volatile(&mut self.regs.interrupt_ack, status);
-                    if read_volatile(&self.regs.interrupt_status).native() == status.native() {
-                        panic!("{:#x}", self.regs.interrupt_status.native());
+    pub fn read_only(&self) -> bool {
+        self.features & VIRTIO_BLK_F_RO != 0
+    }
+
+    /// Optimal I/O size in bytes, if the device reports its topology
+    pub fn optimal_io_size(&self) -> Option<u32> {
+        match self.features & VIRTIO_BLK_F_TOPOLOGY {
+            0 => None,
+            _ => match self.config.topology.opt_io_size.native() {
+                0 => None,
+                blocks => Some(blocks * self.block_size()),
+            },
+        }
+    }
+
+    /// Maximum number of data segments in one request
+    pub fn seg_max(&self) -> Option<u32> {
+        match self.features & VIRTIO_BLK_F_SEG_MAX {
+            0 => None,
+            _ => Some(self.config.seg_max.native()),
+        }
+    }
+
+    /// Maximum size of a segment in bytes
+    pub fn size_max(&self) -> Option<u32> {
+        match self.features & VIRTIO_BLK_F_SIZE_MAX {
+            0 => None,
+            _ => Some(self.config.size_max.native()),
+        }
+    }
+}
+
+impl<'a> VirtIOBlk<'a> {
+    /// Whether the device has set DEVICE_NEEDS_RESET, or been given up on
+    /// after it did
+    fn needs_reset(&self) -> bool {
+        self.regs.lock().status() & (Status::NeedsReset as u32 | Status::Failed as u32) != 0
+    }
+
+    /// Resets a device that set DEVICE_NEEDS_RESET, so it lets go of every
+    /// request it holds and their buffers can be freed, and marks it FAILED
+    /// so nothing more is sent to it. The device is shared by all its queues,
+    /// so this may already have been done from another one.
+    pub(super) fn give_up(&mut self) {
+        let mut regs = self.regs.lock();
+        if regs.status() & Status::Failed as u32 == 0 {
+            regs.set_status(Status::Reset);
+            while regs.status() != 0 {}
+            regs.set_status(Status::Failed);
+        }
+    }
+
+    /// Makes a request available to the device, handling completions until
+    /// there are enough free descriptors for it
+    fn add(&mut self, segments: &[Segment]) -> Result<u16, BlkError> {
+        loop {
+            if self.needs_reset() {
+                return Err(BlkError::NeedsReset);
+            }
+            if let Some(token) = self.queue.add(segments) {
+                if self.queue.should_notify() {
+                    self.regs.lock().notify(self.queue_index);
+                }
+                return Ok(token);
+            }
+            if self.in_flight() == 0 {
+                // It won't fit even in an empty queue
+                return Err(BlkError::TooLarge);
+            }
+            self.handle_interrupt();
+        }
+    }
+
+    /// Submits a request and waits for it, completing any others that finish
+    /// in the meantime
+    fn submit(&mut self, segments: &[Segment], status: &u8) -> Result<(), BlkError> {
+        let token = self.add(segments)? as usize;
+        self.slots[token] = Slot::Blocking(false);
+        self.irq.enable();
+        while let Slot::Blocking(false) = self.slots[token] {
+            // Nothing would wake a `wfi` here until there's an IRQ path, so
+            // spin on the used ring instead
+            //asm!("wfi");
+            self.handle_interrupt();
+            if self.needs_reset() {
+                self.irq.disable();
+                // The chain points at the caller's buffers, so the device
+                // has to be reset before they go away
+                self.give_up();
+                self.slots[token] = Slot::Free;
+                return Err(BlkError::NeedsReset);
+            }
+        }
+        self.irq.disable();
+        self.slots[token] = Slot::Free;
+        status_result(status)
+    }
+
+    /// Converts a run of `len` bytes starting at block `start` into its
+    /// first sector, checking it lies within the disk
+    fn sectors(&self, start: u64, len: usize) -> Result<u64, BlkError> {
+        let block_size = self.block_size() as usize;
+        if len % block_size != 0 || block_size % SECTOR_SIZE != 0 {
+            return Err(BlkError::Misaligned);
+        }
+        let sectors_per_block = (block_size / SECTOR_SIZE) as u64;
+        let sector = start
+            .checked_mul(sectors_per_block)
+            .ok_or(BlkError::OutOfRange)?;
+        match sector.checked_add((len / SECTOR_SIZE) as u64) {
+            Some(end) if end <= self.capacity() => Ok(sector),
+            _ => Err(BlkError::OutOfRange),
+        }
+    }
+
+    /// Largest data segment and largest request, in bytes, the device takes
+    fn request_limits(&self) -> (usize, usize) {
+        let block_size = self.block_size() as usize;
+        // Descriptors can't describe more than this, and no chain, indirect or
+        // not, may be longer than the queue. A SIZE_MAX below a block is
+        // bogus, so it's taken as a block.
+        let segment = (self.size_max().unwrap_or(u32::max_value()) as usize)
+            .min(u32::max_value() as usize & !(block_size - 1))
+            .max(block_size);
+        let segments = (self.seg_max().unwrap_or(u32::max_value()) as usize)
+            .min(self.queue.len() - 2)
+            .max(1);
+        let request = (segment.saturating_mul(segments) & !(block_size - 1)).max(block_size);
+        (segment, request)
+    }
+
+    /// Submits one request of type `req_type` with `data` between its header
+    /// and status byte
+    fn request<I>(&mut self, req_type: u32, sector: u64, data: I) -> Result<(), BlkError>
+    where
+        I: Iterator<Item = Segment>,
+    {
+        // Anything the device doesn't overwrite counts as an error
+        let mut status: u8 = 0xff;
+        let blkreq_hdr = BlkReqHdr {
+            req_type: req_type.into(),
+            reserved: 0,
+            sector: sector.into(),
+        };
+
+        let mut segments = Vec::with_capacity(data.size_hint().0 + 2);
+        segments.push(Segment::readable(&blkreq_hdr));
+        segments.extend(data);
+        segments.push(Segment::writable(&mut status));
+        self.submit(&segments, &status)
+    }
+
+    /// Reads whole blocks starting at block `start`. The length of `data` must
+    /// be a multiple of the block size.
+    pub fn read_blocks(&mut self, start: u64, data: &mut [u8]) -> Result<(), BlkError> {
+        let mut sector = self.sectors(start, data.len())?;
+        let (segment_size, request_size) = self.request_limits();
+        for request in data.chunks_mut(request_size) {
+            let sectors = (request.len() / SECTOR_SIZE) as u64;
+            let segments = request.chunks_mut(segment_size).map(Segment::writable);
+            self.request(VIRTIO_BLK_T_IN, sector, segments)?;
+            sector += sectors;
+        }
+        Ok(())
+    }
+
+    /// Writes whole blocks starting at block `start`. The length of `data`
+    /// must be a multiple of the block size.
+    pub fn write_blocks(&mut self, start: u64, data: &[u8]) -> Result<(), BlkError> {
+        if self.read_only() {
+            return Err(BlkError::ReadOnly);
+        }
+        let mut sector = self.sectors(start, data.len())?;
+        let (segment_size, request_size) = self.request_limits();
+        for request in data.chunks(request_size) {
+            let sectors = (request.len() / SECTOR_SIZE) as u64;
+            let segments = request.chunks(segment_size).map(Segment::readable);
+            self.request(VIRTIO_BLK_T_OUT, sector, segments)?;
+            sector += sectors;
+        }
+        Ok(())
+    }
+
+    /// Makes all completed writes durable. Without FLUSH the device has no
+    /// volatile write cache, so there is nothing to do.
+    pub fn flush(&mut self) -> Result<(), BlkError> {
+        if self.features & VIRTIO_BLK_F_FLUSH == 0 {
+            return Ok(());
+        }
+        self.request(VIRTIO_BLK_T_FLUSH, 0, core::iter::empty())
+    }
+
+    /// Tells the device `count` blocks from `start` are no longer in use
+    pub fn discard(&mut self, start: u64, count: u64) -> Result<(), BlkError> {
+        if self.features & VIRTIO_BLK_F_DISCARD == 0 {
+            return Err(BlkError::Unsupported);
+        }
+        if self.read_only() {
+            return Err(BlkError::ReadOnly);
+        }
+        let limits = (
+            self.config.max_discard_sectors.native(),
+            self.config.max_discard_seg.native(),
+            self.config.discard_sector_alignment.native(),
+        );
+        self.range_request(VIRTIO_BLK_T_DISCARD, start, count, 0, limits)
+    }
+
+    /// Zeroes `count` blocks from `start`. With `unmap` the device may
+    /// deallocate them, as long as they read back as zeroes.
+    pub fn write_zeroes(&mut self, start: u64, count: u64, unmap: bool) -> Result<(), BlkError> {
+        if self.features & VIRTIO_BLK_F_WRITE_ZEROES == 0 {
+            return Err(BlkError::Unsupported);
+        }
+        if self.read_only() {
+            return Err(BlkError::ReadOnly);
+        }
+        let flags = if unmap {
+            VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP
+        } else {
+            0
+        };
+        let limits = (
+            self.config.max_write_zeroes_sectors.native(),
+            self.config.max_write_zeroes_seg.native(),
+            0,
+        );
+        self.range_request(VIRTIO_BLK_T_WRITE_ZEROES, start, count, flags, limits)
+    }
+
+    /// Issues a DISCARD or WRITE_ZEROES for a run of blocks, split into
+    /// segments of at most `max_sectors` and requests of at most `max_seg`
+    /// segments. Segments are split on multiples of `align` sectors where
+    /// they can be, 0 for no preference.
+    fn range_request(
+        &mut self,
+        req_type: u32,
+        start: u64,
+        count: u64,
+        flags: u32,
+        (max_sectors, max_seg, align): (u32, u32, u32),
+    ) -> Result<(), BlkError> {
+        let len = count
+            .checked_mul(self.block_size() as u64)
+            .ok_or(BlkError::OutOfRange)?;
+        let mut sector = self.sectors(start, len as usize)?;
+        let end = sector + len / SECTOR_SIZE as u64;
+
+        // Keep segments block aligned
+        let sectors_per_block = self.block_size() / SECTOR_SIZE as u32;
+        let max_sectors = match max_sectors / sectors_per_block * sectors_per_block {
+            0 => u32::max_value() / sectors_per_block * sectors_per_block,
+            max_sectors => max_sectors,
+        };
+        let max_seg = (max_seg.max(1) as usize).min(MAX_RANGE_SEGMENTS);
+        let align = match align as u64 % sectors_per_block as u64 {
+            0 => (align as u64).max(1),
+            _ => 1,
+        };
+
+        while sector < end {
+            let mut segments = Vec::with_capacity(max_seg);
+            while sector < end && segments.len() < max_seg {
+                let mut num_sectors = (end - sector).min(max_sectors as u64);
+                // A segment that doesn't reach the end stops at the last
+                // aligned sector it covers, if any
+                let split = (sector + num_sectors) / align * align;
+                if sector + num_sectors < end && split > sector {
+                    num_sectors = split - sector;
+                }
+                let num_sectors = num_sectors as u32;
+                segments.push(BlkDiscardWriteZeroes {
+                    sector: sector.into(),
+                    num_sectors: num_sectors.into(),
+                    flags: flags.into(),
+                });
+                sector += num_sectors as u64;
+            }
+            self.request(
+                req_type,
+                0,
+                core::iter::once(Segment::readable(&segments[..])),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// The device's serial number, NUL-padded to 20 bytes
+    pub fn id(&mut self) -> Result<[u8; VIRTIO_BLK_ID_BYTES], BlkError> {
+        let mut id = [0u8; VIRTIO_BLK_ID_BYTES];
+        self.request(
+            VIRTIO_BLK_T_GET_ID,
+            0,
+            core::iter::once(Segment::writable(&mut id)),
+        )?;
+        Ok(id)
+    }
+}
+
+impl<'a> BlockDevice for VirtIOBlk<'a> {
+    fn block_size(&self) -> usize {
+        VirtIOBlk::block_size(self) as usize
+    }
+
+    fn block_count(&self) -> u64 {
+        self.capacity() * SECTOR_SIZE as u64 / VirtIOBlk::block_size(self) as u64
+    }
+
+    fn read(&mut self, start: u64, data: &mut [u8]) -> Result<(), BlkError> {
+        self.read_blocks(start, data)
+    }
+
+    fn write(&mut self, start: u64, data: &[u8]) -> Result<(), BlkError> {
+        self.write_blocks(start, data)
+    }
+
+    fn flush(&mut self) -> Result<(), BlkError> {
+        VirtIOBlk::flush(self)
+    }
+
+    fn discard(&mut self, start: u64, count: u64) -> Result<(), BlkError> {
+        match VirtIOBlk::discard(self, start, count) {
+            // Discarding is only a hint
+            Err(BlkError::Unsupported) => Ok(()),
+            result => result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::boxed::Box;
+    use alloc::sync::Arc;
+    use alloc::vec;
+    use alloc::vec::Vec;
+
+    use super::super::mock::{irq, le32, le64, mode_tests, Handler, MockDevice, Mode};
+    use super::super::{
+        DeviceId, Queue, Status, Transport, VIRTIO_F_INDIRECT_DESC, VIRTIO_F_RING_PACKED,
+    };
+    use super::{BlkError, VirtIOBlk};
+    use crate::block::BlockDevice;
+    use crate::mutex::Mutex;
+    use crate::utils::block_on;
+
+    /// Serial number the mock disk reports
+    const BLK_SERIAL: &[u8] = b"mock-blk-0";
+
+    /// A RAM-backed disk of 16 sectors. Requests past its end fail with
+    /// VIRTIO_BLK_S_IOERR and unknown types with VIRTIO_BLK_S_UNSUPP.
+    fn blk_handler() -> Handler {
+        let mut disk = vec![0u8; 16 * 512];
+        Box::new(move |_, input: &[u8], capacity| {
+            let req_type = le32(&input[0..4]);
+            let offset = le64(&input[8..16]) as usize * 512;
+            match req_type {
+                // FLUSH
+                4 => vec![0],
+                // GET_ID
+                8 => {
+                    let mut out = BLK_SERIAL.to_vec();
+                    out.resize(capacity - 1, 0);
+                    out.push(0);
+                    out
+                }
+                // DISCARD and WRITE_ZEROES both zero the ranges
+                11 | 13 => {
+                    for range in input[16..].chunks(16) {
+                        let start = le64(&range[0..8]) as usize * 512;
+                        let end = start + le32(&range[8..12]) as usize * 512;
+                        if end > disk.len() {
+                            return vec![1];
+                        }
+                        disk[start..end].iter_mut().for_each(|b| *b = 0);
                     }
+                    vec![0]
+                }
+                0 if offset + capacity - 1 <= disk.len() => {
+                    let mut out = disk[offset..offset + capacity - 1].to_vec();
+                    out.push(0);
+                    out
+                }
+                1 if offset + input.len() - 16 <= disk.len() => {
+                    disk[offset..offset + input.len() - 16].copy_from_slice(&input[16..]);
+                    vec![0]
+                }
+                0 | 1 => {
+                    let mut out = vec![0; capacity - 1];
+                    out.push(1);
+                    out
                 }
+                _ => {
+                    let mut out = vec![0; capacity - 1];
+                    out.push(2);
+                    out
+                }
+            }
+        })
+    }
+
+    fn blk_device(mode: Mode, features: u64, sectors: u64, handler: Handler) -> MockDevice {
+        let mut device = MockDevice::new(DeviceId::Blk, features, handler);
+        device.mode = mode;
+        // struct virtio_blk_config, starting with the capacity
+        device.config = vec![0; 64];
+        device.config[..8].copy_from_slice(&sectors.to_le_bytes());
+        device
+    }
+
+    fn read_write_with(mode: Mode, features: u64) {
+        let mut queue = Queue::boxed();
+        let mut device = blk_device(mode, features, 16, blk_handler());
+        {
+            let mut blk = VirtIOBlk::new(&mut device, &mut queue, irq()).expect("init failed");
+            let mut data = [0u8; 512];
+            for (i, b) in data.iter_mut().enumerate() {
+                *b = i as u8;
             }
-            self.irq.disable();
+            blk.write_blocks(3, &data).expect("write failed");
+            let mut read = [0xffu8; 512];
+            blk.read_blocks(3, &mut read).expect("read failed");
+            assert!(read[..] == data[..], "read didn't return written data");
+            blk.read_blocks(4, &mut read).expect("read failed");
+            assert!(read.iter().all(|b| *b == 0), "read wrong sector");
         }
+        assert!(
+            device.driver_features & !VIRTIO_F_RING_PACKED == features & VIRTIO_F_INDIRECT_DESC,
+            "unexpected features negotiated"
+        );
+        assert!(
+            (device.driver_features & VIRTIO_F_RING_PACKED != 0) == (mode == Mode::Packed),
+            "packed ring not used when offered"
+        );
     }
 
-    pub fn write(&mut self, sector: u64, data: &[u8; 512]) {
-        unsafe {
-            let mut status: u8 = 0;
-            let blkreq_hdr = BlkReqHdr {
-                req_type: 1.into(),
-                reserved: 0,
-                sector: sector.into(),
-            };
+    fn read_write(mode: Mode) {
+        read_write_with(mode, 0);
+    }
+
+    fn read_write_indirect(mode: Mode) {
+        read_write_with(mode, VIRTIO_F_INDIRECT_DESC);
+    }
 
-            write_volatile(&mut status, 0);
+    fn multi_block(mode: Mode) {
+        let mut data = vec![0u8; 12 * 512];
+        for (i, b) in data.iter_mut().enumerate() {
+            *b = (i / 512 + i) as u8;
+        }
+        let mut read = vec![0u8; 12 * 512];
+
+        // Without limits a transfer is a single chain
+        let mut queue = Queue::boxed();
+        let mut device = blk_device(mode, VIRTIO_F_INDIRECT_DESC, 16, blk_handler());
+        {
+            let mut blk = VirtIOBlk::new(&mut device, &mut queue, irq()).expect("init failed");
+            blk.write_blocks(2, &data).expect("write failed");
+            blk.read_blocks(2, &mut read).expect("read failed");
+            assert!(read == data, "read didn't return written data");
+        }
+        assert!(device.requests == 2, "transfer split without limits");
 
-            write_volatile(
-                &mut self.queue.descriptors[0],
-                VirtQDesc {
-                    addr: (&blkreq_hdr as *const _ as u64).into(),
-                    len: (core::mem::size_of::<BlkReqHdr>() as u32).into(),
-                    flags: (1).into(),
-                    next: 1.into(),
-                },
+        // SIZE_MAX of 1024 bytes and SEG_MAX of 4 segments: 4096 bytes a request
+        let mut queue = Queue::boxed();
+        let mut device = blk_device(mode, 1 << 1 | 1 << 2, 16, blk_handler());
+        device.config[8..12].copy_from_slice(&1024u32.to_le_bytes());
+        device.config[12..16].copy_from_slice(&4u32.to_le_bytes());
+        {
+            let mut blk = VirtIOBlk::new(&mut device, &mut queue, irq()).expect("init failed");
+            blk.write_blocks(2, &data).expect("write failed");
+            read.iter_mut().for_each(|b| *b = 0);
+            blk.read_blocks(2, &mut read).expect("read failed");
+            assert!(read == data, "split read didn't return written data");
+            assert!(
+                blk.read_blocks(8, &mut read) == Err(BlkError::OutOfRange),
+                "read past the end accepted"
             );
+        }
+        assert!(
+            device.requests == 4,
+            "transfer not split into 4096-byte requests"
+        );
+        assert!(
+            device.longest_chain == 6,
+            "SEG_MAX or SIZE_MAX not respected"
+        );
+
+        // A SIZE_MAX of 0 is bogus, so segments are a block each
+        let mut queue = Queue::boxed();
+        let mut device = blk_device(mode, 1 << 1, 16, blk_handler());
+        {
+            let mut blk = VirtIOBlk::new(&mut device, &mut queue, irq()).expect("init failed");
+            blk.read_blocks(2, &mut read).expect("read failed");
+        }
+        assert!(device.longest_chain == 14, "segments not a block each");
+    }
+
+    fn commands(mode: Mode) {
+        // FLUSH, DISCARD and WRITE_ZEROES
+        let mut queue = Queue::boxed();
+        let mut device = blk_device(mode, 1 << 9 | 1 << 13 | 1 << 14, 16, blk_handler());
+        // Two sectors a range, two ranges a request
+        device.config[48..52].copy_from_slice(&2u32.to_le_bytes());
+        device.config[52..56].copy_from_slice(&2u32.to_le_bytes());
+        {
+            let mut blk = VirtIOBlk::new(&mut device, &mut queue, irq()).expect("init failed");
+            let data = [0xaau8; 16 * 512];
+            blk.write_blocks(0, &data).expect("write failed");
+            blk.flush().expect("flush failed");
 
-            write_volatile(
-                &mut self.queue.descriptors[1],
-                VirtQDesc {
-                    addr: (data.as_ptr() as *const _ as u64).into(),
-                    len: (512).into(),
-                    flags: (1).into(),
-                    next: 2.into(),
-                },
+            let id = blk.id().expect("GET_ID failed");
+            assert!(&id[..BLK_SERIAL.len()] == BLK_SERIAL, "wrong serial");
+            assert!(
+                id[BLK_SERIAL.len()..].iter().all(|b| *b == 0),
+                "serial not NUL-padded"
             );
 
-            write_volatile(
-                &mut self.queue.descriptors[2],
-                VirtQDesc {
-                    addr: (&status as *const _ as u64).into(),
-                    len: (1).into(),
-                    flags: 2.into(),
-                    next: 0.into(),
-                },
+            blk.write_zeroes(2, 8, false).expect("WRITE_ZEROES failed");
+            blk.discard(12, 1).expect("DISCARD failed");
+
+            let mut read = [0u8; 16 * 512];
+            blk.read_blocks(0, &mut read).expect("read failed");
+            for (sector, data) in read.chunks(512).enumerate() {
+                let zeroed = (2..10).contains(&sector) || sector == 12;
+                let expected = if zeroed { 0 } else { 0xaa };
+                assert!(data.iter().all(|b| *b == expected), "wrong sectors zeroed");
+            }
+            assert!(
+                blk.discard(15, 2) == Err(BlkError::OutOfRange),
+                "discard past the end accepted"
             );
+        }
+        // write, flush, GET_ID, 2 WRITE_ZEROES, DISCARD and read
+        assert!(
+            device.requests == 7,
+            "ranges not split by the config limits"
+        );
 
-            write_volatile(
-                &mut self.queue.available.ring[self.queue.available.idx.native() as usize],
-                0.into(),
+        // Four sectors a range, one range a request, split on multiples of
+        // four sectors: 1-3, 4-7 and 8
+        let mut queue = Queue::boxed();
+        let mut device = blk_device(mode, 1 << 13, 16, blk_handler());
+        device.config[36..40].copy_from_slice(&4u32.to_le_bytes());
+        device.config[40..44].copy_from_slice(&1u32.to_le_bytes());
+        device.config[44..48].copy_from_slice(&4u32.to_le_bytes());
+        {
+            let mut blk = VirtIOBlk::new(&mut device, &mut queue, irq()).expect("init failed");
+            blk.discard(1, 8).expect("DISCARD failed");
+        }
+        assert!(
+            device.requests == 3,
+            "ranges not split on the discard alignment"
+        );
+
+        let mut queue = Queue::boxed();
+        let mut device = blk_device(mode, 0, 16, blk_handler());
+        {
+            let mut blk = VirtIOBlk::new(&mut device, &mut queue, irq()).expect("init failed");
+            assert!(blk.flush().is_ok(), "flush without a write cache failed");
+            assert!(
+                blk.discard(0, 1) == Err(BlkError::Unsupported),
+                "DISCARD sent without the feature"
             );
-            mb();
-            write_volatile(
-                &mut self.queue.available.idx,
-                (self.queue.available.idx.native() + 1).into(),
+            assert!(
+                blk.write_zeroes(0, 1, true) == Err(BlkError::Unsupported),
+                "WRITE_ZEROES sent without the feature"
             );
-            mb();
-            write_volatile(&mut self.regs.queue_notify, 0.into());
-            mb();
-            self.irq.enable();
-            while read_volatile(&self.queue.used.idx).native()
-                != read_volatile(&self.queue.available.idx).native()
-            {
-                //asm!("wfi");
-            }
-            self.irq.disable();
         }
+        assert!(device.requests == 0, "request reached the device");
     }
+
+    fn async_requests(mode: Mode) {
+        let mut queue = Queue::boxed();
+        let mut device = blk_device(mode, 0, 16, blk_handler());
+        device.hold_completions(3);
+        let mut blk = VirtIOBlk::new(&mut device, &mut queue, irq()).expect("init failed");
+
+        let order = Arc::new(Mutex::new(Vec::new()));
+        for block in 0..3u64 {
+            let order = order.clone();
+            blk.write_with(block, vec![block as u8 + 1; 512], move |result, data| {
+                order.lock().push((block, result, data[0]));
+            });
+        }
+        assert!(
+            order.lock().is_empty(),
+            "completed before the device finished"
+        );
+        blk.handle_interrupt();
+        let expected = [(2, Ok(()), 3), (1, Ok(()), 2), (0, Ok(()), 1)];
+        assert!(
+            order.lock()[..] == expected[..],
+            "callbacks not run in completion order"
+        );
+
+        // A blocking read completes alongside outstanding futures
+        let first = blk.read_async(0, vec![0; 512]);
+        let second = blk.read_async(1, vec![0; 1024]);
+        let mut data = [0u8; 512];
+        blk.read_blocks(2, &mut data).expect("read failed");
+        assert!(data.iter().all(|b| *b == 3), "blocking read got wrong data");
+        let (result, data) = block_on(second, || blk.handle_interrupt());
+        assert!(result.is_ok(), "second read failed");
+        assert!(
+            data[..512].iter().all(|b| *b == 2),
+            "second read got wrong data"
+        );
+        assert!(
+            data[512..].iter().all(|b| *b == 3),
+            "second read got wrong data"
+        );
+        let (result, data) = block_on(first, || blk.handle_interrupt());
+        assert!(
+            result == Ok(()) && data.iter().all(|b| *b == 1),
+            "first read failed"
+        );
+
+        let (result, _) = block_on(blk.read_async(0, vec![0; 100]), || {});
+        assert!(result == Err(BlkError::Misaligned), "bad request accepted");
+    }
+
+    fn multiqueue(mode: Mode) {
+        let queued = Arc::new(Mutex::new(Vec::new()));
+        let mut handler = blk_handler();
+        let log = queued.clone();
+        let handler: Handler = Box::new(move |queue, input: &[u8], capacity| {
+            log.lock().push(queue);
+            handler(queue, input, capacity)
+        });
+        // MQ with two queues
+        let mut device = blk_device(mode, 1 << 12, 16, handler);
+        device.config[34..36].copy_from_slice(&2u16.to_le_bytes());
+
+        let mut queues = [Queue::boxed(), Queue::boxed(), Queue::boxed()];
+        let queues = queues.iter_mut().map(|queue| &mut **queue).collect();
+        let mut blks = VirtIOBlk::new_multiqueue(&mut device, queues, irq()).expect("init failed");
+        assert!(blks.len() == 2, "wrong number of queues set up");
+
+        let data = [7u8; 512];
+        blks[0].write_blocks(5, &data).expect("write failed");
+        let mut read = [0u8; 512];
+        blks[1].read_blocks(5, &mut read).expect("read failed");
+        assert!(read == data, "queues don't share the disk");
+        let queued = queued.lock().clone();
+        assert!(queued == [0, 1], "requests not sent on their own queue");
+    }
+
+    fn block_device(mode: Mode) {
+        let mut queue = Queue::boxed();
+        // BLK_SIZE of 1024 bytes
+        let mut device = blk_device(mode, 1 << 6, 16, blk_handler());
+        device.config[20..24].copy_from_slice(&1024u32.to_le_bytes());
+        let mut blk = VirtIOBlk::new(&mut device, &mut queue, irq()).expect("init failed");
+        let disk: &mut dyn BlockDevice = &mut blk;
+        assert!(disk.block_size() == 1024, "wrong block size");
+        assert!(disk.block_count() == 8, "wrong block count");
+        let data = [9u8; 2048];
+        disk.write(6, &data).expect("write failed");
+        let mut read = [0u8; 2048];
+        disk.read(6, &mut read).expect("read failed");
+        assert!(read[..] == data[..], "read didn't return written data");
+        assert!(
+            disk.discard(0, 1).is_ok(),
+            "unsupported discard not ignored"
+        );
+        assert!(
+            disk.read(7, &mut read) == Err(BlkError::OutOfRange),
+            "read past the end accepted"
+        );
+    }
+
+    fn config(mode: Mode) {
+        let mut queue = Queue::boxed();
+        // RO, BLK_SIZE and TOPOLOGY
+        let mut device = blk_device(mode, 1 << 5 | 1 << 6 | 1 << 10, 16, blk_handler());
+        device.config[20..24].copy_from_slice(&4096u32.to_le_bytes());
+        device.config[28..32].copy_from_slice(&8u32.to_le_bytes());
+        let mut blk = VirtIOBlk::new(&mut device, &mut queue, irq()).expect("init failed");
+        assert!(blk.capacity() == 16, "wrong capacity");
+        assert!(blk.block_size() == 4096, "wrong block size");
+        assert!(
+            blk.optimal_io_size() == Some(8 * 4096),
+            "wrong optimal I/O size"
+        );
+        assert!(blk.read_only(), "read-only not reported");
+        let mut data = [0u8; 4096];
+        assert!(
+            blk.read_blocks(0, &mut data[..512]) == Err(BlkError::Misaligned),
+            "partial block read accepted"
+        );
+        assert!(blk.read_blocks(0, &mut data).is_ok(), "read failed");
+        assert!(
+            blk.write_blocks(0, &data) == Err(BlkError::ReadOnly),
+            "write to read-only disk reached the device"
+        );
+
+        // A block size of 0 is bogus, so sectors are used instead
+        let mut queue = Queue::boxed();
+        let mut device = blk_device(mode, 1 << 6, 16, blk_handler());
+        let mut blk = VirtIOBlk::new(&mut device, &mut queue, irq()).expect("init failed");
+        assert!(blk.block_size() == 512, "bogus block size used");
+        assert!(blk.read_blocks(0, &mut data[..512]).is_ok(), "read failed");
+    }
+
+    fn errors(mode: Mode) {
+        let mut data = [0u8; 512];
+
+        // The config claims more sectors than the handler's disk has
+        let mut queue = Queue::boxed();
+        let mut device = blk_device(mode, 0, 32, blk_handler());
+        let mut blk = VirtIOBlk::new(&mut device, &mut queue, irq()).expect("init failed");
+        assert!(
+            blk.read_blocks(20, &mut data) == Err(BlkError::IoError),
+            "IOERR not reported"
+        );
+        assert!(
+            blk.read_blocks(32, &mut data) == Err(BlkError::OutOfRange),
+            "out-of-range read reached the device"
+        );
+        assert!(
+            blk.write_blocks(u64::max_value(), &data) == Err(BlkError::OutOfRange),
+            "out-of-range write reached the device"
+        );
+        assert!(blk.read_blocks(0, &mut data).is_ok(), "error status stuck");
+
+        let mut queue = Queue::boxed();
+        let mut device = blk_device(
+            mode,
+            0,
+            16,
+            Box::new(|_, _: &[u8], capacity| {
+                let mut out = vec![0; capacity - 1];
+                out.push(2);
+                out
+            }),
+        );
+        let mut blk = VirtIOBlk::new(&mut device, &mut queue, irq()).expect("init failed");
+        assert!(
+            blk.read_blocks(0, &mut data) == Err(BlkError::Unsupported),
+            "UNSUPP not reported"
+        );
+
+        let mut queue = Queue::boxed();
+        let mut device = blk_device(mode, 0, 16, blk_handler());
+        device.needs_reset_after(1);
+        let mut blk = VirtIOBlk::new(&mut device, &mut queue, irq()).expect("init failed");
+        assert!(blk.read_blocks(0, &mut data).is_ok(), "read failed");
+        assert!(
+            blk.read_blocks(0, &mut data) == Err(BlkError::NeedsReset),
+            "NEEDS_RESET not reported"
+        );
+        assert!(
+            blk.write_blocks(0, &data) == Err(BlkError::NeedsReset),
+            "request sent after NEEDS_RESET"
+        );
+        drop(blk);
+        // Reset, so the device can't touch the buffers of the abandoned read
+        assert!(
+            device.status() == Status::Failed as u32,
+            "device not reset after NEEDS_RESET"
+        );
+
+        // Requests in flight when the device fails complete with the error
+        let mut queue = Queue::boxed();
+        let mut device = blk_device(mode, 0, 16, blk_handler());
+        device.needs_reset_after(0);
+        let mut blk = VirtIOBlk::new(&mut device, &mut queue, irq()).expect("init failed");
+        let read = blk.read_async(0, vec![0; 512]);
+        let (result, _) = block_on(read, || blk.handle_interrupt());
+        assert!(
+            result == Err(BlkError::NeedsReset),
+            "in-flight request not failed"
+        );
+        drop(blk);
+        assert!(
+            device.status() == Status::Failed as u32,
+            "device not reset after NEEDS_RESET"
+        );
+
+        // A device that refuses every feature set fails initialization
+        let mut queue = Queue::boxed();
+        let mut device = blk_device(mode, VIRTIO_F_INDIRECT_DESC, 16, blk_handler());
+        device.reject_features = true;
+        assert!(
+            VirtIOBlk::new(&mut device, &mut queue, irq()).err()
+                == Some(BlkError::FeaturesRejected),
+            "feature negotiation failure not reported"
+        );
+    }
+
+    mode_tests!(
+        read_write,
+        read_write_indirect,
+        multi_block,
+        commands,
+        async_requests,
+        multiqueue,
+        block_device,
+        config,
+        errors,
+    );
 }
@@ -0,0 +1,212 @@
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+
+use crate::mutex::Mutex;
+
+use super::super::Segment;
+use super::{
+    BlkError, BlkReqHdr, VirtIOBlk, VIRTIO_BLK_S_OK, VIRTIO_BLK_S_UNSUPP, VIRTIO_BLK_T_IN,
+    VIRTIO_BLK_T_OUT,
+};
+
+/// Called with a request's result and its buffer from the first
+/// `handle_interrupt` call after the device completes it
+pub type Callback = Box<dyn FnOnce(Result<(), BlkError>, Vec<u8>) + Send + Sync>;
+
+/// An asynchronous request the device holds. The header and status byte are
+/// boxed so they stay put while the device uses them.
+pub struct InFlight {
+    hdr: Box<BlkReqHdr>,
+    status: Box<u8>,
+    data: Vec<u8>,
+    callback: Callback,
+}
+
+pub enum Slot {
+    Free,
+    /// A blocking call waits for this request; true once it completed
+    Blocking(bool),
+    Async(InFlight),
+}
+
+pub fn status_result(status: &u8) -> Result<(), BlkError> {
+    match unsafe { core::ptr::read_volatile(status) } {
+        VIRTIO_BLK_S_OK => Ok(()),
+        VIRTIO_BLK_S_UNSUPP => Err(BlkError::Unsupported),
+        // VIRTIO_BLK_S_IOERR, or a status the device never wrote
+        _ => Err(BlkError::IoError),
+    }
+}
+
+#[derive(Default)]
+struct FutureState {
+    result: Option<(Result<(), BlkError>, Vec<u8>)>,
+    waker: Option<Waker>,
+}
+
+/// Resolves to a request's result and buffer once `handle_interrupt` sees it
+/// complete. Nothing calls that from an interrupt, so whoever awaits this has
+/// to poll it, e.g. from `block_on`'s idle hook.
+pub struct BlkFuture {
+    state: Arc<Mutex<FutureState>>,
+}
+
+impl Future for BlkFuture {
+    type Output = (Result<(), BlkError>, Vec<u8>);
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let mut state = self.state.lock();
+        match state.result.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl<'a> VirtIOBlk<'a> {
+    pub(super) fn in_flight(&self) -> usize {
+        self.slots
+            .iter()
+            .filter(|slot| !matches!(slot, Slot::Free))
+            .count()
+    }
+
+    /// Completes every request the device has finished, in whatever order it
+    /// finished them, running their callbacks. There is no IRQ path calling
+    /// this yet, so it has to be polled: asynchronous requests don't complete
+    /// until it is.
+    ///
+    /// Callbacks run with the driver borrowed, so they mustn't use it.
+    pub fn handle_interrupt(&mut self) {
+        let mut regs = self.regs.lock();
+        if regs.ack_interrupt() & 2 != 0 {
+            // Configuration change, e.g. the disk was resized
+            self.config = super::super::read_config(&**regs);
+        }
+        drop(regs);
+        while let Some((token, _)) = self.queue.pop_used() {
+            let slot = match self.slots.get_mut(token as usize) {
+                Some(slot) => slot,
+                None => continue,
+            };
+            match core::mem::replace(slot, Slot::Free) {
+                Slot::Blocking(_) => *slot = Slot::Blocking(true),
+                Slot::Async(request) => {
+                    let result = status_result(&request.status);
+                    (request.callback)(result, request.data);
+                }
+                Slot::Free => {}
+            }
+        }
+        if self.needs_reset() {
+            // The device won't complete what's left. Reset it before the
+            // buffers it was given are handed back.
+            self.give_up();
+            let pending = self
+                .slots
+                .iter_mut()
+                .filter(|s| matches!(s, Slot::Async(_)));
+            for slot in pending {
+                if let Slot::Async(request) = core::mem::replace(slot, Slot::Free) {
+                    (request.callback)(Err(BlkError::NeedsReset), request.data);
+                }
+            }
+        }
+    }
+
+    fn submit_async(&mut self, req_type: u32, start: u64, data: Vec<u8>, callback: Callback) {
+        let (segment_size, request_size) = self.request_limits();
+        let sector = match self.sectors(start, data.len()) {
+            Ok(_) if data.len() > request_size => Err(BlkError::TooLarge),
+            sector => sector,
+        };
+        let sector = match sector {
+            Ok(sector) => sector,
+            Err(err) => return callback(Err(err), data),
+        };
+
+        let mut request = InFlight {
+            hdr: Box::new(BlkReqHdr {
+                req_type: req_type.into(),
+                reserved: 0,
+                sector: sector.into(),
+            }),
+            // Anything the device doesn't overwrite counts as an error
+            status: Box::new(0xff),
+            data,
+            callback,
+        };
+        let mut segments = Vec::with_capacity(request.data.len() / segment_size + 3);
+        segments.push(Segment::readable(&*request.hdr));
+        if req_type == VIRTIO_BLK_T_IN {
+            segments.extend(request.data.chunks_mut(segment_size).map(Segment::writable));
+        } else {
+            segments.extend(request.data.chunks(segment_size).map(Segment::readable));
+        }
+        segments.push(Segment::writable(&mut *request.status));
+
+        match self.add(&segments) {
+            Ok(token) => self.slots[token as usize] = Slot::Async(request),
+            Err(err) => (request.callback)(Err(err), request.data),
+        }
+    }
+
+    /// Starts reading blocks from `start` into `data` without waiting for
+    /// them. `callback` gets the result and the buffer back, from whichever
+    /// `handle_interrupt` call sees the read complete. The whole read must
+    /// fit in one request.
+    pub fn read_with<F>(&mut self, start: u64, data: Vec<u8>, callback: F)
+    where
+        F: FnOnce(Result<(), BlkError>, Vec<u8>) + Send + Sync + 'static,
+    {
+        self.submit_async(VIRTIO_BLK_T_IN, start, data, Box::new(callback));
+    }
+
+    /// Starts writing `data` to blocks from `start` without waiting for it.
+    /// `callback` gets the result and the buffer back, like `read_with`'s.
+    /// The whole write must fit in one request.
+    pub fn write_with<F>(&mut self, start: u64, data: Vec<u8>, callback: F)
+    where
+        F: FnOnce(Result<(), BlkError>, Vec<u8>) + Send + Sync + 'static,
+    {
+        if self.read_only() {
+            return callback(Err(BlkError::ReadOnly), data);
+        }
+        self.submit_async(VIRTIO_BLK_T_OUT, start, data, Box::new(callback));
+    }
+
+    fn future(&mut self, req_type: u32, start: u64, data: Vec<u8>) -> BlkFuture {
+        let state = Arc::new(Mutex::new(FutureState::default()));
+        let completion = state.clone();
+        let callback = move |result, data| {
+            let mut state = completion.lock();
+            state.result = Some((result, data));
+            if let Some(waker) = state.waker.take() {
+                waker.wake();
+            }
+        };
+        if req_type == VIRTIO_BLK_T_OUT {
+            self.write_with(start, data, callback);
+        } else {
+            self.read_with(start, data, callback);
+        }
+        BlkFuture { state }
+    }
+
+    /// Like `read_with`, but resolves a future instead of calling back
+    pub fn read_async(&mut self, start: u64, data: Vec<u8>) -> BlkFuture {
+        self.future(VIRTIO_BLK_T_IN, start, data)
+    }
+
+    /// Like `write_with`, but resolves a future instead of calling back
+    pub fn write_async(&mut self, start: u64, data: Vec<u8>) -> BlkFuture {
+        self.future(VIRTIO_BLK_T_OUT, start, data)
+    }
+}
@@ -1,104 +1,76 @@
-use crate::utils::*;
-use core::ptr::{read_volatile, write_volatile};
-
-use super::{Queue, Status, VirtIORegs, VirtQDesc};
+use super::{Queue, Segment, Status, Transport, VIRTIO_F_INDIRECT_DESC, VIRTIO_F_RING_PACKED};
 
 pub struct VirtIOEntropy<'a> {
-    regs: &'a mut VirtIORegs,
+    regs: &'a mut dyn Transport,
     queue: &'a mut Queue<128>,
     irq: crate::gic::GIC,
 }
 
+const ENTROPY_DEVICE_FEATURES: u64 = VIRTIO_F_INDIRECT_DESC | VIRTIO_F_RING_PACKED;
+
 impl<'a> VirtIOEntropy<'a> {
-    pub fn new(regs: &'a mut VirtIORegs, queue: &'a mut Queue<128>, irq: crate::gic::GIC) -> Self {
-        unsafe {
-            write_volatile(&mut regs.status, Status::Reset.into());
-            write_volatile(&mut regs.status, Status::Acknowledge.into());
-            write_volatile(&mut regs.status, Status::Driver.into());
+    pub fn new(
+        regs: &'a mut dyn Transport,
+        queue: &'a mut Queue<128>,
+        irq: crate::gic::GIC,
+    ) -> Self {
+        regs.set_status(Status::Reset);
+        regs.set_status(Status::Acknowledge);
+        regs.set_status(Status::Driver);
 
-            write_volatile(&mut regs.device_features_sel, 0.into());
-            let device_features = read_volatile(&mut regs.device_features).native();
-            write_volatile(&mut regs.driver_features_sel, 0.into());
-            write_volatile(&mut regs.driver_features, (0 & device_features).into());
+        let driver_features = ENTROPY_DEVICE_FEATURES & regs.device_features();
+        regs.set_driver_features(driver_features);
+        queue.negotiated(driver_features);
 
-            write_volatile(&mut regs.status, Status::FeaturesOk.into());
-            if read_volatile(&mut regs.status).native() & (Status::FeaturesOk as u32) == 0 {
-                panic!("Coudln't set entropy features");
-            }
+        regs.set_status(Status::FeaturesOk);
+        if regs.status() & (Status::FeaturesOk as u32) == 0 {
+            panic!("Coudln't set entropy features");
+        }
 
-            write_volatile(&mut regs.queue_sel, 0.into());
-            write_volatile(&mut regs.queue_num, (queue.descriptors.len() as u32).into());
-            write_volatile(
-                &mut regs.queue_desc_low,
-                ((queue.descriptors.as_ptr() as usize) as u32).into(),
-            );
-            write_volatile(
-                &mut regs.queue_desc_high,
-                ((queue.descriptors.as_ptr() as usize >> 32) as u32).into(),
-            );
-            write_volatile(
-                &mut regs.queue_avail_low,
-                (&queue.available as *const _ as u32).into(),
-            );
-            write_volatile(
-                &mut regs.queue_avail_high,
-                ((&queue.available as *const _ as usize >> 32) as u32).into(),
-            );
-            write_volatile(
-                &mut regs.queue_used_low,
-                (&queue.used as *const _ as u32).into(),
-            );
-            write_volatile(
-                &mut regs.queue_used_high,
-                ((&queue.used as *const _ as usize >> 32) as u32).into(),
-            );
-            write_volatile(&mut regs.queue_ready, 1.into());
+        regs.setup_queue(0, queue.len() as u16, queue.addresses());
 
-            write_volatile(&mut regs.status, Status::DriverOk.into());
-        }
+        regs.set_status(Status::DriverOk);
         VirtIOEntropy { regs, queue, irq }
     }
 }
 
 impl<'a> VirtIOEntropy<'a> {
     pub fn read(&mut self, data: &mut [u8]) {
-        unsafe {
-            write_volatile(
-                &mut self.queue.descriptors[0],
-                VirtQDesc {
-                    addr: (data.as_ptr() as *const _ as u64).into(),
-                    len: (data.len() as u32).into(),
-                    flags: (2).into(),
-                    next: 0.into(),
-                },
-            );
-
-            write_volatile(
-                &mut self.queue.available.ring[self.queue.available.idx.native() as usize],
-                0.into(),
-            );
-            mb();
-            write_volatile(
-                &mut self.queue.available.idx,
-                (self.queue.available.idx.native() + 1).into(),
-            );
-            mb();
-            write_volatile(&mut self.regs.queue_notify, 0.into());
-            mb();
-            self.irq.enable();
-            while read_volatile(&self.queue.used.idx).native()
-                != read_volatile(&self.queue.available.idx).native()
-            {
-                //asm!("wfi");
-                let status = read_volatile(&self.regs.interrupt_status);
-                if status.native() != 0 {
-                    write_volatile(&mut self.regs.interrupt_ack, status);
-                    if read_volatile(&self.regs.interrupt_status).native() == status.native() {
-                        panic!("{:#x}", self.regs.interrupt_status.native());
-                    }
-                }
-            }
-            self.irq.disable();
+        self.queue
+            .add(&[Segment::writable(data)])
+            .expect("No free descriptors in entropy queue");
+        if self.queue.should_notify() {
+            self.regs.notify(0);
+        }
+        self.irq.enable();
+        while self.queue.pop_used().is_none() {
+            //asm!("wfi");
+            self.regs.ack_interrupt();
         }
+        self.irq.disable();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::boxed::Box;
+    use alloc::vec;
+
+    use super::super::mock::{irq, MockDevice};
+    use super::super::{DeviceId, Queue};
+    use super::VirtIOEntropy;
+
+    #[test]
+    fn read() {
+        let mut queue = Queue::boxed();
+        let mut device = MockDevice::new(
+            DeviceId::Entropy,
+            0,
+            Box::new(|_, _: &[u8], capacity| vec![0xa5; capacity]),
+        );
+        let mut entropy = VirtIOEntropy::new(&mut device, &mut queue, irq());
+        let mut data = [0u8; 16];
+        entropy.read(&mut data);
+        assert!(data.iter().all(|b| *b == 0xa5), "entropy not filled");
     }
 }
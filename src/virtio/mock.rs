@@ -0,0 +1,524 @@
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ptr::{read_volatile, write_volatile};
+
+use crate::gic::GIC;
+use crate::mutex::Mutex;
+use crate::utils::mb;
+
+use super::packed::{VIRTQ_DESC_F_AVAIL, VIRTQ_DESC_F_USED};
+use super::{
+    DeviceId, Status, Transport, LEGACY_PAGE_SIZE, LEGACY_QUEUE_ALIGN, VIRTIO_F_RING_PACKED,
+    VIRTQ_DESC_F_INDIRECT, VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE,
+};
+
+/// Services one request. Gets the queue index, the contents of the request's
+/// device-readable buffers and the space in its device-writable buffers, and
+/// returns what to write into the latter.
+pub type Handler = Box<dyn FnMut(u16, &[u8], usize) -> Vec<u8> + Send + Sync>;
+
+/// The kind of device a `MockDevice` presents to its driver
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Mode {
+    /// A modern device with split rings
+    Split,
+    /// A modern device that also offers VIRTIO_F_RING_PACKED
+    Packed,
+    /// A legacy (version 1) virtio-mmio device: 32 feature bits, no config
+    /// generation, and rings it finds from the descriptor table's address
+    Legacy,
+}
+
+/// Defines a test per `Mode` for each of the given functions, which take the
+/// mode, as `name::split`, `name::packed` and `name::legacy`
+macro_rules! mode_tests {
+    ($($case:ident),* $(,)?) => {
+        $(
+            mod $case {
+                use $crate::virtio::mock::Mode;
+
+                #[test]
+                fn split() {
+                    super::$case(Mode::Split)
+                }
+
+                #[test]
+                fn packed() {
+                    super::$case(Mode::Packed)
+                }
+
+                #[test]
+                fn legacy() {
+                    super::$case(Mode::Legacy)
+                }
+            }
+        )*
+    };
+}
+pub(super) use mode_tests;
+
+#[derive(Default)]
+struct MockQueue {
+    size: u16,
+    desc: u64,
+    driver: u64,
+    device: u64,
+    packed: bool,
+    /// Next entry of the available ring to take, or for packed rings the
+    /// next descriptor
+    next_avail: u16,
+    /// Packed rings only: the wrap counters and the next descriptor to
+    /// write a completion to
+    avail_wrap: bool,
+    used_wrap: bool,
+    next_used: u16,
+}
+
+/// A request taken from a queue
+struct Request {
+    /// Head descriptor for split rings, buffer ID for packed ones
+    id: u16,
+    /// The `(addr, len, device-writable)` buffers making it up
+    buffers: Vec<(u64, u32, bool)>,
+    /// Ring descriptors it takes up
+    descriptors: u16,
+}
+
+/// An in-memory device implementing the device half of split and packed
+/// virtqueues, so drivers can be exercised without a hypervisor.
+///
+/// Requests are serviced by a `Handler` when the driver notifies, except on
+/// queues in `wait_for_data`, whose requests stay posted while the handler
+/// has nothing to write into them, the way a NIC's receive buffers wait for
+/// frames. Those are retried whenever the driver notifies or checks for an
+/// interrupt. Completions can be held back and then returned in reverse
+/// order to simulate a device that completes requests out of order.
+pub struct MockDevice {
+    device_id: DeviceId,
+    device_features: u64,
+    /// What kind of device to present. Must be set before the driver
+    /// negotiates features.
+    pub mode: Mode,
+    pub driver_features: u64,
+    status: u32,
+    pub config: Vec<u8>,
+    pub config_generation: u32,
+    /// Config a test can swap in while a driver holds the device. It's taken
+    /// up, with a configuration change interrupt, when the driver next
+    /// checks for an interrupt.
+    pub pending_config: Arc<Mutex<Option<Vec<u8>>>>,
+    /// Refuse FEATURES_OK whatever the driver accepted
+    pub reject_features: bool,
+    /// Bitmap of queues whose requests only complete once the handler has
+    /// data for them
+    pub wait_for_data: u64,
+    queues: Vec<MockQueue>,
+    handler: Handler,
+    /// Completions held back: queue, request, bytes written and descriptors
+    held: Vec<(u16, u16, u32, u16)>,
+    hold: usize,
+    /// Requests left to process before the device enters DEVICE_NEEDS_RESET
+    reset_after: Option<usize>,
+    interrupt: u32,
+    /// Requests processed so far
+    pub requests: usize,
+    /// Most descriptors seen in one request's chain
+    pub longest_chain: usize,
+}
+
+impl MockDevice {
+    pub fn new(device_id: DeviceId, device_features: u64, handler: Handler) -> MockDevice {
+        MockDevice {
+            device_id,
+            device_features,
+            mode: Mode::Split,
+            driver_features: 0,
+            status: 0,
+            config: Vec::new(),
+            config_generation: 0,
+            pending_config: Arc::new(Mutex::new(None)),
+            reject_features: false,
+            wait_for_data: 0,
+            queues: Vec::new(),
+            handler,
+            held: Vec::new(),
+            hold: 0,
+            reset_after: None,
+            interrupt: 0,
+            requests: 0,
+            longest_chain: 0,
+        }
+    }
+
+    /// Holds the next `count` completions back and then places them in the
+    /// used ring in reverse order
+    pub fn hold_completions(&mut self, count: usize) {
+        self.hold = count;
+    }
+
+    /// Processes `count` more requests and then fails, setting
+    /// DEVICE_NEEDS_RESET and leaving further requests unanswered
+    pub fn needs_reset_after(&mut self, count: usize) {
+        self.reset_after = Some(count);
+    }
+
+    /// Reads descriptor `index` of `table` as its four fields. Split and
+    /// packed descriptors both take 16 bytes, with the address and length
+    /// first.
+    fn read_desc(table: u64, index: u16) -> (u64, u32, u16, u16) {
+        let desc = (table + 16 * index as u64) as *const u8;
+        unsafe {
+            (
+                u64::from_le(read_volatile(desc as *const u64)),
+                u32::from_le(read_volatile(desc.add(8) as *const u32)),
+                u16::from_le(read_volatile(desc.add(12) as *const u16)),
+                u16::from_le(read_volatile(desc.add(14) as *const u16)),
+            )
+        }
+    }
+
+    /// The next request the driver made available on `queue`, if any
+    fn peek(queue: &MockQueue) -> Option<Request> {
+        if queue.packed {
+            Self::peek_packed(queue)
+        } else {
+            Self::peek_split(queue)
+        }
+    }
+
+    fn peek_split(queue: &MockQueue) -> Option<Request> {
+        let avail_idx = unsafe { u16::from_le(read_volatile((queue.driver + 2) as *const u16)) };
+        if queue.next_avail == avail_idx {
+            return None;
+        }
+        let slot = queue.driver + 4 + 2 * (queue.next_avail % queue.size) as u64;
+        let head = unsafe { u16::from_le(read_volatile(slot as *const u16)) };
+
+        let mut buffers = Vec::new();
+        let (mut table, mut index) = (queue.desc, head);
+        let mut desc = Self::read_desc(table, index);
+        if desc.2 & VIRTQ_DESC_F_INDIRECT != 0 {
+            table = desc.0;
+            index = 0;
+            desc = Self::read_desc(table, index);
+        }
+        loop {
+            let (addr, len, flags, next) = desc;
+            buffers.push((addr, len, flags & VIRTQ_DESC_F_WRITE != 0));
+            if flags & VIRTQ_DESC_F_NEXT == 0 {
+                break;
+            }
+            index = next;
+            desc = Self::read_desc(table, index);
+        }
+        Some(Request {
+            id: head,
+            buffers,
+            descriptors: 1,
+        })
+    }
+
+    fn peek_packed(queue: &MockQueue) -> Option<Request> {
+        let available = |flags: u16, wrap: bool| {
+            (flags & VIRTQ_DESC_F_AVAIL != 0) == wrap && (flags & VIRTQ_DESC_F_USED != 0) != wrap
+        };
+        let (mut index, mut wrap) = (queue.next_avail, queue.avail_wrap);
+        let (addr, len, id, flags) = Self::read_desc(queue.desc, index);
+        if !available(flags, wrap) {
+            return None;
+        }
+        if flags & VIRTQ_DESC_F_INDIRECT != 0 {
+            let buffers = (0..len / 16)
+                .map(|entry| {
+                    let (addr, len, _, flags) = Self::read_desc(addr, entry as u16);
+                    (addr, len, flags & VIRTQ_DESC_F_WRITE != 0)
+                })
+                .collect();
+            return Some(Request {
+                id,
+                buffers,
+                descriptors: 1,
+            });
+        }
+
+        let mut buffers = vec![(addr, len, flags & VIRTQ_DESC_F_WRITE != 0)];
+        let (mut id, mut flags) = (id, flags);
+        while flags & VIRTQ_DESC_F_NEXT != 0 {
+            index += 1;
+            if index == queue.size {
+                index = 0;
+                wrap = !wrap;
+            }
+            let desc = Self::read_desc(queue.desc, index);
+            assert!(
+                available(desc.3, wrap),
+                "chain continues into a used descriptor"
+            );
+            buffers.push((desc.0, desc.1, desc.3 & VIRTQ_DESC_F_WRITE != 0));
+            id = desc.2;
+            flags = desc.3;
+        }
+        let descriptors = buffers.len() as u16;
+        Some(Request {
+            id,
+            buffers,
+            descriptors,
+        })
+    }
+
+    /// Moves past a request taken from `queue`
+    fn consume(queue: &mut MockQueue, request: &Request) {
+        if queue.packed {
+            queue.next_avail += request.descriptors;
+            if queue.next_avail >= queue.size {
+                queue.next_avail -= queue.size;
+                queue.avail_wrap = !queue.avail_wrap;
+            }
+        } else {
+            queue.next_avail = queue.next_avail.wrapping_add(1);
+        }
+    }
+
+    fn process(&mut self, index: u16) {
+        loop {
+            let queue = &self.queues[index as usize];
+            let request = match Self::peek(queue) {
+                Some(request) => request,
+                None => break,
+            };
+            match self.reset_after {
+                Some(0) => {
+                    self.status |= Status::NeedsReset as u32;
+                    self.interrupt |= 2;
+                    break;
+                }
+                Some(ref mut count) => *count -= 1,
+                None => {}
+            }
+
+            let mut input = Vec::new();
+            let mut capacity = 0;
+            for &(addr, len, write) in request.buffers.iter() {
+                if write {
+                    capacity += len as usize;
+                } else {
+                    let data =
+                        unsafe { core::slice::from_raw_parts(addr as *const u8, len as usize) };
+                    input.extend_from_slice(data);
+                }
+            }
+
+            let output = (self.handler)(index, &input, capacity);
+            if output.is_empty() && self.wait_for_data & 1 << index != 0 {
+                break;
+            }
+            self.requests += 1;
+            self.longest_chain = self.longest_chain.max(request.buffers.len());
+            let mut written = 0;
+            for &(addr, len, _) in request.buffers.iter().filter(|b| b.2) {
+                let count = (len as usize).min(output.len() - written);
+                unsafe {
+                    core::ptr::copy_nonoverlapping(
+                        output[written..].as_ptr(),
+                        addr as *mut u8,
+                        count,
+                    );
+                }
+                written += count;
+            }
+
+            Self::consume(&mut self.queues[index as usize], &request);
+            self.complete(index, request.id, written as u32, request.descriptors);
+        }
+    }
+
+    /// Gives the queues in `wait_for_data` another chance at their requests
+    fn process_waiting(&mut self) {
+        for index in 0..self.queues.len() as u16 {
+            if self.wait_for_data & 1 << index != 0 && self.queues[index as usize].size != 0 {
+                self.process(index);
+            }
+        }
+    }
+
+    fn complete(&mut self, index: u16, id: u16, len: u32, descriptors: u16) {
+        if self.hold > 0 {
+            self.held.push((index, id, len, descriptors));
+            if self.held.len() < self.hold {
+                return;
+            }
+            self.hold = 0;
+            while let Some((index, id, len, descriptors)) = self.held.pop() {
+                self.push_used(index, id, len, descriptors);
+            }
+        } else {
+            self.push_used(index, id, len, descriptors);
+        }
+    }
+
+    fn push_used(&mut self, index: u16, id: u16, len: u32, descriptors: u16) {
+        let queue = &mut self.queues[index as usize];
+        if queue.packed {
+            // The completion overwrites the next descriptor in ring order,
+            // and the device then skips as many descriptors as the buffer
+            // took up
+            let desc = (queue.desc + 16 * queue.next_used as u64) as *mut u8;
+            let flags = if queue.used_wrap {
+                VIRTQ_DESC_F_AVAIL | VIRTQ_DESC_F_USED
+            } else {
+                0
+            };
+            unsafe {
+                write_volatile(desc.add(8) as *mut u32, len.to_le());
+                write_volatile(desc.add(12) as *mut u16, id.to_le());
+                mb();
+                write_volatile(desc.add(14) as *mut u16, flags.to_le());
+            }
+            queue.next_used += descriptors;
+            if queue.next_used >= queue.size {
+                queue.next_used -= queue.size;
+                queue.used_wrap = !queue.used_wrap;
+            }
+        } else {
+            unsafe {
+                let idx = u16::from_le(read_volatile((queue.device + 2) as *const u16));
+                let element = (queue.device + 4 + 8 * (idx % queue.size) as u64) as *mut u32;
+                write_volatile(element, (id as u32).to_le());
+                write_volatile(element.add(1), len.to_le());
+                mb();
+                write_volatile((queue.device + 2) as *mut u16, idx.wrapping_add(1).to_le());
+            }
+        }
+        self.interrupt |= 1;
+    }
+}
+
+impl Transport for MockDevice {
+    fn device_id(&self) -> DeviceId {
+        self.device_id
+    }
+
+    fn device_features(&mut self) -> u64 {
+        match self.mode {
+            Mode::Split => self.device_features,
+            Mode::Packed => self.device_features | VIRTIO_F_RING_PACKED,
+            Mode::Legacy => self.device_features & 0xffff_ffff,
+        }
+    }
+
+    fn set_driver_features(&mut self, features: u64) {
+        self.driver_features = features;
+    }
+
+    fn status(&self) -> u32 {
+        self.status
+    }
+
+    fn set_status(&mut self, status: Status) {
+        let status = status as u32;
+        if status == Status::FeaturesOk as u32
+            && (self.reject_features || self.driver_features & !self.device_features() != 0)
+        {
+            // Accepting features the device didn't offer fails negotiation
+            return;
+        }
+        if status == 0 {
+            self.queues.clear();
+            self.driver_features = 0;
+        }
+        self.status = status;
+    }
+
+    fn max_queue_size(&mut self, _index: u16) -> u16 {
+        1024
+    }
+
+    fn setup_queue(&mut self, index: u16, size: u16, (desc, driver, device): (u64, u64, u64)) {
+        let (driver, device) = if self.mode == Mode::Legacy {
+            // A legacy device is only given the descriptor table's page, and
+            // expects the rings to follow it
+            let page_size = LEGACY_PAGE_SIZE as u64;
+            let align = LEGACY_QUEUE_ALIGN as u64;
+            assert!(desc % page_size == 0, "legacy queue not page-aligned");
+            let driver = desc + 16 * size as u64;
+            let device = (driver + 6 + 2 * size as u64 + align - 1) & !(align - 1);
+            (driver, device)
+        } else {
+            (driver, device)
+        };
+        if self.queues.len() <= index as usize {
+            self.queues
+                .resize_with(index as usize + 1, MockQueue::default);
+        }
+        self.queues[index as usize] = MockQueue {
+            size,
+            desc,
+            driver,
+            device,
+            packed: self.driver_features & VIRTIO_F_RING_PACKED != 0,
+            next_avail: 0,
+            avail_wrap: true,
+            used_wrap: true,
+            next_used: 0,
+        };
+    }
+
+    fn notify(&mut self, index: u16) {
+        self.process(index);
+        self.process_waiting();
+    }
+
+    fn ack_interrupt(&mut self) -> u32 {
+        if let Some(config) = self.pending_config.lock().take() {
+            self.config = config;
+            self.config_generation += 1;
+            self.interrupt |= 2;
+        }
+        self.process_waiting();
+        core::mem::replace(&mut self.interrupt, 0)
+    }
+
+    fn config_generation(&self) -> u32 {
+        if self.mode == Mode::Legacy {
+            0
+        } else {
+            self.config_generation
+        }
+    }
+
+    fn read_config(&self, offset: usize, data: &mut [u8]) {
+        for (i, b) in data.iter_mut().enumerate() {
+            *b = self.config.get(offset + i).copied().unwrap_or(0);
+        }
+    }
+
+    fn write_config(&mut self, offset: usize, data: &[u8]) {
+        if self.config.len() < offset + data.len() {
+            self.config.resize(offset + data.len(), 0);
+        }
+        self.config[offset..offset + data.len()].copy_from_slice(data);
+    }
+}
+
+pub fn irq() -> GIC {
+    // The mock never raises a real interrupt; SGI 0 is harmless to toggle.
+    unsafe { GIC::new(0) }
+}
+
+pub fn echo_handler() -> Handler {
+    Box::new(|_, input: &[u8], capacity| input.iter().copied().take(capacity).collect())
+}
+
+pub fn le32(bytes: &[u8]) -> u32 {
+    let mut word = [0; 4];
+    word.copy_from_slice(&bytes[..4]);
+    u32::from_le_bytes(word)
+}
+
+pub fn le64(bytes: &[u8]) -> u64 {
+    let mut word = [0; 8];
+    word.copy_from_slice(&bytes[..8]);
+    u64::from_le_bytes(word)
+}
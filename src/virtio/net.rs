@@ -1,26 +1,284 @@
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use crate::gic::GIC;
+use crate::mutex::Mutex;
 use crate::utils::*;
-use core::ptr::{read_volatile, write_volatile};
 
-use super::{Status, VirtIORegs, VirtQDesc, VirtQUsed, VirtqAvailable};
+use super::{
+    Queue, Segment, Status, Transport, VIRTIO_F_INDIRECT_DESC, VIRTIO_F_RING_PACKED,
+    VIRTIO_F_VERSION_1,
+};
 
 type LEU16 = Endian<u16, Little>;
+type LEU32 = Endian<u32, Little>;
+
+const VIRTIO_NET_F_CSUM: u64 = 1 << 0;
+const VIRTIO_NET_F_GUEST_CSUM: u64 = 1 << 1;
+const VIRTIO_NET_F_MTU: u64 = 1 << 3;
+const VIRTIO_NET_F_MAC: u64 = 1 << 5;
+const VIRTIO_NET_F_GUEST_TSO4: u64 = 1 << 7;
+const VIRTIO_NET_F_GUEST_TSO6: u64 = 1 << 8;
+const VIRTIO_NET_F_GUEST_UFO: u64 = 1 << 10;
+const VIRTIO_NET_F_HOST_TSO4: u64 = 1 << 11;
+const VIRTIO_NET_F_HOST_TSO6: u64 = 1 << 12;
+const VIRTIO_NET_F_HOST_UFO: u64 = 1 << 14;
+const VIRTIO_NET_F_MRG_RXBUF: u64 = 1 << 15;
+const VIRTIO_NET_F_STATUS: u64 = 1 << 16;
+const VIRTIO_NET_F_CTRL_VQ: u64 = 1 << 17;
+const VIRTIO_NET_F_CTRL_RX: u64 = 1 << 18;
+const VIRTIO_NET_F_GUEST_ANNOUNCE: u64 = 1 << 21;
+const VIRTIO_NET_F_MQ: u64 = 1 << 22;
+const VIRTIO_NET_F_CTRL_MAC_ADDR: u64 = 1 << 23;
+const VIRTIO_NET_F_RSS: u64 = 1 << 60;
+
+const GUEST_GSO: u64 = VIRTIO_NET_F_GUEST_TSO4 | VIRTIO_NET_F_GUEST_TSO6 | VIRTIO_NET_F_GUEST_UFO;
+const HOST_GSO: u64 = VIRTIO_NET_F_HOST_TSO4 | VIRTIO_NET_F_HOST_TSO6 | VIRTIO_NET_F_HOST_UFO;
+
+const VIRTIO_NET_HDR_F_NEEDS_CSUM: u8 = 1;
+const VIRTIO_NET_HDR_F_DATA_VALID: u8 = 2;
+
+const VIRTIO_NET_HDR_GSO_TCPV4: u8 = 1;
+const VIRTIO_NET_HDR_GSO_UDP: u8 = 3;
+const VIRTIO_NET_HDR_GSO_TCPV6: u8 = 4;
+const VIRTIO_NET_HDR_GSO_ECN: u8 = 0x80;
+
+const VIRTIO_NET_S_LINK_UP: u16 = 1;
+const VIRTIO_NET_S_ANNOUNCE: u16 = 2;
+
+const VIRTIO_NET_CTRL_RX: u8 = 0;
+const VIRTIO_NET_CTRL_RX_PROMISC: u8 = 0;
+const VIRTIO_NET_CTRL_RX_ALLMULTI: u8 = 1;
+const VIRTIO_NET_CTRL_MAC: u8 = 1;
+const VIRTIO_NET_CTRL_MAC_TABLE_SET: u8 = 0;
+const VIRTIO_NET_CTRL_MAC_ADDR_SET: u8 = 1;
+const VIRTIO_NET_CTRL_ANNOUNCE: u8 = 3;
+const VIRTIO_NET_CTRL_ANNOUNCE_ACK: u8 = 0;
+const VIRTIO_NET_CTRL_MQ: u8 = 4;
+const VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET: u8 = 0;
+const VIRTIO_NET_CTRL_MQ_RSS_CONFIG: u8 = 1;
+
+/// RSS hash types over the IPv4 and IPv6 addresses, and the TCP and UDP
+/// ports on top of them
+const RSS_HASH_TYPES: u32 = 0x3f;
+/// Entries in the RSS indirection table, if the device takes that many
+const RSS_TABLE_LEN: usize = 128;
+/// The Toeplitz key from the RSS specification, cut to the length the
+/// device takes
+const RSS_KEY: [u8; 40] = [
+    0x6d, 0x5a, 0x56, 0xda, 0x25, 0x5b, 0x0e, 0xc2, 0x41, 0x67, 0x25, 0x3d, 0x43, 0xa3, 0x8f, 0xb0,
+    0xd0, 0xca, 0x2b, 0xcb, 0xae, 0x7b, 0x30, 0xb4, 0x77, 0xcb, 0x2d, 0xa3, 0x80, 0x30, 0xf2, 0x0c,
+    0x6a, 0x42, 0xb7, 0x3b, 0xbe, 0xac, 0x01, 0xfa,
+];
+
+const VIRTIO_NET_OK: u8 = 0;
+
+/// Interrupt status bit for a configuration change
+const INTERRUPT_CONFIG_CHANGE: u32 = 2;
+
+/// Ethernet header: destination and source MAC and ethertype
+pub const ETH_HEADER_LEN: usize = 14;
+/// MTU when the device doesn't report one
+pub const DEFAULT_MTU: usize = 1500;
+/// Largest frame segmentation offload handles, Ethernet header included.
+/// With the 12-byte header this is the 65562-byte receive buffer the spec
+/// asks for.
+pub const GSO_MAX_LEN: usize = ETH_HEADER_LEN + 65536;
+/// Receive buffers kept posted to the device
+const RX_BUFFERS: usize = 64;
+/// Size of each receive buffer, header included, when frames can be merged
+/// from several
+const MERGEABLE_BUFFER_SIZE: usize = 2048;
+/// Received frames kept waiting to be read before more are dropped
+const RX_BACKLOG: usize = 128;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum NetError {
+    /// The frame is longer than the MTU allows
+    FrameTooLong,
+    /// The device didn't offer the segmentation offload asked for
+    OffloadUnsupported,
+    /// The checksum or segmentation asked for doesn't fit the frame
+    InvalidOffload,
+    /// The device didn't offer the control command
+    Unsupported,
+    /// The device answered a control command with VIRTIO_NET_ERR
+    CommandFailed,
+    /// The control queue has no room for the command
+    QueueFull,
+    /// The device set DEVICE_NEEDS_RESET, so it won't answer until it's
+    /// reset
+    NeedsReset,
+}
+
+impl NetError {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            NetError::FrameTooLong => "frame too long",
+            NetError::OffloadUnsupported => "offload not supported by the device",
+            NetError::InvalidOffload => "invalid offload",
+            NetError::Unsupported => "not supported by the device",
+            NetError::CommandFailed => "device rejected the command",
+            NetError::QueueFull => "control queue full",
+            NetError::NeedsReset => "device needs reset",
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum GsoType {
+    Tcp4,
+    Tcp6,
+    /// UDP fragmentation offload: the datagram is split into IP fragments
+    Udp,
+}
+
+impl GsoType {
+    fn from_raw(gso_type: u8) -> Option<GsoType> {
+        match gso_type & !VIRTIO_NET_HDR_GSO_ECN {
+            VIRTIO_NET_HDR_GSO_TCPV4 => Some(GsoType::Tcp4),
+            VIRTIO_NET_HDR_GSO_TCPV6 => Some(GsoType::Tcp6),
+            VIRTIO_NET_HDR_GSO_UDP => Some(GsoType::Udp),
+            _ => None,
+        }
+    }
+
+    fn raw(self) -> u8 {
+        match self {
+            GsoType::Tcp4 => VIRTIO_NET_HDR_GSO_TCPV4,
+            GsoType::Tcp6 => VIRTIO_NET_HDR_GSO_TCPV6,
+            GsoType::Udp => VIRTIO_NET_HDR_GSO_UDP,
+        }
+    }
+
+    /// The feature the device needs to segment frames of this type
+    fn host_feature(self) -> u64 {
+        match self {
+            GsoType::Tcp4 => VIRTIO_NET_F_HOST_TSO4,
+            GsoType::Tcp6 => VIRTIO_NET_F_HOST_TSO6,
+            GsoType::Udp => VIRTIO_NET_F_HOST_UFO,
+        }
+    }
+}
+
+/// A frame larger than the MTU, cut into segments by the device on the way
+/// out, or one the device coalesced from several on the way in
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Segmentation {
+    pub gso_type: GsoType,
+    /// Payload bytes in each segment: the MSS, or the fragment size for UDP
+    pub size: u16,
+    /// Bytes of Ethernet, IP and TCP/UDP headers repeated in each segment
+    pub header_len: u16,
+}
 
+/// Work left to the device for a frame being sent
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct Offload {
+    /// Where the TCP/UDP/ICMP checksum starts, and the offset from there of
+    /// its field. The field holds the pseudo-header sum, and gets the
+    /// checksum of everything from the start to the end of the frame.
+    pub checksum: Option<(u16, u16)>,
+    /// Segmentation, which needs `checksum` too
+    pub segmentation: Option<Segmentation>,
+}
+
+/// What the device says about a received frame
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct RxInfo {
+    pub len: usize,
+    /// The TCP/UDP checksum is known to be right, so needn't be checked
+    pub checksum_valid: bool,
+    pub segmentation: Option<Segmentation>,
+}
+
+/// Traffic counters and link state, shared so they can be read while the
+/// driver is busy
+#[derive(Default, Debug)]
+pub struct NetStats {
+    pub link_up: AtomicBool,
+    pub rx_packets: AtomicU64,
+    pub rx_bytes: AtomicU64,
+    /// Frames received while the backlog was full
+    pub rx_dropped: AtomicU64,
+    pub tx_packets: AtomicU64,
+    pub tx_bytes: AtomicU64,
+}
+
+/// A received frame, spread over the first `buffers` buffers of
+/// `VirtIONet::received`
+struct Frame {
+    buffers: usize,
+    len: usize,
+    /// Buffers the device has yet to return before the frame is whole
+    missing: usize,
+}
+
+/// What the queue pairs of one device share
+struct Shared<'a> {
+    regs: Mutex<&'a mut dyn Transport>,
+    ctrl_queue: Mutex<&'a mut Queue<128>>,
+    ctrl_index: u16,
+    features: u64,
+    config: Mutex<VirtIONetConfig>,
+    stats: Arc<NetStats>,
+    /// The device has asked for gratuitous packets announcing where we are
+    announce: AtomicBool,
+}
+
+/// One receive/transmit queue pair of a device
 pub struct VirtIONet<'a> {
-    regs: &'a mut VirtIORegs<VirtIONetConfig>,
-    read_queue: &'a mut super::Queue<128>,
-    write_queue: &'a mut super::Queue<128>,
-    irq: crate::gic::GIC,
+    shared: Arc<Shared<'a>>,
+    read_queue: &'a mut Queue<128>,
+    write_queue: &'a mut Queue<128>,
+    /// Index of the receive queue; the transmit queue is the next one
+    queue_index: u16,
+    irq: GIC,
+    /// The pair has an interrupt of its own rather than the device's shared
+    /// one, so it can be routed to the core serving the pair
+    own_irq: bool,
+    features: u64,
+    mtu: usize,
+    /// Bytes of `NetHdr` in front of each frame
+    header_len: usize,
+    /// Size of receive buffers, header included
+    rx_buffer_size: usize,
+    /// Buffers the device holds, indexed by token
+    rx_posted: Vec<Option<Box<[u8]>>>,
+    rx_posted_count: usize,
+    /// Filled buffers waiting to be read, oldest first, with their lengths
+    received: VecDeque<(Box<[u8]>, usize)>,
+    frames: VecDeque<Frame>,
+    /// Buffers still to come of a frame that's being dropped
+    dropping: usize,
+    tx_in_flight: Vec<Option<Box<[u8]>>>,
+    /// Buffers free for reuse, so steady traffic doesn't allocate
+    rx_spare: Vec<Box<[u8]>>,
+    tx_spare: Vec<Box<[u8]>>,
+    stats: Arc<NetStats>,
 }
 
 #[repr(C)]
-#[derive(Debug, Default)]
+#[derive(Clone, Copy, Debug, Default)]
 pub struct VirtIONetConfig {
     pub mac: [u8; 6],
     pub status: LEU16,
     pub max_virtqueue_pairs: LEU16,
     pub mtu: LEU16,
+    /// In Mbit/s
+    pub speed: LEU32,
+    pub duplex: u8,
+    pub rss_max_key_size: u8,
+    pub rss_max_indirection_table_length: LEU16,
+    pub supported_hash_types: LEU32,
 }
 
+/// Header in front of every frame. Legacy devices leave out `num_buffers`
+/// unless VIRTIO_NET_F_MRG_RXBUF is negotiated.
 #[repr(C)]
 #[derive(Debug, Default)]
 pub struct NetHdr {
@@ -30,165 +288,1309 @@ pub struct NetHdr {
     pub gso_size: LEU16,
     pub csum_start: LEU16,
     pub csum_offset: LEU16,
+    /// Buffers a merged received frame spans
+    pub num_buffers: LEU16,
+}
+
+impl NetHdr {
+    fn read(buffer: &[u8]) -> NetHdr {
+        let mut header = NetHdr::default();
+        let len = buffer.len().min(core::mem::size_of::<NetHdr>());
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                buffer.as_ptr(),
+                &mut header as *mut NetHdr as *mut u8,
+                len,
+            );
+        }
+        header
+    }
+
+    fn write(&self, buffer: &mut [u8]) {
+        let len = buffer.len().min(core::mem::size_of::<NetHdr>());
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                self as *const NetHdr as *const u8,
+                buffer.as_mut_ptr(),
+                len,
+            );
+        }
+    }
 }
 
-const NET_DEVICE_FEATURES: u32 = 1 << 5; // VIRTIO_NET_F_MAC
+const NET_DEVICE_FEATURES: u64 = VIRTIO_NET_F_CSUM
+    | VIRTIO_NET_F_GUEST_CSUM
+    | VIRTIO_NET_F_MTU
+    | VIRTIO_NET_F_MAC
+    | GUEST_GSO
+    | HOST_GSO
+    | VIRTIO_NET_F_MRG_RXBUF
+    | VIRTIO_NET_F_STATUS
+    | VIRTIO_NET_F_CTRL_VQ
+    | VIRTIO_NET_F_CTRL_RX
+    | VIRTIO_NET_F_GUEST_ANNOUNCE
+    | VIRTIO_NET_F_CTRL_MAC_ADDR
+    | VIRTIO_F_INDIRECT_DESC
+    | VIRTIO_F_VERSION_1
+    | VIRTIO_F_RING_PACKED;
 
 impl<'a> VirtIONet<'a> {
     pub fn new(
-        regs: &'a mut VirtIORegs<VirtIONetConfig>,
-        read_queue: &'a mut super::Queue<128>,
-        write_queue: &'a mut super::Queue<128>,
-        irq: crate::gic::GIC,
+        regs: &'a mut dyn Transport,
+        read_queue: &'a mut Queue<128>,
+        write_queue: &'a mut Queue<128>,
+        ctrl_queue: &'a mut Queue<128>,
+        irq: GIC,
     ) -> Self {
-        unsafe {
-            write_volatile(&mut regs.status, Status::Reset.into());
-            write_volatile(&mut regs.status, Status::Acknowledge.into());
-            write_volatile(&mut regs.status, Status::Driver.into());
-
-            write_volatile(&mut regs.device_features_sel, 0.into());
-            let device_features = read_volatile(&mut regs.device_features).native();
-            write_volatile(&mut regs.driver_features_sel, 0.into());
-            write_volatile(
-                &mut regs.driver_features,
-                (NET_DEVICE_FEATURES & device_features).into(),
-            );
+        let mut pairs =
+            Self::new_multiqueue(regs, vec![(read_queue, write_queue)], ctrl_queue, irq);
+        pairs.remove(0)
+    }
 
-            write_volatile(&mut regs.status, Status::FeaturesOk.into());
-            if read_volatile(&mut regs.status).native() & (Status::FeaturesOk as u32) == 0 {
-                panic!("Coudln't set blk features");
+    /// Sets the device up with as many of the receive/transmit queue `pairs`
+    /// as it supports, returning a driver for each. The device spreads
+    /// received flows over the pairs, by RSS if it can, so each can be
+    /// served from a core of its own: see `set_core`. That takes per-queue
+    /// MSI-X vectors; without them every pair shares `irq`.
+    pub fn new_multiqueue(
+        regs: &'a mut dyn Transport,
+        mut pairs: Vec<(&'a mut Queue<128>, &'a mut Queue<128>)>,
+        ctrl_queue: &'a mut Queue<128>,
+        irq: GIC,
+    ) -> Vec<Self> {
+        regs.set_status(Status::Reset);
+        regs.set_status(Status::Acknowledge);
+        regs.set_status(Status::Driver);
+
+        let mut wanted = NET_DEVICE_FEATURES;
+        if pairs.len() > 1 {
+            wanted |= VIRTIO_NET_F_MQ | VIRTIO_NET_F_RSS;
+        }
+        let mut driver_features = wanted & regs.device_features();
+        // Segmentation offloads depend on the checksum offloads
+        if driver_features & VIRTIO_NET_F_CSUM == 0 {
+            driver_features &= !HOST_GSO;
+        }
+        if driver_features & VIRTIO_NET_F_GUEST_CSUM == 0 {
+            driver_features &= !GUEST_GSO;
+        }
+        // As do the features that work through the control queue
+        if driver_features & VIRTIO_NET_F_CTRL_VQ == 0 {
+            driver_features &= !(VIRTIO_NET_F_CTRL_RX
+                | VIRTIO_NET_F_GUEST_ANNOUNCE
+                | VIRTIO_NET_F_CTRL_MAC_ADDR
+                | VIRTIO_NET_F_MQ
+                | VIRTIO_NET_F_RSS);
+        }
+        if driver_features & VIRTIO_NET_F_MQ == 0 {
+            driver_features &= !VIRTIO_NET_F_RSS;
+        }
+        regs.set_driver_features(driver_features);
+
+        regs.set_status(Status::FeaturesOk);
+        if regs.status() & (Status::FeaturesOk as u32) == 0 {
+            panic!("Coudln't set blk features");
+        }
+
+        let config: VirtIONetConfig = super::read_config(&*regs);
+        // The control queue comes after every queue pair the device has
+        let (num_pairs, ctrl_index) = match driver_features & VIRTIO_NET_F_MQ {
+            0 => (1, 2),
+            _ => {
+                let max_pairs = config.max_virtqueue_pairs.native().max(1);
+                (max_pairs as usize, 2 * max_pairs)
             }
+        };
+        pairs.truncate(num_pairs);
+
+        for (index, (read_queue, write_queue)) in pairs.iter_mut().enumerate() {
+            let index = 2 * index as u16;
+            read_queue.negotiated(driver_features);
+            write_queue.negotiated(driver_features);
+            regs.setup_queue(index, read_queue.len() as u16, read_queue.addresses());
+            regs.setup_queue(index + 1, write_queue.len() as u16, write_queue.addresses());
+        }
+        ctrl_queue.negotiated(driver_features);
+        if driver_features & VIRTIO_NET_F_CTRL_VQ != 0 {
+            regs.setup_queue(ctrl_index, ctrl_queue.len() as u16, ctrl_queue.addresses());
+        }
+        // A pair only has a vector of its own if no other pair's queue was
+        // left to share it when the vectors ran short
+        let vectors: Vec<Option<u32>> = (0..2 * pairs.len() as u16)
+            .map(|index| regs.queue_irq(index))
+            .collect();
+        let irqs: Vec<Option<u32>> = (0..pairs.len())
+            .map(|pair| {
+                vectors[2 * pair].filter(|vector| {
+                    (0..vectors.len())
+                        .filter(|&index| index / 2 != pair)
+                        .all(|index| vectors[index] != Some(*vector))
+                })
+            })
+            .collect();
+
+        regs.set_status(Status::DriverOk);
+        let stats = Arc::new(NetStats::default());
+        let shared = Arc::new(Shared {
+            regs: Mutex::new(regs),
+            ctrl_queue: Mutex::new(ctrl_queue),
+            ctrl_index,
+            features: driver_features,
+            config: Mutex::new(config),
+            stats: stats.clone(),
+            announce: AtomicBool::new(false),
+        });
+        shared.config_changed();
+        // The device starts out using only the first pair
+        if pairs.len() > 1 && shared.set_queue_pairs(&irq, pairs.len()).is_err() {
+            pairs.truncate(1);
+        }
+
+        let header_len = match driver_features & (VIRTIO_F_VERSION_1 | VIRTIO_NET_F_MRG_RXBUF) {
+            0 => core::mem::size_of::<NetHdr>() - 2,
+            _ => core::mem::size_of::<NetHdr>(),
+        };
+        let mtu = match driver_features & VIRTIO_NET_F_MTU {
+            0 => DEFAULT_MTU,
+            _ => config.mtu.native() as usize,
+        };
+        pairs
+            .into_iter()
+            .zip(irqs)
+            .enumerate()
+            .map(|(index, ((read_queue, write_queue), queue_irq))| {
+                let mut net = VirtIONet {
+                    shared: shared.clone(),
+                    queue_index: 2 * index as u16,
+                    irq: unsafe { GIC::new(queue_irq.unwrap_or(irq.irq())) },
+                    own_irq: queue_irq.is_some(),
+                    features: driver_features,
+                    mtu,
+                    header_len,
+                    rx_buffer_size: 0,
+                    rx_posted: (0..read_queue.len()).map(|_| None).collect(),
+                    rx_posted_count: 0,
+                    received: VecDeque::new(),
+                    frames: VecDeque::new(),
+                    dropping: 0,
+                    tx_in_flight: (0..write_queue.len()).map(|_| None).collect(),
+                    rx_spare: Vec::new(),
+                    tx_spare: Vec::new(),
+                    stats: stats.clone(),
+                    read_queue,
+                    write_queue,
+                };
+                net.rx_buffer_size = match driver_features & VIRTIO_NET_F_MRG_RXBUF {
+                    0 => header_len + net.max_receive_len(),
+                    _ => MERGEABLE_BUFFER_SIZE,
+                };
+                net.fill_rx();
+                net
+            })
+            .collect()
+    }
+}
 
-            for (i, queue) in [&read_queue, &write_queue].iter().enumerate() {
-                write_volatile(&mut regs.queue_sel, (i as u32).into());
-                write_volatile(&mut regs.queue_num, (queue.descriptors.len() as u32).into());
-                write_volatile(
-                    &mut regs.queue_desc_low,
-                    ((queue.descriptors.as_ptr() as usize) as u32).into(),
-                );
-                write_volatile(
-                    &mut regs.queue_desc_high,
-                    ((queue.descriptors.as_ptr() as usize >> 32) as u32).into(),
-                );
-                write_volatile(
-                    &mut regs.queue_avail_low,
-                    (&queue.available as *const VirtqAvailable as u32).into(),
-                );
-                write_volatile(
-                    &mut regs.queue_avail_high,
-                    ((&queue.available as *const VirtqAvailable as usize >> 32) as u32).into(),
-                );
-                write_volatile(
-                    &mut regs.queue_used_low,
-                    (&queue.used as *const VirtQUsed as u32).into(),
-                );
-                write_volatile(
-                    &mut regs.queue_used_high,
-                    ((&queue.used as *const VirtQUsed as usize >> 32) as u32).into(),
-                );
-                write_volatile(&mut regs.queue_ready, 1.into());
+impl<'a> Shared<'a> {
+    fn link_up(&self) -> bool {
+        match self.features & VIRTIO_NET_F_STATUS {
+            0 => true,
+            _ => self.config.lock().status.native() & VIRTIO_NET_S_LINK_UP != 0,
+        }
+    }
+
+    /// Acknowledges the interrupt, picking up configuration changes. With
+    /// per-queue MSI-X vectors there's nothing to acknowledge and
+    /// configuration changes come on a vector of their own; otherwise the
+    /// pairs share one interrupt, so any of them may get here first.
+    fn ack_interrupt(&self) {
+        let status = self.regs.lock().ack_interrupt();
+        if status & INTERRUPT_CONFIG_CHANGE != 0 {
+            self.config_changed();
+        }
+    }
+
+    /// Rereads the link status and whether an announcement is wanted
+    fn config_changed(&self) {
+        let config: VirtIONetConfig = super::read_config(&**self.regs.lock());
+        self.config.lock().status = config.status;
+        self.stats.link_up.store(self.link_up(), Ordering::Relaxed);
+        if self.features & VIRTIO_NET_F_GUEST_ANNOUNCE != 0
+            && config.status.native() & VIRTIO_NET_S_ANNOUNCE != 0
+        {
+            self.announce.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// If the device set DEVICE_NEEDS_RESET, resets it, so it lets go of the
+    /// buffers it holds, and marks it FAILED so nothing more is sent to it.
+    /// Returns whether it did, or had already been given up on.
+    fn give_up_if_broken(&self) -> bool {
+        let mut regs = self.regs.lock();
+        let status = regs.status();
+        if status & Status::Failed as u32 != 0 {
+            return true;
+        }
+        if status & Status::NeedsReset as u32 == 0 {
+            return false;
+        }
+        regs.set_status(Status::Reset);
+        while regs.status() != 0 {}
+        regs.set_status(Status::Failed);
+        true
+    }
+
+    /// Sends a command on the control queue and waits for the device's
+    /// answer
+    fn control(&self, irq: &GIC, class: u8, command: u8, data: &[u8]) -> Result<(), NetError> {
+        let header = [class, command];
+        let mut ack = !VIRTIO_NET_OK;
+        let mut segments = vec![Segment::readable(&header)];
+        if !data.is_empty() {
+            segments.push(Segment::readable(data));
+        }
+        segments.push(Segment::writable(&mut ack));
+        let mut ctrl_queue = self.ctrl_queue.lock();
+        if ctrl_queue.add(&segments).is_none() {
+            return Err(NetError::QueueFull);
+        }
+        self.regs.lock().notify(self.ctrl_index);
+        irq.enable();
+        while ctrl_queue.pop_used().is_none() {
+            //asm!("wfi");
+            self.ack_interrupt();
+            if self.give_up_if_broken() {
+                irq.disable();
+                return Err(NetError::NeedsReset);
             }
+        }
+        irq.disable();
+        match unsafe { core::ptr::read_volatile(&ack) } {
+            VIRTIO_NET_OK => Ok(()),
+            _ => Err(NetError::CommandFailed),
+        }
+    }
 
-            write_volatile(&mut regs.status, Status::DriverOk.into());
+    /// Has the device use `pairs` queue pairs, steering flows between them
+    /// with RSS where it can
+    fn set_queue_pairs(&self, irq: &GIC, pairs: usize) -> Result<(), NetError> {
+        if self.features & VIRTIO_NET_F_RSS != 0 {
+            let rss = self.rss_config(pairs);
+            if self
+                .control(irq, VIRTIO_NET_CTRL_MQ, VIRTIO_NET_CTRL_MQ_RSS_CONFIG, &rss)
+                .is_ok()
+            {
+                return Ok(());
+            }
         }
-        VirtIONet {
-            regs,
-            read_queue,
-            write_queue,
+        let pairs = (pairs as u16).to_le_bytes();
+        self.control(
             irq,
+            VIRTIO_NET_CTRL_MQ,
+            VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET,
+            &pairs,
+        )
+    }
+
+    /// The RSS configuration spreading flows evenly over `pairs` receive
+    /// queues
+    fn rss_config(&self, pairs: usize) -> Vec<u8> {
+        let config = *self.config.lock();
+        let hash_types = config.supported_hash_types.native() & RSS_HASH_TYPES;
+        // The table's length has to be a power of two
+        let max_len = (config.rss_max_indirection_table_length.native() as usize).max(1);
+        let table_len = RSS_TABLE_LEN.min(1 << (usize::BITS - 1 - max_len.leading_zeros()));
+        let key = &RSS_KEY[..(config.rss_max_key_size as usize).min(RSS_KEY.len())];
+
+        let mut rss = Vec::new();
+        rss.extend_from_slice(&hash_types.to_le_bytes());
+        rss.extend_from_slice(&(table_len as u16 - 1).to_le_bytes());
+        // Frames that can't be hashed go to the first queue
+        rss.extend_from_slice(&0u16.to_le_bytes());
+        for entry in 0..table_len {
+            rss.extend_from_slice(&((entry % pairs) as u16).to_le_bytes());
         }
+        rss.extend_from_slice(&(pairs as u16).to_le_bytes());
+        rss.push(key.len() as u8);
+        rss.extend_from_slice(key);
+        rss
     }
 }
 
 impl<'a> VirtIONet<'a> {
-    pub fn config(&self) -> &VirtIONetConfig {
-        unsafe { &*(&self.regs.config as *const _ as *const VirtIONetConfig) }
+    pub fn config(&self) -> VirtIONetConfig {
+        super::read_config(&**self.shared.regs.lock())
     }
 
-    fn enqueue(&mut self, qnum: u32, descriptor: u16) {
-        let queue = match qnum {
-            0 => &mut self.read_queue,
-            _ => &mut self.write_queue,
-        };
+    pub fn mac(&self) -> [u8; 6] {
+        self.shared.config.lock().mac
+    }
 
-        queue.available.ring[queue.available.idx.native() as usize % queue.available.ring.len()] =
-            descriptor.into();
-        mb();
+    /// Routes this pair's interrupt to `core`, the one serving it. Does
+    /// nothing unless the transport gave the pair an MSI-X vector of its own:
+    /// a shared interrupt can't follow every pair to its core.
+    pub fn set_core(&self, core: u32) {
+        if self.own_irq {
+            crate::gic::set_core(self.irq.irq(), core);
+        }
+    }
 
-        queue.available.idx = (queue.available.idx.native().wrapping_add(1)).into();
-        mb();
-        self.regs.queue_notify = qnum.into();
+    /// Whether the link is up, as far as the device says. Devices without
+    /// VIRTIO_NET_F_STATUS are taken to always be.
+    pub fn link_up(&self) -> bool {
+        self.shared.link_up()
     }
 
-    pub fn read(&mut self, data: &mut [u8; 1526]) {
-        let mut blkreq_hdr = NetHdr {
-            ..Default::default()
-        };
+    /// Whether the device wants gratuitous ARPs sent, after the VM moved
+    /// host
+    pub fn announce_pending(&self) -> bool {
+        self.shared.announce.load(Ordering::Relaxed)
+    }
+
+    /// Claims a pending announcement. Only one of the queue pairs sharing
+    /// the device gets true, and it should send the gratuitous ARPs and then
+    /// call `announced`.
+    pub fn take_announce(&self) -> bool {
+        self.shared.announce.swap(false, Ordering::Relaxed)
+    }
+
+    /// Tells the device its announcement has gone out
+    pub fn announced(&mut self) -> Result<(), NetError> {
+        if self.features & VIRTIO_NET_F_GUEST_ANNOUNCE == 0 {
+            return Err(NetError::Unsupported);
+        }
+        self.control(VIRTIO_NET_CTRL_ANNOUNCE, VIRTIO_NET_CTRL_ANNOUNCE_ACK, &[])
+    }
+
+    /// Receives every frame, whoever it's addressed to
+    pub fn set_promiscuous(&mut self, on: bool) -> Result<(), NetError> {
+        self.rx_mode(VIRTIO_NET_CTRL_RX_PROMISC, on)
+    }
+
+    /// Receives every multicast frame, not just those in the filter
+    pub fn set_all_multicast(&mut self, on: bool) -> Result<(), NetError> {
+        self.rx_mode(VIRTIO_NET_CTRL_RX_ALLMULTI, on)
+    }
 
-        self.read_queue.descriptors[0] = VirtQDesc {
-            addr: (&mut blkreq_hdr as *mut _ as u64).into(),
-            len: (core::mem::size_of::<NetHdr>() as u32).into(),
-            flags: (3).into(),
-            next: 1.into(),
+    fn rx_mode(&mut self, command: u8, on: bool) -> Result<(), NetError> {
+        if self.features & VIRTIO_NET_F_CTRL_RX == 0 {
+            return Err(NetError::Unsupported);
+        }
+        self.control(VIRTIO_NET_CTRL_RX, command, &[on as u8])
+    }
+
+    /// Sets the addresses frames are received for besides our own: the
+    /// device may still pass others on if its table is too small
+    pub fn set_mac_filter(
+        &mut self,
+        unicast: &[[u8; 6]],
+        multicast: &[[u8; 6]],
+    ) -> Result<(), NetError> {
+        if self.features & VIRTIO_NET_F_CTRL_RX == 0 {
+            return Err(NetError::Unsupported);
+        }
+        let mut tables = Vec::new();
+        for table in [unicast, multicast].iter() {
+            tables.extend_from_slice(&(table.len() as u32).to_le_bytes());
+            for mac in table.iter() {
+                tables.extend_from_slice(mac);
+            }
+        }
+        self.control(VIRTIO_NET_CTRL_MAC, VIRTIO_NET_CTRL_MAC_TABLE_SET, &tables)
+    }
+
+    /// Changes the address frames are sent from and received for
+    pub fn set_mac(&mut self, mac: [u8; 6]) -> Result<(), NetError> {
+        if self.features & VIRTIO_NET_F_CTRL_MAC_ADDR == 0 {
+            return Err(NetError::Unsupported);
+        }
+        self.control(VIRTIO_NET_CTRL_MAC, VIRTIO_NET_CTRL_MAC_ADDR_SET, &mac)?;
+        self.shared.config.lock().mac = mac;
+        Ok(())
+    }
+
+    fn control(&mut self, class: u8, command: u8, data: &[u8]) -> Result<(), NetError> {
+        self.shared.control(&self.irq, class, command, data)
+    }
+
+    /// Largest IP packet a frame can carry
+    pub fn mtu(&self) -> usize {
+        self.mtu
+    }
+
+    /// Largest frame, Ethernet header included, that can be sent or received
+    pub fn max_frame_len(&self) -> usize {
+        ETH_HEADER_LEN + self.mtu()
+    }
+
+    /// Largest frame `read` can return, which is more than `max_frame_len`
+    /// when the device may coalesce segments
+    pub fn max_receive_len(&self) -> usize {
+        match self.features & GUEST_GSO {
+            0 => self.max_frame_len(),
+            _ => GSO_MAX_LEN,
+        }
+    }
+
+    /// Whether the device computes checksums for `write_offloaded`, rather
+    /// than the driver
+    pub fn checksum_offload(&self) -> bool {
+        self.features & VIRTIO_NET_F_CSUM != 0
+    }
+
+    /// Whether the device can segment frames of `gso_type`
+    pub fn segmentation_offload(&self, gso_type: GsoType) -> bool {
+        self.features & gso_type.host_feature() != 0
+    }
+
+    /// The driver's traffic counters
+    pub fn stats(&self) -> Arc<NetStats> {
+        self.stats.clone()
+    }
+
+    /// Posts receive buffers until `RX_BUFFERS` are with the device or the
+    /// queue is full
+    fn fill_rx(&mut self) {
+        let mut added = false;
+        while self.rx_posted_count < RX_BUFFERS {
+            let size = self.rx_buffer_size;
+            let mut buffer = self
+                .rx_spare
+                .pop()
+                .unwrap_or_else(|| vec![0; size].into_boxed_slice());
+            let (header, frame) = buffer.split_at_mut(self.header_len);
+            // Legacy devices without VIRTIO_F_ANY_LAYOUT want the header in
+            // a descriptor of its own
+            let token = self
+                .read_queue
+                .add(&[Segment::writable(header), Segment::writable(frame)]);
+            match token {
+                Some(token) => {
+                    self.rx_posted[token as usize] = Some(buffer);
+                    self.rx_posted_count += 1;
+                    added = true;
+                }
+                None => {
+                    self.rx_spare.push(buffer);
+                    break;
+                }
+            }
+        }
+        if added && self.read_queue.should_notify() {
+            self.shared.regs.lock().notify(self.queue_index);
+        }
+    }
+
+    /// Reaps completed buffers: received frames are queued to be read, with
+    /// fresh buffers posted in their place, and sent ones are freed. Only
+    /// pairs with an MSI-X vector of their own are pinned to a core (see
+    /// `set_core`); otherwise the shared interrupt may land on any core, so
+    /// call this for every pair of the device.
+    pub fn handle_interrupt(&mut self) {
+        self.shared.ack_interrupt();
+        while let Some((token, len)) = self.read_queue.pop_used() {
+            let buffer = match self.rx_posted[token as usize].take() {
+                Some(buffer) => buffer,
+                None => continue,
+            };
+            self.rx_posted_count -= 1;
+            self.receive(buffer, len as usize);
+        }
+        self.fill_rx();
+        let tx_buffer_size = self.header_len + self.max_frame_len();
+        while let Some((token, _)) = self.write_queue.pop_used() {
+            // Buffers for segmentation offload are too big to keep around
+            match self.tx_in_flight[token as usize].take() {
+                Some(buffer) if buffer.len() == tx_buffer_size => self.tx_spare.push(buffer),
+                _ => {}
+            }
+        }
+    }
+
+    /// Adds a filled buffer to the frame it's part of, or starts a new frame
+    fn receive(&mut self, buffer: Box<[u8]>, len: usize) {
+        let len = len.min(buffer.len());
+        if self.dropping > 0 {
+            self.dropping -= 1;
+            self.rx_spare.push(buffer);
+            return;
+        }
+        if let Some(frame) = self.frames.back_mut().filter(|frame| frame.missing > 0) {
+            frame.buffers += 1;
+            frame.len += len;
+            frame.missing -= 1;
+            self.received.push_back((buffer, len));
+            if frame.missing == 0 {
+                self.stats.rx_packets.fetch_add(1, Ordering::Relaxed);
+                self.stats
+                    .rx_bytes
+                    .fetch_add(frame.len as u64, Ordering::Relaxed);
+            }
+            return;
+        }
+
+        let buffers = match self.features & VIRTIO_NET_F_MRG_RXBUF {
+            0 => 1,
+            _ => (NetHdr::read(&buffer[..self.header_len])
+                .num_buffers
+                .native() as usize)
+                .max(1),
         };
+        if self.frames.len() >= RX_BACKLOG {
+            self.stats.rx_dropped.fetch_add(1, Ordering::Relaxed);
+            self.dropping = buffers - 1;
+            self.rx_spare.push(buffer);
+            return;
+        }
+        let len = len.saturating_sub(self.header_len);
+        self.frames.push_back(Frame {
+            buffers: 1,
+            len,
+            missing: buffers - 1,
+        });
+        self.received.push_back((buffer, len + self.header_len));
+        if buffers == 1 {
+            self.stats.rx_packets.fetch_add(1, Ordering::Relaxed);
+            self.stats.rx_bytes.fetch_add(len as u64, Ordering::Relaxed);
+        }
+    }
 
-        self.read_queue.descriptors[1] = VirtQDesc {
-            addr: (data.as_ptr() as *const _ as u64).into(),
-            len: (data.len() as u32).into(),
-            flags: (2).into(),
-            next: 0.into(),
+    /// Waits for a frame and copies it into `data`, returning its length.
+    /// Frames longer than `data` are cut short. Returns 0 without a frame if
+    /// the device asks for an announcement meanwhile.
+    pub fn read(&mut self, data: &mut [u8]) -> usize {
+        self.read_offloaded(data).len
+    }
+
+    /// Like `read`, but also says whether the checksum still needs checking
+    /// and whether the frame is made of coalesced segments
+    pub fn read_offloaded(&mut self, data: &mut [u8]) -> RxInfo {
+        self.irq.enable();
+        while self.frames.front().is_none_or(|frame| frame.missing > 0) && !self.announce_pending()
+        {
+            //asm!("wfi");
+            self.handle_interrupt();
+        }
+        self.irq.disable();
+        let frame = match self.frames.front() {
+            Some(frame) if frame.missing == 0 => self.frames.pop_front().unwrap(),
+            _ => {
+                return RxInfo {
+                    len: 0,
+                    checksum_valid: false,
+                    segmentation: None,
+                }
+            }
         };
+        let mut header = NetHdr::default();
+        let mut copied = 0;
+        for i in 0..frame.buffers {
+            let (buffer, len) = self.received.pop_front().unwrap();
+            // Only the first buffer of a frame starts with the header
+            let start = if i == 0 {
+                header = NetHdr::read(&buffer[..self.header_len]);
+                self.header_len
+            } else {
+                0
+            };
+            let count = (len - start).min(data.len() - copied);
+            data[copied..copied + count].copy_from_slice(&buffer[start..start + count]);
+            copied += count;
+            self.rx_spare.push(buffer);
+        }
+
+        let mut checksum_valid = header.flags & VIRTIO_NET_HDR_F_DATA_VALID != 0;
+        if header.flags & VIRTIO_NET_HDR_F_NEEDS_CSUM != 0 && copied == frame.len {
+            // Sent by another guest on the same host, which left the
+            // checksum to be done on the way out
+            let start = header.csum_start.native() as usize;
+            let offset = header.csum_offset.native() as usize;
+            checksum_valid = fill_checksum(&mut data[..copied], start, offset);
+        }
+        let segmentation = GsoType::from_raw(header.gso_type).map(|gso_type| Segmentation {
+            gso_type,
+            size: header.gso_size.native(),
+            header_len: header.hdr_len.native(),
+        });
+        RxInfo {
+            len: copied,
+            checksum_valid,
+            segmentation,
+        }
+    }
 
-        self.enqueue(0, 0);
+    /// Queues a frame to be sent. Only waits if the transmit queue is full
+    /// of frames the device hasn't got to yet.
+    pub fn write(&mut self, frame: &[u8]) -> Result<(), NetError> {
+        self.write_offloaded(frame, &Offload::default())
+    }
 
-        unsafe {
-            self.irq.enable();
-            while read_volatile(&self.read_queue.used.idx)
-                != read_volatile(&self.read_queue.available.idx)
-            {
-                //asm!("wfi");
-                let status = read_volatile(&self.regs.interrupt_status);
-                if status.native() != 0 {
-                    write_volatile(&mut self.regs.interrupt_ack, 0b11.into());
+    /// Like `write`, but leaves the checksum and segmentation in `offload`
+    /// to the device. Checksums are done here if the device can't do them.
+    pub fn write_offloaded(&mut self, frame: &[u8], offload: &Offload) -> Result<(), NetError> {
+        let mut header = NetHdr::default();
+        match offload.segmentation {
+            None if frame.len() > self.max_frame_len() => return Err(NetError::FrameTooLong),
+            None => {}
+            Some(segmentation) => {
+                if !self.segmentation_offload(segmentation.gso_type) {
+                    return Err(NetError::OffloadUnsupported);
+                }
+                if frame.len() > GSO_MAX_LEN {
+                    return Err(NetError::FrameTooLong);
+                }
+                if offload.checksum.is_none()
+                    || segmentation.size == 0
+                    || segmentation.header_len as usize > frame.len()
+                {
+                    return Err(NetError::InvalidOffload);
                 }
+                header.gso_type = segmentation.gso_type.raw();
+                header.gso_size = segmentation.size.into();
+                header.hdr_len = segmentation.header_len.into();
+            }
+        }
+        if let Some((start, offset)) = offload.checksum {
+            if start as usize + offset as usize + 2 > frame.len() {
+                return Err(NetError::InvalidOffload);
+            }
+            header.flags = VIRTIO_NET_HDR_F_NEEDS_CSUM;
+            header.csum_start = start.into();
+            header.csum_offset = offset.into();
+        }
+
+        self.handle_interrupt();
+        let size = self.header_len + frame.len();
+        let mut buffer = match self.tx_spare.pop() {
+            Some(buffer) if buffer.len() >= size => buffer,
+            _ => vec![0; size.max(self.header_len + self.max_frame_len())].into_boxed_slice(),
+        };
+        let (header_bytes, data) = buffer.split_at_mut(self.header_len);
+        data[..frame.len()].copy_from_slice(frame);
+        if header.flags & VIRTIO_NET_HDR_F_NEEDS_CSUM != 0 && !self.checksum_offload() {
+            let start = header.csum_start.native() as usize;
+            let offset = header.csum_offset.native() as usize;
+            fill_checksum(&mut data[..frame.len()], start, offset);
+            header = NetHdr::default();
+        }
+        header.write(header_bytes);
+        let token = loop {
+            let token = self.write_queue.add(&[
+                Segment::readable(&buffer[..self.header_len]),
+                Segment::readable(&buffer[self.header_len..size]),
+            ]);
+            match token {
+                Some(token) => break token,
+                None => self.handle_interrupt(),
             }
-            self.irq.disable();
+        };
+        self.tx_in_flight[token as usize] = Some(buffer);
+        self.stats.tx_packets.fetch_add(1, Ordering::Relaxed);
+        self.stats
+            .tx_bytes
+            .fetch_add(frame.len() as u64, Ordering::Relaxed);
+        if self.write_queue.should_notify() {
+            self.shared.regs.lock().notify(self.queue_index + 1);
         }
+        Ok(())
+    }
+}
+
+/// Computes the checksum of `frame` from `start` to the end, the field at
+/// `start + offset` holding the pseudo-header sum, and stores it there.
+/// Returns false if the field isn't within the frame.
+fn fill_checksum(frame: &mut [u8], start: usize, offset: usize) -> bool {
+    let field = start + offset;
+    if field + 2 > frame.len() {
+        return false;
     }
+    let checksum = !ones_complement_sum(0, &frame[start..]);
+    frame[field..field + 2].copy_from_slice(&checksum.to_be_bytes());
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::boxed::Box;
+    use alloc::collections::VecDeque;
+    use alloc::sync::Arc;
+    use alloc::vec;
+    use alloc::vec::Vec;
 
-    pub fn write(&mut self, data: &[u8; 1526]) {
-        let mut blkreq_hdr = NetHdr {
-            ..Default::default()
+    use super::super::mock::{echo_handler, irq, mode_tests, MockDevice, Mode};
+    use super::super::{DeviceId, Queue, VIRTIO_F_VERSION_1};
+    use super::*;
+
+    fn loopback(mode: Mode) {
+        let mut rx = Queue::boxed();
+        let mut tx = Queue::boxed();
+        let mut ctrl = Queue::boxed();
+        // Loops transmitted frames back to the receive queue, in order, as
+        // receive buffers become available
+        let mut looped = VecDeque::new();
+        let mut device = MockDevice::new(
+            DeviceId::Net,
+            1 << 5,
+            Box::new(move |queue, input: &[u8], capacity| {
+                if queue == 1 {
+                    looped.push_back(input.to_vec());
+                    Vec::new()
+                } else {
+                    let mut frame = looped.pop_front().unwrap_or_default();
+                    frame.truncate(capacity);
+                    frame
+                }
+            }),
+        );
+        device.config = vec![0x52, 0x54, 0, 0x12, 0x34, 0x56, 1, 0];
+        device.mode = mode;
+        device.wait_for_data = 1 << 0;
+        let mut net = VirtIONet::new(&mut device, &mut rx, &mut tx, &mut ctrl, irq());
+        assert!(
+            net.config().mac == [0x52, 0x54, 0, 0x12, 0x34, 0x56],
+            "wrong MAC address"
+        );
+
+        let mut frame = [0u8; 60];
+        frame[..6].copy_from_slice(&[0xff; 6]);
+        frame[12] = 0x08;
+        frame[13] = 0x06;
+        net.write(&frame).unwrap();
+        let mut received = [0u8; 1514];
+        let len = net.read(&mut received);
+        assert!(received[..len] == frame[..], "frame not looped back");
+        assert!(
+            net.write(&[0; 1515]) == Err(NetError::FrameTooLong),
+            "frame longer than the MTU sent"
+        );
+        assert!(net.link_up(), "link down without VIRTIO_NET_F_STATUS");
+        assert!(
+            net.set_promiscuous(true) == Err(NetError::Unsupported),
+            "control command without a control queue"
+        );
+
+        // More frames than the ring and backlog hold arrive without being read:
+        // the oldest are kept and the rest dropped rather than stalling sends
+        let sent = 300;
+        for i in 0..sent {
+            frame[14..16].copy_from_slice(&(i as u16).to_le_bytes());
+            net.write(&frame).unwrap();
+        }
+        net.handle_interrupt();
+        let stats = net.stats();
+        let count = |counter: &core::sync::atomic::AtomicU64| {
+            counter.load(core::sync::atomic::Ordering::Relaxed)
         };
+        assert!(
+            count(&stats.tx_packets) == sent + 1,
+            "sent frames not counted"
+        );
+        assert!(
+            count(&stats.tx_bytes) == (sent + 1) * 60,
+            "sent bytes not counted"
+        );
+        assert!(
+            count(&stats.rx_packets) + count(&stats.rx_dropped) == sent + 1,
+            "received frames lost"
+        );
+        assert!(count(&stats.rx_dropped) > 0, "backlog not bounded");
+        for i in 0..count(&stats.rx_packets) - 1 {
+            let len = net.read(&mut received);
+            assert!(
+                len == 60 && received[14..16] == (i as u16).to_le_bytes(),
+                "frames received out of order"
+            );
+        }
+    }
+
+    /// Jumbo frames spread over several receive buffers with
+    /// VIRTIO_NET_F_MRG_RXBUF, which has the 12-byte header even on legacy
+    /// devices
+    fn jumbo_frames(mode: Mode) {
+        let mut rx = Queue::boxed();
+        let mut tx = Queue::boxed();
+        let mut ctrl = Queue::boxed();
+        let header_len = 12;
+        // Frames sent, without their headers, and what's left of the one being
+        // received
+        let mut looped: VecDeque<Vec<u8>> = VecDeque::new();
+        let mut rest = Vec::new();
+        let headers = Arc::new(Mutex::new(Vec::new()));
+        let sent_headers = headers.clone();
+        let mut device = MockDevice::new(
+            DeviceId::Net,
+            1 << 3 | 1 << 5 | 1 << 15 | VIRTIO_F_VERSION_1,
+            Box::new(move |queue, input: &[u8], capacity| {
+                if queue == 1 {
+                    sent_headers.lock().push(input[..header_len].to_vec());
+                    looped.push_back(input[header_len..].to_vec());
+                    return Vec::new();
+                }
+                if rest.is_empty() {
+                    let frame = match looped.pop_front() {
+                        Some(frame) => frame,
+                        None => return Vec::new(),
+                    };
+                    let buffers = (header_len + frame.len() + capacity - 1) / capacity;
+                    let mut header = vec![0; header_len];
+                    header[10..12].copy_from_slice(&(buffers as u16).to_le_bytes());
+                    rest = header;
+                    rest.extend_from_slice(&frame);
+                }
+                let count = rest.len().min(capacity);
+                rest.drain(..count).collect()
+            }),
+        );
+        let mut config = vec![0x52, 0x54, 0, 0x12, 0x34, 0x56, 1, 0, 1, 0];
+        config.extend_from_slice(&9000u16.to_le_bytes());
+        device.config = config;
+        device.mode = mode;
+        device.wait_for_data = 1 << 0;
+        let mut net = VirtIONet::new(&mut device, &mut rx, &mut tx, &mut ctrl, irq());
+        assert!(net.mtu() == 9000, "MTU not read");
+        assert!(net.max_frame_len() == 9014, "wrong maximum frame length");
+
+        let mut received = vec![0; net.max_frame_len()];
+        for &len in [9014, 60, 2036, 2037, 4000].iter() {
+            let frame: Vec<u8> = (0..len).map(|i| (i * 7 + len) as u8).collect();
+            net.write(&frame).unwrap();
+            let got = net.read(&mut received);
+            assert!(got == len, "wrong frame length");
+            assert!(received[..len] == frame[..], "merged frame corrupted");
+        }
+        // Several frames in flight at once still come back whole and in order
+        for len in 3000..3004 {
+            net.write(&vec![len as u8; len]).unwrap();
+        }
+        for len in 3000..3004 {
+            let got = net.read(&mut received);
+            assert!(
+                got == len && received[..len].iter().all(|&b| b == len as u8),
+                "merged frames mixed up"
+            );
+        }
+        // Shorter buffers get as much of the frame as fits
+        net.write(&[1; 5000]).unwrap();
+        let mut short = [0; 100];
+        assert!(net.read(&mut short) == 100, "frame not cut short");
+        let stats = net.stats();
+        assert!(
+            stats.rx_bytes.load(core::sync::atomic::Ordering::Relaxed)
+                == stats.tx_bytes.load(core::sync::atomic::Ordering::Relaxed),
+            "merged frames miscounted"
+        );
+        let zeroed = headers.lock().iter().all(|header| header[..] == [0; 12]);
+        assert!(zeroed, "wrong transmit header");
+    }
 
-        self.write_queue.descriptors[0] = VirtQDesc {
-            addr: (&mut blkreq_hdr as *mut _ as u64).into(),
-            len: (core::mem::size_of::<NetHdr>() as u32).into(),
-            flags: (1).into(),
-            next: 1.into(),
+    /// A header as the device sees it: flags, gso_type, hdr_len, gso_size,
+    /// csum_start, csum_offset and, if it's 12 bytes long, num_buffers
+    fn net_header(len: usize, flags: u8, gso_type: u8, fields: [u16; 5]) -> Vec<u8> {
+        let mut header = vec![flags, gso_type];
+        for field in fields.iter() {
+            header.extend_from_slice(&field.to_le_bytes());
+        }
+        header.truncate(len);
+        header
+    }
+
+    fn offloads(mode: Mode) {
+        // An ICMP echo, whose checksum covers everything after the IP header
+        let mut frame = vec![0u8; 14 + 20 + 8 + 37];
+        frame[12] = 0x08;
+        frame[14] = 0x45;
+        frame[14 + 9] = 1;
+        frame[34] = 8;
+        for (i, b) in frame[42..].iter_mut().enumerate() {
+            *b = i as u8 * 3;
+        }
+        let icmp_checksum = Offload {
+            checksum: Some((34, 2)),
+            segmentation: None,
         };
+        let checksum_ok = |frame: &[u8]| ones_complement_sum(0, &frame[34..]) == 0xffff;
+        // A legacy device doesn't offer VERSION_1, so the header has no
+        // num_buffers
+        let header_len = if mode == Mode::Legacy { 10 } else { 12 };
 
-        self.write_queue.descriptors[1] = VirtQDesc {
-            addr: (data.as_ptr() as *const _ as u64).into(),
-            len: (data.len() as u32).into(),
-            flags: (0).into(),
-            next: 0.into(),
+        // Without VIRTIO_NET_F_CSUM the driver does the checksum itself
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let log = sent.clone();
+        let mut rx = Queue::boxed();
+        let mut tx = Queue::boxed();
+        let mut ctrl = Queue::boxed();
+        let mut device = MockDevice::new(
+            DeviceId::Net,
+            1 << 5 | VIRTIO_F_VERSION_1,
+            Box::new(move |queue, input: &[u8], _| {
+                if queue == 1 {
+                    log.lock().push(input.to_vec());
+                }
+                Vec::new()
+            }),
+        );
+        device.mode = mode;
+        device.wait_for_data = 1 << 0;
+        let mut net = VirtIONet::new(&mut device, &mut rx, &mut tx, &mut ctrl, irq());
+        assert!(!net.checksum_offload(), "checksum offload not negotiated");
+        net.write_offloaded(&frame, &icmp_checksum).unwrap();
+        let segmented = Offload {
+            checksum: Some((34, 16)),
+            segmentation: Some(Segmentation {
+                gso_type: GsoType::Tcp4,
+                size: 1460,
+                header_len: 54,
+            }),
         };
+        assert!(
+            net.write_offloaded(&vec![0; 20000], &segmented) == Err(NetError::OffloadUnsupported),
+            "segmentation without the feature"
+        );
+        drop(net);
+        let sent_frames = sent.lock().clone();
+        assert!(sent_frames.len() == 1, "frame not sent");
+        assert!(
+            sent_frames[0][..header_len].iter().all(|&b| b == 0)
+                && checksum_ok(&sent_frames[0][header_len..]),
+            "checksum not computed by the driver"
+        );
 
-        self.enqueue(1, 0);
+        // With the offloads the frame goes out as it is, with the header telling
+        // the device what to do, and the device says what it did on the way in
+        let incoming: Arc<Mutex<VecDeque<Vec<u8>>>> = Arc::new(Mutex::new(VecDeque::new()));
+        let feed = incoming.clone();
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let log = sent.clone();
+        let mut rx = Queue::boxed();
+        let mut tx = Queue::boxed();
+        let mut ctrl = Queue::boxed();
+        let mut device = MockDevice::new(
+            DeviceId::Net,
+            // CSUM, GUEST_CSUM, MAC, GUEST_TSO4 and HOST_TSO4
+            1 << 0 | 1 << 1 | 1 << 5 | 1 << 7 | 1 << 11 | VIRTIO_F_VERSION_1,
+            Box::new(move |queue, input: &[u8], capacity| {
+                if queue == 1 {
+                    log.lock().push(input.to_vec());
+                    return Vec::new();
+                }
+                let mut frame = feed.lock().pop_front().unwrap_or_default();
+                frame.truncate(capacity);
+                frame
+            }),
+        );
+        device.mode = mode;
+        device.wait_for_data = 1 << 0;
+        let mut net = VirtIONet::new(&mut device, &mut rx, &mut tx, &mut ctrl, irq());
+        assert!(
+            net.checksum_offload()
+                && net.segmentation_offload(GsoType::Tcp4)
+                && !net.segmentation_offload(GsoType::Tcp6),
+            "offloads not negotiated"
+        );
+        assert!(
+            net.max_receive_len() > 65000,
+            "no room for coalesced frames"
+        );
 
-        unsafe {
-            self.irq.enable();
-            while read_volatile(&self.write_queue.used.idx)
-                != read_volatile(&self.write_queue.available.idx)
-            {
-                //asm!("wfi");
-                let status = read_volatile(&self.regs.interrupt_status);
-                if status.native() != 0 {
-                    write_volatile(&mut self.regs.interrupt_ack, 0b11.into());
+        net.write_offloaded(&frame, &icmp_checksum).unwrap();
+        let big = vec![0x5a; 20000];
+        net.write_offloaded(&big, &segmented).unwrap();
+        assert!(
+            net.write(&big) == Err(NetError::FrameTooLong),
+            "frame longer than the MTU sent"
+        );
+        assert!(
+            net.write_offloaded(
+                &big,
+                &Offload {
+                    checksum: None,
+                    ..segmented
+                },
+            ) == Err(NetError::InvalidOffload),
+            "segmentation without a checksum"
+        );
+        assert!(
+            net.write_offloaded(&frame[..35], &icmp_checksum) == Err(NetError::InvalidOffload),
+            "checksum field past the end of the frame"
+        );
+        let sent_frames = sent.lock().clone();
+        assert!(sent_frames.len() == 2, "offloaded frames not sent");
+        assert!(
+            sent_frames[0][..header_len] == net_header(header_len, 1, 0, [0, 0, 34, 2, 0])[..]
+                && sent_frames[0][header_len..] == frame[..],
+            "wrong checksum offload header"
+        );
+        assert!(
+            sent_frames[1][..header_len] == net_header(header_len, 1, 1, [54, 1460, 34, 16, 0])[..]
+                && sent_frames[1][header_len..] == big[..],
+            "wrong segmentation offload header"
+        );
+
+        // DATA_VALID, a checksum left to the driver by another guest, and a
+        // frame coalesced from TCP segments
+        let mut partial = frame.clone();
+        partial[36..38].copy_from_slice(&[0, 0]);
+        let mut coalesced = net_header(header_len, 0, 1, [54, 1448, 0, 0, 1]);
+        coalesced.extend_from_slice(&vec![0xa5; 30000]);
+        {
+            let mut incoming = incoming.lock();
+            let mut valid = net_header(header_len, 2, 0, [0, 0, 0, 0, 1]);
+            valid.extend_from_slice(&frame);
+            incoming.push_back(valid);
+            let mut needs_checksum = net_header(header_len, 1, 0, [0, 0, 34, 2, 1]);
+            needs_checksum.extend_from_slice(&partial);
+            incoming.push_back(needs_checksum);
+            incoming.push_back(coalesced);
+            let mut unchecked = net_header(header_len, 0, 0, [0, 0, 0, 0, 1]);
+            unchecked.extend_from_slice(&frame);
+            incoming.push_back(unchecked);
+        }
+        net.handle_interrupt();
+        let mut received = vec![0; net.max_receive_len()];
+        let info = net.read_offloaded(&mut received);
+        assert!(
+            info.len == frame.len() && info.checksum_valid && info.segmentation.is_none(),
+            "DATA_VALID not honoured"
+        );
+        let info = net.read_offloaded(&mut received);
+        assert!(
+            info.checksum_valid && checksum_ok(&received[..info.len]),
+            "partial checksum not completed"
+        );
+        let info = net.read_offloaded(&mut received);
+        assert!(
+            info.len == 30000
+                && info.segmentation
+                    == Some(Segmentation {
+                        gso_type: GsoType::Tcp4,
+                        size: 1448,
+                        header_len: 54,
+                    }),
+            "coalesced frame not reported"
+        );
+        let info = net.read_offloaded(&mut received);
+        assert!(!info.checksum_valid, "unchecked frame reported valid");
+    }
+
+    fn control_queue(mode: Mode) {
+        let commands = Arc::new(Mutex::new(Vec::new()));
+        let log = commands.clone();
+        let mut rx = Queue::boxed();
+        let mut tx = Queue::boxed();
+        let mut ctrl = Queue::boxed();
+        let mut device = MockDevice::new(
+            DeviceId::Net,
+            // MAC, STATUS, CTRL_VQ, CTRL_RX, GUEST_ANNOUNCE and CTRL_MAC_ADDR
+            1 << 5 | 1 << 16 | 1 << 17 | 1 << 18 | 1 << 21 | 1 << 23 | VIRTIO_F_VERSION_1,
+            Box::new(move |queue, input: &[u8], _| {
+                if queue != 2 {
+                    return Vec::new();
                 }
-            }
-            self.irq.disable();
+                log.lock().push(input.to_vec());
+                // Refuses to turn all-multicast on
+                match input {
+                    [0, 1, 1] => vec![1],
+                    _ => vec![0],
+                }
+            }),
+        );
+        let mac = [0x52, 0x54, 0, 0x12, 0x34, 0x56];
+        let config = |status: u8| {
+            let mut config = mac.to_vec();
+            config.extend_from_slice(&[status, 0, 1, 0]);
+            config
+        };
+        device.config = config(1);
+        device.mode = mode;
+        device.wait_for_data = 1 << 0;
+        let pending_config = device.pending_config.clone();
+        let mut net = VirtIONet::new(&mut device, &mut rx, &mut tx, &mut ctrl, irq());
+        let stats = net.stats();
+        let link_up = || stats.link_up.load(core::sync::atomic::Ordering::Relaxed);
+        assert!(net.link_up() && link_up(), "link not up");
+
+        net.set_promiscuous(true).unwrap();
+        assert!(
+            net.set_all_multicast(true) == Err(NetError::CommandFailed),
+            "rejected command succeeded"
+        );
+        let unicast = [[0x52, 0x54, 0, 0, 0, 1]];
+        let multicast = [[0x01, 0, 0x5e, 0, 0, 1], [0x33, 0x33, 0, 0, 0, 1]];
+        net.set_mac_filter(&unicast, &multicast).unwrap();
+        let new_mac = [0x52, 0x54, 0, 0xab, 0xcd, 0xef];
+        net.set_mac(new_mac).unwrap();
+        assert!(net.mac() == new_mac, "MAC address not changed");
+        let mut tables = vec![1, 0, 0, 0];
+        tables.extend_from_slice(&unicast[0]);
+        tables.extend_from_slice(&[2, 0, 0, 0]);
+        tables.extend_from_slice(&multicast[0]);
+        tables.extend_from_slice(&multicast[1]);
+        let expected = [
+            vec![0, 0, 1],
+            vec![0, 1, 1],
+            [&[1, 0][..], &tables].concat(),
+            [&[1, 1][..], &new_mac].concat(),
+        ];
+        assert!(
+            commands.lock()[..] == expected[..],
+            "wrong control commands"
+        );
+
+        *pending_config.lock() = Some(config(0));
+        net.handle_interrupt();
+        assert!(!net.link_up() && !link_up(), "link down not noticed");
+        *pending_config.lock() = Some(config(1 | 2));
+        net.handle_interrupt();
+        assert!(net.link_up() && link_up(), "link up not noticed");
+        assert!(net.announce_pending(), "announcement not requested");
+        // Reading gives way to the announcement rather than waiting for a frame
+        let mut received = [0; 1514];
+        assert!(net.read(&mut received) == 0, "read didn't return");
+        assert!(net.take_announce(), "announcement not claimed");
+        assert!(!net.take_announce(), "announcement claimed twice");
+        net.announced().unwrap();
+        assert!(!net.announce_pending(), "announcement still pending");
+        let acked = commands.lock().last() == Some(&vec![3, 0]);
+        assert!(acked, "announcement not acknowledged");
+
+        // A device that breaks instead of answering
+        let mut device = MockDevice::new(DeviceId::Net, 1 << 17 | 1 << 18, echo_handler());
+        device.mode = mode;
+        device.wait_for_data = 1 << 0;
+        device.needs_reset_after(0);
+        let (mut rx, mut tx, mut ctrl) = (Queue::boxed(), Queue::boxed(), Queue::boxed());
+        {
+            let mut net = VirtIONet::new(&mut device, &mut rx, &mut tx, &mut ctrl, irq());
+            assert!(
+                net.set_promiscuous(true) == Err(NetError::NeedsReset),
+                "broken device waited for"
+            );
+        }
+        assert!(
+            device.status() == Status::Failed as u32,
+            "broken device not reset"
+        );
+    }
+
+    /// A device with four queue pairs that loops frames sent on a pair back to
+    /// the same pair, and logs control commands, refusing them if `refuse`
+    fn multiqueue_device(
+        mode: Mode,
+        features: u64,
+        refuse: bool,
+    ) -> (MockDevice, Arc<Mutex<Vec<Vec<u8>>>>) {
+        let commands = Arc::new(Mutex::new(Vec::new()));
+        let log = commands.clone();
+        let mut looped: Vec<VecDeque<Vec<u8>>> = (0..4).map(|_| VecDeque::new()).collect();
+        let mut device = MockDevice::new(
+            DeviceId::Net,
+            features,
+            Box::new(move |queue, input: &[u8], _| match queue {
+                8 => {
+                    log.lock().push(input.to_vec());
+                    vec![refuse as u8]
+                }
+                _ if queue % 2 == 1 => {
+                    looped[queue as usize / 2].push_back(input.to_vec());
+                    Vec::new()
+                }
+                _ => looped[queue as usize / 2].pop_front().unwrap_or_default(),
+            }),
+        );
+        let mut config = vec![0x52, 0x54, 0, 0x12, 0x34, 0x56, 1, 0, 4, 0, 0xdc, 0x05];
+        // Speed and duplex, then a 40-byte key, a 128-entry table and all the
+        // hash types
+        config.extend_from_slice(&[0, 0, 0, 0, 0, 40, 128, 0, 0xff, 1, 0, 0]);
+        device.mode = mode;
+        device.config = config;
+        device.wait_for_data = 0x55;
+        (device, commands)
+    }
+
+    /// Splits `queues` into receive/transmit pairs
+    fn queue_pairs(queues: &mut [Box<Queue<128>>]) -> Vec<(&mut Queue<128>, &mut Queue<128>)> {
+        queues
+            .chunks_exact_mut(2)
+            .map(|pair| {
+                let (rx, tx) = pair.split_at_mut(1);
+                (&mut *rx[0], &mut *tx[0])
+            })
+            .collect()
+    }
+
+    fn multiqueue(mode: Mode) {
+        // MAC, CTRL_VQ, MQ, CTRL_MAC_ADDR and RSS
+        let rss = 1 << 5 | 1 << 17 | 1 << 22 | 1 << 23 | 1 << 60 | VIRTIO_F_VERSION_1;
+        let (mut device, commands) = multiqueue_device(mode, rss, false);
+        let mut queues: Vec<Box<Queue<128>>> = (0..7).map(|_| Queue::boxed()).collect();
+        let (ctrl, queues) = queues.split_last_mut().unwrap();
+        let pairs = queue_pairs(queues);
+        let mut net = VirtIONet::new_multiqueue(&mut device, pairs, ctrl, irq());
+        assert!(net.len() == 3, "wrong number of queue pairs");
+
+        // The RSS configuration asks for the three pairs, spreading the hash
+        // table over their receive queues
+        let mut expected = vec![4, 1, 0x3f, 0, 0, 0, 127, 0, 0, 0];
+        for entry in 0..128u16 {
+            expected.extend_from_slice(&(entry % 3).to_le_bytes());
+        }
+        expected.extend_from_slice(&[3, 0, 40]);
+        expected.extend_from_slice(&[
+            0x6d, 0x5a, 0x56, 0xda, 0x25, 0x5b, 0x0e, 0xc2, 0x41, 0x67, 0x25, 0x3d, 0x43, 0xa3,
+            0x8f, 0xb0, 0xd0, 0xca, 0x2b, 0xcb, 0xae, 0x7b, 0x30, 0xb4, 0x77, 0xcb, 0x2d, 0xa3,
+            0x80, 0x30, 0xf2, 0x0c, 0x6a, 0x42, 0xb7, 0x3b, 0xbe, 0xac, 0x01, 0xfa,
+        ]);
+        if mode == Mode::Legacy {
+            // RSS is past the 32 feature bits a legacy device has
+            expected = vec![4, 0, 3, 0];
         }
+        let configured = commands.lock()[..] == [expected];
+        assert!(configured, "wrong RSS configuration");
+
+        // Each pair has queues of its own
+        let mut received = [0; 1514];
+        for (index, pair) in net.iter_mut().enumerate() {
+            pair.write(&[index as u8; 60]).unwrap();
+        }
+        for (index, pair) in net.iter_mut().enumerate().rev() {
+            let len = pair.read(&mut received);
+            assert!(
+                len == 60 && received[..60].iter().all(|&b| b == index as u8),
+                "frame received on the wrong pair"
+            );
+        }
+        let stats = net[0].stats();
+        assert!(
+            stats.rx_packets.load(core::sync::atomic::Ordering::Relaxed) == 3,
+            "pairs don't share counters"
+        );
+        // Control commands can come from any pair
+        net[2].set_mac([0x52, 0x54, 0, 1, 2, 3]).unwrap();
+        assert!(
+            net.iter().all(|pair| pair.mac() == net[0].mac()),
+            "pairs disagree on the MAC address"
+        );
+        drop(net);
+
+        // Without RSS the number of pairs is set on its own
+        let (mut device, commands) = multiqueue_device(mode, rss & !(1 << 60), false);
+        let mut queues: Vec<Box<Queue<128>>> = (0..11).map(|_| Queue::boxed()).collect();
+        let (ctrl, queues) = queues.split_last_mut().unwrap();
+        let pairs = queue_pairs(queues);
+        let net = VirtIONet::new_multiqueue(&mut device, pairs, ctrl, irq());
+        assert!(net.len() == 4, "more pairs than the device has");
+        let configured = commands.lock()[..] == [vec![4, 0, 4, 0]];
+        assert!(configured, "wrong number of pairs set");
+        drop(net);
+
+        // A device that refuses carries on with the first pair alone
+        let (mut device, _) = multiqueue_device(mode, rss, true);
+        let mut queues: Vec<Box<Queue<128>>> = (0..5).map(|_| Queue::boxed()).collect();
+        let (ctrl, queues) = queues.split_last_mut().unwrap();
+        let pairs = queue_pairs(queues);
+        let net = VirtIONet::new_multiqueue(&mut device, pairs, ctrl, irq());
+        assert!(net.len() == 1, "refused queue pairs used");
     }
+
+    mode_tests!(loopback, jumbo_frames, offloads, control_queue, multiqueue,);
 }
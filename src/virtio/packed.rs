@@ -0,0 +1,286 @@
+use alloc::boxed::Box;
+use core::ptr::{read_volatile, write_volatile};
+
+use crate::utils::*;
+
+use super::{Segment, LEU16, LEU32, LEU64, VIRTQ_DESC_F_INDIRECT};
+
+pub(super) const VIRTQ_DESC_F_AVAIL: u16 = 1 << 7;
+pub(super) const VIRTQ_DESC_F_USED: u16 = 1 << 15;
+
+/// Event suppression flags
+const RING_EVENT_FLAGS_ENABLE: u16 = 0;
+const RING_EVENT_FLAGS_DISABLE: u16 = 1;
+
+#[derive(Copy, Clone)]
+#[repr(C, align(16))]
+pub struct PackedDesc {
+    addr: LEU64,
+    len: LEU32,
+    id: LEU16,
+    flags: LEU16,
+}
+
+impl PackedDesc {
+    pub const fn empty() -> PackedDesc {
+        PackedDesc {
+            addr: Endian::from_raw(0),
+            len: Endian::from_raw(0),
+            id: Endian::from_raw(0),
+            flags: Endian::from_raw(0),
+        }
+    }
+}
+
+/// Driver and device event suppression structure. The driver's tells the
+/// device when to send interrupts, the device's tells the driver when to send
+/// notifications.
+#[repr(C, align(4))]
+pub struct EventSuppression {
+    off_wrap: LEU16,
+    flags: LEU16,
+}
+
+impl EventSuppression {
+    pub const fn empty() -> EventSuppression {
+        EventSuppression {
+            off_wrap: Endian::from_raw(0),
+            flags: Endian::from_raw(RING_EVENT_FLAGS_ENABLE.to_le()),
+        }
+    }
+}
+
+/// A packed virtqueue (VIRTIO_F_RING_PACKED).
+///
+/// Descriptors are used in ring order and the device overwrites them in place
+/// as it completes chains. Whether a descriptor belongs to the driver or the
+/// device is decided by comparing its AVAIL/USED flags with the wrap counters.
+pub struct PackedQueue<const S: usize> {
+    pub descriptors: [PackedDesc; S],
+    pub driver_event: EventSuppression,
+    pub device_event: EventSuppression,
+    next_avail: u16,
+    avail_wrap: bool,
+    next_used: u16,
+    used_wrap: bool,
+    num_free: u16,
+    /// Free buffer IDs, linked through `next_id`
+    free_id: u16,
+    next_id: [u16; S],
+    /// Number of ring descriptors each in-flight buffer ID occupies
+    chain_len: [u16; S],
+    use_indirect: bool,
+    indirect: [Option<Box<[PackedDesc]>>; S],
+}
+
+impl<const S: usize> PackedQueue<S> {
+    /// An empty ring. Kept as a plain constant, with no calls that could
+    /// drop, so `&EMPTY` is promoted to a static and can be copied into
+    /// place without building the ring on the stack.
+    pub const EMPTY: Self = {
+        const NO_TABLE: Option<Box<[PackedDesc]>> = None;
+        PackedQueue {
+            descriptors: [PackedDesc::empty(); S],
+            driver_event: EventSuppression::empty(),
+            device_event: EventSuppression::empty(),
+            next_avail: 0,
+            avail_wrap: true,
+            next_used: 0,
+            used_wrap: true,
+            num_free: S as u16,
+            free_id: 0,
+            next_id: Self::free_ids(),
+            chain_len: [0; S],
+            use_indirect: false,
+            indirect: [NO_TABLE; S],
+        }
+    };
+
+    pub const fn new() -> Self {
+        Self::EMPTY
+    }
+
+    /// Every buffer ID linked into the free list, in order
+    const fn free_ids() -> [u16; S] {
+        let mut next_id = [0; S];
+        let mut i = 0;
+        while i < S {
+            next_id[i] = i as u16 + 1;
+            i += 1;
+        }
+        next_id
+    }
+
+    pub fn set_indirect(&mut self, enabled: bool) {
+        self.use_indirect = enabled;
+    }
+
+    /// AVAIL/USED bits marking a descriptor as available in the current lap
+    fn avail_flags(&self) -> u16 {
+        if self.avail_wrap {
+            VIRTQ_DESC_F_AVAIL
+        } else {
+            VIRTQ_DESC_F_USED
+        }
+    }
+
+    /// Makes `segments` available as one buffer and returns its buffer ID.
+    pub fn add(&mut self, segments: &[Segment]) -> Option<u16> {
+        let indirect = self.use_indirect && segments.len() > 1;
+        let needed = if indirect { 1 } else { segments.len() as u16 };
+        if segments.is_empty() || self.num_free < needed || self.free_id as usize >= S {
+            return None;
+        }
+
+        let id = self.free_id;
+        self.free_id = self.next_id[id as usize];
+        self.chain_len[id as usize] = needed;
+        self.num_free -= needed;
+
+        let head = self.next_avail;
+        let head_flags;
+        if indirect {
+            // Descriptors in an indirect table are consumed in order, so only
+            // WRITE is meaningful in them.
+            let table: Box<[PackedDesc]> = segments
+                .iter()
+                .map(|segment| PackedDesc {
+                    flags: segment.flags(false).into(),
+                    ..segment.packed_descriptor(id)
+                })
+                .collect();
+            self.descriptors[head as usize] = PackedDesc {
+                addr: (table.as_ptr() as u64).into(),
+                len: (core::mem::size_of_val(&*table) as u32).into(),
+                id: id.into(),
+                flags: 0.into(),
+            };
+            head_flags = VIRTQ_DESC_F_INDIRECT | self.avail_flags();
+            self.indirect[id as usize] = Some(table);
+            self.advance_avail();
+        } else {
+            head_flags = segments[0].flags(segments.len() > 1) | self.avail_flags();
+            for (i, segment) in segments.iter().enumerate() {
+                let pos = self.next_avail as usize;
+                self.descriptors[pos] = segment.packed_descriptor(id);
+                if i > 0 {
+                    // Only the head's flags publish the chain, so the rest can
+                    // be handed over immediately.
+                    let flags = segment.flags(i + 1 < segments.len()) | self.avail_flags();
+                    self.descriptors[pos].flags = flags.into();
+                }
+                self.advance_avail();
+            }
+        }
+
+        mb();
+        unsafe {
+            write_volatile(
+                &mut self.descriptors[head as usize].flags,
+                head_flags.into(),
+            );
+        }
+        mb();
+        Some(id)
+    }
+
+    fn advance_avail(&mut self) {
+        self.next_avail += 1;
+        if self.next_avail as usize == S {
+            self.next_avail = 0;
+            self.avail_wrap = !self.avail_wrap;
+        }
+    }
+
+    pub fn has_used(&self) -> bool {
+        let flags = unsafe { read_volatile(&self.descriptors[self.next_used as usize].flags) };
+        let avail = flags.native() & VIRTQ_DESC_F_AVAIL != 0;
+        let used = flags.native() & VIRTQ_DESC_F_USED != 0;
+        avail == used && used == self.used_wrap
+    }
+
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        let (id, len, chain_len) = loop {
+            if !self.has_used() {
+                return None;
+            }
+            mb();
+            let desc = unsafe { read_volatile(&self.descriptors[self.next_used as usize]) };
+            let id = desc.id.native();
+            match self.chain_len.get(id as usize) {
+                Some(&chain_len) if chain_len > 0 => break (id, desc.len.native(), chain_len),
+                // A buggy device named a buffer it doesn't hold. There's
+                // nothing to complete, so skip the descriptor.
+                _ => self.advance_used(1),
+            }
+        };
+
+        self.advance_used(chain_len);
+        self.num_free += chain_len;
+        self.chain_len[id as usize] = 0;
+        self.indirect[id as usize] = None;
+        self.next_id[id as usize] = self.free_id;
+        self.free_id = id;
+
+        Some((id, len))
+    }
+
+    fn advance_used(&mut self, descriptors: u16) {
+        self.next_used += descriptors;
+        if self.next_used as usize >= S {
+            self.next_used -= S as u16;
+            self.used_wrap = !self.used_wrap;
+        }
+    }
+
+    /// Whether the device wants to be notified of new buffers
+    pub fn should_notify(&self) -> bool {
+        let flags = unsafe { read_volatile(&self.device_event.flags) };
+        flags.native() != RING_EVENT_FLAGS_DISABLE
+    }
+
+    pub fn addresses(&self) -> (u64, u64, u64) {
+        (
+            self.descriptors.as_ptr() as u64,
+            &self.driver_event as *const _ as u64,
+            &self.device_event as *const _ as u64,
+        )
+    }
+}
+
+impl Segment {
+    fn packed_descriptor(&self, id: u16) -> PackedDesc {
+        PackedDesc {
+            addr: self.addr.into(),
+            len: self.len.into(),
+            id: id.into(),
+            flags: 0.into(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bogus_completions() {
+        let mut queue = PackedQueue::<4>::new();
+        let mut byte = 0u8;
+        let id = queue
+            .add(&[Segment::writable(&mut byte)])
+            .expect("ring full");
+        // A buffer ID out of range, the real completion and then that again,
+        // once it's no longer in flight
+        for (desc, &used_id) in queue.descriptors.iter_mut().zip([9, id, id].iter()) {
+            *desc = PackedDesc {
+                addr: 0.into(),
+                len: 1.into(),
+                id: used_id.into(),
+                flags: (VIRTQ_DESC_F_AVAIL | VIRTQ_DESC_F_USED).into(),
+            };
+        }
+        assert!(queue.pop_used() == Some((id, 1)), "completion lost");
+        assert!(queue.pop_used().is_none(), "bogus completion returned");
+    }
+}
@@ -0,0 +1,241 @@
+use alloc::vec::Vec;
+use core::ptr::{read_volatile, write_volatile};
+
+use crate::pci::{self, Interrupt, PCI_CAP_ID_VNDR};
+use crate::utils::*;
+
+use super::{DeviceId, Status, Transport, LEU16, LEU32};
+
+const VIRTIO_PCI_VENDOR_ID: u16 = 0x1af4;
+
+/// `cfg_type` of the virtio vendor-specific capabilities
+const VIRTIO_PCI_CAP_COMMON_CFG: u8 = 1;
+const VIRTIO_PCI_CAP_NOTIFY_CFG: u8 = 2;
+const VIRTIO_PCI_CAP_ISR_CFG: u8 = 3;
+const VIRTIO_PCI_CAP_DEVICE_CFG: u8 = 4;
+
+const VIRTIO_MSI_NO_VECTOR: u16 = 0xffff;
+
+#[repr(C)]
+pub struct CommonCfg {
+    pub device_feature_select: LEU32,
+    pub device_feature: LEU32,
+    pub driver_feature_select: LEU32,
+    pub driver_feature: LEU32,
+    pub msix_config: LEU16,
+    pub num_queues: LEU16,
+    pub device_status: u8,
+    pub config_generation: u8,
+    pub queue_select: LEU16,
+    pub queue_size: LEU16,
+    pub queue_msix_vector: LEU16,
+    pub queue_enable: LEU16,
+    pub queue_notify_off: LEU16,
+    pub queue_desc_low: LEU32,
+    pub queue_desc_high: LEU32,
+    pub queue_driver_low: LEU32,
+    pub queue_driver_high: LEU32,
+    pub queue_device_low: LEU32,
+    pub queue_device_high: LEU32,
+}
+
+/// virtio-pci modern transport, reached through the structures the device's
+/// vendor-specific capabilities point at
+pub struct VirtIOPci {
+    device: pci::Device,
+    device_id: DeviceId,
+    common: &'static mut CommonCfg,
+    notify: usize,
+    notify_off_multiplier: u32,
+    isr: *mut u8,
+    /// Device-specific configuration, which devices such as the entropy
+    /// source don't have
+    config: Option<*mut u8>,
+    /// Notification address of each queue that was set up
+    queue_notify: Vec<usize>,
+}
+
+unsafe impl Send for VirtIOPci {}
+unsafe impl Sync for VirtIOPci {}
+
+impl VirtIOPci {
+    /// Wraps a PCI function if it is a virtio device with the modern
+    /// (virtio 1.0) interface
+    pub fn new(device: pci::Device) -> Option<VirtIOPci> {
+        if device.vendor_id != VIRTIO_PCI_VENDOR_ID {
+            return None;
+        }
+        let device_id = match device.device_id {
+            // Transitional devices
+            0x1000..=0x103f => DeviceId::from(device.subsystem_id() as u32),
+            0x1040..=0x107f => DeviceId::from((device.device_id - 0x1040) as u32),
+            _ => return None,
+        };
+
+        let (mut common, mut notify, mut isr, mut config) = (None, None, None, None);
+        let mut notify_off_multiplier = 0;
+        for (id, cap) in device.capabilities() {
+            if id != PCI_CAP_ID_VNDR {
+                continue;
+            }
+            let address = match device.bars[device.read8(cap + 4) as usize % 6] {
+                Some(bar) if !bar.io => bar.address + device.read32(cap + 8) as usize,
+                _ => continue,
+            };
+            match device.read8(cap + 3) {
+                VIRTIO_PCI_CAP_COMMON_CFG => common = common.or(Some(address)),
+                VIRTIO_PCI_CAP_NOTIFY_CFG if notify.is_none() => {
+                    notify = Some(address);
+                    notify_off_multiplier = device.read32(cap + 16);
+                }
+                VIRTIO_PCI_CAP_ISR_CFG => isr = isr.or(Some(address)),
+                VIRTIO_PCI_CAP_DEVICE_CFG => config = config.or(Some(address)),
+                _ => {}
+            }
+        }
+
+        Some(VirtIOPci {
+            device,
+            device_id,
+            common: unsafe { &mut *(common? as *mut CommonCfg) },
+            notify: notify?,
+            notify_off_multiplier,
+            isr: isr? as *mut u8,
+            config: config.map(|config| config as *mut u8),
+            queue_notify: Vec::new(),
+        })
+    }
+
+    /// GIC interrupt the device signals
+    pub fn irq(&self) -> Option<u32> {
+        self.device.interrupt.irq()
+    }
+
+    /// MSI-X vector queue `index` signals. Vector 0 is for configuration
+    /// changes, each queue gets its own after that as long as they last.
+    fn queue_vector(&self, index: u16) -> Option<u16> {
+        match self.device.interrupt {
+            Interrupt::MsiX { count, .. } => Some((index as u32 + 1).min(count - 1) as u16),
+            _ => None,
+        }
+    }
+}
+
+impl Transport for VirtIOPci {
+    fn device_id(&self) -> DeviceId {
+        self.device_id
+    }
+
+    fn device_features(&mut self) -> u64 {
+        let common = &mut *self.common;
+        unsafe {
+            write_volatile(&mut common.device_feature_select, 0.into());
+            let low = read_volatile(&common.device_feature).native() as u64;
+            write_volatile(&mut common.device_feature_select, 1.into());
+            let high = read_volatile(&common.device_feature).native() as u64;
+            high << 32 | low
+        }
+    }
+
+    fn set_driver_features(&mut self, features: u64) {
+        let common = &mut *self.common;
+        unsafe {
+            write_volatile(&mut common.driver_feature_select, 0.into());
+            write_volatile(&mut common.driver_feature, (features as u32).into());
+            write_volatile(&mut common.driver_feature_select, 1.into());
+            write_volatile(&mut common.driver_feature, ((features >> 32) as u32).into());
+        }
+    }
+
+    fn status(&self) -> u32 {
+        unsafe { read_volatile(&self.common.device_status) as u32 }
+    }
+
+    fn set_status(&mut self, status: Status) {
+        unsafe { write_volatile(&mut self.common.device_status, status as u8) }
+    }
+
+    fn max_queue_size(&mut self, index: u16) -> u16 {
+        let common = &mut *self.common;
+        unsafe {
+            write_volatile(&mut common.queue_select, index.into());
+            read_volatile(&common.queue_size).native()
+        }
+    }
+
+    fn setup_queue(&mut self, index: u16, size: u16, (desc, driver, device): (u64, u64, u64)) {
+        let vector = self.queue_vector(index);
+        let common = &mut *self.common;
+        let notify_off = unsafe {
+            write_volatile(&mut common.queue_select, index.into());
+            write_volatile(&mut common.queue_size, size.into());
+            if let Some(vector) = vector {
+                write_volatile(&mut common.msix_config, 0.into());
+                write_volatile(&mut common.queue_msix_vector, vector.into());
+            } else {
+                write_volatile(&mut common.queue_msix_vector, VIRTIO_MSI_NO_VECTOR.into());
+            }
+            write_volatile(&mut common.queue_desc_low, (desc as u32).into());
+            write_volatile(&mut common.queue_desc_high, ((desc >> 32) as u32).into());
+            write_volatile(&mut common.queue_driver_low, (driver as u32).into());
+            write_volatile(
+                &mut common.queue_driver_high,
+                ((driver >> 32) as u32).into(),
+            );
+            write_volatile(&mut common.queue_device_low, (device as u32).into());
+            write_volatile(
+                &mut common.queue_device_high,
+                ((device >> 32) as u32).into(),
+            );
+            write_volatile(&mut common.queue_enable, 1.into());
+            read_volatile(&common.queue_notify_off).native()
+        };
+
+        if self.queue_notify.len() <= index as usize {
+            self.queue_notify.resize(index as usize + 1, 0);
+        }
+        self.queue_notify[index as usize] =
+            self.notify + notify_off as usize * self.notify_off_multiplier as usize;
+    }
+
+    fn notify(&mut self, index: u16) {
+        mb();
+        unsafe { write_volatile(self.queue_notify[index as usize] as *mut u16, index.to_le()) }
+    }
+
+    fn queue_irq(&self, index: u16) -> Option<u32> {
+        match self.device.interrupt {
+            Interrupt::MsiX { first, .. } => Some(first + self.queue_vector(index)? as u32),
+            _ => None,
+        }
+    }
+
+    fn ack_interrupt(&mut self) -> u32 {
+        // Reading the ISR status acknowledges it
+        unsafe { read_volatile(self.isr) as u32 }
+    }
+
+    fn config_generation(&self) -> u32 {
+        unsafe { read_volatile(&self.common.config_generation) as u32 }
+    }
+
+    /// Reads zeros if the device has no configuration
+    fn read_config(&self, offset: usize, data: &mut [u8]) {
+        let config = match self.config {
+            Some(config) => config,
+            None => return data.fill(0),
+        };
+        for (i, b) in data.iter_mut().enumerate() {
+            *b = unsafe { read_volatile(config.add(offset + i)) };
+        }
+    }
+
+    /// Ignored if the device has no configuration
+    fn write_config(&mut self, offset: usize, data: &[u8]) {
+        if let Some(config) = self.config {
+            for (i, b) in data.iter().enumerate() {
+                unsafe { write_volatile(config.add(offset + i), *b) };
+            }
+        }
+    }
+}
@@ -0,0 +1,693 @@
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::str::from_utf8;
+
+use crate::uart::UART;
+
+const SOH: u8 = 0x01;
+const STX: u8 = 0x02;
+const EOT: u8 = 0x04;
+const ACK: u8 = 0x06;
+const NAK: u8 = 0x15;
+const CAN: u8 = 0x18;
+/// What the last packet of an XMODEM transfer is padded with
+const SUB: u8 = 0x1a;
+/// Sent by a receiver that wants CRCs instead of NAK
+const CRC_START: u8 = b'C';
+
+/// Tries at a packet before giving up on it
+const MAX_ERRORS: u32 = 10;
+/// Time allowed for each byte once a packet has started
+const BYTE_TIMEOUT_MS: u64 = 1000;
+/// Time between a receiver's requests to start
+const START_INTERVAL_MS: u64 = 3000;
+/// Requests to start before giving up, which gives a minute to get the
+/// other side going
+const START_TRIES: u32 = 20;
+/// Time allowed for the next packet, or the answer to one
+const PACKET_TIMEOUT_MS: u64 = 10_000;
+
+/// A byte-at-a-time serial line
+pub trait Serial {
+    fn write_byte(&mut self, byte: u8);
+
+    /// The next byte, if one arrives within `timeout_ms` milliseconds
+    fn read_byte_timeout(&mut self, timeout_ms: u64) -> Option<u8>;
+}
+
+impl Serial for UART {
+    fn write_byte(&mut self, byte: u8) {
+        UART::write_byte(self, byte)
+    }
+
+    fn read_byte_timeout(&mut self, timeout_ms: u64) -> Option<u8> {
+        UART::read_byte_timeout(self, timeout_ms)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TransferError {
+    /// The other side didn't start, or stopped answering
+    Timeout,
+    /// The other side cancelled the transfer
+    Cancelled,
+    /// A packet failed too many times in a row
+    TooManyErrors,
+    /// A packet arrived out of order, so some data was lost
+    OutOfSequence,
+    /// A YMODEM header couldn't be parsed
+    BadHeader,
+    /// Reading or writing the data here failed, so the transfer was cancelled
+    Local(&'static str),
+}
+
+impl TransferError {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TransferError::Timeout => "timed out",
+            TransferError::Cancelled => "cancelled by the other side",
+            TransferError::TooManyErrors => "too many errors",
+            TransferError::OutOfSequence => "packet out of sequence",
+            TransferError::BadHeader => "bad YMODEM header",
+            TransferError::Local(err) => err,
+        }
+    }
+}
+
+/// CRC-16/XMODEM: polynomial 0x1021, starting from 0
+pub fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0, |crc, b| {
+        (0..8).fold(crc ^ (*b as u16) << 8, |crc, _| {
+            if crc & 0x8000 != 0 {
+                crc << 1 ^ 0x1021
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum: u8, b| sum.wrapping_add(*b))
+}
+
+/// Tells the other side to stop, and drains what it sent meanwhile
+fn cancel(serial: &mut dyn Serial) {
+    serial.write_byte(CAN);
+    serial.write_byte(CAN);
+    purge(serial);
+}
+
+/// Reads until the line has been quiet for a second, to resynchronise after
+/// a bad packet
+fn purge(serial: &mut dyn Serial) {
+    while serial.read_byte_timeout(BYTE_TIMEOUT_MS).is_some() {}
+}
+
+/// After one CAN, whether a second follows, which is how either side cancels
+fn cancelled(serial: &mut dyn Serial) -> bool {
+    serial.read_byte_timeout(BYTE_TIMEOUT_MS) == Some(CAN)
+}
+
+/// A file announced in a YMODEM batch
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct FileInfo {
+    /// The last component of the name the sender gave
+    pub name: String,
+    /// Length in bytes, if the sender said
+    pub size: Option<u64>,
+}
+
+enum Incoming {
+    /// A packet with its number, its data in the receive buffer
+    Packet(u8, usize),
+    End,
+    /// A packet that was cut short or failed its check
+    Damaged,
+}
+
+/// The receiving end of an XMODEM or YMODEM transfer
+pub struct Receiver<'a> {
+    serial: &'a mut dyn Serial,
+    /// Number, its complement, data and check of the last packet
+    buf: Vec<u8>,
+    /// Whether packets end in a CRC rather than a checksum
+    crc: bool,
+}
+
+impl<'a> Receiver<'a> {
+    pub fn new(serial: &'a mut dyn Serial) -> Receiver<'a> {
+        Receiver {
+            serial,
+            buf: vec![0; 2 + 1024 + 2],
+            crc: true,
+        }
+    }
+
+    /// Receives a file by XMODEM, with CRCs, or checksums if the sender
+    /// doesn't answer the request for CRCs. Passes the data to `write` as it
+    /// arrives, without the padding at the end of the last packet, and
+    /// returns its length.
+    pub fn xmodem(
+        &mut self,
+        write: &mut dyn FnMut(&[u8]) -> Result<(), &'static str>,
+    ) -> Result<u64, TransferError> {
+        let first = self.start(true)?;
+        self.data(first, None, write)
+    }
+
+    /// Waits for the header of the next file in a YMODEM batch. Returns None
+    /// once the sender says the batch is over.
+    pub fn next_file(&mut self) -> Result<Option<FileInfo>, TransferError> {
+        let mut incoming = self.start(false)?;
+        let mut errors = 0;
+        loop {
+            match incoming {
+                Incoming::Packet(0, len) => {
+                    let info = match parse_header(&self.buf[2..2 + len]) {
+                        Some(info) => info,
+                        None => {
+                            cancel(self.serial);
+                            return Err(TransferError::BadHeader);
+                        }
+                    };
+                    self.serial.write_byte(ACK);
+                    return Ok(Some(info).filter(|info| !info.name.is_empty()));
+                }
+                Incoming::Packet(..) => {
+                    cancel(self.serial);
+                    return Err(TransferError::OutOfSequence);
+                }
+                // A repeat of the end of the last file, whose ACK got lost
+                Incoming::End => self.serial.write_byte(ACK),
+                Incoming::Damaged => {
+                    errors += 1;
+                    if errors >= MAX_ERRORS {
+                        cancel(self.serial);
+                        return Err(TransferError::TooManyErrors);
+                    }
+                    self.serial.write_byte(NAK);
+                }
+            }
+            incoming = self.packet(PACKET_TIMEOUT_MS)?;
+        }
+    }
+
+    /// Receives the file `next_file` announced, passing at most its size in
+    /// bytes to `write`, and returns how many were passed
+    pub fn receive_file(
+        &mut self,
+        info: &FileInfo,
+        write: &mut dyn FnMut(&[u8]) -> Result<(), &'static str>,
+    ) -> Result<u64, TransferError> {
+        let first = self.start(false)?;
+        self.data(first, info.size, write)
+    }
+
+    /// Stops the transfer from this end
+    pub fn cancel(&mut self) {
+        cancel(self.serial);
+    }
+
+    /// Asks the sender to start until it does, and returns the first thing
+    /// it sends. Halfway through, switches to asking for checksums if
+    /// `allow_checksum`, for senders that don't know CRCs.
+    fn start(&mut self, allow_checksum: bool) -> Result<Incoming, TransferError> {
+        for attempt in 0..START_TRIES {
+            self.crc = !allow_checksum || attempt < START_TRIES / 2;
+            self.serial
+                .write_byte(if self.crc { CRC_START } else { NAK });
+            match self.packet(START_INTERVAL_MS) {
+                Err(TransferError::Timeout) => continue,
+                incoming => return incoming,
+            }
+        }
+        Err(TransferError::Timeout)
+    }
+
+    /// Waits up to `timeout_ms` for a packet to start, and reads it
+    fn packet(&mut self, timeout_ms: u64) -> Result<Incoming, TransferError> {
+        let len = loop {
+            match self.serial.read_byte_timeout(timeout_ms) {
+                Some(SOH) => break 128,
+                Some(STX) => break 1024,
+                Some(EOT) => return Ok(Incoming::End),
+                Some(CAN) if cancelled(self.serial) => return Err(TransferError::Cancelled),
+                // Line noise, or what a terminal sent before the transfer
+                Some(_) => {}
+                None => return Err(TransferError::Timeout),
+            }
+        };
+        let total = 2 + len + if self.crc { 2 } else { 1 };
+        for i in 0..total {
+            match self.serial.read_byte_timeout(BYTE_TIMEOUT_MS) {
+                Some(b) => self.buf[i] = b,
+                None => return Ok(Incoming::Damaged),
+            }
+        }
+        let data = &self.buf[2..2 + len];
+        let valid = self.buf[0] == !self.buf[1]
+            && if self.crc {
+                crc16(data) == u16::from_be_bytes([self.buf[2 + len], self.buf[3 + len]])
+            } else {
+                checksum(data) == self.buf[2 + len]
+            };
+        if !valid {
+            purge(self.serial);
+            return Ok(Incoming::Damaged);
+        }
+        Ok(Incoming::Packet(self.buf[0], len))
+    }
+
+    /// Receives data packets from `first` on until the end of the file.
+    /// Each packet's data is held back until the next arrives, so at the end
+    /// it can be cut to `size`, or without one have its padding dropped.
+    fn data(
+        &mut self,
+        first: Incoming,
+        size: Option<u64>,
+        write: &mut dyn FnMut(&[u8]) -> Result<(), &'static str>,
+    ) -> Result<u64, TransferError> {
+        let mut incoming = first;
+        let mut expected = 1u8;
+        let mut errors = 0;
+        let mut seen_end = false;
+        let mut pending = Vec::new();
+        let mut written = 0u64;
+        let mut deliver = |data: &[u8], serial: &mut dyn Serial, written: &mut u64| {
+            let len = match size {
+                Some(size) => (size.saturating_sub(*written) as usize).min(data.len()),
+                None => data.len(),
+            };
+            *written += len as u64;
+            write(&data[..len]).map_err(|err| {
+                cancel(serial);
+                TransferError::Local(err)
+            })
+        };
+        loop {
+            match incoming {
+                Incoming::Packet(number, len) if number == expected => {
+                    deliver(&pending, self.serial, &mut written)?;
+                    pending.clear();
+                    pending.extend_from_slice(&self.buf[2..2 + len]);
+                    expected = expected.wrapping_add(1);
+                    errors = 0;
+                    // An EOT followed by more data was line noise
+                    seen_end = false;
+                    self.serial.write_byte(ACK);
+                }
+                // Sent again because the ACK got lost
+                Incoming::Packet(number, _) if number == expected.wrapping_sub(1) => {
+                    self.serial.write_byte(ACK)
+                }
+                Incoming::Packet(..) => {
+                    cancel(self.serial);
+                    return Err(TransferError::OutOfSequence);
+                }
+                // The first EOT is NAKed, so one that's really line noise
+                // doesn't end the file early
+                Incoming::End if !seen_end => {
+                    seen_end = true;
+                    self.serial.write_byte(NAK);
+                }
+                Incoming::End => {
+                    self.serial.write_byte(ACK);
+                    if size.is_none() {
+                        let len = pending.iter().rposition(|b| *b != SUB).map_or(0, |i| i + 1);
+                        pending.truncate(len);
+                    }
+                    deliver(&pending, self.serial, &mut written)?;
+                    return Ok(written);
+                }
+                Incoming::Damaged => {
+                    errors += 1;
+                    if errors >= MAX_ERRORS {
+                        cancel(self.serial);
+                        return Err(TransferError::TooManyErrors);
+                    }
+                    self.serial.write_byte(NAK);
+                }
+            }
+            incoming = match self.packet(PACKET_TIMEOUT_MS) {
+                Err(TransferError::Timeout) if errors + 1 < MAX_ERRORS => Incoming::Damaged,
+                incoming => incoming?,
+            };
+        }
+    }
+}
+
+/// The name and size in a YMODEM header, or an empty name at the end of a
+/// batch
+fn parse_header(header: &[u8]) -> Option<FileInfo> {
+    let mut fields = header.split(|b| *b == 0);
+    let name = from_utf8(fields.next()?).ok()?;
+    let size = fields
+        .next()
+        .and_then(|rest| from_utf8(rest).ok())
+        .and_then(|rest| rest.split(' ').next())
+        .and_then(|size| size.parse::<u64>().ok());
+    // Only the last component, so a sender can't write outside the
+    // directory files are received into
+    let name = name.rsplit('/').next().unwrap_or("");
+    if name == "." || name == ".." {
+        return None;
+    }
+    Some(FileInfo {
+        name: name.into(),
+        size,
+    })
+}
+
+/// The sending end of an XMODEM or YMODEM transfer
+pub struct Sender<'a> {
+    serial: &'a mut dyn Serial,
+    /// Whether the receiver asked for CRCs rather than checksums
+    crc: bool,
+}
+
+impl<'a> Sender<'a> {
+    pub fn new(serial: &'a mut dyn Serial) -> Sender<'a> {
+        Sender { serial, crc: true }
+    }
+
+    /// Sends what `read` gives until it returns 0 by XMODEM, in 128-byte
+    /// packets the last of which is padded, and returns its length
+    pub fn xmodem(
+        &mut self,
+        read: &mut dyn FnMut(&mut [u8]) -> Result<usize, &'static str>,
+    ) -> Result<u64, TransferError> {
+        self.wait_start()?;
+        let sent = self.send_data(read, 128)?;
+        self.send_end()?;
+        Ok(sent)
+    }
+
+    /// Sends one file of a YMODEM batch: a header with its name and size,
+    /// then what `read` gives in 1KiB packets
+    pub fn ymodem_file(
+        &mut self,
+        name: &str,
+        size: u64,
+        read: &mut dyn FnMut(&mut [u8]) -> Result<usize, &'static str>,
+    ) -> Result<u64, TransferError> {
+        let mut header = Vec::from(name.as_bytes());
+        header.push(0);
+        header.extend_from_slice(size.to_string().as_bytes());
+        header.push(0);
+        self.wait_start()?;
+        self.send_packet(0, &header, if header.len() <= 128 { 128 } else { 1024 }, 0)?;
+        self.wait_start()?;
+        let sent = self.send_data(read, 1024)?;
+        self.send_end()?;
+        Ok(sent)
+    }
+
+    /// Ends a YMODEM batch with a header with no file in it
+    pub fn ymodem_end(&mut self) -> Result<(), TransferError> {
+        self.wait_start()?;
+        self.send_packet(0, &[], 128, 0)
+    }
+
+    /// Stops the transfer from this end
+    pub fn cancel(&mut self) {
+        cancel(self.serial);
+    }
+
+    /// Waits for the receiver to ask for packets, noting whether it wants
+    /// CRCs
+    fn wait_start(&mut self) -> Result<(), TransferError> {
+        for _ in 0..START_TRIES {
+            match self.serial.read_byte_timeout(START_INTERVAL_MS) {
+                Some(CRC_START) => {
+                    self.crc = true;
+                    return Ok(());
+                }
+                Some(NAK) => {
+                    self.crc = false;
+                    return Ok(());
+                }
+                Some(CAN) if cancelled(self.serial) => return Err(TransferError::Cancelled),
+                _ => {}
+            }
+        }
+        Err(TransferError::Timeout)
+    }
+
+    fn send_data(
+        &mut self,
+        read: &mut dyn FnMut(&mut [u8]) -> Result<usize, &'static str>,
+        packet_len: usize,
+    ) -> Result<u64, TransferError> {
+        let mut data = vec![0; packet_len];
+        let mut number = 1u8;
+        let mut sent = 0;
+        loop {
+            let mut filled = 0;
+            while filled < packet_len {
+                match read(&mut data[filled..]) {
+                    Ok(0) => break,
+                    Ok(len) => filled += len,
+                    Err(err) => {
+                        cancel(self.serial);
+                        return Err(TransferError::Local(err));
+                    }
+                }
+            }
+            if filled == 0 {
+                return Ok(sent);
+            }
+            self.send_packet(number, &data[..filled], packet_len, SUB)?;
+            number = number.wrapping_add(1);
+            sent += filled as u64;
+            if filled < packet_len {
+                return Ok(sent);
+            }
+        }
+    }
+
+    /// Sends `data` padded to `len` with `pad` as packet `number`, again
+    /// each time the receiver NAKs it, until it's ACKed
+    fn send_packet(
+        &mut self,
+        number: u8,
+        data: &[u8],
+        len: usize,
+        pad: u8,
+    ) -> Result<(), TransferError> {
+        let mut packet = Vec::with_capacity(len + 5);
+        packet.push(if len == 128 { SOH } else { STX });
+        packet.push(number);
+        packet.push(!number);
+        packet.extend_from_slice(data);
+        packet.resize(3 + len, pad);
+        if self.crc {
+            let crc = crc16(&packet[3..]);
+            packet.extend_from_slice(&crc.to_be_bytes());
+        } else {
+            let sum = checksum(&packet[3..]);
+            packet.push(sum);
+        }
+        for _ in 0..MAX_ERRORS {
+            packet.iter().for_each(|b| self.serial.write_byte(*b));
+            if self.answer()? {
+                return Ok(());
+            }
+        }
+        cancel(self.serial);
+        Err(TransferError::TooManyErrors)
+    }
+
+    /// Sends EOT until it's ACKed. Receivers NAK the first one.
+    fn send_end(&mut self) -> Result<(), TransferError> {
+        for _ in 0..MAX_ERRORS {
+            self.serial.write_byte(EOT);
+            if self.answer()? {
+                return Ok(());
+            }
+        }
+        cancel(self.serial);
+        Err(TransferError::TooManyErrors)
+    }
+
+    /// Whether the receiver ACKs what was just sent, or wants it again
+    fn answer(&mut self) -> Result<bool, TransferError> {
+        loop {
+            match self.serial.read_byte_timeout(PACKET_TIMEOUT_MS) {
+                Some(ACK) => return Ok(true),
+                // A receiver still asking to start didn't get the packet
+                Some(NAK) | Some(CRC_START) | None => return Ok(false),
+                Some(CAN) if cancelled(self.serial) => return Err(TransferError::Cancelled),
+                Some(_) => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::collections::VecDeque;
+
+    use super::*;
+
+    /// One end of a serial line, with the other end's side of the conversation
+    /// scripted: each chunk arrives once this end has written something since
+    /// the last, and until then the line is silent
+    struct ScriptedSerial {
+        chunks: VecDeque<Vec<u8>>,
+        arrived: VecDeque<u8>,
+        written: Vec<u8>,
+        /// How much had been written when the last chunk arrived
+        answered: usize,
+    }
+
+    impl ScriptedSerial {
+        fn new(first: &[u8], chunks: &[&[u8]]) -> ScriptedSerial {
+            ScriptedSerial {
+                chunks: chunks.iter().map(|chunk| chunk.to_vec()).collect(),
+                arrived: first.iter().copied().collect(),
+                written: Vec::new(),
+                answered: 0,
+            }
+        }
+    }
+
+    impl Serial for ScriptedSerial {
+        fn write_byte(&mut self, byte: u8) {
+            self.written.push(byte);
+        }
+
+        fn read_byte_timeout(&mut self, _timeout_ms: u64) -> Option<u8> {
+            if self.arrived.is_empty() && self.written.len() > self.answered {
+                self.answered = self.written.len();
+                self.arrived
+                    .extend(self.chunks.pop_front().unwrap_or_default());
+            }
+            self.arrived.pop_front()
+        }
+    }
+
+    #[test]
+    fn xmodem() {
+        const ACK: u8 = 0x06;
+        const NAK: u8 = 0x15;
+        const CAN: u8 = 0x18;
+        let data: Vec<u8> = (0..2000u32).map(|i| (i % 251) as u8).collect();
+        fn reader(mut source: &[u8]) -> impl FnMut(&mut [u8]) -> Result<usize, &'static str> + '_ {
+            move |buf| {
+                let len = buf.len().min(source.len());
+                buf[..len].copy_from_slice(&source[..len]);
+                source = &source[len..];
+                Ok(len)
+            }
+        }
+
+        // XMODEM, to a receiver that wants CRCs and NAKs the first EOT
+        let mut line = ScriptedSerial::new(b"C", &[&[ACK], &[ACK], &[ACK], &[NAK], &[ACK]]);
+        let sent = Sender::new(&mut line).xmodem(&mut reader(&data[..300]));
+        assert!(sent == Ok(300), "XMODEM send failed");
+        let packets = line.written;
+        assert!(packets.len() == 3 * 133 + 2, "wrong XMODEM packets");
+
+        // Which arrive with the second damaged the first time
+        let mut damaged = packets[133..266].to_vec();
+        damaged[50] ^= 0xff;
+        let chunks = [
+            &packets[..133],
+            &damaged,
+            &packets[133..266],
+            &packets[266..399],
+            &packets[399..400],
+            &packets[400..],
+        ];
+        let mut line = ScriptedSerial::new(&[], &chunks);
+        let mut received = Vec::new();
+        let len = Receiver::new(&mut line).xmodem(&mut |data| {
+            received.extend_from_slice(data);
+            Ok(())
+        });
+        assert!(len == Ok(300), "XMODEM receive failed");
+        assert!(received[..] == data[..300], "XMODEM data damaged");
+        assert!(
+            line.written == [b'C', ACK, NAK, ACK, ACK, NAK, ACK],
+            "wrong XMODEM answers"
+        );
+
+        // Line noise that looks like an EOT before the second packet, which
+        // mustn't count towards confirming the real one
+        let chunks = [
+            &packets[..133],
+            &packets[399..400],
+            &packets[133..266],
+            &packets[266..399],
+            &packets[399..400],
+            &packets[400..],
+        ];
+        let mut line = ScriptedSerial::new(&[], &chunks);
+        let len = Receiver::new(&mut line).xmodem(&mut |_| Ok(()));
+        assert!(len == Ok(300), "XMODEM receive after a stray EOT failed");
+        assert!(
+            line.written == [b'C', ACK, NAK, ACK, ACK, NAK, ACK],
+            "stray EOT confirmed the end"
+        );
+
+        // A YMODEM batch of one file, whose last packet is cut to its size
+        let mut line = ScriptedSerial::new(
+            b"C",
+            &[&[ACK, b'C'], &[ACK], &[ACK], &[NAK], &[ACK, b'C'], &[ACK]],
+        );
+        let mut sender = Sender::new(&mut line);
+        let sent = sender.ymodem_file("dir/boot.img", 2000, &mut reader(&data));
+        assert!(sent == Ok(2000), "YMODEM send failed");
+        assert!(sender.ymodem_end().is_ok(), "YMODEM batch end failed");
+        let packets = line.written;
+        assert!(
+            packets.len() == 133 + 2 * 1029 + 2 + 133,
+            "wrong YMODEM packets"
+        );
+        let chunks = [
+            &packets[..133],
+            &packets[133..1162],
+            &packets[1162..2191],
+            &packets[2191..2192],
+            &packets[2192..2193],
+            &packets[2193..],
+        ];
+        let mut line = ScriptedSerial::new(&[], &chunks);
+        let mut receiver = Receiver::new(&mut line);
+        let info = receiver.next_file();
+        let expected = FileInfo {
+            name: "boot.img".into(),
+            size: Some(2000),
+        };
+        assert!(info == Ok(Some(expected.clone())), "wrong YMODEM header");
+        let mut received = Vec::new();
+        let len = receiver.receive_file(&expected, &mut |data| {
+            received.extend_from_slice(data);
+            Ok(())
+        });
+        assert!(
+            len == Ok(2000) && received[..] == data[..],
+            "YMODEM data damaged"
+        );
+        assert!(receiver.next_file() == Ok(None), "YMODEM batch didn't end");
+        assert!(
+            line.written == [b'C', ACK, b'C', ACK, ACK, NAK, ACK, b'C', ACK],
+            "wrong YMODEM answers"
+        );
+
+        // A sender that never starts, then one that gives up
+        let mut line = ScriptedSerial::new(&[], &[]);
+        let len = Receiver::new(&mut line).xmodem(&mut |_| Ok(()));
+        assert!(len == Err(TransferError::Timeout), "silence not timed out");
+        assert!(
+            line.written.iter().filter(|b| **b == b'C').count() == 10
+                && line.written.iter().filter(|b| **b == NAK).count() == 10,
+            "didn't fall back to checksums"
+        );
+        let mut line = ScriptedSerial::new(&[], &[&[CAN, CAN]]);
+        let len = Receiver::new(&mut line).xmodem(&mut |_| Ok(()));
+        assert!(len == Err(TransferError::Cancelled), "cancel not noticed");
+    }
+}